@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -9,20 +10,31 @@ use egui_taffy::taffy::Overflow;
 use egui_taffy::taffy::Point;
 use egui_taffy::taffy::prelude::*;
 use indexmap::IndexMap;
+use network_common::SignatureAlgorithm;
 use web_time::Duration;
 use web_time::Instant;
+use zeroize::Zeroize;
 
 use crate::applets::*;
 use crate::data::AppState;
+use crate::data::AppletStore;
+use crate::data::ChangeSet;
+use crate::data::Cloud;
 use crate::data::CloudFileLoader;
 use crate::data::CloudMetadata;
+use crate::data::SchemaStatus;
 use crate::theme::setup_themes;
 
+/// Wait after a failed migration before trying again, doubled for each further failure.
+const MIGRATION_RETRY: Duration = Duration::from_secs(2);
+const MAX_MIGRATION_RETRY: Duration = Duration::from_secs(300);
+
 pub enum AppEvent {
     ActiveAppletChanged(String),
     ActiveCloudChanged,
-    /// An update was received from the remote. The ui should be resynced
-    RemoteCloudUpdate([u8; 32]),
+    /// Transactions were appended to a cloud journal, locally or from the remote. Applets
+    /// should reload what the changes touch.
+    CloudChanged([u8; 32], Arc<ChangeSet>),
 }
 
 pub enum ActionRequest {
@@ -30,19 +42,35 @@ pub enum ActionRequest {
     // the id to switch to
     SwitchCloud(Option<[u8; 32]>),
     UpdateCloudMetadata([u8; 32], CloudMetadata),
+    /// Remove a cloud from this device
+    DeleteCloud([u8; 32]),
+    /// Swap in a cloud rebuilt from the remote
+    ReplaceCloud(Arc<Cloud>),
+    /// Show the active cloud as it was after a journal index, `None` for the present
+    ViewAtIndex(Option<u64>),
+    /// Revert the active cloud to a journal index with a new transaction
+    RevertCloud(u64),
 }
 
 pub struct App {
     state: AppState,
     show_stats: bool,
     show_clouds_menu: bool,
+    /// Signature algorithm used when creating a cloud from the clouds menu.
+    new_cloud_algorithm: SignatureAlgorithm,
     last_render_time: Duration,
     active_applet: String,
     applets: IndexMap<String, Box<dyn Applet>>,
     showing_import: bool,
     import_key: String,
+    /// Open a public cloud by id instead of importing a private key.
+    import_public: bool,
     sync_status: HashMap<[u8; 32], String>,
     cloud_file_loader: Arc<CloudFileLoader>,
+    /// Clouds where every applet's migrations have been applied this session.
+    migrated_clouds: HashSet<[u8; 32]>,
+    /// Clouds whose migration failed, keyed to when to retry and the number of failures.
+    migration_failures: HashMap<[u8; 32], (Instant, u32)>,
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -112,6 +140,7 @@ impl App {
             Box::new(MailApplet::default()) as Box<dyn Applet>,
             Box::new(SettingsApplet::default()) as Box<dyn Applet>,
             Box::new(HistoryApplet::default()) as Box<dyn Applet>,
+            Box::new(TrashApplet::default()) as Box<dyn Applet>,
         ] {
             applet.as_mut().init(&state)?;
             applets.insert(applet.name().into(), applet);
@@ -128,10 +157,14 @@ impl App {
             show_stats: cfg!(debug_assertions),
             last_render_time: Duration::default(),
             show_clouds_menu: false,
+            new_cloud_algorithm: SignatureAlgorithm::default(),
             showing_import: false,
             import_key: String::default(),
+            import_public: false,
             sync_status: HashMap::default(),
             cloud_file_loader,
+            migrated_clouds: HashSet::default(),
+            migration_failures: HashMap::default(),
         };
 
         // on the web allow customizing the initial view
@@ -159,6 +192,12 @@ impl App {
                 if key == "clouds" {
                     out.show_clouds_menu = true;
                 }
+                // stable links to published clouds, e.g. `?public=<cloud id>`
+                if key == "public" {
+                    let cloud_id = out.state.open_public_cloud(&val)?;
+                    out.state.load_clouds()?;
+                    out.state.set_active_cloud(Some(cloud_id))?;
+                }
             }
             query_pairs_mut.finish();
             drop(query_pairs_mut);
@@ -177,6 +216,62 @@ impl App {
             .expect("failed to send app event");
     }
 
+    /// Apply pending applet migrations to the active cloud once it can be written to.
+    fn migrate_active_cloud(&mut self) {
+        let Some((cloud, _)) = self.state.active_cloud() else {
+            return;
+        };
+        if self.migrated_clouds.contains(cloud.id()) || !self.state.can_migrate(&cloud) {
+            return;
+        }
+        if let Some((retry_at, _)) = self.migration_failures.get(cloud.id())
+            && Instant::now() < *retry_at
+        {
+            return;
+        }
+        for applet in self.applets.values() {
+            if let Some(namespace) = applet.namespace()
+                && let Err(e) = AppletStore::new(&cloud.db, namespace).migrate(applet.migrations())
+            {
+                let failures = self
+                    .migration_failures
+                    .get(cloud.id())
+                    .map(|(_, failures)| failures + 1)
+                    .unwrap_or(1);
+                let retry = MIGRATION_RETRY
+                    .saturating_mul(2u32.saturating_pow(failures - 1))
+                    .min(MAX_MIGRATION_RETRY);
+                println!(
+                    "Error migrating {} applet, retrying in {}s: {:?}",
+                    applet.name(),
+                    retry.as_secs(),
+                    e
+                );
+                self.migration_failures
+                    .insert(*cloud.id(), (Instant::now() + retry, failures));
+                self.state.ctx.request_repaint_after(retry);
+                return;
+            }
+        }
+        self.migration_failures.remove(cloud.id());
+        self.migrated_clouds.insert(*cloud.id());
+    }
+
+    /// Bring a snapshot of an old index up to the current table layout so applets can read it.
+    /// The snapshot is in memory, nothing is written to the cloud.
+    fn migrate_snapshot(&self) {
+        let Some(cloud) = self.state.snapshot_cloud() else {
+            return;
+        };
+        for applet in self.applets.values() {
+            if let Some(namespace) = applet.namespace()
+                && let Err(e) = AppletStore::new(&cloud.db, namespace).migrate(applet.migrations())
+            {
+                println!("Error migrating {} applet snapshot: {:?}", applet.name(), e);
+            }
+        }
+    }
+
     fn handle_keyboard_input(&mut self, ctx: &egui::Context) {
         // Use CMD+num_key to switch to an applet
         let number_keys = [
@@ -236,15 +331,28 @@ impl App {
                         ui.heading("Clouds");
                         if ui.button("+").clicked() {
                             self.state
-                                .create_cloud(None)
+                                .create_cloud(None, self.new_cloud_algorithm)
                                 .expect("failed to create cloud");
                             self.state.load_clouds().expect("failed to load clouds");
                         }
                         if ui.button("import").clicked() {
                             self.showing_import = true;
-                            self.import_key = String::default();
+                            self.import_key.zeroize();
                         }
                     });
+                    egui::ComboBox::from_id_salt("new_cloud_algorithm")
+                        .selected_text(self.new_cloud_algorithm.name())
+                        .show_ui(ui, |ui| {
+                            for algorithm in SignatureAlgorithm::ALL {
+                                ui.selectable_value(
+                                    &mut self.new_cloud_algorithm,
+                                    algorithm,
+                                    algorithm.name(),
+                                );
+                            }
+                        })
+                        .response
+                        .on_hover_text("signature algorithm for new clouds");
                 });
 
                 ui.separator();
@@ -316,12 +424,18 @@ impl App {
         let response = egui::Modal::new("import cloud".into()).show(ctx, |ui| {
             ui.heading("Import an encrypted cloud");
             ui.add_space(4.0);
+            ui.checkbox(&mut self.import_public, "open a public cloud (read only)");
             let text_edit = egui::TextEdit::singleline(&mut self.import_key)
-                .hint_text("paste your private key here")
+                .hint_text(if self.import_public {
+                    "paste the public cloud id here"
+                } else {
+                    "paste your private key here"
+                })
+                .password(!self.import_public)
                 .desired_width(window_size.x);
             let input = ui.add(text_edit);
 
-            if self.import_key.trim().len() == 64 {
+            if self.import_key.trim().len() == 64 || self.import_key.trim().len() == 66 {
                 input.show_tooltip_ui(|ui| {
                     ui.label("press enter to import");
                 });
@@ -329,16 +443,21 @@ impl App {
 
             if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
                 self.showing_import = false;
-                self.import_key = String::default();
+                self.import_key.zeroize();
             }
 
             if input.lost_focus() && ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
-                match self.state.import_cloud(&self.import_key) {
+                let imported = if self.import_public {
+                    self.state.open_public_cloud(&self.import_key)
+                } else {
+                    self.state.import_cloud(&self.import_key)
+                };
+                match imported {
                     Ok(cloud_id) => {
                         self.state.load_clouds().unwrap();
                         self.state.set_active_cloud(Some(cloud_id)).unwrap();
                         self.showing_import = false;
-                        self.import_key = String::default();
+                        self.import_key.zeroize();
                     }
                     Err(_) => {}
                 }
@@ -349,13 +468,13 @@ impl App {
             ui.vertical_centered(|ui| {
                 if ui.button("cancel").clicked() {
                     self.showing_import = false;
-                    self.import_key = String::default();
+                    self.import_key.zeroize();
                 }
             });
         });
         if response.should_close() {
             self.showing_import = false;
-            self.import_key = String::default();
+            self.import_key.zeroize();
         }
     }
 
@@ -380,7 +499,12 @@ impl App {
                 .show(|tui| {
                     if let Some((cloud, metadata)) = self.state.active_cloud() {
                         tui.label(&metadata.name);
-                        if let Some(status) = self.sync_status.get(cloud.id()) {
+                        if let Some(index) = self.state.viewed_index() {
+                            tui.label(format!("Viewing mutation #{}, read only", index + 1));
+                            if tui.button(|tui| tui.label("Return to present")).clicked() {
+                                self.state.view_at_index(None);
+                            }
+                        } else if let Some(status) = self.sync_status.get(cloud.id()) {
                             tui.label(status);
                         } else {
                             tui.label("Initializing...");
@@ -393,6 +517,20 @@ impl App {
     }
 }
 
+/// Schema of the applet's data in the active cloud. Applets without a namespace are always
+/// current.
+fn schema_status(state: &AppState, applet: &dyn Applet) -> SchemaStatus {
+    let (Some(namespace), Some((cloud, _))) = (applet.namespace(), state.active_cloud()) else {
+        return SchemaStatus::Current;
+    };
+    AppletStore::new(&cloud.db, namespace)
+        .status(applet.migrations())
+        .unwrap_or_else(|e| {
+            println!("Error reading {} schema version: {:?}", applet.name(), e);
+            SchemaStatus::Current
+        })
+}
+
 impl eframe::App for App {
     /// Called by the framework to save state before shutdown.
     fn save(&mut self, _storage: &mut dyn eframe::Storage) {
@@ -465,15 +603,28 @@ impl eframe::App for App {
             self.render_clouds_menu(ctx);
         }
 
+        self.migrate_active_cloud();
+        if let Err(e) = self.state.collect_changes() {
+            println!("Error collecting cloud changes: {:?}", e);
+        }
         let pending_events: Vec<_> = self.state.pending_events.1.drain().collect();
         if !pending_events.is_empty() {
             for applet in self.applets.values_mut() {
+                // we can't read tables laid out by a newer client
+                if matches!(
+                    schema_status(&self.state, applet.as_ref()),
+                    SchemaStatus::Newer(_)
+                ) {
+                    continue;
+                }
                 applet
                     .handle_app_events(&pending_events, &self.state)
                     .expect(&format!("applet {} failed to handle events", applet.name()));
             }
             for event in &pending_events {
-                if matches!(event, AppEvent::RemoteCloudUpdate(_)) {
+                if let AppEvent::CloudChanged(_, changes) = event
+                    && Cloud::metadata_changed(changes).unwrap_or(true)
+                {
                     // TODO: de duplicate this
                     self.state.reload_clouds();
                 }
@@ -487,7 +638,29 @@ impl eframe::App for App {
 
         // applet content renderer
         if let Some(applet) = self.applets.get_mut(&self.active_applet) {
-            applet.render(ctx, &self.state);
+            match schema_status(&self.state, applet.as_ref()) {
+                SchemaStatus::Newer(version) => {
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        ui.heading(applet.name());
+                        ui.label(format!(
+                            "This cloud was upgraded by a newer version of the app (schema {version}, we support {}). Update the app to open it.",
+                            applet.migrations().len()
+                        ));
+                    });
+                }
+                SchemaStatus::Outdated
+                    if self
+                        .state
+                        .active_cloud()
+                        .is_some_and(|(cloud, _)| cloud.is_read_only()) =>
+                {
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        ui.heading(applet.name());
+                        ui.label("This cloud hasn't been upgraded by its owner yet.");
+                    });
+                }
+                _ => applet.render(ctx, &self.state),
+            }
         } else {
             egui::Window::new("unknown applet")
                 .resizable(true)
@@ -519,6 +692,31 @@ impl eframe::App for App {
                         .set_active_cloud(cloud_id)
                         .expect("failed to set active cloud");
                 }
+                ActionRequest::DeleteCloud(cloud_id) => {
+                    self.migrated_clouds.remove(&cloud_id);
+                    self.migration_failures.remove(&cloud_id);
+                    self.state
+                        .delete_cloud(cloud_id)
+                        .expect("failed to delete cloud");
+                }
+                ActionRequest::ReplaceCloud(cloud) => {
+                    self.state
+                        .replace_cloud(cloud)
+                        .expect("failed to replace cloud");
+                }
+                ActionRequest::RevertCloud(index) => {
+                    if let Some((cloud, _)) = self.state.live_active_cloud() {
+                        // the revert may restore tables laid out by older migrations
+                        self.migrated_clouds.remove(cloud.id());
+                    }
+                    if let Err(e) = self.state.revert_active_cloud(index) {
+                        println!("Error reverting cloud: {:?}", e);
+                    }
+                }
+                ActionRequest::ViewAtIndex(index) => match self.state.set_view_index(index) {
+                    Ok(()) => self.migrate_snapshot(),
+                    Err(e) => println!("Error viewing cloud at index: {:?}", e),
+                },
             }
         }
     }
@@ -13,7 +13,20 @@ use egui_taffy::taffy::prelude::*;
 use super::Applet;
 use crate::app::AppEvent;
 use crate::data::AppState;
+use crate::data::AppletStore;
+use crate::data::Cloud;
+use crate::data::FILES_NAMESPACE;
+use crate::data::FILES_TABLE;
+use crate::data::Migration;
+use crate::data::TransferRecord;
+use crate::data::Trash;
 use crate::widgets::ConfirmButton;
+use crate::widgets::TransferMenu;
+use crate::widgets::TransferTarget;
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    move_tables: &[("files", "files/files")],
+}];
 
 #[derive(Default)]
 pub struct FilesApplet {
@@ -26,6 +39,10 @@ pub struct FilesApplet {
 }
 
 impl FilesApplet {
+    fn files_table(cloud: &Cloud) -> String {
+        AppletStore::new(&cloud.db, FILES_NAMESPACE).table(FILES_TABLE)
+    }
+
     #[cfg(target_arch = "wasm32")]
     fn download_selected_file(&mut self) -> Result<()> {
         let blob = gloo_file::Blob::new_with_options(self.selected_file_bytes.as_slice(), None);
@@ -65,9 +82,14 @@ impl FilesApplet {
 
     fn delete_selected_file(&mut self, state: &AppState) -> Result<()> {
         if let Some((active_cloud, _)) = state.active_cloud() {
-            active_cloud
-                .db
-                .remove::<String, Bytes>("files", &self.selected_filename)?;
+            if active_cloud.is_read_only() {
+                anyhow::bail!("cannot delete a file from a read only cloud");
+            }
+            Trash::new(&active_cloud).delete(
+                "file",
+                &self.selected_filename,
+                &Self::file_records(&active_cloud, &self.selected_filename),
+            )?;
             self.selected_filename = String::default();
             self.selected_file_bytes = Vec::default();
             self.load_files(state)?;
@@ -75,11 +97,35 @@ impl FilesApplet {
         Ok(())
     }
 
+    fn add_file(&mut self, state: &AppState) -> Result<()> {
+        let (cloud, _) = state
+            .active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        // public clouds and past versions shown by `AppState::set_view_index`
+        if cloud.is_read_only() {
+            anyhow::bail!("cannot add a file to a read only cloud");
+        }
+        let files_table = Self::files_table(&cloud);
+        // a file in the trash still holds its name until it's restored or purged
+        if Trash::new(&cloud).is_deleted(&files_table, Some(&self.add_file_name))? {
+            anyhow::bail!("{} is in the trash", self.add_file_name);
+        }
+        cloud.db.insert::<String, Bytes>(
+            &files_table,
+            &self.add_file_name,
+            &std::mem::take(&mut self.add_file_bytes).into(),
+        )?;
+        self.load_files(state)?;
+        self.showing_add_file_window = false;
+        self.add_file_name = String::default();
+        Ok(())
+    }
+
     fn load_selected_file(&mut self, state: &AppState) {
         if let Some((cloud, _)) = state.active_cloud() {
             self.selected_file_bytes = cloud
                 .db
-                .get::<String, Bytes>("files", &self.selected_filename)
+                .get::<String, Bytes>(&Self::files_table(&cloud), &self.selected_filename)
                 .unwrap_or_else(|e| {
                     println!("WARNING: failed to load selected file: {e:?}");
                     None
@@ -98,7 +144,57 @@ impl FilesApplet {
             return Ok(());
         }
         let (cloud, _metadata) = active_cloud.unwrap();
-        self.filenames = cloud.db.list_keys::<String>("files")?;
+        let files_table = Self::files_table(&cloud);
+        let deleted = Trash::new(&cloud).deleted_keys(&files_table)?;
+        self.filenames = cloud
+            .db
+            .list_keys::<String>(&files_table)?
+            .into_iter()
+            .filter(|filename| !deleted.contains(filename))
+            .collect();
+        Ok(())
+    }
+
+    fn file_records(cloud: &Cloud, filename: &str) -> [TransferRecord; 1] {
+        [TransferRecord::Entry(
+            Self::files_table(cloud),
+            filename.to_string(),
+        )]
+    }
+
+    /// Write the version of a file shown by `AppState::set_view_index` to the present.
+    fn restore_file(&self, filename: &str, state: &AppState) -> Result<()> {
+        let (cloud, _) = state
+            .active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        state.restore_from_snapshot(&Self::file_records(&cloud, filename))
+    }
+
+    fn transfer_file(
+        &mut self,
+        filename: &str,
+        target: TransferTarget,
+        state: &AppState,
+    ) -> Result<()> {
+        let (cloud, _) = state
+            .active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        let records = Self::file_records(&cloud, filename);
+        state.transfer(
+            cloud.id(),
+            &target.cloud_id,
+            FILES_NAMESPACE,
+            MIGRATIONS,
+            &records,
+            target.remove_source,
+        )?;
+        if target.remove_source {
+            if self.selected_filename == filename {
+                self.selected_filename = String::default();
+                self.selected_file_bytes = Vec::default();
+            }
+            self.load_files(state)?;
+        }
         Ok(())
     }
 
@@ -122,20 +218,8 @@ impl FilesApplet {
                 }
 
                 if input.lost_focus() && ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
-                    if let Some((cloud, _)) = state.active_cloud() {
-                        cloud
-                            .db
-                            .insert::<String, Bytes>(
-                                "files",
-                                &self.add_file_name,
-                                &std::mem::take(&mut self.add_file_bytes).into(),
-                            )
-                            .expect("failed to add file");
-                        self.load_files(state).ok();
-                        self.showing_add_file_window = false;
-                        self.add_file_name = String::default();
-                    } else {
-                        println!("WARNING: no active cloud!");
+                    if let Err(e) = self.add_file(state) {
+                        println!("failed to add file! {:?}", e);
                     }
                 }
                 input.request_focus();
@@ -156,6 +240,9 @@ impl FilesApplet {
     }
 
     fn render_file_info(&mut self, ctx: &egui::Context, state: &AppState) {
+        let read_only = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| cloud.is_read_only());
         let viewport_size = ctx.screen_rect();
         egui::SidePanel::right("file_info")
             .default_width((viewport_size.width() / 2.0).min(500.0))
@@ -189,6 +276,9 @@ impl FilesApplet {
                         .add(|tui| {
                             tui.heading(&self.selected_filename);
                             tui.ui(|ui| {
+                                if read_only {
+                                    return;
+                                }
                                 let delete_button =
                                     ConfirmButton::init("file_info_delete".to_string(), ui, &|b| {
                                         b.text = "Delete".to_string();
@@ -297,6 +387,14 @@ impl Applet for FilesApplet {
         "Files"
     }
 
+    fn namespace(&self) -> Option<&'static str> {
+        Some(FILES_NAMESPACE)
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        MIGRATIONS
+    }
+
     fn handle_app_events(
         &mut self,
         events: &Vec<crate::app::AppEvent>,
@@ -308,8 +406,12 @@ impl Applet for FilesApplet {
                     self.load_files(state)?;
                     self.selected_filename = String::default();
                 }
-                AppEvent::RemoteCloudUpdate(cloud_id) => {
-                    if cloud_id == &state.active_cloud_id.unwrap_or_default() {
+                AppEvent::CloudChanged(cloud_id, changes) => {
+                    if cloud_id == &state.active_cloud_id.unwrap_or_default()
+                        && let Some((cloud, _)) = state.active_cloud()
+                        && (changes.matches(&Self::files_table(&cloud))
+                            || changes.matches(&Trash::new(&cloud).items_table()))
+                    {
                         self.load_files(state)?;
                     }
                 }
@@ -325,8 +427,15 @@ impl Applet for FilesApplet {
     }
 
     fn render(&mut self, ctx: &egui::Context, state: &AppState) {
+        let read_only = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| cloud.is_read_only());
         ctx.input(|i| {
             if !i.raw.dropped_files.is_empty() {
+                if read_only {
+                    println!("WARNING: cannot add files to a read only cloud");
+                    return;
+                }
                 if i.raw.dropped_files.len() > 1 {
                     println!("WARNING: may only drop 1 file at a time");
                     return;
@@ -404,8 +513,10 @@ impl Applet for FilesApplet {
                             .on_hover_cursor(egui::CursorIcon::Help);
                     });
                     let mut selected_file_changed = false;
+                    let mut transfer = None;
+                    let mut restore = None;
                     for name in &self.filenames {
-                        if tui
+                        let response = tui
                             .style(Style {
                                 flex_direction: FlexDirection::Row,
                                 justify_content: Some(JustifyContent::SpaceBetween),
@@ -415,16 +526,36 @@ impl Applet for FilesApplet {
                             })
                             .selectable(&self.selected_filename == name, |tui| {
                                 tui.heading(name);
-                            })
-                            .clicked()
-                        {
+                            });
+                        if response.clicked() {
                             self.selected_filename = name.clone();
                             selected_file_changed = true;
                         }
+                        response.context_menu(|ui| {
+                            if state.viewed_index().is_some() {
+                                if ui.button("Restore this version").clicked() {
+                                    restore = Some(name.clone());
+                                    ui.close();
+                                }
+                            } else if let Some(target) = TransferMenu::show(ui, state) {
+                                transfer = Some((name.clone(), target));
+                                ui.close();
+                            }
+                        });
                     }
                     if selected_file_changed {
                         self.load_selected_file(state);
                     }
+                    if let Some(name) = restore
+                        && let Err(e) = self.restore_file(&name, state)
+                    {
+                        println!("failed to restore file! {:?}", e);
+                    }
+                    if let Some((name, target)) = transfer
+                        && let Err(e) = self.transfer_file(&name, target, state)
+                    {
+                        println!("failed to transfer file! {:?}", e);
+                    }
                 });
         });
     }
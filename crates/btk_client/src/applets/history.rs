@@ -8,6 +8,7 @@ use egui_taffy::taffy::prelude::*;
 
 use crate::app::AppEvent;
 use crate::data::AppState;
+use crate::widgets::ConfirmButton;
 
 use super::Applet;
 
@@ -17,12 +18,14 @@ pub struct HistoryApplet {
     showing_create_duplicate_modal: bool,
     duplicate_index: u64,
     duplicate_cloud_name: String,
+    /// Mutation number selected on the timeline, the cloud is viewed after it.
+    timeline_index: usize,
 }
 
 impl HistoryApplet {
     fn render_create_duplicate_modal(&mut self, ctx: &egui::Context, state: &AppState) {
         let modal = egui::Modal::new("create_duplicate_modal".into()).show(ctx, |ui| {
-            let active_cloud = state.active_cloud();
+            let active_cloud = state.live_active_cloud();
             if active_cloud.is_none() {
                 println!("WARNING: no active cloud");
                 self.showing_create_duplicate_modal = false;
@@ -65,13 +68,41 @@ impl HistoryApplet {
     }
 
     fn reload_history(&mut self, state: &AppState) -> Result<()> {
-        if let Some((active_cloud, _metadata)) = state.active_cloud() {
+        if let Some((active_cloud, _metadata)) = state.live_active_cloud() {
             self.history = active_cloud.db.journal_transactions()?;
         } else {
             self.history = Vec::default();
         }
+        self.timeline_index = match state.viewed_index() {
+            Some(index) => index as usize + 1,
+            None => self.history.len(),
+        };
         Ok(())
     }
+
+    /// Slider over the mutations, releasing it shows every applet the cloud at that point.
+    fn render_timeline(&mut self, ui: &mut egui::Ui, state: &AppState) {
+        if self.history.is_empty() {
+            return;
+        }
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::Slider::new(&mut self.timeline_index, 1..=self.history.len())
+                    .text("view as of mutation"),
+            );
+            if response.drag_stopped() || (response.changed() && !response.dragged()) {
+                if self.timeline_index == self.history.len() {
+                    state.view_at_index(None);
+                } else {
+                    state.view_at_index(Some(self.timeline_index as u64 - 1));
+                }
+            }
+            if state.viewed_index().is_some() && ui.button("Return to present").clicked() {
+                self.timeline_index = self.history.len();
+                state.view_at_index(None);
+            }
+        });
+    }
 }
 
 impl Applet for HistoryApplet {
@@ -90,8 +121,10 @@ impl Applet for HistoryApplet {
                 AppEvent::ActiveCloudChanged => {
                     self.reload_history(state)?;
                 }
-                AppEvent::RemoteCloudUpdate(_) => {
-                    self.reload_history(state)?;
+                AppEvent::CloudChanged(cloud_id, _) => {
+                    if cloud_id == &state.active_cloud_id.unwrap_or_default() {
+                        self.reload_history(state)?;
+                    }
                 }
             }
         }
@@ -124,6 +157,10 @@ impl Applet for HistoryApplet {
                 })
                 .show(|tui| {
                     tui.heading("Cloud history");
+                    let can_revert = state
+                        .live_active_cloud()
+                        .is_some_and(|(cloud, _)| !cloud.is_read_only());
+                    tui.ui(|ui| self.render_timeline(ui, state));
                     for (i, tx) in self.history.iter().rev().enumerate() {
                         let index = self.history.len() - i;
                         tui.style(Style {
@@ -153,6 +190,27 @@ impl Applet for HistoryApplet {
                                     self.duplicate_index = (index - 1) as u64;
                                     self.duplicate_cloud_name = String::default();
                                 }
+                                if tui.button(|tui| tui.label("View")).clicked() {
+                                    self.timeline_index = index;
+                                    state.view_at_index(Some((index - 1) as u64));
+                                }
+                                if can_revert && index < self.history.len() {
+                                    tui.ui(|ui| {
+                                        let revert_button = ConfirmButton::init(
+                                            format!("history_revert_{index}"),
+                                            ui,
+                                            &|b| {
+                                                b.text = "Revert to here".to_string();
+                                                b.confirm_text =
+                                                    "Undo every later mutation?".to_string();
+                                            },
+                                        );
+                                        if revert_button.confirmed() {
+                                            state.revert_cloud((index - 1) as u64);
+                                        }
+                                        ui.add(revert_button);
+                                    });
+                                }
                             });
                             tui.style(Style {
                                 flex_direction: FlexDirection::Column,
@@ -1,7 +1,6 @@
 /// This widget is superseded by the clouds menu in app.rs
 ///
 ///
-use std::collections::HashSet;
 use std::sync::Arc;
 
 use egui_taffy::Tui;
@@ -9,15 +8,15 @@ use egui_taffy::TuiBuilderLogic;
 use egui_taffy::taffy::Overflow;
 use egui_taffy::taffy::Point;
 use egui_taffy::taffy::prelude::*;
+use network_common::SignatureAlgorithm;
 
 use super::Applet;
 use crate::data::AppState;
 use crate::data::*;
+use crate::widgets::SecretLabel;
 
 #[derive(Default)]
-pub struct HomeApplet {
-    showing_private_key: HashSet<[u8; 32]>,
-}
+pub struct HomeApplet;
 
 impl HomeApplet {
     fn render_cloud_cell(
@@ -45,14 +44,18 @@ impl HomeApplet {
                 tui.heading(&format!("{}", metadata.name));
                 tui.label(&format!("created at: {}", metadata.created_at));
                 tui.label(&format!("cloud id: {}", cloud.id_hex()));
-                if self.showing_private_key.contains(cloud.id()) {
-                    tui.label(&format!("cloud key: {}", hex::encode(cloud.private_key())));
-                } else {
+                if let Some(exported_key) = cloud.export_key() {
                     tui.ui(|ui| {
-                        if ui.button("show private key").clicked() {
-                            self.showing_private_key.insert(*cloud.id());
-                        }
+                        ui.horizontal(|ui| {
+                            ui.label("cloud key:");
+                            ui.add(SecretLabel::new(
+                                ("home_cloud_key", cloud.id()),
+                                exported_key.as_str(),
+                            ));
+                        });
                     });
+                } else {
+                    tui.label("read only public cloud");
                 }
             });
             tui.style(Style {
@@ -123,7 +126,9 @@ impl Applet for HomeApplet {
                             tui.heading("Your encrypted clouds");
                             tui.ui(|ui| {
                                 if ui.button("+").clicked() {
-                                    state.create_cloud(None).expect("failed to create cloud");
+                                    state
+                                        .create_cloud(None, SignatureAlgorithm::default())
+                                        .expect("failed to create cloud");
                                     state.reload_clouds();
                                 }
                             });
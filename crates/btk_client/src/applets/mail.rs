@@ -9,8 +9,47 @@ impl Applet for MailApplet {
         "Mail"
     }
 
-    fn render(&mut self, ctx: &egui::Context, _state: &AppState) {
+    fn render(&mut self, ctx: &egui::Context, state: &AppState) {
         egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Invites");
+            ui.horizontal(|ui| {
+                ui.label("your invite address:");
+                ui.label(state.mailbox.recipient_id_hex());
+                if ui.button("copy").clicked() {
+                    ui.ctx().copy_text(state.mailbox.recipient_id_hex());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("received through:");
+                ui.label(state.mailbox.http_url());
+            })
+            .response
+            .on_hover_text("change in the settings of a cloud synchronized with another server");
+            let invites = state.pending_invites.read().unwrap().clone();
+            if invites.is_empty() {
+                ui.label("no pending invites");
+            }
+            for invite in invites {
+                ui.separator();
+                ui.label(format!("cloud: {}", hex::encode(invite.cloud_id())));
+                ui.label(format!("received at: {}", invite.received_at));
+                if !invite.invite.note.is_empty() {
+                    ui.label(&invite.invite.note);
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("accept").clicked() {
+                        match state.accept_invite(&invite) {
+                            Ok(cloud_id) => state.switch_cloud(Some(cloud_id)),
+                            Err(e) => println!("Error accepting invite! {:?}", e),
+                        }
+                    }
+                    if ui.button("decline").clicked() {
+                        state.decline_invite(&invite).ok();
+                    }
+                });
+            }
+
+            ui.separator();
             ui.heading("actually fuck gmail");
             ui.label("they built a rest api and put pop3/imap behind oauth");
             ui.label("this is a direct attack on information ownership");
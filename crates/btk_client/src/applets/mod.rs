@@ -7,6 +7,7 @@ mod mail;
 mod notes;
 mod settings;
 mod tasks;
+mod trash;
 
 pub use files::FilesApplet;
 pub use history::HistoryApplet;
@@ -14,9 +15,11 @@ pub use mail::MailApplet;
 pub use notes::NotesApplet;
 pub use settings::SettingsApplet;
 pub use tasks::TasksApplet;
+pub use trash::TrashApplet;
 
 use crate::app::AppEvent;
 use crate::data::AppState;
+use crate::data::Migration;
 
 pub struct DefaultApplet;
 impl Applet for DefaultApplet {}
@@ -30,6 +33,18 @@ pub trait Applet {
         "unimplemented"
     }
 
+    /// Prefix of the applet's tables in each cloud, see `AppletStore`. `None` for applets that
+    /// don't store data in clouds.
+    fn namespace(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Migrations of the namespace, oldest first. The schema version is the number of
+    /// migrations.
+    fn migrations(&self) -> &'static [Migration] {
+        &[]
+    }
+
     fn handle_app_events(&mut self, _events: &Vec<AppEvent>, _state: &AppState) -> Result<()> {
         Ok(())
     }
@@ -14,7 +14,16 @@ use egui_taffy::taffy::prelude::*;
 use super::Applet;
 use crate::app::AppEvent;
 use crate::data::AppState;
+use crate::data::AppletStore;
+use crate::data::Cloud;
+use crate::data::FILES_NAMESPACE;
+use crate::data::FILES_TABLE;
+use crate::data::Migration;
+use crate::data::TransferRecord;
+use crate::data::Trash;
 use crate::widgets::ConfirmButton;
+use crate::widgets::TransferMenu;
+use crate::widgets::TransferTarget;
 
 #[derive(Default, PartialEq)]
 enum LastScrolled {
@@ -23,8 +32,16 @@ enum LastScrolled {
     Rendered,
 }
 
-/// Table in anondb reserved for notes applet
-const NOTES_TABLE_NAME: &str = "notes";
+const NOTES_NAMESPACE: &str = "notes";
+/// Note names keyed to `()`. Each note has a table of diffs, see `note_table`.
+const NAMES_TABLE: &str = "names";
+/// Note names keyed to `(saved, unsaved)` content, in `Cloud::local` so edits survive a
+/// restart without being uploaded.
+const DRAFTS_TABLE: &str = "drafts";
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    move_tables: &[("notes", "notes/names"), ("note-*", "notes/note-*")],
+}];
 
 /// Inputs we sometimes want to explicitly focus
 const INPUT_NOTE_NAME: &str = "name_text_input";
@@ -54,18 +71,84 @@ pub struct NotesApplet {
 }
 
 impl NotesApplet {
-    fn table_name(note_name: &str) -> String {
-        format!("note-{}", note_name)
+    fn names_table(cloud: &Cloud) -> String {
+        AppletStore::new(&cloud.db, NOTES_NAMESPACE).table(NAMES_TABLE)
+    }
+
+    fn note_table(cloud: &Cloud, note_name: &str) -> String {
+        AppletStore::new(&cloud.db, NOTES_NAMESPACE).table(&format!("note-{}", note_name))
+    }
+
+    fn drafts_table(cloud: &Cloud) -> String {
+        AppletStore::new(&cloud.local, NOTES_NAMESPACE).table(DRAFTS_TABLE)
+    }
+
+    /// Keep the unsaved changes of the open note on this device, or forget them once saved.
+    fn store_draft(&self, state: &AppState) -> Result<()> {
+        let Some((cloud, _)) = state.active_cloud() else {
+            return Ok(());
+        };
+        if cloud.is_read_only() || self.active_note_name.is_empty() {
+            return Ok(());
+        }
+        if self.active_note_unsaved == self.active_note {
+            cloud.local.remove::<String, (String, String)>(
+                &Self::drafts_table(&cloud),
+                &self.active_note_name,
+            )?;
+        } else {
+            cloud.local.insert(
+                &Self::drafts_table(&cloud),
+                &self.active_note_name,
+                &(self.active_note.clone(), self.active_note_unsaved.clone()),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Apply a draft left by `store_draft` to the freshly loaded note.
+    fn restore_draft(&mut self, state: &AppState) -> Result<()> {
+        let Some((cloud, _)) = state.active_cloud() else {
+            return Ok(());
+        };
+        let Some((saved, unsaved)) = cloud
+            .local
+            .get::<String, (String, String)>(&Self::drafts_table(&cloud), &self.active_note_name)?
+        else {
+            return Ok(());
+        };
+        self.active_note_unsaved = match diffy::merge(&saved, &unsaved, &self.active_note) {
+            Ok(merged) => merged,
+            Err(_e) => {
+                println!(
+                    "draft of {} conflicts with the saved note, keeping the draft",
+                    self.active_note_name
+                );
+                unsaved
+            }
+        };
+        Ok(())
+    }
+
+    /// The diffs and name entry of a note, for the trash, transfers and restores.
+    fn note_records(cloud: &Cloud, note_name: &str) -> [TransferRecord; 2] {
+        [
+            TransferRecord::Table(Self::note_table(cloud, note_name)),
+            TransferRecord::Entry(Self::names_table(cloud), note_name.to_string()),
+        ]
     }
 
     fn reload_note_names(&mut self, state: &AppState) -> Result<()> {
         if let Some((active_cloud, _)) = state.active_cloud() {
+            let names_table = Self::names_table(&active_cloud);
+            let deleted = Trash::new(&active_cloud).deleted_keys(&names_table)?;
             self.note_names = active_cloud
                 .db
-                .find_many::<String, (), _>(NOTES_TABLE_NAME, |_, _| true)
+                .find_many::<String, (), _>(&names_table, |_, _| true)
                 .unwrap_or(vec![])
                 .into_iter()
                 .map(|(name, _)| name)
+                .filter(|name| !deleted.contains(name))
                 .collect::<Vec<_>>();
         }
         Ok(())
@@ -106,7 +189,7 @@ impl NotesApplet {
             }
         }
 
-        Ok(())
+        self.store_draft(state)
     }
 
     /// Attempt to open a note by loading all diffs and applying them in sequence.
@@ -119,22 +202,7 @@ impl NotesApplet {
             }
         };
 
-        // load the diffs and apply them to construct the latest state
-        let mut active_note = String::default();
-        let tx = active_cloud.db.begin_read()?;
-        let table = tx.open_table(Journal::table_definition(&Self::table_name(&note_name)))?;
-        let mut range = table.range::<anondb::Bytes>(..)?;
-        while let Some(entry) = range.next() {
-            let (_index_bytes, bytes) = entry?;
-            // let index: u64 = index_bytes.value().into();
-            // println!("{}", index);
-            let bytes = bytes.value();
-            let diff = diffy::Patch::from_str((&bytes).into())?;
-            // println!("{diff}");
-            active_note = diffy::apply(&active_note, &diff)?;
-        }
-
-        Ok(active_note)
+        note_content(&active_cloud, note_name)
     }
 
     fn delete(&mut self, note_name: &str, state: &AppState) -> Result<()> {
@@ -145,17 +213,22 @@ impl NotesApplet {
                 return Ok(());
             }
         };
+        // public clouds and past versions shown by `AppState::set_view_index`
+        if active_cloud.is_read_only() {
+            anyhow::bail!("cannot delete a note from a read only cloud");
+        }
 
-        let mut tx = active_cloud.db.begin_write()?;
-        // Delete history of changes
-        tx.delete_table(&Self::table_name(note_name))?;
+        // Hide the note until the trash is purged, the diffs and name stay in place
+        Trash::new(&active_cloud).delete(
+            "note",
+            note_name,
+            &Self::note_records(&active_cloud, note_name),
+        )?;
 
-        // Delete note from names table
-        let mut note_names_table = tx.open_table(NOTES_TABLE_NAME)?;
-        note_names_table.remove(&note_name)?;
-        drop(note_names_table);
-
-        tx.commit()?;
+        active_cloud.local.remove::<String, (String, String)>(
+            &Self::drafts_table(&active_cloud),
+            &note_name.to_string(),
+        )?;
 
         self.active_note = String::default();
         self.active_note_unsaved = String::default();
@@ -174,6 +247,7 @@ impl NotesApplet {
         self.active_note = self.load_note(note_name, state)?;
         self.active_note_unsaved = self.active_note.clone();
         self.active_note_name = note_name.to_string();
+        self.restore_draft(state)?;
 
         Ok(())
     }
@@ -190,33 +264,89 @@ impl NotesApplet {
                 return Ok(());
             }
         };
+        if active_cloud.is_read_only() {
+            anyhow::bail!("cannot save a note to a read only cloud");
+        }
+
+        // a note in the trash still holds its name until it's restored or purged
+        if Trash::new(&active_cloud).is_deleted(
+            &Self::names_table(&active_cloud),
+            Some(&self.active_note_name),
+        )? {
+            anyhow::bail!("{} is in the trash", self.active_note_name);
+        }
 
         // We'll save each note to its own table. Each entry in the table represents a diff from
         // the previous version.
         let mut tx = active_cloud.db.begin_write()?;
 
         // Save our text diff for the current note
-        let mut note_table = tx.open_table(&Self::table_name(&self.active_note_name))?;
+        let mut note_table =
+            tx.open_table(&Self::note_table(&active_cloud, &self.active_note_name))?;
         let diff = diffy::create_patch(&self.active_note, &self.active_note_unsaved);
         let diff_index = note_table.len()?;
         note_table.insert_bytes(&diff_index.into(), &diff.to_string().into())?;
         drop(note_table);
 
         // Make sure our note is registered in the list of notes
-        let mut note_names_table = tx.open_table(NOTES_TABLE_NAME)?;
+        let mut note_names_table = tx.open_table(&Self::names_table(&active_cloud))?;
         note_names_table.insert(&self.active_note_name, &())?;
         drop(note_names_table);
 
         tx.commit()?;
 
         self.active_note = self.active_note_unsaved.clone();
+        self.store_draft(state)?;
 
         self.reload_note_names(state)?;
 
         Ok(())
     }
 
+    /// Send a note and its diffs to another cloud. Unsaved changes to the open note are saved
+    /// first, moving it closes it.
+    fn transfer_note(
+        &mut self,
+        note_name: &str,
+        target: TransferTarget,
+        state: &AppState,
+    ) -> Result<()> {
+        let (cloud, _) = state
+            .active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        if self.active_note_name == note_name && self.active_note_unsaved != self.active_note {
+            self.save(state)?;
+        }
+        let records = Self::note_records(&cloud, note_name);
+        state.transfer(
+            cloud.id(),
+            &target.cloud_id,
+            NOTES_NAMESPACE,
+            MIGRATIONS,
+            &records,
+            target.remove_source,
+        )?;
+        if target.remove_source {
+            if self.active_note_name == note_name {
+                self.reset_note_state();
+            }
+            self.reload_note_names(state)?;
+        }
+        Ok(())
+    }
+
+    /// Write the version of a note shown by `AppState::set_view_index` to the present.
+    fn restore_note(&self, note_name: &str, state: &AppState) -> Result<()> {
+        let (cloud, _) = state
+            .active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        state.restore_from_snapshot(&Self::note_records(&cloud, note_name))
+    }
+
     fn render_side_list(&mut self, ctx: &egui::Context, state: &AppState) {
+        let read_only = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| cloud.is_read_only());
         egui::SidePanel::left("notes_list")
             .resizable(true)
             .show(ctx, |ui| {
@@ -249,7 +379,7 @@ impl NotesApplet {
                                 ..Default::default()
                             })
                             .ui(|ui| {
-                                if ui.button("+").clicked() {
+                                if !read_only && ui.button("+").clicked() {
                                     self.reset_note_state();
                                     ctx.memory_mut(|mem| {
                                         mem.request_focus(INPUT_NOTE_NAME.into());
@@ -257,8 +387,10 @@ impl NotesApplet {
                                 }
                             });
                         });
+                        let mut transfer = None;
+                        let mut restore = None;
                         for name in self.note_names.clone() {
-                            if tui
+                            let response = tui
                                 .style(Style {
                                     padding: length(4.0),
                                     margin: length(2.0),
@@ -266,24 +398,97 @@ impl NotesApplet {
                                 })
                                 .selectable(name == self.active_note_name, |tui| {
                                     tui.label(&name);
-                                })
-                                .clicked()
-                            {
+                                });
+                            if response.clicked() {
                                 ctx.memory_mut(|mem| mem.request_focus(INPUT_NOTE_SOURCE.into()));
                                 self.open(&name, state)
                                     .expect(&format!("failed to open note: {name}"));
                             }
+                            response.context_menu(|ui| {
+                                if state.viewed_index().is_some() {
+                                    if ui.button("Restore this version").clicked() {
+                                        restore = Some(name.clone());
+                                        ui.close();
+                                    }
+                                } else if let Some(target) = TransferMenu::show(ui, state) {
+                                    transfer = Some((name.clone(), target));
+                                    ui.close();
+                                }
+                            });
+                        }
+                        if let Some(name) = restore
+                            && let Err(e) = self.restore_note(&name, state)
+                        {
+                            println!("failed to restore note! {:?}", e);
+                        }
+                        if let Some((name, target)) = transfer
+                            && let Err(e) = self.transfer_note(&name, target, state)
+                        {
+                            println!("failed to transfer note! {:?}", e);
                         }
                     });
             });
     }
 }
 
+/// Load the diffs of a note and apply them in sequence.
+fn note_content(cloud: &Cloud, note_name: &str) -> Result<String> {
+    let mut content = String::default();
+    let tx = cloud.db.begin_read()?;
+    let table = tx.open_table(Journal::table_definition(&NotesApplet::note_table(
+        cloud, note_name,
+    )))?;
+    let mut range = table.range::<anondb::Bytes>(..)?;
+    while let Some(entry) = range.next() {
+        let (_index_bytes, bytes) = entry?;
+        let bytes = bytes.value();
+        let diff = diffy::Patch::from_str((&bytes).into())?;
+        content = diffy::apply(&content, &diff)?;
+    }
+    Ok(content)
+}
+
+/// Replace the diffs of each note with a single diff of its content. Old diffs stay in the
+/// journal, only the note tables shrink and open faster. Returns the number of diffs removed.
+pub(super) fn compact_notes(cloud: &Cloud) -> Result<u64> {
+    let mut compacted = Vec::default();
+    for note_name in cloud
+        .db
+        .list_keys::<String>(&NotesApplet::names_table(cloud))?
+    {
+        let table_name = NotesApplet::note_table(cloud, &note_name);
+        let diffs = cloud.db.count::<Bytes, Bytes>(&table_name)?;
+        if diffs > 1 {
+            compacted.push((table_name, diffs, note_content(cloud, &note_name)?));
+        }
+    }
+    let mut removed = 0;
+    let mut tx = cloud.db.begin_write()?;
+    for (table_name, diffs, content) in compacted {
+        tx.delete_table(&table_name)?;
+        let mut note_table = tx.open_table(&table_name)?;
+        let diff = diffy::create_patch("", &content);
+        note_table.insert_bytes(&0u64.into(), &diff.to_string().into())?;
+        drop(note_table);
+        removed += diffs - 1;
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
 impl Applet for NotesApplet {
     fn name(&self) -> &str {
         "Notes"
     }
 
+    fn namespace(&self) -> Option<&'static str> {
+        Some(NOTES_NAMESPACE)
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        MIGRATIONS
+    }
+
     fn handle_app_events(&mut self, events: &Vec<AppEvent>, state: &AppState) -> Result<()> {
         for event in events {
             match event {
@@ -305,12 +510,22 @@ impl Applet for NotesApplet {
                     self.reload_note_names(state)?;
                     self.reset_note_state();
                 }
-                AppEvent::RemoteCloudUpdate(cloud_id) => {
-                    if cloud_id == &state.active_cloud_id.unwrap_or_default() {
+                AppEvent::CloudChanged(cloud_id, changes) => {
+                    if cloud_id != &state.active_cloud_id.unwrap_or_default() {
+                        continue;
+                    }
+                    let Some((cloud, _)) = state.active_cloud() else {
+                        continue;
+                    };
+                    if changes.matches(&Self::names_table(&cloud))
+                        || changes.matches(&Trash::new(&cloud).items_table())
+                    {
                         self.reload_note_names(state)?;
-                        if !self.active_note_name.is_empty() {
-                            self.merge_if_needed(state)?;
-                        }
+                    }
+                    if !self.active_note_name.is_empty()
+                        && changes.matches(&Self::note_table(&cloud, &self.active_note_name))
+                    {
+                        self.merge_if_needed(state)?;
                     }
                 }
             }
@@ -336,6 +551,9 @@ impl Applet for NotesApplet {
 
         self.render_side_list(ctx, state);
 
+        let read_only = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| cloud.is_read_only());
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.horizontal_top(|ui| {
                 let response = egui::TextEdit::singleline(&mut self.active_note_name)
@@ -350,7 +568,8 @@ impl Applet for NotesApplet {
                         self.active_note_unsaved = String::default();
                     }
                 }
-                if response.has_focus()
+                if !read_only
+                    && response.has_focus()
                     && self.active_note.is_empty()
                     && self.active_note_name.len() > 3
                 {
@@ -358,7 +577,8 @@ impl Applet for NotesApplet {
                         ui.label("Press enter to create");
                     });
                 }
-                if response.lost_focus()
+                if !read_only
+                    && response.lost_focus()
                     && ui.input(|i| i.key_pressed(egui::Key::Enter))
                     && self.active_note_name.len() > 0
                 {
@@ -381,17 +601,22 @@ impl Applet for NotesApplet {
                 // {
                 //     self.is_showing_history = !self.is_showing_history;
                 // }
-                if self.active_note != self.active_note_unsaved {
+                if !read_only && self.active_note != self.active_note_unsaved {
                     let save_pressed =
                         ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::S));
                     let button = egui::Button::new("Save").shortcut_text("Cmd+S");
                     if ui.add(button).clicked() || save_pressed {
-                        self.save(state).expect("failed to save");
+                        if let Err(e) = self.save(state) {
+                            println!("failed to save note! {:?}", e);
+                        }
                     }
                     // we have unsaved changes
                     ui.colored_label(Color32::RED, "unsaved changes!");
                 }
-                if !self.active_note.trim().is_empty() && !self.active_note_name.trim().is_empty() {
+                if !read_only
+                    && !self.active_note.trim().is_empty()
+                    && !self.active_note_name.trim().is_empty()
+                {
                     if ui
                         .button("Export")
                         .on_hover_text("Export as file in cloud")
@@ -401,7 +626,8 @@ impl Applet for NotesApplet {
                             cloud
                                 .db
                                 .insert::<String, Bytes>(
-                                    "files",
+                                    &AppletStore::new(&cloud.db, FILES_NAMESPACE)
+                                        .table(FILES_TABLE),
                                     &self.active_note_name,
                                     &self.active_note.as_bytes().into(),
                                 )
@@ -409,7 +635,7 @@ impl Applet for NotesApplet {
                         }
                     }
                 }
-                if !self.active_note_name.trim().is_empty() {
+                if !read_only && !self.active_note_name.trim().is_empty() {
                     let button = ConfirmButton::init("note_delete_confirm".into(), ui, &|b| {
                         b.text = "Delete".to_string();
                         b.confirm_text = "Are you sure?".to_string();
@@ -443,15 +669,21 @@ impl Applet for NotesApplet {
                                 .id_salt("source_scroll_area")
                                 .show(ui, |ui| {
                                     ui.set_height(available_height);
-                                    TextEdit::multiline(&mut self.active_note_unsaved)
+                                    let output = TextEdit::multiline(&mut self.active_note_unsaved)
                                         .id(INPUT_NOTE_SOURCE.into())
                                         .frame(false)
                                         .hint_text("Your markdown text here...")
+                                        .interactive(!read_only)
                                         .clip_text(true)
                                         // subtract one to avoid scroll bars on an empty text
                                         // editor :roll_eyes:
                                         .desired_rows(desired_rows.max(1) - 1)
                                         .show(ui);
+                                    if output.response.changed()
+                                        && let Err(e) = self.store_draft(state)
+                                    {
+                                        println!("failed to store note draft! {:?}", e);
+                                    }
                                 });
 
                             self.last_source_height =
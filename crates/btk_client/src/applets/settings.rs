@@ -1,16 +1,51 @@
+use std::sync::Arc;
+use std::sync::RwLock;
+
 use anyhow::Result;
 use egui::Color32;
+use network_common::SignatureAlgorithm;
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
 
 use crate::app::ActionRequest;
 use crate::app::AppEvent;
 use crate::applets::Applet;
+use crate::applets::notes::compact_notes;
 use crate::data::AppState;
+use crate::data::Cloud;
+use crate::data::IntegrityReport;
+use crate::data::Mailbox;
+use crate::data::MailboxServer;
+use crate::data::RemoteCloud;
+use crate::data::Repair;
+use crate::data::StorageStats;
+use crate::data::format_bytes;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::network::TlsTrust;
+use crate::tokio;
 use crate::widgets::ConfirmButton;
 use crate::widgets::EditableLabel;
+use crate::widgets::SecretLabel;
 
 #[derive(Default)]
 pub struct SettingsApplet {
     new_remote_url: String,
+    invite_recipient: String,
+    invite_note: String,
+    invite_status: Arc<RwLock<Option<String>>>,
+    publish_status: Arc<RwLock<Option<String>>>,
+    /// Last mutation to publish, defaults to the latest confirmed one.
+    publish_upto: Option<u64>,
+    delete_status: Arc<RwLock<Option<String>>>,
+    integrity_status: Arc<RwLock<Option<String>>>,
+    /// Last `RemoteCloud::verify` result for the active cloud.
+    integrity_report: Arc<RwLock<Option<IntegrityReport>>>,
+    storage_stats: Arc<RwLock<Option<StorageStats>>>,
+    storage_status: Arc<RwLock<Option<String>>>,
+    creation_token: String,
+    tls_fingerprint: String,
+    tls_ca_pem: String,
+    tls_status: Option<String>,
 }
 
 impl Applet for SettingsApplet {
@@ -26,8 +61,21 @@ impl Applet for SettingsApplet {
                 }
                 AppEvent::ActiveCloudChanged => {
                     self.new_remote_url = String::default();
+                    self.invite_recipient = String::default();
+                    self.invite_note = String::default();
+                    *self.invite_status.write().unwrap() = None;
+                    *self.publish_status.write().unwrap() = None;
+                    self.publish_upto = None;
+                    *self.delete_status.write().unwrap() = None;
+                    *self.integrity_status.write().unwrap() = None;
+                    *self.integrity_report.write().unwrap() = None;
+                    *self.storage_stats.write().unwrap() = None;
+                    *self.storage_status.write().unwrap() = None;
+                    self.tls_fingerprint = String::default();
+                    self.tls_ca_pem = String::default();
+                    self.tls_status = None;
                 }
-                AppEvent::RemoteCloudUpdate(_cloud_id) => {
+                AppEvent::CloudChanged(_cloud_id, _changes) => {
                     // nothing to handle
                 }
             }
@@ -107,13 +155,24 @@ impl Applet for SettingsApplet {
             });
             ui.separator();
             ui.horizontal(|ui| {
-                ui.label("key:");
-                ui.label(hex::encode(active_cloud.private_key()));
+                ui.label("signature algorithm:");
+                ui.label(active_cloud.signature_algorithm().name());
             });
-            ui.colored_label(
-                Color32::RED,
-                "WARNING: sharing this key irreversibly shares access to this cloud!",
-            );
+            if let Some(exported_key) = active_cloud.export_key() {
+                ui.horizontal(|ui| {
+                    ui.label("key:");
+                    ui.add(SecretLabel::new(
+                        ("cloud_key", active_cloud_id),
+                        exported_key.as_str(),
+                    ));
+                });
+                ui.colored_label(
+                    Color32::RED,
+                    "WARNING: sharing this key irreversibly shares access to this cloud!",
+                );
+            } else {
+                ui.label("This is a public cloud. It can be read but not changed.");
+            }
 
             ui.separator();
             ui.horizontal(|ui| {
@@ -126,9 +185,15 @@ impl Applet for SettingsApplet {
                     state.delete_cloud(*active_cloud.id()).ok();
                 }
                 ui.add(delete_button);
-                ui.label("Cloud data will be deleted from this device only");
+                ui.label("Cloud data will be moved to the trash on this device only");
             });
 
+            self.render_storage(ui, &active_cloud);
+
+            if let Some(private_key) = active_cloud.private_key() {
+                self.render_invite(ui, state, private_key, active_cloud.signature_algorithm());
+            }
+
             ui.separator();
             ui.label("Remote connection");
             let remote = state
@@ -174,6 +239,470 @@ impl Applet for SettingsApplet {
                     }
                 }
             });
+            ui.horizontal(|ui| {
+                ui.label("invites:");
+                let server = MailboxServer {
+                    http_url: remote.http_url(),
+                    tls_trust: remote.tls_trust(),
+                };
+                if state.mailbox.server() == server {
+                    ui.label("received through this server");
+                } else if ui.button("receive invites through this server").clicked()
+                    && let Err(e) = state.set_mailbox_server(server)
+                {
+                    println!("failed to change mailbox server! {:?}", e);
+                }
+            });
+            #[cfg(not(target_arch = "wasm32"))]
+            self.render_tls(ui, &remote);
+            self.render_integrity(ui, state, &remote);
+
+            if !active_cloud.is_read_only() && remote.latest_confirmed_index().is_none() {
+                ui.horizontal(|ui| {
+                    ui.label("invite token:");
+                    ui.add(egui::TextEdit::singleline(&mut self.creation_token).password(true))
+                        .on_hover_text(
+                            "required to create clouds on invite only or private servers",
+                        );
+                    if ui.button("save").clicked()
+                        && remote.set_creation_token(&self.creation_token).is_ok()
+                    {
+                        self.creation_token.zeroize();
+                    }
+                });
+            }
+
+            if !active_cloud.is_read_only() {
+                self.render_publish(ui, &remote);
+                self.render_delete_remote(ui, state, &remote);
+            }
+        });
+    }
+}
+
+impl SettingsApplet {
+    /// Trust a self-signed or privately issued remote certificate.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn render_tls(&mut self, ui: &mut egui::Ui, remote: &RemoteCloud) {
+        let tls_trust = remote.tls_trust();
+        ui.horizontal(|ui| {
+            ui.label("certificate:");
+            match &tls_trust {
+                TlsTrust::System => ui.label("system roots"),
+                TlsTrust::PinnedCertificate(fingerprint) => {
+                    ui.label(format!("pinned {}", hex::encode(fingerprint)))
+                }
+                TlsTrust::CustomCa(_) => ui.label("custom CA"),
+            };
+            if tls_trust != TlsTrust::System && ui.button("use system roots").clicked() {
+                self.tls_status = remote
+                    .set_tls_trust(TlsTrust::System)
+                    .err()
+                    .map(|e| e.to_string());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("pin fingerprint:");
+            ui.text_edit_singleline(&mut self.tls_fingerprint)
+                .on_hover_text("SHA-256 fingerprint of the remote certificate");
+            if ui.button("pin").clicked() {
+                match TlsTrust::pinned(&self.tls_fingerprint)
+                    .and_then(|tls_trust| remote.set_tls_trust(tls_trust))
+                {
+                    Ok(()) => {
+                        self.tls_fingerprint = String::default();
+                        self.tls_status = None;
+                    }
+                    Err(e) => self.tls_status = Some(format!("invalid fingerprint: {e}")),
+                }
+            }
+        });
+        ui.label("trusted CA certificates (PEM):");
+        ui.add(egui::TextEdit::multiline(&mut self.tls_ca_pem).desired_rows(3));
+        if ui.button("trust CA").clicked() {
+            match TlsTrust::custom_ca(&self.tls_ca_pem)
+                .and_then(|tls_trust| remote.set_tls_trust(tls_trust))
+            {
+                Ok(()) => {
+                    self.tls_ca_pem = String::default();
+                    self.tls_status = None;
+                }
+                Err(e) => self.tls_status = Some(format!("invalid CA certificate: {e}")),
+            }
+        }
+        if let Some(status) = &self.tls_status {
+            ui.colored_label(Color32::RED, status);
+        }
+    }
+
+    /// Where the space in the cloud goes, with an option to compact note histories.
+    fn render_storage(&mut self, ui: &mut egui::Ui, cloud: &Arc<Cloud>) {
+        ui.separator();
+        ui.label("Storage");
+        ui.horizontal(|ui| {
+            if ui.button("Compute usage").clicked() {
+                self.compute_storage_stats(ui.ctx(), cloud, None);
+            }
+            if !cloud.is_read_only()
+                && ui
+                    .button("Compact notes")
+                    .on_hover_text("replace each note's history with its current content")
+                    .clicked()
+            {
+                self.compact_notes(ui.ctx(), cloud);
+            }
+            if let Some(status) = self.storage_status.read().unwrap().as_ref() {
+                ui.label(status);
+            }
+        });
+        let storage_stats = self.storage_stats.read().unwrap();
+        let Some(stats) = storage_stats.as_ref() else {
+            return;
+        };
+        ui.label(format!(
+            "journal: {} changes, {} encrypted",
+            stats.journal_len,
+            format_bytes(stats.mutation_bytes)
+        ));
+        egui::Grid::new("storage_by_namespace")
+            .striped(true)
+            .show(ui, |ui| {
+                ui.label("applet");
+                ui.label("keys");
+                ui.label("size");
+                ui.label("journaled");
+                ui.end_row();
+                for (namespace, table_stats) in stats.by_namespace() {
+                    ui.label(namespace);
+                    ui.label(table_stats.keys.to_string());
+                    ui.label(format_bytes(table_stats.bytes));
+                    ui.label(format_bytes(table_stats.journal_bytes));
+                    ui.end_row();
+                }
+            });
+        ui.collapsing("Tables", |ui| {
+            egui::Grid::new("storage_by_table")
+                .striped(true)
+                .show(ui, |ui| {
+                    for (table_name, table_stats) in &stats.tables {
+                        ui.label(table_name);
+                        ui.label(table_stats.keys.to_string());
+                        ui.label(format_bytes(table_stats.bytes));
+                        ui.label(format_bytes(table_stats.journal_bytes));
+                        ui.end_row();
+                    }
+                });
         });
+        ui.collapsing("Largest entries", |ui| {
+            for entry in &stats.largest_entries {
+                ui.label(format!(
+                    "{} {}: {}",
+                    entry.table_name,
+                    entry.key,
+                    format_bytes(entry.bytes)
+                ));
+            }
+        });
+        ui.label("Compacting doesn't shrink the journal, duplicate the cloud from History to drop old changes.");
+    }
+
+    /// Walk the cloud's tables and journal in the background, showing `status` when done.
+    fn compute_storage_stats(
+        &self,
+        ctx: &egui::Context,
+        cloud: &Arc<Cloud>,
+        status: Option<String>,
+    ) {
+        let cloud = cloud.clone();
+        let storage_stats = self.storage_stats.clone();
+        let storage_status = self.storage_status.clone();
+        let ctx = ctx.clone();
+        *storage_status.write().unwrap() = Some("computing usage...".to_string());
+        tokio::spawn(async move {
+            let status = refresh_storage_stats(&cloud, &storage_stats, status);
+            *storage_status.write().unwrap() = status;
+            ctx.request_repaint();
+        });
+    }
+
+    /// Compact the notes in the background, then recompute usage so the savings show.
+    fn compact_notes(&self, ctx: &egui::Context, cloud: &Arc<Cloud>) {
+        let cloud = cloud.clone();
+        let storage_stats = self.storage_stats.clone();
+        let storage_status = self.storage_status.clone();
+        let ctx = ctx.clone();
+        *storage_status.write().unwrap() = Some("compacting notes...".to_string());
+        tokio::spawn(async move {
+            let status = match compact_notes(&cloud) {
+                Ok(removed) => format!("removed {removed} note diffs"),
+                Err(e) => format!("failed to compact notes: {e}"),
+            };
+            let status = refresh_storage_stats(&cloud, &storage_stats, Some(status));
+            *storage_status.write().unwrap() = status;
+            ctx.request_repaint();
+        });
+    }
+
+    /// Verify the local journal against the remote and offer repairs.
+    fn render_integrity(&mut self, ui: &mut egui::Ui, state: &AppState, remote: &RemoteCloud) {
+        ui.separator();
+        ui.label("Integrity");
+        ui.horizontal(|ui| {
+            if ui.button("Verify").clicked() {
+                let remote = remote.clone();
+                let integrity_status = self.integrity_status.clone();
+                let integrity_report = self.integrity_report.clone();
+                let ctx = ui.ctx().clone();
+                *integrity_status.write().unwrap() = Some("verifying...".to_string());
+                tokio::spawn(async move {
+                    let status = match remote.verify().await {
+                        Ok(report) => {
+                            let status = report.to_string();
+                            *integrity_report.write().unwrap() = Some(report);
+                            status
+                        }
+                        Err(e) => format!("failed to verify: {e}"),
+                    };
+                    *integrity_status.write().unwrap() = Some(status);
+                    ctx.request_repaint();
+                });
+            }
+            if let Some(status) = self.integrity_status.read().unwrap().as_ref() {
+                ui.label(status);
+            }
+        });
+        let suggested_repair = self
+            .integrity_report
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|report| report.suggested_repair());
+        ui.horizontal(|ui| {
+            let rebuild_button =
+                ConfirmButton::init("confirm_cloud_rebuild".to_string(), ui, &|b| {
+                    b.text = "Rebuild from remote".to_string();
+                    b.confirm_text = "Replace local data with the remote's?".to_string();
+                });
+            if rebuild_button.confirmed() {
+                let remote = remote.clone();
+                let integrity_status = self.integrity_status.clone();
+                let integrity_report = self.integrity_report.clone();
+                let pending_requests = state.pending_requests.0.clone();
+                let ctx = ui.ctx().clone();
+                *integrity_status.write().unwrap() = Some("rebuilding...".to_string());
+                tokio::spawn(async move {
+                    let status = match remote.rebuild().await {
+                        Ok(cloud) => {
+                            pending_requests
+                                .send(ActionRequest::ReplaceCloud(cloud))
+                                .ok();
+                            *integrity_report.write().unwrap() = None;
+                            "rebuilt from remote".to_string()
+                        }
+                        Err(e) => format!("failed to rebuild: {e}"),
+                    };
+                    *integrity_status.write().unwrap() = Some(status);
+                    ctx.request_repaint();
+                });
+            }
+            ui.add(rebuild_button);
+            if suggested_repair == Some(Repair::Rebuild) {
+                ui.colored_label(Color32::RED, "suggested");
+            }
+        });
+        if !remote.cloud.is_read_only() {
+            ui.horizontal(|ui| {
+                if ui
+                    .button("Confirm again")
+                    .on_hover_text("compare every local change with the remote again")
+                    .clicked()
+                {
+                    let status = match remote.reset_confirmations() {
+                        Ok(()) => "confirmations reset".to_string(),
+                        Err(e) => format!("failed to reset confirmations: {e}"),
+                    };
+                    *self.integrity_status.write().unwrap() = Some(status);
+                    *self.integrity_report.write().unwrap() = None;
+                }
+                if suggested_repair == Some(Repair::Reconfirm) {
+                    ui.colored_label(Color32::RED, "suggested");
+                }
+            });
+        }
+        ui.colored_label(
+            Color32::RED,
+            "WARNING: rebuilding loses local changes that aren't on the remote!",
+        );
+    }
+
+    fn render_invite(
+        &mut self,
+        ui: &mut egui::Ui,
+        state: &AppState,
+        cloud_key: &[u8; 32],
+        signature_algorithm: SignatureAlgorithm,
+    ) {
+        ui.separator();
+        ui.label("Invite someone to this cloud");
+        ui.horizontal(|ui| {
+            ui.label("invite address:");
+            ui.text_edit_singleline(&mut self.invite_recipient);
+        });
+        ui.horizontal(|ui| {
+            ui.label("note:");
+            ui.text_edit_singleline(&mut self.invite_note);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Send invite").clicked() {
+                match hex::decode(self.invite_recipient.trim())
+                    .ok()
+                    .and_then(|v| <[u8; 32]>::try_from(v).ok())
+                {
+                    Some(recipient_id) => {
+                        let server = state.mailbox.server();
+                        let note = std::mem::take(&mut self.invite_note);
+                        let invite_status = self.invite_status.clone();
+                        let ctx = ui.ctx().clone();
+                        let cloud_key = Zeroizing::new(*cloud_key);
+                        *invite_status.write().unwrap() = Some("sending...".to_string());
+                        tokio::spawn(async move {
+                            let status = match Mailbox::send_invite(
+                                &server.http_url,
+                                &server.tls_trust,
+                                recipient_id,
+                                &cloud_key,
+                                signature_algorithm,
+                                note,
+                            )
+                            .await
+                            {
+                                Ok(()) => "invite sent".to_string(),
+                                Err(e) => format!("failed to send invite: {e}"),
+                            };
+                            *invite_status.write().unwrap() = Some(status);
+                            ctx.request_repaint();
+                        });
+                    }
+                    None => {
+                        *self.invite_status.write().unwrap() =
+                            Some("invalid invite address".to_string());
+                    }
+                }
+            }
+            if let Some(status) = self.invite_status.read().unwrap().as_ref() {
+                ui.label(status);
+            }
+        });
+        ui.colored_label(
+            Color32::RED,
+            "WARNING: an invite irreversibly shares access to this cloud!",
+        );
+    }
+
+    fn render_publish(&mut self, ui: &mut egui::Ui, remote: &RemoteCloud) {
+        ui.separator();
+        ui.label("Publishing");
+        ui.horizontal(|ui| {
+            ui.label("public id:");
+            ui.label(remote.cloud.id_hex());
+        });
+        let Some(confirmed_index) = remote.latest_confirmed_index() else {
+            ui.label("nothing has been confirmed by the remote yet");
+            return;
+        };
+        let mut upto = self
+            .publish_upto
+            .unwrap_or(confirmed_index)
+            .min(confirmed_index);
+        ui.horizontal(|ui| {
+            ui.label("publish changes up to:");
+            ui.add(egui::Slider::new(&mut upto, 0..=confirmed_index));
+        });
+        self.publish_upto = Some(upto);
+        ui.horizontal(|ui| {
+            let publish_button =
+                ConfirmButton::init("confirm_cloud_publish".to_string(), ui, &|b| {
+                    b.text = "Publish cloud".to_string();
+                    b.confirm_text = "Make the selected changes public?".to_string();
+                });
+            if publish_button.confirmed() {
+                let remote = remote.clone();
+                let publish_status = self.publish_status.clone();
+                let ctx = ui.ctx().clone();
+                *publish_status.write().unwrap() = Some("publishing...".to_string());
+                tokio::spawn(async move {
+                    let status = match remote.publish(upto).await {
+                        Ok(public_count) => format!("{public_count} changes are public"),
+                        Err(e) => format!("failed to publish: {e}"),
+                    };
+                    *publish_status.write().unwrap() = Some(status);
+                    ctx.request_repaint();
+                });
+            }
+            ui.add(publish_button);
+            if let Some(status) = self.publish_status.read().unwrap().as_ref() {
+                ui.label(status);
+            }
+        });
+        ui.colored_label(
+            Color32::RED,
+            "WARNING: anyone with the public id will be able to read all published changes. This can't be undone!",
+        );
+    }
+
+    fn render_delete_remote(&mut self, ui: &mut egui::Ui, state: &AppState, remote: &RemoteCloud) {
+        ui.separator();
+        ui.horizontal(|ui| {
+            let delete_button =
+                ConfirmButton::init("confirm_cloud_delete_remote".to_string(), ui, &|b| {
+                    b.text = "Delete everywhere".to_string();
+                    b.confirm_text = "Delete from the remote and this device?".to_string();
+                });
+            if delete_button.confirmed() {
+                let remote = remote.clone();
+                let delete_status = self.delete_status.clone();
+                let pending_requests = state.pending_requests.0.clone();
+                let ctx = ui.ctx().clone();
+                *delete_status.write().unwrap() = Some("deleting...".to_string());
+                tokio::spawn(async move {
+                    match remote.delete_remote().await {
+                        Ok(()) => {
+                            *delete_status.write().unwrap() = None;
+                            pending_requests
+                                .send(ActionRequest::DeleteCloud(*remote.cloud.id()))
+                                .ok();
+                        }
+                        Err(e) => {
+                            *delete_status.write().unwrap() =
+                                Some(format!("failed to delete: {e}"));
+                        }
+                    }
+                    ctx.request_repaint();
+                });
+            }
+            ui.add(delete_button);
+            if let Some(status) = self.delete_status.read().unwrap().as_ref() {
+                ui.label(status);
+            }
+        });
+        ui.colored_label(
+            Color32::RED,
+            "WARNING: the cloud will be removed from the remote permanently and can't be created there again!",
+        );
+    }
+}
+
+/// Store fresh stats for `cloud`, returning the status to show.
+fn refresh_storage_stats(
+    cloud: &Cloud,
+    storage_stats: &RwLock<Option<StorageStats>>,
+    status: Option<String>,
+) -> Option<String> {
+    match cloud.storage_stats() {
+        Ok(stats) => {
+            *storage_stats.write().unwrap() = Some(stats);
+            status
+        }
+        Err(e) => Some(format!("failed to compute usage: {e}")),
     }
 }
@@ -1,10 +1,285 @@
+use anondb::Bytes;
+use anyhow::Result;
+use egui::ScrollArea;
+use serde::Deserialize;
+use serde::Serialize;
+use web_time::SystemTime;
+
 use super::Applet;
+use crate::app::AppEvent;
+use crate::data::AppState;
+use crate::data::AppletStore;
+use crate::data::Cloud;
+use crate::data::Collection;
+use crate::data::Document;
+use crate::data::Migration;
+use crate::data::index_value;
+
+const TASKS_NAMESPACE: &str = "tasks";
+
+/// Moves the collection and its index tables into the namespace.
+const MIGRATIONS: &[Migration] = &[Migration {
+    move_tables: &[("tasks", "tasks/tasks"), ("tasks.*", "tasks/tasks.*")],
+}];
+
+/// Input we focus when the applet opens
+const INPUT_TASK_TITLE: &str = "task_title_input";
+
+/// Most recently created done tasks shown with "show done"
+const DONE_TASKS_SHOWN: usize = 50;
+
+/// View settings in `Cloud::local`, each device keeps its own.
+const VIEW_TABLE: &str = "view";
+const SHOW_DONE_KEY: &str = "show_done";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Task {
+    title: String,
+    done: bool,
+    created_at: u64,
+}
+
+impl Document for Task {
+    const COLLECTION: &'static str = "tasks";
+
+    fn index_values(&self) -> Result<Vec<(&'static str, Bytes)>> {
+        Ok(vec![("done", index_value(&self.done)?)])
+    }
+}
 
 #[derive(Default)]
-pub struct TasksApplet {}
+pub struct TasksApplet {
+    new_task_title: String,
+    search: String,
+    show_done: bool,
+    /// `(id, task)`, open tasks first, newest first
+    tasks: Vec<(String, Task)>,
+}
+
+impl TasksApplet {
+    fn view_table(cloud: &Cloud) -> String {
+        AppletStore::new(&cloud.local, TASKS_NAMESPACE).table(VIEW_TABLE)
+    }
+
+    fn load_view(&mut self, state: &AppState) -> Result<()> {
+        self.show_done = match state.active_cloud() {
+            Some((active_cloud, _)) => active_cloud
+                .local
+                .get(&Self::view_table(&active_cloud), &SHOW_DONE_KEY.to_string())?
+                .unwrap_or_default(),
+            None => false,
+        };
+        Ok(())
+    }
+
+    fn save_view(&self, state: &AppState) -> Result<()> {
+        if let Some((active_cloud, _)) = state.active_cloud() {
+            active_cloud.local.insert(
+                &Self::view_table(&active_cloud),
+                &SHOW_DONE_KEY.to_string(),
+                &self.show_done,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn reload_tasks(&mut self, state: &AppState) -> Result<()> {
+        self.tasks = Vec::default();
+        if let Some((active_cloud, _)) = state.active_cloud() {
+            let store = AppletStore::new(&active_cloud.db, TASKS_NAMESPACE);
+            let tasks = Collection::<Task>::new(&store);
+            let search = self.search.trim().to_lowercase();
+            let matches_search = |task: &Task| task.title.to_lowercase().contains(&search);
+            self.tasks = tasks
+                .query()
+                .eq("done", &false)?
+                .filter(matches_search)
+                .sort_by(|a, b| b.created_at.cmp(&a.created_at))
+                .run()?;
+            if self.show_done {
+                self.tasks.extend(
+                    tasks
+                        .query()
+                        .eq("done", &true)?
+                        .filter(matches_search)
+                        .sort_by(|a, b| b.created_at.cmp(&a.created_at))
+                        .limit(DONE_TASKS_SHOWN)
+                        .run()?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn add_task(&mut self, state: &AppState) -> Result<()> {
+        let title = self.new_task_title.trim().to_string();
+        if title.is_empty() {
+            return Ok(());
+        }
+        let (active_cloud, _) = match state.active_cloud() {
+            Some(c) => c,
+            None => {
+                println!("WARNING: cannot add task: no active cloud");
+                return Ok(());
+            }
+        };
+        // public clouds and past versions shown by `AppState::set_view_index`
+        if active_cloud.is_read_only() {
+            anyhow::bail!("cannot add a task to a read only cloud");
+        }
+        let task = Task {
+            title,
+            done: false,
+            created_at: SystemTime::now()
+                .duration_since(web_time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs(),
+        };
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let store = AppletStore::new(&active_cloud.db, TASKS_NAMESPACE);
+        Collection::<Task>::new(&store).insert(&id, &task)?;
+        self.new_task_title = String::default();
+        self.reload_tasks(state)
+    }
+
+    fn set_done(&mut self, id: &str, done: bool, state: &AppState) -> Result<()> {
+        if let Some((active_cloud, _)) = state.active_cloud() {
+            if active_cloud.is_read_only() {
+                anyhow::bail!("cannot update a task in a read only cloud");
+            }
+            let store = AppletStore::new(&active_cloud.db, TASKS_NAMESPACE);
+            let tasks = Collection::<Task>::new(&store);
+            if let Some(mut task) = tasks.get(id)? {
+                task.done = done;
+                tasks.insert(id, &task)?;
+            }
+        }
+        self.reload_tasks(state)
+    }
+
+    fn delete(&mut self, id: &str, state: &AppState) -> Result<()> {
+        if let Some((active_cloud, _)) = state.active_cloud() {
+            if active_cloud.is_read_only() {
+                anyhow::bail!("cannot delete a task from a read only cloud");
+            }
+            let store = AppletStore::new(&active_cloud.db, TASKS_NAMESPACE);
+            Collection::<Task>::new(&store).remove(id)?;
+        }
+        self.reload_tasks(state)
+    }
+}
 
 impl Applet for TasksApplet {
     fn name(&self) -> &str {
         "Tasks"
     }
+
+    fn namespace(&self) -> Option<&'static str> {
+        Some(TASKS_NAMESPACE)
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        MIGRATIONS
+    }
+
+    fn handle_app_events(&mut self, events: &Vec<AppEvent>, state: &AppState) -> Result<()> {
+        for event in events {
+            match event {
+                AppEvent::ActiveAppletChanged(applet_name) => {
+                    if applet_name == self.name() {
+                        self.load_view(state)?;
+                        self.reload_tasks(state)?;
+                        state
+                            .ctx
+                            .memory_mut(|mem| mem.request_focus(INPUT_TASK_TITLE.into()));
+                    }
+                }
+                AppEvent::ActiveCloudChanged => {
+                    self.load_view(state)?;
+                    self.reload_tasks(state)?;
+                }
+                AppEvent::CloudChanged(cloud_id, changes) => {
+                    if cloud_id == &state.active_cloud_id.unwrap_or_default()
+                        && let Some((cloud, _)) = state.active_cloud()
+                        && changes.matches(&AppletStore::new(&cloud.db, TASKS_NAMESPACE).pattern())
+                    {
+                        self.reload_tasks(state)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, ctx: &egui::Context, state: &AppState) {
+        let read_only = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| cloud.is_read_only());
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Tasks");
+            ui.horizontal(|ui| {
+                if !read_only {
+                    let input = egui::TextEdit::singleline(&mut self.new_task_title)
+                        .id(INPUT_TASK_TITLE.into())
+                        .hint_text("new task")
+                        .show(ui)
+                        .response;
+                    let add_pressed =
+                        input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    if ui.button("Add").clicked() || add_pressed {
+                        if let Err(e) = self.add_task(state) {
+                            println!("Error adding task: {:?}", e);
+                        }
+                        // keep typing tasks
+                        input.request_focus();
+                    }
+                }
+                let search = egui::TextEdit::singleline(&mut self.search)
+                    .hint_text("search")
+                    .show(ui)
+                    .response;
+                let show_done = ui.checkbox(&mut self.show_done, "show done");
+                if show_done.changed()
+                    && let Err(e) = self.save_view(state)
+                {
+                    println!("Error saving task view: {:?}", e);
+                }
+                if (search.changed() || show_done.changed())
+                    && let Err(e) = self.reload_tasks(state)
+                {
+                    println!("Error loading tasks: {:?}", e);
+                }
+            });
+            ui.separator();
+            // changes are applied after rendering the list
+            let mut toggled = None;
+            let mut deleted = None;
+            ScrollArea::vertical().show(ui, |ui| {
+                for (id, task) in &self.tasks {
+                    ui.horizontal(|ui| {
+                        let mut done = task.done;
+                        if ui
+                            .add_enabled(!read_only, egui::Checkbox::new(&mut done, &task.title))
+                            .changed()
+                        {
+                            toggled = Some((id.clone(), done));
+                        }
+                        if !read_only && ui.small_button("x").on_hover_text("Delete").clicked() {
+                            deleted = Some(id.clone());
+                        }
+                    });
+                }
+            });
+            if let Some((id, done)) = toggled
+                && let Err(e) = self.set_done(&id, done, state)
+            {
+                println!("Error updating task: {:?}", e);
+            }
+            if let Some(id) = deleted
+                && let Err(e) = self.delete(&id, state)
+            {
+                println!("Error deleting task: {:?}", e);
+            }
+        });
+    }
 }
@@ -0,0 +1,190 @@
+use anyhow::Result;
+use egui::Color32;
+
+use super::Applet;
+use crate::app::AppEvent;
+use crate::data::AppState;
+use crate::data::AppletStore;
+use crate::data::DeletedCloud;
+use crate::data::TRASH_NAMESPACE;
+use crate::data::TRASH_RETENTION;
+use crate::data::Trash;
+use crate::data::TrashItem;
+use crate::data::format_age;
+use crate::widgets::ConfirmButton;
+
+/// Deleted notes and files of the active cloud, and clouds deleted from this device.
+#[derive(Default)]
+pub struct TrashApplet {
+    items: Vec<([u8; 32], TrashItem)>,
+    deleted_clouds: Vec<([u8; 32], DeletedCloud)>,
+    error: Option<String>,
+}
+
+impl TrashApplet {
+    fn reload(&mut self, state: &AppState) -> Result<()> {
+        self.deleted_clouds = state.deleted_clouds()?;
+        self.items = match state.active_cloud() {
+            Some((cloud, _)) => {
+                let trash = Trash::new(&cloud);
+                // expired items are removed by whichever device opens the trash first
+                if state.can_migrate(&cloud) {
+                    trash.purge_expired()?;
+                }
+                trash.items()?
+            }
+            None => Vec::default(),
+        };
+        Ok(())
+    }
+
+    fn render_items(&mut self, ui: &mut egui::Ui, state: &AppState) {
+        ui.heading("Deleted items");
+        let Some((cloud, _)) = state.active_cloud() else {
+            ui.label("no active cloud");
+            return;
+        };
+        if self.items.is_empty() {
+            ui.label("nothing here");
+            return;
+        }
+        let trash = Trash::new(&cloud);
+        let mut changed = false;
+        egui::Grid::new("trash_items").striped(true).show(ui, |ui| {
+            for (id, item) in &self.items {
+                ui.label(&item.kind);
+                ui.label(&item.name);
+                ui.label(format_age(item.deleted_at));
+                if !cloud.is_read_only() {
+                    if ui.button("Restore").clicked() {
+                        if let Err(e) = trash.restore(id) {
+                            self.error = Some(format!("failed to restore: {e}"));
+                        }
+                        changed = true;
+                    }
+                    let purge_button =
+                        ConfirmButton::init(format!("trash_purge_{}", hex::encode(id)), ui, &|b| {
+                            b.text = "Delete forever".to_string();
+                            b.confirm_text = "Are you sure?".to_string();
+                        });
+                    if purge_button.confirmed() {
+                        if let Err(e) = trash.purge(id) {
+                            self.error = Some(format!("failed to delete: {e}"));
+                        }
+                        changed = true;
+                    }
+                    ui.add(purge_button);
+                }
+                ui.end_row();
+            }
+        });
+        if changed && let Err(e) = self.reload(state) {
+            println!("failed to reload trash! {:?}", e);
+        }
+    }
+
+    fn render_deleted_clouds(&mut self, ui: &mut egui::Ui, state: &AppState) {
+        ui.heading("Deleted clouds");
+        ui.label("Keys of clouds deleted from this device are kept here, on this device only.");
+        if self.deleted_clouds.is_empty() {
+            ui.label("nothing here");
+            return;
+        }
+        let mut changed = false;
+        egui::Grid::new("trash_clouds")
+            .striped(true)
+            .show(ui, |ui| {
+                for (id, deleted) in &self.deleted_clouds {
+                    if deleted.name.trim().is_empty() {
+                        ui.label(hex::encode(id));
+                    } else {
+                        ui.label(&deleted.name);
+                    }
+                    ui.label(if deleted.is_public {
+                        "public"
+                    } else {
+                        "private"
+                    });
+                    ui.label(format_age(deleted.deleted_at));
+                    if ui.button("Restore").clicked() {
+                        if let Err(e) = state.restore_cloud(*id) {
+                            self.error = Some(format!("failed to restore cloud: {e}"));
+                        }
+                        changed = true;
+                    }
+                    let purge_button = ConfirmButton::init(
+                        format!("trash_purge_cloud_{}", hex::encode(id)),
+                        ui,
+                        &|b| {
+                            b.text = "Delete forever".to_string();
+                            b.confirm_text = "Are you sure?".to_string();
+                        },
+                    );
+                    if purge_button.confirmed() {
+                        if let Err(e) = state.purge_cloud(*id) {
+                            self.error = Some(format!("failed to delete cloud: {e}"));
+                        }
+                        changed = true;
+                    }
+                    ui.add(purge_button);
+                    ui.end_row();
+                }
+            });
+        if changed && let Err(e) = self.reload(state) {
+            println!("failed to reload trash! {:?}", e);
+        }
+    }
+}
+
+impl Applet for TrashApplet {
+    fn name(&self) -> &str {
+        "Trash"
+    }
+
+    fn namespace(&self) -> Option<&'static str> {
+        Some(TRASH_NAMESPACE)
+    }
+
+    fn handle_app_events(&mut self, events: &Vec<AppEvent>, state: &AppState) -> Result<()> {
+        for event in events {
+            match event {
+                AppEvent::ActiveAppletChanged(applet_name) => {
+                    if applet_name == self.name() {
+                        self.error = None;
+                        self.reload(state)?;
+                    }
+                }
+                AppEvent::ActiveCloudChanged => {
+                    self.reload(state)?;
+                }
+                AppEvent::CloudChanged(cloud_id, changes) => {
+                    if cloud_id == &state.active_cloud_id.unwrap_or_default()
+                        && let Some((cloud, _)) = state.active_cloud()
+                        && changes.matches(&AppletStore::new(&cloud.db, TRASH_NAMESPACE).pattern())
+                    {
+                        self.reload(state)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&mut self, ctx: &egui::Context, state: &AppState) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.label(format!(
+                    "Deleted items are kept for {} days.",
+                    TRASH_RETENTION.as_secs() / (24 * 60 * 60)
+                ));
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::RED, error);
+                }
+                ui.separator();
+                self.render_items(ui, state);
+                ui.separator();
+                self.render_deleted_clouds(ui, state);
+            });
+        });
+    }
+}
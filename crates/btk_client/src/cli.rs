@@ -0,0 +1,95 @@
+//! Maintenance commands. They open the app's databases directly, close the app first.
+
+use anyhow::Result;
+
+use crate::data::AppState;
+use crate::data::IntegrityReport;
+use crate::data::RemoteCloud;
+use crate::data::Repair;
+
+const USAGE: &str = "usage: btk_client [command]
+
+With no command the app is started.
+
+commands:
+  verify [cloud_id]     check the local journal of each cloud against the remote
+  repair <cloud_id>     verify a cloud and apply the suggested repair
+  rebuild <cloud_id>    replace the local journal with the remote's mutations, local changes
+                        that aren't on the remote are lost
+  reconfirm <cloud_id>  compare every local change with the remote again";
+
+pub async fn run(args: &[String]) -> Result<()> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    if matches!(args.as_slice(), ["help"] | ["--help"] | ["-h"]) {
+        println!("{USAGE}");
+        return Ok(());
+    }
+    let mut state = AppState::new(egui::Context::default())?;
+    state.load_clouds()?;
+    match args.as_slice() {
+        ["verify"] => {
+            let remotes = state
+                .remote_clouds
+                .read()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+            for remote in remotes {
+                verify(&remote).await?;
+            }
+        }
+        ["verify", cloud_id] => {
+            verify(&remote(&state, cloud_id)?).await?;
+        }
+        ["repair", cloud_id] => {
+            let remote = remote(&state, cloud_id)?;
+            match verify(&remote).await?.suggested_repair() {
+                Some(Repair::Rebuild) => rebuild(&remote).await?,
+                Some(Repair::Reconfirm) => reconfirm(&remote)?,
+                None => println!("nothing to repair"),
+            }
+        }
+        ["rebuild", cloud_id] => rebuild(&remote(&state, cloud_id)?).await?,
+        ["reconfirm", cloud_id] => reconfirm(&remote(&state, cloud_id)?)?,
+        _ => anyhow::bail!("unknown command\n\n{USAGE}"),
+    }
+    Ok(())
+}
+
+fn remote(state: &AppState, cloud_id: &str) -> Result<RemoteCloud> {
+    let mut id = [0u8; 32];
+    hex::decode_to_slice(cloud_id.trim(), &mut id)?;
+    state
+        .remote_clouds
+        .read()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(anyhow::anyhow!("unknown cloud {cloud_id}"))
+}
+
+async fn verify(remote: &RemoteCloud) -> Result<IntegrityReport> {
+    let report = remote.verify().await?;
+    println!("{}: {report}", remote.cloud.id_hex());
+    Ok(report)
+}
+
+async fn rebuild(remote: &RemoteCloud) -> Result<()> {
+    let cloud = remote.rebuild().await?;
+    println!(
+        "rebuilt {} from {} remote mutations",
+        cloud.id_hex(),
+        cloud.db.journal_tx_len()?
+    );
+    Ok(())
+}
+
+fn reconfirm(remote: &RemoteCloud) -> Result<()> {
+    remote.reset_confirmations()?;
+    println!(
+        "{}: changes will be confirmed again when the app synchronizes",
+        remote.cloud.id_hex()
+    );
+    Ok(())
+}
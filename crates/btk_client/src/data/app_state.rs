@@ -6,13 +6,28 @@ use std::sync::RwLock;
 
 use anondb::Journal;
 use anyhow::Result;
+use network_common::MailboxEntry;
+use network_common::SignatureAlgorithm;
 use web_time::Duration;
+use zeroize::Zeroizing;
 
 use crate::app::ActionRequest;
 use crate::app::AppEvent;
+use crate::data::AppletStore;
+use crate::data::ChangeSet;
 use crate::data::Cloud;
 use crate::data::CloudMetadata;
+use crate::data::DeletedCloud;
+use crate::data::Mailbox;
+use crate::data::MailboxServer;
+use crate::data::Migration;
+use crate::data::ReceivedInvite;
 use crate::data::RemoteCloud;
+use crate::data::SchemaStatus;
+use crate::data::TransferRecord;
+use crate::data::remote_cloud::DEFAULT_SYNC_HTTP_URL;
+use crate::data::transfer;
+use crate::data::trash;
 use crate::tokio;
 
 /// We're going to need a few different databases.
@@ -21,9 +36,46 @@ use crate::tokio;
 /// Stored locally only.
 const CLOUD_KEYS_TABLE: &str = "_______known_keys";
 
+/// Cloud id keyed to the signature algorithm of the cloud. Clouds without an entry use
+/// `SignatureAlgorithm::MlDsa87`.
+/// Stored locally only.
+const KEY_ALGORITHMS_TABLE: &str = "_______key_algorithms";
+
+/// Ids of public clouds that were opened without a key.
+/// Stored locally only.
+const PUBLIC_CLOUDS_TABLE: &str = "_______public_clouds";
+
+/// Cloud id keyed to a `DeletedCloud`, so a deleted cloud can be restored until it's purged.
+/// Stored locally only.
+const DELETED_CLOUDS_TABLE: &str = "_______deleted_clouds";
+
+/// Cloud id keyed to the private key of a deleted cloud. Kept apart from `DeletedCloud` so
+/// listing the trash never decodes keys. Stored locally only.
+const DELETED_CLOUD_KEYS_TABLE: &str = "_______deleted_cloud_keys";
+
 /// Key for the id of the last cloud that was active.
 const ACTIVE_CLOUD_KEY: [u8; 32] = [0; 32];
 
+/// Seed for the device mailbox keypair and the `MailboxServer` it's read from. Stored locally
+/// only.
+const MAILBOX_TABLE: &str = "_______mailbox";
+const MAILBOX_SEED_KEY: &str = "seed";
+const MAILBOX_SERVER_KEY: &str = "server";
+
+/// Server http url keyed to the highest mailbox index received from it. Stored locally only.
+const MAILBOX_INDEX_TABLE: &str = "_______mailbox_index";
+
+/// Server http url and mailbox index keyed to the encrypted `MailboxEntry` of an invite that
+/// hasn't been accepted or declined. Stored locally only.
+const PENDING_INVITES_TABLE: &str = "_______pending_invites";
+
+/// Indices of default server mailbox entries accepted or declined before pending invites were
+/// stored. Skipped the first time that mailbox is read.
+const HANDLED_INVITES_TABLE: &str = "_______handled_invites";
+
+/// How often the mailbox is checked for new invites.
+const MAILBOX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Everything we need to interface with an encrypted cloud.
 /// This includes local first mutations, handling differences with the remote cloud, persisting and
 /// propagating.
@@ -44,6 +96,17 @@ pub struct AppState {
     pub remote_clouds: Arc<RwLock<HashMap<[u8; 32], RemoteCloud>>>,
     pub sorted_clouds: Vec<(Arc<Cloud>, CloudMetadata)>,
     pub active_cloud_id: Option<[u8; 32]>,
+    /// Receives invites to clouds from other users.
+    pub mailbox: Arc<Mailbox>,
+    /// Invites that have not been accepted or declined.
+    pub pending_invites: Arc<RwLock<Vec<ReceivedInvite>>>,
+    /// Journal length of each cloud when changes were last collected.
+    journal_lens: RwLock<HashMap<[u8; 32], u64>>,
+    /// Clouds whose journal length changed, sent by the synchronization loop after each tick.
+    journal_appended: (flume::Sender<[u8; 32]>, flume::Receiver<[u8; 32]>),
+    /// Journal index and read only copy of the active cloud at that index, see
+    /// `set_view_index`.
+    snapshot: Option<(u64, Arc<Cloud>)>,
 }
 
 impl AppState {
@@ -65,41 +128,137 @@ impl AppState {
             .expect("failed to send app request");
     }
 
+    pub fn view_at_index(&self, index: Option<u64>) {
+        self.pending_requests
+            .0
+            .send(ActionRequest::ViewAtIndex(index))
+            .expect("failed to send app request");
+    }
+
+    pub fn revert_cloud(&self, index: u64) {
+        self.pending_requests
+            .0
+            .send(ActionRequest::RevertCloud(index))
+            .expect("failed to send app request");
+    }
+
     pub fn drain_pending_app_requests(&self) -> Vec<ActionRequest> {
         self.pending_requests.1.drain().collect()
     }
 
+    /// Send `AppEvent::CloudChanged` for transactions appended to a cloud journal since the
+    /// last call, whether written by an applet or received from the remote. Only clouds the
+    /// synchronization loop reported in `journal_appended` are read.
+    pub fn collect_changes(&self) -> Result<()> {
+        let appended = self.journal_appended.1.drain().collect::<HashSet<_>>();
+        let clouds = self
+            .clouds
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(cloud_id, (cloud, _))| (*cloud_id, cloud.clone()))
+            .collect::<Vec<_>>();
+        let mut journal_lens = self.journal_lens.write().unwrap();
+        journal_lens.retain(|cloud_id, _| clouds.iter().any(|(id, _)| id == cloud_id));
+        for (cloud_id, cloud) in clouds {
+            if journal_lens.contains_key(&cloud_id) && !appended.contains(&cloud_id) {
+                continue;
+            }
+            let len = cloud.db.journal_tx_len()?;
+            // applets load a cloud when it becomes active, no need to announce it
+            let Some(last_len) = journal_lens.insert(cloud_id, len) else {
+                continue;
+            };
+            let changes = if len < last_len {
+                ChangeSet::everything()
+            } else if len > last_len {
+                let mut transactions = Vec::default();
+                for index in last_len..len {
+                    if let Some(tx) = cloud.db.journal_tx_by_index(index)? {
+                        transactions.push(tx);
+                    }
+                }
+                ChangeSet::from_transactions(&transactions)
+            } else {
+                continue;
+            };
+            self.pending_events
+                .0
+                .send(AppEvent::CloudChanged(cloud_id, Arc::new(changes)))?;
+        }
+        Ok(())
+    }
+
     pub fn new(ctx: egui::Context) -> Result<Self> {
+        let db: Journal = if let Some(data_dir) = Self::local_data_dir()? {
+            redb::Database::create(data_dir.join("local_data.redb"))?.into()
+        } else {
+            Journal::in_memory(None)?
+        };
+        let mailbox_seed = Self::load_mailbox_seed(&db)?;
+        let mailbox_server = db
+            .get::<_, MailboxServer>(MAILBOX_TABLE, &MAILBOX_SERVER_KEY.to_string())?
+            .unwrap_or_default();
         Ok(Self {
             ctx,
             pending_events: flume::unbounded(),
             pending_requests: flume::unbounded(),
             sync_status: flume::unbounded(),
-            db: if let Some(data_dir) = Self::local_data_dir()? {
-                redb::Database::create(data_dir.join("local_data.redb"))?.into()
-            } else {
-                Journal::in_memory(None)?
-            },
+            db,
             clouds: RwLock::new(HashMap::default()),
             active_cloud_id: None,
             sorted_clouds: Vec::default(),
             remote_clouds: Arc::new(RwLock::new(HashMap::default())),
+            mailbox: Arc::new(Mailbox::from_seed(&mailbox_seed, mailbox_server)?),
+            pending_invites: Arc::new(RwLock::new(Vec::default())),
+            journal_lens: RwLock::new(HashMap::default()),
+            journal_appended: flume::unbounded(),
+            snapshot: None,
         })
     }
 
+    /// Load the mailbox seed, generating one if none exists.
+    fn load_mailbox_seed(db: &Journal) -> Result<Zeroizing<[u8; 64]>> {
+        #[cfg(target_arch = "wasm32")]
+        let stored = Self::load_mailbox_seed_localstorage();
+        #[cfg(not(target_arch = "wasm32"))]
+        let stored =
+            db.get::<_, ([u8; 32], [u8; 32])>(MAILBOX_TABLE, &MAILBOX_SEED_KEY.to_string())?;
+
+        let (first, second) = match stored {
+            Some(seed) => seed,
+            None => {
+                let seed: ([u8; 32], [u8; 32]) = (rand::random(), rand::random());
+                db.insert(MAILBOX_TABLE, &MAILBOX_SEED_KEY.to_string(), &seed)?;
+                #[cfg(target_arch = "wasm32")]
+                Self::persist_mailbox_seed_localstorage(&seed);
+                seed
+            }
+        };
+        let mut seed = Zeroizing::new([0u8; 64]);
+        seed[..32].copy_from_slice(&first);
+        seed[32..].copy_from_slice(&second);
+        Ok(seed)
+    }
+
     /// Initialize `LocalState` using `self.db`.
     pub fn init(&mut self) -> Result<()> {
         #[cfg(target_arch = "wasm32")]
         self.load_keys_localstorage()?;
 
         self.load_clouds()?;
+        self.purge_expired_clouds()?;
 
         self.active_cloud_id = self.db.get(CLOUD_KEYS_TABLE, &ACTIVE_CLOUD_KEY)?;
 
         let remote_clouds = self.remote_clouds.clone();
-        let events_tx = self.pending_events.0.clone();
         let sync_status_tx = self.sync_status.0.clone();
+        let journal_appended_tx = self.journal_appended.0.clone();
+        let ctx = self.ctx.clone();
         tokio::spawn(async move {
+            // applets write between ticks, so lengths are compared after each tick rather than
+            // only when the remote appends
+            let mut journal_lens = HashMap::<[u8; 32], u64>::default();
             loop {
                 let remotes = remote_clouds
                     .read()
@@ -115,14 +274,54 @@ impl AppState {
                     {
                         continue;
                     }
-                    if let Err(e) = remote.tick(events_tx.clone(), sync_status_tx.clone()).await {
+                    if let Err(e) = remote.tick(sync_status_tx.clone()).await {
                         println!("Error ticking remote! {:?}", e);
+                        sync_status_tx
+                            .send((
+                                *remote.cloud.id(),
+                                format!("Sync error, verify in settings: {e}"),
+                            ))
+                            .ok();
+                    }
+                    let Ok(len) = remote.cloud.db.journal_tx_len() else {
+                        continue;
+                    };
+                    if journal_lens.insert(*remote.cloud.id(), len) != Some(len) {
+                        journal_appended_tx.send(*remote.cloud.id()).ok();
+                        ctx.request_repaint();
                     }
                 }
                 tokio::time::sleep(Duration::from_secs(1)).await;
             }
         });
 
+        *self.pending_invites.write().unwrap() = self.stored_invites()?;
+        let mailbox = self.mailbox.clone();
+        let pending_invites = self.pending_invites.clone();
+        let db = self.db.clone();
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            // the key is published again when the mailbox server changes
+            let mut published_to = None;
+            loop {
+                if published_to != Some(mailbox.http_url()) {
+                    match mailbox.publish_key().await {
+                        Ok(http_url) => published_to = Some(http_url),
+                        Err(e) => println!("Error publishing mailbox key! {:?}", e),
+                    }
+                }
+                match Self::receive_invites(&db, &mailbox).await {
+                    Ok(invites) if !invites.is_empty() => {
+                        pending_invites.write().unwrap().extend(invites);
+                        ctx.request_repaint();
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Error checking mailbox! {:?}", e),
+                }
+                tokio::time::sleep(MAILBOX_POLL_INTERVAL).await;
+            }
+        });
+
         Ok(())
     }
 
@@ -130,14 +329,28 @@ impl AppState {
         let data_dir_maybe = Self::local_data_dir()?;
 
         let mut next_cloud_ids = HashSet::<[u8; 32]>::default();
-        for key in self.cloud_keys()? {
-            let cloud_id = Cloud::id_from_key(key.into());
+        for (key, algorithm) in self.cloud_keys()? {
+            let cloud_id = Cloud::id_from_key(&key, algorithm);
             next_cloud_ids.insert(cloud_id);
             let mut clouds = self.clouds.write().unwrap();
             if let Some((cloud, _)) = clouds.get(&cloud_id).cloned() {
                 clouds.insert(cloud_id, (cloud.clone(), cloud.load_metadata()?));
             } else {
-                let cloud = Arc::new(Cloud::from_key(key.into(), data_dir_maybe.clone())?);
+                let cloud = Arc::new(Cloud::from_key(&key, algorithm, data_dir_maybe.clone())?);
+                clouds.insert(cloud_id, (cloud.clone(), cloud.load_metadata()?));
+            }
+        }
+        for cloud_id in self.public_cloud_ids()? {
+            if next_cloud_ids.contains(&cloud_id) {
+                // we have the key, no need to open read only
+                continue;
+            }
+            next_cloud_ids.insert(cloud_id);
+            let mut clouds = self.clouds.write().unwrap();
+            if let Some((cloud, _)) = clouds.get(&cloud_id).cloned() {
+                clouds.insert(cloud_id, (cloud.clone(), cloud.load_metadata()?));
+            } else {
+                let cloud = Arc::new(Cloud::public(cloud_id, data_dir_maybe.clone())?);
                 clouds.insert(cloud_id, (cloud.clone(), cloud.load_metadata()?));
             }
         }
@@ -185,56 +398,176 @@ impl AppState {
         Ok(())
     }
 
+    /// Remove a cloud from this device. The key and database files are kept with the deleted
+    /// clouds until `purge_cloud` is called or `TRASH_RETENTION` passes.
     pub fn delete_cloud(&self, id: [u8; 32]) -> Result<()> {
+        let mut filepaths = Vec::default();
+        let mut name = String::default();
+        if let Some((cloud, metadata)) = self.clouds.write().unwrap().remove(&id) {
+            name = metadata.name;
+            filepaths.extend(cloud.filepath().cloned());
+            filepaths.extend(cloud.local_filepath().cloned());
+        }
+        filepaths.extend(
+            self.remote_clouds
+                .write()
+                .unwrap()
+                .remove(&id)
+                .and_then(|remote| remote.filepath_maybe),
+        );
+        let private_key = self
+            .db
+            .get::<[u8; 32], [u8; 32]>(CLOUD_KEYS_TABLE, &id)?
+            .map(Zeroizing::new);
+        let deleted = DeletedCloud {
+            name,
+            deleted_at: trash::now(),
+            is_public: private_key.is_none(),
+            algorithm: self.db.get(KEY_ALGORITHMS_TABLE, &id)?.unwrap_or_default(),
+            filepaths,
+        };
+
         let mut tx = self.db.begin_write()?;
         let mut table = tx.open_table(CLOUD_KEYS_TABLE)?;
         table.remove(&id)?;
         drop(table);
+        let mut public_table = tx.open_table(PUBLIC_CLOUDS_TABLE)?;
+        public_table.remove(&id)?;
+        drop(public_table);
+        let mut algorithm_table = tx.open_table(KEY_ALGORITHMS_TABLE)?;
+        algorithm_table.remove(&id)?;
+        drop(algorithm_table);
+        let mut deleted_table = tx.open_table(DELETED_CLOUDS_TABLE)?;
+        deleted_table.insert(&id, &deleted)?;
+        drop(deleted_table);
+        if let Some(private_key) = &private_key {
+            let mut deleted_keys_table = tx.open_table(DELETED_CLOUD_KEYS_TABLE)?;
+            deleted_keys_table.insert(&id, &**private_key)?;
+            drop(deleted_keys_table);
+        }
         tx.commit()?;
 
-        // delete the cloud store
-        if let Some(filepath) = self
-            .clouds
-            .write()
-            .unwrap()
-            .remove(&id)
-            .and_then(|(cloud, _)| cloud.filepath().cloned())
-        {
-            std::fs::remove_file(filepath)?;
+        #[cfg(target_arch = "wasm32")]
+        self.persist_keys_localstorage()?;
+
+        self.reload_clouds();
+        self.ctx.request_repaint();
+
+        Ok(())
+    }
+
+    /// Deleted clouds that can be restored, most recently deleted first.
+    pub fn deleted_clouds(&self) -> Result<Vec<([u8; 32], DeletedCloud)>> {
+        let mut deleted = self
+            .db
+            .find_many::<[u8; 32], DeletedCloud, _>(DELETED_CLOUDS_TABLE, |_, _| true)?;
+        deleted.sort_by_key(|(_, deleted)| std::cmp::Reverse(deleted.deleted_at));
+        Ok(deleted)
+    }
+
+    /// Put a deleted cloud back on this device. Clouds without files reload from the remote.
+    pub fn restore_cloud(&self, id: [u8; 32]) -> Result<()> {
+        let deleted = self
+            .db
+            .get::<[u8; 32], DeletedCloud>(DELETED_CLOUDS_TABLE, &id)?
+            .ok_or(anyhow::anyhow!("cloud is not in the trash"))?;
+        let private_key = self
+            .db
+            .get::<[u8; 32], [u8; 32]>(DELETED_CLOUD_KEYS_TABLE, &id)?
+            .map(Zeroizing::new);
+        if private_key.is_none() && !deleted.is_public {
+            anyhow::bail!("key for the deleted cloud is missing");
         }
-        // delete the sync state
-        if let Some(filepath) = self
-            .remote_clouds
-            .write()
-            .unwrap()
-            .remove(&id)
-            .and_then(|remote| remote.filepath_maybe)
-        {
-            std::fs::remove_file(filepath)?;
+        let mut tx = self.db.begin_write()?;
+        if let Some(private_key) = &private_key {
+            let mut table = tx.open_table(CLOUD_KEYS_TABLE)?;
+            table.insert(&id, &**private_key)?;
+            drop(table);
+            let mut algorithm_table = tx.open_table(KEY_ALGORITHMS_TABLE)?;
+            algorithm_table.insert(&id, &deleted.algorithm)?;
+            drop(algorithm_table);
+        } else {
+            let mut public_table = tx.open_table(PUBLIC_CLOUDS_TABLE)?;
+            public_table.insert(&id, &())?;
+            drop(public_table);
         }
+        let mut deleted_table = tx.open_table(DELETED_CLOUDS_TABLE)?;
+        deleted_table.remove(&id)?;
+        drop(deleted_table);
+        let mut deleted_keys_table = tx.open_table(DELETED_CLOUD_KEYS_TABLE)?;
+        deleted_keys_table.remove(&id)?;
+        drop(deleted_keys_table);
+        tx.commit()?;
 
         #[cfg(target_arch = "wasm32")]
         self.persist_keys_localstorage()?;
 
         self.reload_clouds();
+        Ok(())
+    }
+
+    /// Forget a deleted cloud's key and delete its files from this device.
+    pub fn purge_cloud(&self, id: [u8; 32]) -> Result<()> {
+        self.db
+            .remove::<[u8; 32], [u8; 32]>(DELETED_CLOUD_KEYS_TABLE, &id)?;
+        if let Some(deleted) = self
+            .db
+            .remove::<[u8; 32], DeletedCloud>(DELETED_CLOUDS_TABLE, &id)?
+        {
+            for filepath in deleted.filepaths {
+                if filepath.exists() {
+                    std::fs::remove_file(filepath)?;
+                }
+            }
+        }
         self.ctx.request_repaint();
+        Ok(())
+    }
 
+    fn purge_expired_clouds(&self) -> Result<()> {
+        for (id, deleted) in self.deleted_clouds()? {
+            if trash::is_expired(deleted.deleted_at) {
+                self.purge_cloud(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Swap in a cloud rebuilt with `RemoteCloud::rebuild`. Applets reload it if it's active.
+    pub fn replace_cloud(&mut self, cloud: Arc<Cloud>) -> Result<()> {
+        let cloud_id = *cloud.id();
+        let remote = self.remote_clouds.read().unwrap().get(&cloud_id).cloned();
+        if let Some(remote) = remote {
+            self.remote_clouds
+                .write()
+                .unwrap()
+                .insert(cloud_id, remote.with_cloud(cloud.clone()));
+        }
+        let metadata = cloud.load_metadata()?;
+        self.clouds
+            .write()
+            .unwrap()
+            .insert(cloud_id, (cloud, metadata));
+        self.load_clouds()?;
+        if self.active_cloud_id == Some(cloud_id) {
+            self.set_active_cloud(Some(cloud_id))?;
+        }
         Ok(())
     }
 
     pub fn duplicate_active_cloud(&self, index: u64, name: String) -> Result<Arc<Cloud>> {
         let (cloud, _) = self
-            .active_cloud()
+            .live_active_cloud()
             .ok_or(anyhow::anyhow!("no active cloud"))?;
         let genesis_tx = cloud.db.flatten_at_index(index)?;
+        let algorithm = cloud.signature_algorithm();
         drop(cloud); // prevent using the wrong name below
-        let new_cloud = Arc::new(Cloud::new(Self::local_data_dir()?)?);
+        let new_cloud = Arc::new(Cloud::new(algorithm, Self::local_data_dir()?)?);
         new_cloud.db.append_tx(&genesis_tx)?;
         let mut metadata = CloudMetadata::create();
         metadata.name = name;
         new_cloud.set_metadata(metadata.clone())?;
-        self.db
-            .insert(CLOUD_KEYS_TABLE, new_cloud.id(), new_cloud.private_key())?;
+        self.insert_cloud_key(&new_cloud)?;
         self.clouds
             .write()
             .unwrap()
@@ -244,6 +577,59 @@ impl AppState {
         Ok(new_cloud)
     }
 
+    /// Make the active cloud look as it did after journal `index` with one new transaction, see
+    /// `Cloud::revert_to`. Unlike `duplicate_active_cloud` the id and sharing stay the same.
+    pub fn revert_active_cloud(&mut self, index: u64) -> Result<()> {
+        let (cloud, _) = self
+            .live_active_cloud()
+            .ok_or(anyhow::anyhow!("no active cloud"))?;
+        if !self.can_migrate(&cloud) {
+            anyhow::bail!("wait for the cloud to finish synchronizing before reverting");
+        }
+        cloud.revert_to(index)?;
+        if self.snapshot.is_some() {
+            self.set_view_index(None)?;
+        }
+        Ok(())
+    }
+
+    /// Copy records such as notes or files from one cloud to another, one journaled transaction
+    /// per cloud. `remove_source` makes it a move. Both clouds must have every migration of the
+    /// records' applet namespace applied, so the tables are laid out the same way.
+    pub fn transfer(
+        &self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+        namespace: &'static str,
+        migrations: &[Migration],
+        records: &[TransferRecord],
+        remove_source: bool,
+    ) -> Result<()> {
+        let (source, source_metadata) = self
+            .cloud_by_id(from)
+            .ok_or(anyhow::anyhow!("unknown cloud {}", hex::encode(from)))?;
+        let (destination, destination_metadata) = self
+            .cloud_by_id(to)
+            .ok_or(anyhow::anyhow!("unknown cloud {}", hex::encode(to)))?;
+        for (cloud, metadata) in [
+            (&source, source_metadata),
+            (&destination, destination_metadata),
+        ] {
+            match AppletStore::new(&cloud.db, namespace).status(migrations)? {
+                SchemaStatus::Current => {}
+                SchemaStatus::Outdated => anyhow::bail!(
+                    "{} hasn't been migrated, open it before transferring",
+                    metadata.name
+                ),
+                SchemaStatus::Newer(_) => anyhow::bail!(
+                    "{} was migrated by a newer version of the app",
+                    metadata.name
+                ),
+            }
+        }
+        transfer::transfer(&source, &destination, records, remove_source)
+    }
+
     /// Create a new encrypted cloud. This is a local keypair keyed
     /// to an entry in the database.
     ///
@@ -252,18 +638,22 @@ impl AppState {
     ///
     /// The database is journaled by tracking redb k/v operations
     /// and replicating them on remote instances, behind chacha20
-    /// encryption. A syntax for expressing queries
-    /// (like MongoDB/SQL) can trivially be built around this.
+    /// encryption. Applets store typed documents with
+    /// `Collection`, which keeps secondary indexes and answers
+    /// queries.
     ///
-    pub fn create_cloud(&self, name_maybe: Option<String>) -> Result<Arc<Cloud>> {
-        let cloud = Arc::new(Cloud::new(Self::local_data_dir()?)?);
+    pub fn create_cloud(
+        &self,
+        name_maybe: Option<String>,
+        algorithm: SignatureAlgorithm,
+    ) -> Result<Arc<Cloud>> {
+        let cloud = Arc::new(Cloud::new(algorithm, Self::local_data_dir()?)?);
         let mut metadata = CloudMetadata::create();
         if let Some(name) = name_maybe {
             metadata.name = name;
         }
         cloud.set_metadata(metadata.clone())?;
-        self.db
-            .insert(CLOUD_KEYS_TABLE, cloud.id(), cloud.private_key())?;
+        self.insert_cloud_key(&cloud)?;
         self.clouds
             .write()
             .unwrap()
@@ -273,21 +663,156 @@ impl AppState {
         Ok(cloud)
     }
 
-    /// Returns the new cloud id
+    /// Import a key exported with `Cloud::encode_key`. Returns the new cloud id
     pub fn import_cloud(&self, key_str: &str) -> Result<[u8; 32]> {
-        let key_vec = hex::decode(key_str.trim())?;
-        if key_vec.len() != 32 {
-            anyhow::bail!("Key is not correct length");
+        let (private_key, algorithm) = Cloud::decode_key(key_str)?;
+        self.import_cloud_key(&private_key, algorithm)
+    }
+
+    /// Returns the new cloud id
+    pub fn import_cloud_key(
+        &self,
+        private_key: &[u8; 32],
+        algorithm: SignatureAlgorithm,
+    ) -> Result<[u8; 32]> {
+        let cloud = Cloud::from_key(private_key, algorithm, Self::local_data_dir()?)?;
+        self.insert_cloud_key(&cloud)?;
+        Ok(*cloud.id())
+    }
+
+    fn insert_cloud_key(&self, cloud: &Cloud) -> Result<()> {
+        let private_key = cloud
+            .private_key()
+            .ok_or(anyhow::anyhow!("cloud {} has no key", cloud.id_hex()))?;
+        self.db.insert(CLOUD_KEYS_TABLE, cloud.id(), private_key)?;
+        self.db.insert(
+            KEY_ALGORITHMS_TABLE,
+            cloud.id(),
+            &cloud.signature_algorithm(),
+        )?;
+        Ok(())
+    }
+
+    /// Open a public cloud by id. The cloud is read only and replicated from the decrypted
+    /// mutations served by the remote. Returns the cloud id.
+    pub fn open_public_cloud(&self, id_str: &str) -> Result<[u8; 32]> {
+        let id_vec = hex::decode(id_str.trim())?;
+        if id_vec.len() != 32 {
+            anyhow::bail!("Cloud id is not correct length");
         }
-        let mut private_key = <[u8; 32]>::default();
-        private_key.copy_from_slice(&key_vec);
-        let cloud = Cloud::from_key(private_key, Self::local_data_dir()?)?;
+        let mut cloud_id = <[u8; 32]>::default();
+        cloud_id.copy_from_slice(&id_vec);
+        self.db.insert(PUBLIC_CLOUDS_TABLE, &cloud_id, &())?;
+        Ok(cloud_id)
+    }
+
+    fn public_cloud_ids(&self) -> Result<Vec<[u8; 32]>> {
+        Ok(self
+            .db
+            .find_many::<[u8; 32], (), _>(PUBLIC_CLOUDS_TABLE, |_, _| true)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    /// Read mailbox entries newer than the last one received from the mailbox server. Invites are
+    /// stored encrypted until they're accepted or declined, so each entry is only downloaded
+    /// once.
+    async fn receive_invites(db: &Journal, mailbox: &Mailbox) -> Result<Vec<ReceivedInvite>> {
+        let http_url = mailbox.http_url();
+        let last_index = db.get::<String, u64>(MAILBOX_INDEX_TABLE, &http_url)?;
+        let (fetched_from, entries) = mailbox
+            .fetch_entries(last_index.map(|index| index + 1).unwrap_or(0))
+            .await?;
+        if fetched_from != http_url {
+            // the server changed while fetching, read the new one next time
+            return Ok(Vec::default());
+        }
+        let mut invites = Vec::default();
+        let mut highest_index = last_index;
+        for entry in entries {
+            highest_index = highest_index.max(Some(entry.index));
+            if last_index.is_none()
+                && http_url == DEFAULT_SYNC_HTTP_URL
+                && db
+                    .get::<u64, ()>(HANDLED_INVITES_TABLE, &entry.index)?
+                    .is_some()
+            {
+                continue;
+            }
+            match mailbox.open_entry(&http_url, &entry) {
+                Ok(invite) => {
+                    db.insert(
+                        PENDING_INVITES_TABLE,
+                        &(http_url.clone(), entry.index),
+                        &entry,
+                    )?;
+                    invites.push(invite);
+                }
+                Err(e) => println!("WARNING: discarding mailbox entry {}: {:?}", entry.index, e),
+            }
+        }
+        if let Some(highest_index) = highest_index
+            && Some(highest_index) != last_index
+        {
+            db.insert(MAILBOX_INDEX_TABLE, &http_url, &highest_index)?;
+        }
+        Ok(invites)
+    }
+
+    /// Invites received before this session that haven't been accepted or declined.
+    fn stored_invites(&self) -> Result<Vec<ReceivedInvite>> {
+        Ok(self
+            .db
+            .find_many::<(String, u64), MailboxEntry, _>(PENDING_INVITES_TABLE, |_, _| true)?
+            .into_iter()
+            .filter_map(|((http_url, _), entry)| {
+                self.mailbox
+                    .open_entry(&http_url, &entry)
+                    .inspect_err(|e| println!("WARNING: unreadable stored invite: {:?}", e))
+                    .ok()
+            })
+            .collect())
+    }
+
+    /// Publish the mailbox key to and read invites from `server`, usually the sync server of a
+    /// cloud. Invites already received from other servers stay pending.
+    pub fn set_mailbox_server(&self, server: MailboxServer) -> Result<()> {
+        self.mailbox.set_server(server.clone())?;
         self.db
-            .insert(CLOUD_KEYS_TABLE, cloud.id(), cloud.private_key())?;
-        Ok(*cloud.id())
+            .insert(MAILBOX_TABLE, &MAILBOX_SERVER_KEY.to_string(), &server)
+    }
+
+    /// Import the cloud key from an invite. Returns the cloud id.
+    pub fn accept_invite(&self, invite: &ReceivedInvite) -> Result<[u8; 32]> {
+        let cloud_id =
+            self.import_cloud_key(&invite.invite.cloud_key, invite.invite.signature_algorithm)?;
+        self.mark_invite_handled(invite)?;
+        #[cfg(target_arch = "wasm32")]
+        self.persist_keys_localstorage()?;
+        self.reload_clouds();
+        Ok(cloud_id)
+    }
+
+    pub fn decline_invite(&self, invite: &ReceivedInvite) -> Result<()> {
+        self.mark_invite_handled(invite)
+    }
+
+    fn mark_invite_handled(&self, handled: &ReceivedInvite) -> Result<()> {
+        self.db.remove::<_, MailboxEntry>(
+            PENDING_INVITES_TABLE,
+            &(handled.http_url.clone(), handled.index),
+        )?;
+        self.pending_invites
+            .write()
+            .unwrap()
+            .retain(|invite| invite.http_url != handled.http_url || invite.index != handled.index);
+        self.ctx.request_repaint();
+        Ok(())
     }
 
     pub fn set_active_cloud(&mut self, id: Option<[u8; 32]>) -> Result<()> {
+        self.snapshot = None;
         if let Some(id) = id {
             self.db.insert(CLOUD_KEYS_TABLE, &ACTIVE_CLOUD_KEY, &id)?;
             self.active_cloud_id = Some(id);
@@ -302,11 +827,35 @@ impl AppState {
         Ok(())
     }
 
+    /// Whether applet migrations may be written to a cloud. Read only clouds are migrated by a
+    /// keyholder, synchronized clouds once the remote history has been downloaded.
+    pub fn can_migrate(&self, cloud: &Cloud) -> bool {
+        if cloud.is_read_only() {
+            return false;
+        }
+        match self.remote_clouds.read().unwrap().get(cloud.id()) {
+            Some(remote) => !remote.synchronization_enabled() || remote.is_caught_up(),
+            None => true,
+        }
+    }
+
     pub fn cloud_by_id(&self, cloud_id: &[u8; 32]) -> Option<(Arc<Cloud>, CloudMetadata)> {
         self.clouds.read().unwrap().get(cloud_id).cloned()
     }
 
+    /// The active cloud, or its snapshot while viewing a past index.
     pub fn active_cloud(&self) -> Option<(Arc<Cloud>, CloudMetadata)> {
+        let (cloud, metadata) = self.live_active_cloud()?;
+        match &self.snapshot {
+            Some((_, snapshot)) if snapshot.id() == cloud.id() => {
+                Some((snapshot.clone(), metadata))
+            }
+            _ => Some((cloud, metadata)),
+        }
+    }
+
+    /// The active cloud as it is now, even while viewing a past index.
+    pub fn live_active_cloud(&self) -> Option<(Arc<Cloud>, CloudMetadata)> {
         if let Some(cloud_id) = self.active_cloud_id {
             self.cloud_by_id(&cloud_id)
         } else {
@@ -314,15 +863,57 @@ impl AppState {
         }
     }
 
+    /// Show every applet the active cloud as it was after journal `index`, or as it is now with
+    /// `None`. The snapshot is flattened into an in memory journal and is read only.
+    pub fn set_view_index(&mut self, index: Option<u64>) -> Result<()> {
+        self.snapshot = match index {
+            Some(index) => {
+                let (cloud, _) = self
+                    .live_active_cloud()
+                    .ok_or(anyhow::anyhow!("no active cloud"))?;
+                Some((index, Arc::new(cloud.snapshot(index)?)))
+            }
+            None => None,
+        };
+        self.pending_events.0.send(AppEvent::ActiveCloudChanged)?;
+        Ok(())
+    }
+
+    /// The journal index being viewed, `None` for the present.
+    pub fn viewed_index(&self) -> Option<u64> {
+        self.snapshot.as_ref().map(|(index, _)| *index)
+    }
+
+    pub fn snapshot_cloud(&self) -> Option<Arc<Cloud>> {
+        self.snapshot.as_ref().map(|(_, cloud)| cloud.clone())
+    }
+
+    /// Write the viewed version of `records` to the active cloud as a new transaction.
+    pub fn restore_from_snapshot(&self, records: &[TransferRecord]) -> Result<()> {
+        let (_, snapshot) = self
+            .snapshot
+            .as_ref()
+            .ok_or(anyhow::anyhow!("not viewing a past index"))?;
+        let (cloud, _) = self
+            .cloud_by_id(snapshot.id())
+            .ok_or(anyhow::anyhow!("unknown cloud {}", snapshot.id_hex()))?;
+        transfer::restore(snapshot, &cloud, records)
+    }
+
     /// Retrieve all the encrypted clouds that we know how to decrypt.
-    fn cloud_keys(&self) -> Result<Vec<[u8; 32]>> {
-        Ok(self
-            .db
+    fn cloud_keys(&self) -> Result<Vec<(Zeroizing<[u8; 32]>, SignatureAlgorithm)>> {
+        self.db
             .find_many::<[u8; 32], [u8; 32], _>(CLOUD_KEYS_TABLE, |_, _| true)?
             .into_iter()
             .filter(|(k, _v)| k != &ACTIVE_CLOUD_KEY)
-            .map(|(_k, v)| v)
-            .collect())
+            .map(|(k, v)| {
+                let algorithm = self
+                    .db
+                    .get::<[u8; 32], SignatureAlgorithm>(KEY_ALGORITHMS_TABLE, &k)?
+                    .unwrap_or_default();
+                Ok((Zeroizing::new(v), algorithm))
+            })
+            .collect()
     }
 
     #[cfg(not(target_arch = "wasm32"))]
@@ -351,12 +942,20 @@ impl AppState {
     fn load_keys_localstorage(&mut self) -> Result<()> {
         use gloo_storage::Storage;
 
-        let keys_str = gloo_storage::LocalStorage::get::<String>("btk_keys")
-            .ok()
-            .unwrap_or_default();
+        let keys_str = Zeroizing::new(
+            gloo_storage::LocalStorage::get::<String>("btk_keys")
+                .ok()
+                .unwrap_or_default(),
+        );
         for key_str in keys_str.split(",") {
             self.import_cloud(key_str).ok();
         }
+        let public_ids_str = gloo_storage::LocalStorage::get::<String>("btk_public_clouds")
+            .ok()
+            .unwrap_or_default();
+        for id_str in public_ids_str.split(",") {
+            self.open_public_cloud(id_str).ok();
+        }
 
         if let Some(active_cloud_id) =
             gloo_storage::LocalStorage::get::<String>("btk_active_cloud_id").ok()
@@ -373,18 +972,54 @@ impl AppState {
     fn persist_keys_localstorage(&self) -> Result<()> {
         use gloo_storage::Storage;
 
-        let keys_str = self
+        let keys = self
             .cloud_keys()?
             .into_iter()
-            .map(|key| hex::encode(key))
-            .collect::<Vec<_>>()
-            .join(",");
+            .map(|(key, algorithm)| Cloud::encode_key(&key, algorithm))
+            .collect::<Vec<_>>();
+        let keys_str = Zeroizing::new(
+            keys.iter()
+                .map(|key| key.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        );
         if let Some(active_cloud_id) = self.active_cloud_id {
             gloo_storage::LocalStorage::set("btk_active_cloud_id", hex::encode(active_cloud_id))
                 .ok();
         }
 
-        gloo_storage::LocalStorage::set("btk_keys", keys_str).ok();
+        gloo_storage::LocalStorage::set("btk_keys", keys_str.as_str()).ok();
+
+        let public_ids_str = self
+            .public_cloud_ids()?
+            .into_iter()
+            .map(|id| hex::encode(id))
+            .collect::<Vec<_>>()
+            .join(",");
+        gloo_storage::LocalStorage::set("btk_public_clouds", public_ids_str).ok();
         Ok(())
     }
+
+    #[cfg(target_arch = "wasm32")]
+    fn load_mailbox_seed_localstorage() -> Option<([u8; 32], [u8; 32])> {
+        use gloo_storage::Storage;
+
+        let seed_str = gloo_storage::LocalStorage::get::<String>("btk_mailbox_seed").ok()?;
+        let seed_vec = hex::decode(seed_str).ok()?;
+        if seed_vec.len() != 64 {
+            return None;
+        }
+        let mut seed = <([u8; 32], [u8; 32])>::default();
+        seed.0.copy_from_slice(&seed_vec[..32]);
+        seed.1.copy_from_slice(&seed_vec[32..]);
+        Some(seed)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn persist_mailbox_seed_localstorage(seed: &([u8; 32], [u8; 32])) {
+        use gloo_storage::Storage;
+
+        let seed_str = hex::encode(seed.0) + &hex::encode(seed.1);
+        gloo_storage::LocalStorage::set("btk_mailbox_seed", seed_str).ok();
+    }
 }
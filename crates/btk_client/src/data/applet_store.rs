@@ -0,0 +1,228 @@
+use std::cmp::Ordering;
+
+use anondb::Bytes;
+use anondb::Journal;
+use anyhow::Result;
+use redb::TableHandle;
+
+/// Namespace keyed to the schema version of its tables. Written in the same transaction as each
+/// migration, so every device replays a migration together with its version.
+const SCHEMA_VERSIONS_TABLE: &str = "_______schema_versions";
+
+/// A change to the layout of an applet's tables, applied in a single journaled transaction.
+pub struct Migration {
+    /// `(from, to)` table names, every entry is moved. A trailing `*` on both names moves each
+    /// table starting with the prefix and keeps the rest of the name, e.g.
+    /// `("note-*", "notes/note-*")`.
+    pub move_tables: &'static [(&'static str, &'static str)],
+}
+
+impl Migration {
+    /// `(from, to)` for each existing table the migration moves.
+    fn table_moves(&self, table_names: &[String]) -> Vec<(String, String)> {
+        let mut moves = Vec::default();
+        for (from, to) in self.move_tables {
+            match (from.strip_suffix('*'), to.strip_suffix('*')) {
+                (Some(from_prefix), Some(to_prefix)) => {
+                    for table_name in table_names {
+                        if let Some(rest) = table_name.strip_prefix(from_prefix) {
+                            moves.push((table_name.clone(), format!("{to_prefix}{rest}")));
+                        }
+                    }
+                }
+                _ => {
+                    if table_names.iter().any(|table_name| table_name == from) {
+                        moves.push((from.to_string(), to.to_string()));
+                    }
+                }
+            }
+        }
+        moves
+    }
+}
+
+pub enum SchemaStatus {
+    Current,
+    /// Migrations remain to be applied.
+    Outdated,
+    /// Migrated by a newer client, holds the version. We don't know the layout of the tables.
+    Newer(u32),
+}
+
+/// The tables of one applet in a cloud database. Table names are prefixed with `{namespace}/`
+/// and the namespace has a schema version, the number of migrations applied to it.
+pub struct AppletStore<'a> {
+    db: &'a Journal,
+    namespace: &'static str,
+}
+
+impl<'a> AppletStore<'a> {
+    pub fn new(db: &'a Journal, namespace: &'static str) -> Self {
+        Self { db, namespace }
+    }
+
+    pub fn db(&self) -> &'a Journal {
+        self.db
+    }
+
+    pub fn table(&self, name: &str) -> String {
+        format!("{}/{name}", self.namespace)
+    }
+
+    /// Matches every table in the namespace, for `ChangeSet::matches`.
+    pub fn pattern(&self) -> String {
+        self.table("*")
+    }
+
+    pub fn version(&self) -> Result<u32> {
+        Ok(self
+            .db
+            .get(SCHEMA_VERSIONS_TABLE, &self.namespace.to_string())?
+            .unwrap_or_default())
+    }
+
+    pub fn status(&self, migrations: &[Migration]) -> Result<SchemaStatus> {
+        let version = self.version()?;
+        Ok(match version.cmp(&(migrations.len() as u32)) {
+            Ordering::Less => SchemaStatus::Outdated,
+            Ordering::Equal => SchemaStatus::Current,
+            Ordering::Greater => SchemaStatus::Newer(version),
+        })
+    }
+
+    /// Apply the migrations after the stored version. Each migration is committed with its
+    /// version bump, so it's applied once even if a later one fails. The version and the moved
+    /// entries are read in the migration's transaction, so a concurrent sync can't be
+    /// overwritten with stale entries.
+    ///
+    /// Two devices migrating at once both journal the same moves, replaying the second is a
+    /// no-op.
+    pub fn migrate(&self, migrations: &[Migration]) -> Result<()> {
+        let version = self.version()? as usize;
+        for (index, migration) in migrations.iter().enumerate().skip(version) {
+            let next_version = index as u32 + 1;
+            let table_names = self.table_names()?;
+            let mut tx = self.db.begin_write()?;
+            let versions = tx.open_table(SCHEMA_VERSIONS_TABLE)?;
+            let stored_version = versions
+                .get::<String, u32>(&self.namespace.to_string())?
+                .unwrap_or_default();
+            drop(versions);
+            if stored_version >= next_version {
+                // applied by a sync since we read the version, the transaction is discarded
+                continue;
+            }
+            for (from, to) in migration.table_moves(&table_names) {
+                let table = tx.open_table(&from)?;
+                let mut entries = Vec::default();
+                for entry in table.range::<Bytes>(..)? {
+                    let (key, value) = entry?;
+                    entries.push((key.value(), value.value()));
+                }
+                drop(table);
+                let mut table = tx.open_table(&to)?;
+                for (key, value) in &entries {
+                    table.insert_bytes(key, value)?;
+                }
+                drop(table);
+                tx.delete_table(&from)?;
+            }
+            let mut versions = tx.open_table(SCHEMA_VERSIONS_TABLE)?;
+            versions.insert(&self.namespace.to_string(), &next_version)?;
+            drop(versions);
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    fn table_names(&self) -> Result<Vec<String>> {
+        let tx = self.db.begin_read()?;
+        Ok(tx
+            .list_tables()?
+            .map(|table| table.name().to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            move_tables: &[("files", "files/files")],
+        },
+        Migration {
+            move_tables: &[("files/note-*", "files/notes/*")],
+        },
+    ];
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn table_moves_match_names_and_prefixes() {
+        let migration = Migration {
+            move_tables: &[("files", "files/files"), ("note-*", "notes/note-*")],
+        };
+        let moves = migration.table_moves(&names(&["files", "note-a", "note-b", "notes", "other"]));
+        assert_eq!(
+            moves,
+            vec![
+                ("files".to_string(), "files/files".to_string()),
+                ("note-a".to_string(), "notes/note-a".to_string()),
+                ("note-b".to_string(), "notes/note-b".to_string()),
+            ]
+        );
+        assert!(migration.table_moves(&names(&["other"])).is_empty());
+    }
+
+    #[test]
+    fn migrations_run_once() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        db.insert("files", &"a.txt".to_string(), &vec![1u8])?;
+        let store = AppletStore::new(&db, "files");
+        assert!(matches!(store.status(MIGRATIONS)?, SchemaStatus::Outdated));
+        store.migrate(&MIGRATIONS[..1])?;
+        assert_eq!(store.version()?, 1);
+        assert_eq!(
+            db.get::<_, Vec<u8>>(&store.table("files"), &"a.txt".to_string())?,
+            Some(vec![1])
+        );
+        assert_eq!(db.get::<_, Vec<u8>>("files", &"a.txt".to_string())?, None);
+
+        db.insert(&store.table("note-a"), &"x".to_string(), &())?;
+        store.migrate(MIGRATIONS)?;
+        assert_eq!(store.version()?, 2);
+        assert!(matches!(store.status(MIGRATIONS)?, SchemaStatus::Current));
+        assert_eq!(
+            db.get::<_, ()>(&store.table("notes/a"), &"x".to_string())?,
+            Some(())
+        );
+
+        // nothing left to apply, nothing is journaled
+        let journal_len = db.journal_tx_len()?;
+        store.migrate(MIGRATIONS)?;
+        assert_eq!(db.journal_tx_len()?, journal_len);
+        Ok(())
+    }
+
+    #[test]
+    fn newer_schema_is_left_alone() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        db.insert("files", &"a.txt".to_string(), &vec![1u8])?;
+        let store = AppletStore::new(&db, "files");
+        store.migrate(MIGRATIONS)?;
+        // an older client only knows the first migration
+        assert!(matches!(
+            store.status(&MIGRATIONS[..1])?,
+            SchemaStatus::Newer(2)
+        ));
+        let journal_len = db.journal_tx_len()?;
+        store.migrate(&MIGRATIONS[..1])?;
+        assert_eq!(db.journal_tx_len()?, journal_len);
+        assert_eq!(store.version()?, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use anondb::Bytes;
+use anondb::JournalTransaction;
+use anondb::TransactionOperation;
+use anyhow::Result;
+use serde::Serialize;
+
+/// Tables and keys changed by journal transactions, local or remote. Sent to applets with
+/// `AppEvent::CloudChanged` so they reload only what they display.
+#[derive(Clone, Debug, Default)]
+pub struct ChangeSet {
+    /// Table name keyed to the encoded keys that were inserted or removed.
+    keys: HashMap<String, HashSet<Vec<u8>>>,
+    /// Tables that were deleted, every key changed.
+    deleted_tables: HashSet<String>,
+    /// Set for operations we don't know the effect of. Matches everything.
+    unknown: bool,
+}
+
+impl ChangeSet {
+    /// Matches every table, for when the journal was replaced rather than appended to.
+    pub fn everything() -> Self {
+        Self {
+            unknown: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_transactions(transactions: &[JournalTransaction]) -> Self {
+        let mut changes = Self::default();
+        for tx in transactions {
+            for operation in &tx.operations {
+                match operation {
+                    TransactionOperation::Insert {
+                        table_name, key, ..
+                    }
+                    | TransactionOperation::Remove(table_name, key) => {
+                        changes
+                            .keys
+                            .entry(table_name.clone())
+                            .or_default()
+                            .insert(key.to_vec());
+                    }
+                    TransactionOperation::DeleteTable(table_name) => {
+                        changes.deleted_tables.insert(table_name.clone());
+                    }
+                    _ => changes.unknown = true,
+                }
+            }
+        }
+        changes
+    }
+
+    /// Whether a table matching `pattern` changed. A trailing `*` matches any table starting
+    /// with the rest of the pattern, e.g. `note-*`.
+    pub fn matches(&self, pattern: &str) -> bool {
+        if self.unknown {
+            return true;
+        }
+        let is_match = |table_name: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => table_name.starts_with(prefix),
+            None => table_name == pattern,
+        };
+        self.keys.keys().any(is_match) || self.deleted_tables.iter().any(is_match)
+    }
+
+    /// Whether `key` in `table_name` was inserted or removed.
+    pub fn matches_key<K: Serialize>(&self, table_name: &str, key: &K) -> Result<bool> {
+        if self.unknown || self.deleted_tables.contains(table_name) {
+            return Ok(true);
+        }
+        let key = Bytes::encode(key)?.to_vec();
+        Ok(self
+            .keys
+            .get(table_name)
+            .is_some_and(|keys| keys.contains(&key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anondb::Journal;
+
+    use super::*;
+
+    fn changes_of(db: &Journal, write: impl FnOnce(&Journal) -> Result<()>) -> Result<ChangeSet> {
+        let start = db.journal_tx_len()?;
+        write(db)?;
+        let mut transactions = Vec::default();
+        for index in start..db.journal_tx_len()? {
+            if let Some(tx) = db.journal_tx_by_index(index)? {
+                transactions.push(tx);
+            }
+        }
+        Ok(ChangeSet::from_transactions(&transactions))
+    }
+
+    #[test]
+    fn matches_tables_and_prefixes() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        // tables exist before the change, only the insert is collected
+        db.insert("notes/note-a", &0u64, &"draft".to_string())?;
+        let changes = changes_of(&db, |db| {
+            db.insert("notes/note-a", &0u64, &"text".to_string())
+        })?;
+        assert!(changes.matches("notes/note-a"));
+        assert!(changes.matches("notes/*"));
+        assert!(changes.matches("notes/note-*"));
+        assert!(changes.matches("*"));
+        assert!(!changes.matches("notes"));
+        assert!(!changes.matches("notes/note-b"));
+        assert!(!changes.matches("files/*"));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_inserted_and_removed_keys() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        db.insert("files", &"old".to_string(), &())?;
+        let changes = changes_of(&db, |db| {
+            db.insert("files", &"new".to_string(), &())?;
+            db.remove::<_, ()>("files", &"old".to_string())?;
+            Ok(())
+        })?;
+        assert!(changes.matches_key("files", &"new".to_string())?);
+        assert!(changes.matches_key("files", &"old".to_string())?);
+        assert!(!changes.matches_key("files", &"other".to_string())?);
+        assert!(!changes.matches_key("notes", &"new".to_string())?);
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_table_matches_every_key() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        db.insert("notes/note-a", &0u64, &"text".to_string())?;
+        let changes = changes_of(&db, |db| {
+            let mut tx = db.begin_write()?;
+            tx.delete_table("notes/note-a")?;
+            tx.commit()
+        })?;
+        assert!(changes.matches("notes/*"));
+        assert!(changes.matches_key("notes/note-a", &7u64)?);
+        assert!(!changes.matches_key("notes/note-b", &0u64)?);
+        Ok(())
+    }
+
+    #[test]
+    fn everything_matches() -> Result<()> {
+        let changes = ChangeSet::everything();
+        assert!(changes.matches("notes/*"));
+        assert!(changes.matches("files"));
+        assert!(changes.matches_key("files", &"any".to_string())?);
+        assert!(!ChangeSet::default().matches("*"));
+        Ok(())
+    }
+}
@@ -1,20 +1,26 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::path::PathBuf;
 
 use anondb::Bytes;
 use anondb::Journal;
 use anondb::JournalTransaction;
+use anondb::TransactionOperation;
 use anyhow::Result;
-use chacha20::ChaCha20;
-use chacha20::cipher::KeyIvInit;
-use chacha20::cipher::StreamCipher;
-use ml_dsa::KeyGen;
-use ml_dsa::MlDsa87;
-use ml_dsa::signature::Signer;
 use serde::Deserialize;
 use serde::Serialize;
 use web_time::SystemTime;
+use zeroize::Zeroizing;
 
+use network_common::CipherAlgorithm;
+use network_common::CloudSigner;
 use network_common::Mutation;
+use network_common::PublicKeyRecord;
+use network_common::SignatureAlgorithm;
+
+use crate::data::ChangeSet;
+use crate::data::StorageStats;
 
 const CLOUD_TABLE_NAME: &str = "_______cloud_data";
 const METADATA_KEY: &str = "metadata";
@@ -41,20 +47,75 @@ impl CloudMetadata {
 }
 
 /// Meta info about an encrypted cloud.
-#[derive(Clone)]
+///
+/// Not `Clone`, the private key and signer are zeroized when the cloud is dropped. Share with
+/// `Arc<Cloud>`.
 pub struct Cloud {
-    /// Public key that identifies the cloud.
+    /// Public key that identifies the cloud. Empty for public clouds opened by id.
     public_key: Vec<u8>,
-    /// Private key that may mutate the cloud.
-    private_key: [u8; 32],
+    /// Private key that may mutate the cloud. `None` for public clouds, which are read only.
+    private_key: Option<Zeroizing<[u8; 32]>>,
+    /// Derived from `private_key` once when the cloud is opened.
+    signer: Option<CloudSigner>,
+    signature_algorithm: SignatureAlgorithm,
+    cipher_algorithm: CipherAlgorithm,
     pub db: Journal,
+    /// Device local tables: caches, drafts, UI state. Never uploaded or replicated and not
+    /// part of `db`, so `flatten_at_index` doesn't see them. In memory in the browser and for
+    /// snapshots.
+    pub local: Journal,
     id: [u8; 32],
     filepath: Option<PathBuf>,
+    local_filepath: Option<PathBuf>,
 }
 
 impl Cloud {
-    pub(crate) fn private_key(&self) -> &[u8; 32] {
-        &self.private_key
+    pub(crate) fn private_key(&self) -> Option<&[u8; 32]> {
+        self.private_key.as_deref()
+    }
+
+    pub fn signature_algorithm(&self) -> SignatureAlgorithm {
+        self.signature_algorithm
+    }
+
+    /// Hex encoding of the private key suitable for import on another device.
+    pub(crate) fn export_key(&self) -> Option<Zeroizing<String>> {
+        self.private_key()
+            .map(|private_key| Self::encode_key(private_key, self.signature_algorithm))
+    }
+
+    /// Keys for ML-DSA-87 clouds are encoded as 64 hex characters. Keys for other algorithms are
+    /// prefixed with the algorithm byte.
+    pub fn encode_key(private_key: &[u8; 32], algorithm: SignatureAlgorithm) -> Zeroizing<String> {
+        let mut encoded = Zeroizing::new(String::with_capacity(66));
+        if algorithm != SignatureAlgorithm::MlDsa87 {
+            encoded.push_str(&hex::encode([algorithm.to_byte()]));
+        }
+        // pushed a character at a time so no temporary copies of the key are left behind
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        for byte in private_key {
+            encoded.push(HEX[(byte >> 4) as usize] as char);
+            encoded.push(HEX[(byte & 0x0f) as usize] as char);
+        }
+        encoded
+    }
+
+    /// Parse a key encoded with `encode_key`.
+    pub fn decode_key(key_str: &str) -> Result<(Zeroizing<[u8; 32]>, SignatureAlgorithm)> {
+        let key_vec = Zeroizing::new(hex::decode(key_str.trim())?);
+        let (algorithm, key_bytes) = match key_vec.len() {
+            32 => (SignatureAlgorithm::MlDsa87, key_vec.as_slice()),
+            33 => (SignatureAlgorithm::from_byte(key_vec[0])?, &key_vec[1..]),
+            _ => anyhow::bail!("Key is not correct length"),
+        };
+        let mut private_key = Zeroizing::new(<[u8; 32]>::default());
+        private_key.copy_from_slice(key_bytes);
+        Ok((private_key, algorithm))
+    }
+
+    /// Public clouds are replicated from decrypted mutations and can't be mutated.
+    pub fn is_read_only(&self) -> bool {
+        self.private_key.is_none()
     }
 
     pub fn id(&self) -> &[u8; 32] {
@@ -72,81 +133,302 @@ impl Cloud {
         self.filepath.as_ref()
     }
 
+    pub fn local_filepath(&self) -> Option<&PathBuf> {
+        self.local_filepath.as_ref()
+    }
+
     pub fn set_metadata(&self, metadata: CloudMetadata) -> Result<()> {
         self.db
             .insert(CLOUD_TABLE_NAME, &METADATA_KEY.to_string(), &metadata)?;
         Ok(())
     }
 
+    /// Whether `changes` include the cloud metadata.
+    pub fn metadata_changed(changes: &ChangeSet) -> Result<bool> {
+        changes.matches_key(CLOUD_TABLE_NAME, &METADATA_KEY.to_string())
+    }
+
     pub fn load_metadata(&self) -> Result<CloudMetadata> {
         let metadata = self.db.get(CLOUD_TABLE_NAME, &METADATA_KEY.to_string())?;
         Ok(metadata.unwrap_or_default())
     }
 
-    pub fn new(data_dir_maybe: Option<PathBuf>) -> Result<Self> {
-        let private_key = rand::random();
-        Self::from_key(private_key, data_dir_maybe)
+    /// Per table sizes, journal size and the largest entries. Walks every table and journal
+    /// transaction.
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        StorageStats::collect(&self.db, self.signature_algorithm)
     }
 
-    pub fn id_from_key(private_key: [u8; 32]) -> [u8; 32] {
-        let signer = MlDsa87::key_gen_internal(&private_key.into());
-        let public_key = signer.verifying_key().encode().to_vec();
-        let id: [u8; 32] = blake3::hash(&public_key).into();
-        id
+    pub fn new(algorithm: SignatureAlgorithm, data_dir_maybe: Option<PathBuf>) -> Result<Self> {
+        let private_key = Zeroizing::new(rand::random());
+        Self::from_key(&private_key, algorithm, data_dir_maybe)
     }
 
-    pub fn from_key(private_key: [u8; 32], data_dir_maybe: Option<PathBuf>) -> Result<Self> {
-        let signer = MlDsa87::key_gen_internal(&private_key.into());
-        let public_key = signer.verifying_key().encode().to_vec();
-        let id: [u8; 32] = blake3::hash(&public_key).into();
-        #[cfg(debug_assertions)]
-        {
-            assert_eq!(
-                id,
-                Self::id_from_key(private_key),
-                "cloud id mismatch, data::Cloud"
-            );
-        }
-        let hex_string = hex::encode(&id) + ".redb";
-        let (db, filepath_maybe) = if let Some(data_dir) = data_dir_maybe {
-            let filepath = data_dir.join(hex_string);
-            (Journal::at_path(&filepath)?, Some(filepath))
-        } else {
-            (Journal::in_memory(None)?, None)
-        };
+    pub fn id_from_key(private_key: &[u8; 32], algorithm: SignatureAlgorithm) -> [u8; 32] {
+        CloudSigner::from_seed(private_key, algorithm).id()
+    }
+
+    pub fn from_key(
+        private_key: &[u8; 32],
+        algorithm: SignatureAlgorithm,
+        data_dir_maybe: Option<PathBuf>,
+    ) -> Result<Self> {
+        let signer = CloudSigner::from_seed(private_key, algorithm);
+        let public_key = signer.public_key().clone();
+        let id = signer.id();
+        let (db, filepath_maybe) = Self::open_db(&id, data_dir_maybe.clone())?;
+        let (local, local_filepath_maybe) = Self::open_local_db(&id, data_dir_maybe)?;
 
         Ok(Self {
             id,
             db,
+            local,
             filepath: filepath_maybe,
-            private_key,
+            local_filepath: local_filepath_maybe,
+            private_key: Some(Zeroizing::new(*private_key)),
+            signer: Some(signer),
+            signature_algorithm: algorithm,
+            cipher_algorithm: CipherAlgorithm::default(),
             public_key,
         })
     }
 
+    /// Open a public cloud by id. The cloud can only be read.
+    pub fn public(id: [u8; 32], data_dir_maybe: Option<PathBuf>) -> Result<Self> {
+        let (db, filepath_maybe) = Self::open_db(&id, data_dir_maybe.clone())?;
+        let (local, local_filepath_maybe) = Self::open_local_db(&id, data_dir_maybe)?;
+
+        Ok(Self {
+            id,
+            db,
+            local,
+            filepath: filepath_maybe,
+            local_filepath: local_filepath_maybe,
+            private_key: None,
+            signer: None,
+            // mutations of public clouds are verified by `RemoteCloud` against the genesis key
+            signature_algorithm: SignatureAlgorithm::default(),
+            cipher_algorithm: CipherAlgorithm::default(),
+            public_key: Vec::default(),
+        })
+    }
+
+    /// Read only, in memory copy of the cloud as it was after journal `index`. Device local
+    /// tables start empty so nothing written while viewing the past reaches the real ones.
+    pub fn snapshot(&self, index: u64) -> Result<Self> {
+        let db = Journal::in_memory(None)?;
+        db.append_tx(&self.db.flatten_at_index(index)?)?;
+        Ok(Self {
+            id: self.id,
+            db,
+            local: Journal::in_memory(None)?,
+            filepath: None,
+            local_filepath: None,
+            private_key: None,
+            signer: None,
+            signature_algorithm: self.signature_algorithm,
+            cipher_algorithm: self.cipher_algorithm,
+            public_key: self.public_key.clone(),
+        })
+    }
+
+    /// Append one transaction that makes every table match its state after journal `index`.
+    /// Earlier history is kept, so synchronized devices replay the revert like any other change.
+    /// The cloud metadata is left as is. Nothing is written if nothing changed since `index`.
+    ///
+    /// Only entries that differ are written: keys missing at `index` are removed, changed keys
+    /// are inserted and tables that didn't exist at `index` are deleted. Keys are only known in
+    /// their encoded form, so the transaction is built from operations and appended directly.
+    pub fn revert_to(&self, index: u64) -> Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("cannot revert a read only cloud");
+        }
+        let len = self.db.journal_tx_len()?;
+        if index >= len {
+            anyhow::bail!("index {index} is past the end of the journal ({len})");
+        }
+        let current = flattened_tables(&self.db.flatten_at_index(len - 1)?);
+        let target = flattened_tables(&self.db.flatten_at_index(index)?);
+        let table_names = current
+            .keys()
+            .chain(target.keys())
+            .filter(|table_name| table_name.as_str() != CLOUD_TABLE_NAME)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let empty = BTreeMap::default();
+        let mut operations = Vec::default();
+        for table_name in table_names {
+            let current_entries = current.get(&table_name).unwrap_or(&empty);
+            let Some(target_entries) = target.get(&table_name) else {
+                operations.push(TransactionOperation::DeleteTable(table_name));
+                continue;
+            };
+            for key in current_entries.keys() {
+                if !target_entries.contains_key(key) {
+                    operations.push(TransactionOperation::Remove(
+                        table_name.clone(),
+                        key.clone(),
+                    ));
+                }
+            }
+            for (key, value) in target_entries {
+                if current_entries.get(key) != Some(value) {
+                    operations.push(TransactionOperation::Insert {
+                        table_name: table_name.clone(),
+                        key: key.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+        }
+        if operations.is_empty() {
+            return Ok(());
+        }
+
+        let last_tx = self
+            .db
+            .journal_tx_by_index(len - 1)?
+            .ok_or(anyhow::anyhow!("journal index {} is missing", len - 1))?;
+        self.db.append_tx(&JournalTransaction {
+            operations,
+            last_tx_hash: last_tx.hash()?,
+        })?;
+        Ok(())
+    }
+
+    /// A new instance whose journal holds exactly `transactions`, to replace a journal that is
+    /// corrupted or has diverged. Device local tables are kept.
+    ///
+    /// The journal is built in a temporary file next to the current one and renamed over it
+    /// once every transaction is appended. If anything fails the current journal is left in
+    /// place and the temporary file is removed. The replaced file is deleted after the swap,
+    /// instances still holding it keep reading the unlinked file until they're dropped.
+    pub(crate) fn rebuilt(&self, transactions: &[JournalTransaction]) -> Result<Self> {
+        let db = match &self.filepath {
+            Some(filepath) => {
+                let rebuild_path = filepath.with_extension("redb.rebuild");
+                if rebuild_path.exists() {
+                    // left by an interrupted rebuild
+                    std::fs::remove_file(&rebuild_path)?;
+                }
+                let appended = Self::append_all(Journal::at_path(&rebuild_path)?, transactions);
+                if let Err(e) = appended {
+                    std::fs::remove_file(&rebuild_path).ok();
+                    return Err(e);
+                }
+                Self::swap_files(filepath, &rebuild_path)?;
+                Journal::at_path(filepath)?
+            }
+            None => {
+                let db = Journal::in_memory(None)?;
+                Self::append_all(db.clone(), transactions)?;
+                db
+            }
+        };
+        Ok(Self {
+            id: self.id,
+            db,
+            // the local journal stays open in this instance
+            local: self.local.clone(),
+            filepath: self.filepath.clone(),
+            local_filepath: self.local_filepath.clone(),
+            private_key: self.private_key.clone(),
+            signer: self
+                .private_key()
+                .map(|private_key| CloudSigner::from_seed(private_key, self.signature_algorithm)),
+            signature_algorithm: self.signature_algorithm,
+            cipher_algorithm: self.cipher_algorithm,
+            public_key: self.public_key.clone(),
+        })
+    }
+
+    /// Append every transaction, closing the journal when done.
+    fn append_all(db: Journal, transactions: &[JournalTransaction]) -> Result<()> {
+        for tx in transactions {
+            db.append_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    /// Move `rebuild_path` to `filepath`. The current file is set aside first and only deleted
+    /// once the rebuilt one is in place, it's restored if the rename fails.
+    fn swap_files(filepath: &Path, rebuild_path: &Path) -> Result<()> {
+        let replaced_path = filepath.with_extension("redb.replaced");
+        std::fs::rename(filepath, &replaced_path)?;
+        if let Err(e) = std::fs::rename(rebuild_path, filepath) {
+            std::fs::rename(&replaced_path, filepath)?;
+            std::fs::remove_file(rebuild_path).ok();
+            return Err(e.into());
+        }
+        // the swap succeeded, a leftover file is only wasted space
+        if let Err(e) = std::fs::remove_file(&replaced_path) {
+            println!("failed to remove replaced journal: {:?}", e);
+        }
+        Ok(())
+    }
+
+    fn open_db(
+        id: &[u8; 32],
+        data_dir_maybe: Option<PathBuf>,
+    ) -> Result<(Journal, Option<PathBuf>)> {
+        let hex_string = hex::encode(id) + ".redb";
+        if let Some(data_dir) = data_dir_maybe {
+            let filepath = data_dir.join(hex_string);
+            Ok((Journal::at_path(&filepath)?, Some(filepath)))
+        } else {
+            Ok((Journal::in_memory(None)?, None))
+        }
+    }
+
+    fn open_local_db(
+        id: &[u8; 32],
+        data_dir_maybe: Option<PathBuf>,
+    ) -> Result<(Journal, Option<PathBuf>)> {
+        if let Some(data_dir) = data_dir_maybe {
+            let filepath = data_dir.join(format!("local-{}.redb", hex::encode(id)));
+            Ok((Journal::at_path(&filepath)?, Some(filepath)))
+        } else {
+            Ok((Journal::in_memory(None)?, None))
+        }
+    }
+
+    fn require_private_key(&self) -> Result<&[u8; 32]> {
+        self.private_key()
+            .ok_or(anyhow::anyhow!("cloud {} is read only", self.id_hex()))
+    }
+
+    /// Compute the key that decrypts `mutation`. Revealing this key makes the mutation public.
+    pub(crate) fn mutation_key(&self, mutation: &Mutation) -> Result<[u8; 32]> {
+        Mutation::derive_key(self.require_private_key()?, mutation.index, &mutation.salt)
+    }
+
+    /// Sign arbitrary bytes with the cloud key.
+    pub(crate) fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let signer = self
+            .signer
+            .as_ref()
+            .ok_or(anyhow::anyhow!("cloud {} is read only", self.id_hex()))?;
+        Ok(signer.sign(message))
+    }
+
     pub(crate) fn decrypt_tx(&self, mutation: Mutation) -> Result<(JournalTransaction, u64)> {
-        let signer = MlDsa87::key_gen_internal(&self.private_key.into());
+        self.require_private_key()?;
 
         if &mutation.public_key_hash != self.id() {
             anyhow::bail!("received mutation for wrong cloud id: {}", self.id_hex());
         }
-        let salt: &[u8; 32] = &mutation.salt;
-
-        mutation.verify(signer.verifying_key().encode().to_vec())?;
 
-        let mutation_key_preimage = Bytes::encode(&(&self.private_key, mutation.index, &salt))?;
-        let mutation_key = blake3::hash(&mutation_key_preimage.as_slice())
-            .as_bytes()
-            .to_vec();
+        mutation.verify(&PublicKeyRecord {
+            signature_algorithm: self.signature_algorithm,
+            cipher_algorithm: self.cipher_algorithm,
+            public_key: self.public_key.clone(),
+        })?;
 
+        let mutation_key = Zeroizing::new(self.mutation_key(&mutation)?);
         let mut tx_bytes = mutation.data; // encrypted tx data
-        let mut chacha = ChaCha20::new(
-            mutation_key.as_slice().into(),
-            // we can safely choose 0 as the nonce because the encryption key is salted with a
-            // strong random value preventing any encryption key from being used twice.
-            vec![0_u8; 12].as_slice().into(),
-        );
-        chacha.apply_keystream(&mut tx_bytes);
+        self.cipher_algorithm
+            .apply_keystream(&mutation_key, &mut tx_bytes);
 
         // tx_bytes is now decrypted
         Ok((Bytes::parse(&tx_bytes.into())?, mutation.index))
@@ -158,35 +440,28 @@ impl Cloud {
         transaction: JournalTransaction,
         index: u64,
     ) -> Result<Mutation> {
-        let signer = MlDsa87::key_gen_internal(&self.private_key.into());
+        let private_key = self.require_private_key()?;
 
         let salt: [u8; 32] = rand::random();
 
-        let mutation_key_preimage = Bytes::encode(&(&self.private_key, index, &salt))?;
-        let mutation_key = blake3::hash(&mutation_key_preimage.as_slice())
-            .as_bytes()
-            .to_vec();
+        let mutation_key = Zeroizing::new(Mutation::derive_key(private_key, index, &salt)?);
 
         // now we can encrypt the transaction data
 
         let mut tx_bytes: Vec<u8> = Bytes::encode(&transaction)?.into();
-        let mut chacha = ChaCha20::new(
-            mutation_key.as_slice().into(),
-            // we can safely choose 0 as the nonce because the encryption key is salted with a
-            // strong random value preventing any encryption key from being used twice.
-            vec![0_u8; 12].as_slice().into(),
-        );
-        chacha.apply_keystream(&mut tx_bytes);
-        drop(chacha);
+        self.cipher_algorithm
+            .apply_keystream(&mutation_key, &mut tx_bytes);
 
         // tx_bytes are now encrypted
 
-        let signature = signer.sign(&tx_bytes).encode().to_vec();
+        let signature = self.sign(&tx_bytes)?;
 
         Ok(Mutation {
             index,
             data: tx_bytes,
             signature,
+            signature_algorithm: self.signature_algorithm,
+            cipher_algorithm: self.cipher_algorithm,
             public_key_hash: self.id,
             public_key: if index == 0 {
                 Some(self.public_key.clone())
@@ -195,6 +470,105 @@ impl Cloud {
             },
             salt,
             mutation_key: None,
+            creation_proof: None,
         })
     }
 }
+
+/// Entries of each table after applying the operations of a flattened transaction.
+fn flattened_tables(tx: &JournalTransaction) -> BTreeMap<String, BTreeMap<Bytes, Bytes>> {
+    let mut tables = BTreeMap::<String, BTreeMap<Bytes, Bytes>>::default();
+    for operation in &tx.operations {
+        match operation {
+            TransactionOperation::Insert {
+                table_name,
+                key,
+                value,
+            } => {
+                tables
+                    .entry(table_name.clone())
+                    .or_default()
+                    .insert(key.clone(), value.clone());
+            }
+            TransactionOperation::Remove(table_name, key) => {
+                if let Some(table) = tables.get_mut(table_name) {
+                    table.remove(key);
+                }
+            }
+            TransactionOperation::DeleteTable(table_name) => {
+                tables.remove(table_name);
+            }
+            // creating a table adds no entries
+            _ => {}
+        }
+    }
+    tables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(cloud: &Cloud, table_name: &str, key: &str, value: u32) -> Result<()> {
+        cloud.db.insert::<&str, u32>(table_name, &key, &value)
+    }
+
+    /// Tables after journal `index`, without the metadata `revert_to` leaves alone.
+    fn tables_at(cloud: &Cloud, index: u64) -> Result<BTreeMap<String, BTreeMap<Bytes, Bytes>>> {
+        let mut tables = flattened_tables(&cloud.db.flatten_at_index(index)?);
+        tables.remove(CLOUD_TABLE_NAME);
+        Ok(tables)
+    }
+
+    #[test]
+    fn revert_matches_flattened_index() -> Result<()> {
+        let cloud = Cloud::new(SignatureAlgorithm::default(), None)?;
+        insert(&cloud, "kept", "a", 1)?;
+        insert(&cloud, "kept", "b", 2)?;
+        insert(&cloud, "deleted", "a", 1)?;
+        let index = cloud.db.journal_tx_len()? - 1;
+
+        insert(&cloud, "kept", "b", 3)?;
+        insert(&cloud, "kept", "c", 4)?;
+        cloud.db.remove::<&str, u32>("kept", &"a")?;
+        let mut tx = cloud.db.begin_write()?;
+        tx.delete_table("deleted")?;
+        tx.commit()?;
+        insert(&cloud, "created", "a", 1)?;
+
+        cloud.revert_to(index)?;
+        let len = cloud.db.journal_tx_len()?;
+        assert_eq!(tables_at(&cloud, len - 1)?, tables_at(&cloud, index)?);
+
+        // unchanged entries and tables that existed at `index` aren't rewritten
+        let revert = cloud.db.journal_tx_by_index(len - 1)?.unwrap();
+        assert_eq!(revert.operations.len(), 5);
+        assert!(revert.operations.iter().all(|operation| !matches!(
+            operation,
+            TransactionOperation::DeleteTable(table_name) if table_name != "created"
+        )));
+
+        // reverting again changes nothing
+        cloud.revert_to(index)?;
+        assert_eq!(cloud.db.journal_tx_len()?, len);
+        Ok(())
+    }
+
+    #[test]
+    fn local_writes_stay_out_of_the_journal() -> Result<()> {
+        let cloud = Cloud::new(SignatureAlgorithm::default(), None)?;
+        insert(&cloud, "synced", "a", 1)?;
+        let len = cloud.db.journal_tx_len()?;
+        let tables = tables_at(&cloud, len - 1)?;
+
+        cloud.local.insert::<&str, u32>("notes/drafts", &"a", &2)?;
+        cloud.local.insert::<&str, u32>("synced", &"b", &3)?;
+        cloud.local.remove::<&str, u32>("synced", &"b")?;
+
+        assert_eq!(cloud.db.journal_tx_len()?, len);
+        assert_eq!(tables_at(&cloud, len - 1)?, tables);
+        assert_eq!(cloud.db.get::<&str, u32>("notes/drafts", &"a")?, None);
+        assert_eq!(cloud.local.get::<&str, u32>("notes/drafts", &"a")?, Some(2));
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use anondb::Bytes;
+use anondb::Journal;
+use anyhow::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::AppletStore;
+
+/// A serde typed document stored in a `Collection`, keyed by a string id.
+pub trait Document: Serialize + DeserializeOwned {
+    /// Table holding the documents in the applet's namespace. Index tables are prefixed with
+    /// it.
+    const COLLECTION: &'static str;
+
+    /// `(index name, value)` for each secondary index of the document. Values are built with
+    /// `index_value`.
+    fn index_values(&self) -> Result<Vec<(&'static str, Bytes)>> {
+        Ok(Vec::default())
+    }
+}
+
+/// Encode a value for `Document::index_values` or `Query::eq`.
+pub fn index_value<T: Serialize>(value: &T) -> Result<Bytes> {
+    Bytes::encode(value)
+}
+
+/// Table listing the ids of documents in `table_name` with `value` in `index`. Values are hashed
+/// to keep table names short.
+fn index_table(table_name: &str, index: &str, value: &Bytes) -> String {
+    format!(
+        "{table_name}.{index}.{}",
+        blake3::hash(&value.to_vec()).to_hex()
+    )
+}
+
+/// Documents in an applet's namespace with secondary indexes.
+///
+/// Each indexed value gets a table listing the ids of the documents with that value. Index
+/// tables are written in the same transaction as the document, so they are journaled and
+/// replicate to other devices with it.
+pub struct Collection<'a, D: Document> {
+    db: &'a Journal,
+    table_name: String,
+    _document: PhantomData<D>,
+}
+
+impl<'a, D: Document> Collection<'a, D> {
+    pub fn new(store: &AppletStore<'a>) -> Self {
+        Self {
+            db: store.db(),
+            table_name: store.table(D::COLLECTION),
+            _document: PhantomData,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<D>> {
+        self.db.get(&self.table_name, &id)
+    }
+
+    /// Insert or replace a document. Index entries are only written for values that changed.
+    pub fn insert(&self, id: &str, document: &D) -> Result<()> {
+        let mut tx = self.db.begin_write()?;
+        let mut table = tx.open_table(&self.table_name)?;
+        // read in the transaction so a concurrent write can't leave stale index entries
+        let previous = table.get::<&str, D>(&id)?;
+        table.insert(&id, document)?;
+        drop(table);
+        let previous_index_tables = match &previous {
+            Some(previous) => self.index_tables(previous)?,
+            None => Vec::default(),
+        };
+        let index_tables = self.index_tables(document)?;
+        for table_name in &previous_index_tables {
+            if index_tables.contains(table_name) {
+                continue;
+            }
+            let mut index_table = tx.open_table(table_name)?;
+            index_table.remove(&id)?;
+            let is_empty = index_table.len()? == 0;
+            drop(index_table);
+            if is_empty {
+                tx.delete_table(table_name)?;
+            }
+        }
+        for table_name in &index_tables {
+            if previous_index_tables.contains(table_name) {
+                continue;
+            }
+            let mut index_table = tx.open_table(table_name)?;
+            index_table.insert(&id, &())?;
+        }
+        tx.commit()
+    }
+
+    pub fn remove(&self, id: &str) -> Result<Option<D>> {
+        let mut tx = self.db.begin_write()?;
+        let mut table = tx.open_table(&self.table_name)?;
+        let Some(previous) = table.get::<&str, D>(&id)? else {
+            return Ok(None);
+        };
+        table.remove(&id)?;
+        drop(table);
+        for table_name in self.index_tables(&previous)? {
+            let mut index_table = tx.open_table(&table_name)?;
+            index_table.remove(&id)?;
+            let is_empty = index_table.len()? == 0;
+            drop(index_table);
+            if is_empty {
+                tx.delete_table(&table_name)?;
+            }
+        }
+        tx.commit()?;
+        Ok(Some(previous))
+    }
+
+    /// Index tables listing `document`, emptied tables are deleted when it's removed.
+    fn index_tables(&self, document: &D) -> Result<Vec<String>> {
+        Ok(document
+            .index_values()?
+            .iter()
+            .map(|(index, value)| index_table(&self.table_name, index, value))
+            .collect())
+    }
+
+    pub fn query(&self) -> Query<'a, D> {
+        Query {
+            db: self.db,
+            table_name: self.table_name.clone(),
+            eq: Vec::default(),
+            filters: Vec::default(),
+            sort: None,
+            limit: None,
+        }
+    }
+}
+
+type Filter<'a, D> = Box<dyn Fn(&D) -> bool + 'a>;
+type Comparator<'a, D> = Box<dyn Fn(&D, &D) -> Ordering + 'a>;
+
+/// Filter, sort and limit the documents in a collection. The first `eq` filter selects
+/// documents through its index, everything else is applied in memory.
+pub struct Query<'a, D: Document> {
+    db: &'a Journal,
+    table_name: String,
+    eq: Vec<(&'static str, Bytes)>,
+    filters: Vec<Filter<'a, D>>,
+    sort: Option<Comparator<'a, D>>,
+    limit: Option<usize>,
+}
+
+impl<'a, D: Document> Query<'a, D> {
+    /// Documents where `index` equals `value`.
+    pub fn eq<T: Serialize>(mut self, index: &'static str, value: &T) -> Result<Self> {
+        self.eq.push((index, index_value(value)?));
+        Ok(self)
+    }
+
+    pub fn filter(mut self, filter: impl Fn(&D) -> bool + 'a) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn sort_by(mut self, compare: impl Fn(&D, &D) -> Ordering + 'a) -> Self {
+        self.sort = Some(Box::new(compare));
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn run(self) -> Result<Vec<(String, D)>> {
+        let documents = if let Some((index, value)) = self.eq.first() {
+            let mut documents = Vec::default();
+            for id in self
+                .db
+                .list_keys::<String>(&index_table(&self.table_name, index, value))?
+            {
+                if let Some(document) = self.db.get::<String, D>(&self.table_name, &id)? {
+                    documents.push((id, document));
+                }
+            }
+            documents
+        } else {
+            self.db
+                .find_many::<String, D, _>(&self.table_name, |_, _| true)?
+        };
+        let mut matching = Vec::default();
+        for (id, document) in documents {
+            if !self.filters.iter().all(|filter| filter(&document)) {
+                continue;
+            }
+            if self.eq.len() > 1 {
+                let values = document.index_values()?;
+                let matches = |(index, value): &(&str, Bytes)| {
+                    values
+                        .iter()
+                        .any(|(name, v)| name == index && v.to_vec() == value.to_vec())
+                };
+                if !self.eq[1..].iter().all(matches) {
+                    continue;
+                }
+            }
+            matching.push((id, document));
+        }
+        if let Some(compare) = &self.sort {
+            matching.sort_by(|(_, a), (_, b)| compare(a, b));
+        }
+        if let Some(limit) = self.limit {
+            matching.truncate(limit);
+        }
+        Ok(matching)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        kind: String,
+        size: u32,
+    }
+
+    impl Document for Item {
+        const COLLECTION: &'static str = "items";
+
+        fn index_values(&self) -> Result<Vec<(&'static str, Bytes)>> {
+            Ok(vec![("kind", index_value(&self.kind)?)])
+        }
+    }
+
+    fn item(kind: &str, size: u32) -> Item {
+        Item {
+            kind: kind.to_string(),
+            size,
+        }
+    }
+
+    fn ids(documents: Vec<(String, Item)>) -> Vec<String> {
+        documents.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn indexes_replicate_through_the_journal() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        let store = AppletStore::new(&db, "test");
+        let items = Collection::<Item>::new(&store);
+        items.insert("a", &item("x", 1))?;
+        items.insert("b", &item("y", 2))?;
+        items.insert("c", &item("x", 3))?;
+        // moves "a" to another index value, "b" keeps its value
+        items.insert("a", &item("y", 4))?;
+        items.insert("b", &item("y", 5))?;
+        items.remove("c")?;
+
+        let replica = Journal::in_memory(None)?;
+        for tx in db.journal_transactions()? {
+            replica.append_tx(&tx)?;
+        }
+        let store = AppletStore::new(&replica, "test");
+        let items = Collection::<Item>::new(&store);
+        let y = items
+            .query()
+            .eq("kind", &"y".to_string())?
+            .sort_by(|a, b| a.size.cmp(&b.size))
+            .run()?;
+        assert_eq!(ids(y), vec!["a", "b"]);
+        assert!(
+            items
+                .query()
+                .eq("kind", &"x".to_string())?
+                .run()?
+                .is_empty()
+        );
+        assert_eq!(items.get("b")?, Some(item("y", 5)));
+        assert_eq!(items.get("c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn query_filters_sorts_and_limits() -> Result<()> {
+        let db = Journal::in_memory(None)?;
+        let store = AppletStore::new(&db, "test");
+        let items = Collection::<Item>::new(&store);
+        for (id, size) in [("a", 3), ("b", 1), ("c", 2), ("d", 4)] {
+            items.insert(id, &item("x", size))?;
+        }
+        let smallest = items
+            .query()
+            .filter(|item| item.size < 4)
+            .sort_by(|a, b| a.size.cmp(&b.size))
+            .limit(2)
+            .run()?;
+        assert_eq!(ids(smallest), vec!["b", "c"]);
+        Ok(())
+    }
+}
@@ -7,7 +7,15 @@ use egui::load::BytesLoader;
 use egui::load::BytesPoll;
 use egui::load::LoadError;
 
+use super::AppletStore;
 use super::Cloud;
+use super::Trash;
+
+/// Namespace of the files applet.
+pub const FILES_NAMESPACE: &str = "files";
+/// Filename keyed to the file contents. Written by the files applet and the notes export, read
+/// by `CloudFileLoader`.
+pub const FILES_TABLE: &str = "files";
 
 #[derive(Default)]
 pub struct CloudFileLoader {
@@ -30,7 +38,11 @@ impl BytesLoader for CloudFileLoader {
     fn load(&self, _ctx: &egui::Context, uri: &str) -> egui::load::BytesLoadResult {
         let name = uri.trim_start_matches("file://").to_string();
         if let Some(cloud) = self.active_cloud.read().unwrap().clone()
-            && let Some(data) = cloud.db.get::<_, Bytes>("files", &name).ok().flatten()
+            && let files_table = AppletStore::new(&cloud.db, FILES_NAMESPACE).table(FILES_TABLE)
+            && !Trash::new(&cloud)
+                .is_deleted(&files_table, Some(&name))
+                .unwrap_or(false)
+            && let Some(data) = cloud.db.get::<_, Bytes>(&files_table, &name).ok().flatten()
         {
             self.data.write().unwrap().insert(name, data.to_vec());
             Ok(BytesPoll::Ready {
@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// Why a journal index failed verification.
+#[derive(Clone, Debug)]
+pub enum IntegrityProblem {
+    /// The local transaction is missing or can't be decoded.
+    Unreadable(String),
+    /// `last_tx_hash` doesn't match the hash of the previous transaction.
+    BrokenChain,
+    /// The local transaction differs from the remote's mutation.
+    Diverged,
+    /// The remote's mutation couldn't be downloaded or decrypted.
+    RemoteUnreadable(String),
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreadable(e) => write!(f, "local transaction is unreadable: {e}"),
+            Self::BrokenChain => write!(f, "hash chain is broken"),
+            Self::Diverged => write!(f, "local transaction differs from the remote"),
+            Self::RemoteUnreadable(e) => write!(f, "remote mutation is unreadable: {e}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Repair {
+    /// Replace the local journal with the remote's mutations, see `RemoteCloud::rebuild`.
+    Rebuild,
+    /// Forget the confirmed index so every transaction is compared with the remote again, see
+    /// `RemoteCloud::reset_confirmations`.
+    Reconfirm,
+}
+
+/// Result of `RemoteCloud::verify`.
+#[derive(Clone, Debug)]
+pub struct IntegrityReport {
+    pub local_len: u64,
+    pub remote_len: u64,
+    /// Number of local transactions checked before the first problem.
+    pub verified_len: u64,
+    pub first_bad: Option<(u64, IntegrityProblem)>,
+    /// `latest_confirmed_index` from the sync state when the journal was walked.
+    pub latest_confirmed_index: Option<u64>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.first_bad.is_none() && !self.confirmed_index_invalid()
+    }
+
+    /// Whether the sync state claims a confirmation for a transaction that isn't on the remote
+    /// or failed verification.
+    pub fn confirmed_index_invalid(&self) -> bool {
+        self.latest_confirmed_index
+            .is_some_and(|index| index >= self.verified_len.min(self.remote_len))
+    }
+
+    /// `None` when there's nothing to repair, or the remote itself is the problem.
+    pub fn suggested_repair(&self) -> Option<Repair> {
+        match &self.first_bad {
+            Some((_, IntegrityProblem::RemoteUnreadable(_))) => None,
+            Some(_) => Some(Repair::Rebuild),
+            None if self.confirmed_index_invalid() => Some(Repair::Reconfirm),
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} local, {} remote, {} verified",
+            self.local_len, self.remote_len, self.verified_len
+        )?;
+        if let Some((index, problem)) = &self.first_bad {
+            write!(f, "; first bad index #{index}: {problem}")?;
+        }
+        if self.confirmed_index_invalid() {
+            write!(
+                f,
+                "; confirmed index #{} is invalid",
+                self.latest_confirmed_index.unwrap_or_default()
+            )?;
+        }
+        if self.is_ok() {
+            write!(f, "; no problems found")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::RwLock;
+
+use anondb::Bytes;
+use anyhow::Result;
+use chacha20::ChaCha20;
+use chacha20::cipher::KeyIvInit;
+use chacha20::cipher::StreamCipher;
+use ml_kem::B32;
+use ml_kem::EncapsulateDeterministic;
+use ml_kem::EncodedSizeUser;
+use ml_kem::KemCore;
+use ml_kem::MlKem768;
+use ml_kem::kem::Decapsulate;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde::Serialize;
+use web_time::SystemTime;
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
+
+use network_common::*;
+
+use crate::network::TlsTrust;
+use crate::network::http_client;
+
+use super::remote_cloud::DEFAULT_SYNC_HTTP_URL;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// An offer to join a cloud, sent to another user's mailbox.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub cloud_key: [u8; 32],
+    pub signature_algorithm: SignatureAlgorithm,
+    pub note: String,
+    pub sent_at: u64,
+}
+
+impl Drop for Invite {
+    fn drop(&mut self) {
+        self.cloud_key.zeroize();
+    }
+}
+
+/// An invite that was successfully decrypted from our mailbox.
+#[derive(Clone)]
+pub struct ReceivedInvite {
+    /// Server the invite was delivered to. Indexes count up separately on each server.
+    pub http_url: String,
+    pub index: u64,
+    pub received_at: u64,
+    pub invite: Invite,
+}
+
+impl ReceivedInvite {
+    pub fn cloud_id(&self) -> [u8; 32] {
+        super::Cloud::id_from_key(&self.invite.cloud_key, self.invite.signature_algorithm)
+    }
+}
+
+/// Keys used to derive the encryption and authentication keys for a message from a KEM shared
+/// key.
+fn message_keys(shared_key: &[u8]) -> (Zeroizing<[u8; 32]>, Zeroizing<[u8; 32]>) {
+    (
+        Zeroizing::new(blake3::derive_key(
+            "btk mailbox message encryption",
+            shared_key,
+        )),
+        Zeroizing::new(blake3::derive_key(
+            "btk mailbox message authentication",
+            shared_key,
+        )),
+    )
+}
+
+fn apply_keystream(key: &[u8; 32], data: &mut [u8]) {
+    let mut chacha = ChaCha20::new(
+        key.into(),
+        // each message uses a fresh encapsulation so the key is never reused
+        vec![0_u8; 12].as_slice().into(),
+    );
+    chacha.apply_keystream(data);
+}
+
+/// Sync server a mailbox is published to and read from, see `AppState::set_mailbox_server`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MailboxServer {
+    pub http_url: String,
+    pub tls_trust: TlsTrust,
+}
+
+impl Default for MailboxServer {
+    fn default() -> Self {
+        Self {
+            http_url: DEFAULT_SYNC_HTTP_URL.to_string(),
+            // the default remote is verified with the system roots
+            tls_trust: TlsTrust::System,
+        }
+    }
+}
+
+/// A device identity able to receive encrypted invites. The encapsulation key is published to
+/// the sync server, and the hash of it is the address others send invites to.
+pub struct Mailbox {
+    /// The http client is built from the server's `tls_trust`.
+    server: RwLock<(MailboxServer, reqwest::Client)>,
+    /// Zeroized on drop by `ml-kem`.
+    decapsulation_key: DecapsulationKey,
+    encapsulation_key: EncapsulationKey,
+    recipient_id: [u8; 32],
+}
+
+impl Mailbox {
+    /// Derive the mailbox keypair from a 64 byte seed. The keypair is the same on every server.
+    pub fn from_seed(seed: &[u8; 64], server: MailboxServer) -> Result<Self> {
+        let mut d = B32::try_from(&seed[..32]).expect("seed is 64 bytes");
+        let mut z = B32::try_from(&seed[32..]).expect("seed is 64 bytes");
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate_deterministic(&d, &z);
+        d.zeroize();
+        z.zeroize();
+        let recipient_id = recipient_id(encapsulation_key.as_bytes().as_slice());
+        Ok(Self {
+            server: RwLock::new((server.clone(), http_client(&server.tls_trust)?)),
+            decapsulation_key,
+            encapsulation_key,
+            recipient_id,
+        })
+    }
+
+    pub fn server(&self) -> MailboxServer {
+        self.server.read().unwrap().0.clone()
+    }
+
+    pub fn http_url(&self) -> String {
+        self.server.read().unwrap().0.http_url.clone()
+    }
+
+    /// Publish to and read from another server. The key is published again by the mailbox loop
+    /// in `AppState::init`.
+    pub fn set_server(&self, server: MailboxServer) -> Result<()> {
+        let http_client = http_client(&server.tls_trust)?;
+        *self.server.write().unwrap() = (server, http_client);
+        Ok(())
+    }
+
+    fn http(&self) -> (String, reqwest::Client) {
+        let server = self.server.read().unwrap();
+        (server.0.http_url.clone(), server.1.clone())
+    }
+
+    pub fn recipient_id(&self) -> &[u8; 32] {
+        &self.recipient_id
+    }
+
+    pub fn recipient_id_hex(&self) -> String {
+        hex::encode(self.recipient_id)
+    }
+
+    /// Make our encapsulation key available so others can send us invites. Returns the url of
+    /// the server it was published to.
+    pub async fn publish_key(&self) -> Result<String> {
+        let (http_url, http_client) = self.http();
+        let mut url = reqwest::Url::parse(&http_url)?.join("/mailbox/key")?;
+        url.set_query(Some(&format!("recipient_id={}", self.recipient_id_hex())));
+        let encapsulation_key = self.encapsulation_key.as_bytes().to_vec();
+        let res = http_client
+            .post(url)
+            .body(Bytes::encode(&encapsulation_key)?.to_vec())
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to publish mailbox key: {:?}", res.status());
+        }
+        Ok(http_url)
+    }
+
+    /// Retrieve the still encrypted entries with `index >= after`. Returns the url of the server
+    /// they were read from.
+    pub async fn fetch_entries(&self, after: u64) -> Result<(String, Vec<MailboxEntry>)> {
+        let (http_url, http_client) = self.http();
+        let mut url = reqwest::Url::parse(&http_url)?.join("/mailbox")?;
+        url.set_query(Some(&format!(
+            "recipient_id={}&after={after}",
+            self.recipient_id_hex()
+        )));
+        let res = http_client.get(url).send().await?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to load mailbox: {:?}", res.status());
+        }
+        let entries = Bytes::from(res.bytes().await?.to_vec()).parse::<Vec<MailboxEntry>>()?;
+        Ok((http_url, entries))
+    }
+
+    /// Decrypt an entry read from the server at `http_url`.
+    pub fn open_entry(&self, http_url: &str, entry: &MailboxEntry) -> Result<ReceivedInvite> {
+        Ok(ReceivedInvite {
+            http_url: http_url.to_string(),
+            index: entry.index,
+            received_at: entry.created_at,
+            invite: self.decrypt(&entry.message)?,
+        })
+    }
+
+    fn decrypt(&self, message: &MailboxMessage) -> Result<Invite> {
+        let kem_ciphertext =
+            ml_kem::Ciphertext::<MlKem768>::try_from(message.kem_ciphertext.as_slice())
+                .map_err(|_| anyhow::anyhow!("invalid kem ciphertext length"))?;
+        let shared_key = Zeroizing::new(
+            self.decapsulation_key
+                .decapsulate(&kem_ciphertext)
+                .map_err(|_| anyhow::anyhow!("failed to decapsulate"))?,
+        );
+        let (encryption_key, authentication_key) = message_keys(shared_key.as_slice());
+        let tag: [u8; 32] = blake3::keyed_hash(&authentication_key, &message.ciphertext).into();
+        if tag != message.tag {
+            anyhow::bail!("message authentication failed");
+        }
+        let mut plaintext = Zeroizing::new(message.ciphertext.clone());
+        apply_keystream(&encryption_key, &mut plaintext);
+        Ok(bincode::deserialize::<Invite>(&plaintext)?)
+    }
+
+    /// Encrypt an invite to the encapsulation key published for `recipient_id` and append it to
+    /// their mailbox.
+    pub async fn send_invite(
+        http_url: &str,
+        tls_trust: &TlsTrust,
+        recipient_id: [u8; 32],
+        cloud_key: &[u8; 32],
+        signature_algorithm: SignatureAlgorithm,
+        note: String,
+    ) -> Result<()> {
+        let base_url = reqwest::Url::parse(http_url)?;
+        let query = format!("recipient_id={}", hex::encode(recipient_id));
+        let client = http_client(tls_trust)?;
+
+        let mut url = base_url.join("/mailbox/key")?;
+        url.set_query(Some(&query));
+        let res = client.get(url).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            anyhow::bail!("recipient has not published a mailbox key");
+        } else if !res.status().is_success() {
+            anyhow::bail!("failed to load mailbox key: {:?}", res.status());
+        }
+        let key_bytes = Bytes::from(res.bytes().await?.to_vec()).parse::<Vec<u8>>()?;
+        if network_common::recipient_id(&key_bytes) != recipient_id {
+            anyhow::bail!("server returned the wrong mailbox key");
+        }
+        let encoded_key = ml_kem::Encoded::<EncapsulationKey>::try_from(key_bytes.as_slice())
+            .map_err(|_| anyhow::anyhow!("invalid encapsulation key length"))?;
+        let encapsulation_key = EncapsulationKey::from_bytes(&encoded_key);
+
+        let randomness: [u8; 32] = rand::random();
+        let (kem_ciphertext, shared_key) = encapsulation_key
+            .encapsulate_deterministic(&B32::from(randomness))
+            .map_err(|_| anyhow::anyhow!("failed to encapsulate"))?;
+        let shared_key = Zeroizing::new(shared_key);
+        let (encryption_key, authentication_key) = message_keys(shared_key.as_slice());
+
+        let invite = Invite {
+            cloud_key: *cloud_key,
+            signature_algorithm,
+            note,
+            sent_at: SystemTime::now()
+                .duration_since(web_time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs(),
+        };
+        let mut ciphertext: Vec<u8> = Bytes::encode(&invite)?.into();
+        apply_keystream(&encryption_key, &mut ciphertext);
+        let message = MailboxMessage {
+            kem_ciphertext: kem_ciphertext.to_vec(),
+            tag: blake3::keyed_hash(&authentication_key, &ciphertext).into(),
+            ciphertext,
+        };
+
+        let mut url = base_url.join("/mailbox")?;
+        url.set_query(Some(&query));
+        let res = client
+            .post(url)
+            .body(Bytes::encode(&message)?.to_vec())
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to deliver invite: {:?}", res.status());
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,42 @@
 mod app_state;
+mod applet_store;
+mod changes;
 mod cloud;
+mod collection;
 mod file_loader;
+mod integrity;
+mod mailbox;
 mod remote_cloud;
+mod storage_stats;
+mod transfer;
+mod trash;
 
 pub use app_state::AppState;
+pub use applet_store::AppletStore;
+pub use applet_store::Migration;
+pub use applet_store::SchemaStatus;
+pub use changes::ChangeSet;
 pub use cloud::Cloud;
 pub use cloud::CloudMetadata;
+pub use collection::Collection;
+pub use collection::Document;
+pub use collection::index_value;
 pub use file_loader::CloudFileLoader;
+pub use file_loader::FILES_NAMESPACE;
+pub use file_loader::FILES_TABLE;
+pub use integrity::IntegrityProblem;
+pub use integrity::IntegrityReport;
+pub use integrity::Repair;
+pub use mailbox::Mailbox;
+pub use mailbox::MailboxServer;
+pub use mailbox::ReceivedInvite;
 pub use remote_cloud::RemoteCloud;
+pub use storage_stats::StorageStats;
+pub use storage_stats::format_bytes;
+pub use transfer::TransferRecord;
+pub use trash::DeletedCloud;
+pub use trash::TRASH_NAMESPACE;
+pub use trash::TRASH_RETENTION;
+pub use trash::Trash;
+pub use trash::TrashItem;
+pub use trash::format_age;
@@ -4,20 +4,40 @@ use std::sync::RwLock;
 
 use anondb::Bytes;
 use anondb::Journal;
+use anondb::JournalTransaction;
 use anyhow::Result;
 use reqwest::StatusCode;
 use serde::Deserialize;
 use serde::Serialize;
+use web_time::Duration;
 use web_time::Instant;
 
-use crate::app::AppEvent;
 use crate::network::NetworkConnection;
+use crate::network::TlsTrust;
+use crate::network::http_client;
+use crate::tokio;
 use network_common::*;
 
 use super::Cloud;
+use super::IntegrityProblem;
+use super::IntegrityReport;
 
-const DEFAULT_SYNC_HTTP_URL: &str = "https://btk_worker.jchancehud.workers.dev";
+pub(crate) const DEFAULT_SYNC_HTTP_URL: &str = "https://btk_worker.jchancehud.workers.dev";
 const DEFAULT_SYNC_WS_URL: &str = "wss://btk_worker.jchancehud.workers.dev";
+/// Proof of work difficulty keyed to a solution for this cloud.
+const CREATION_POW_TABLE: &str = "creation_pow";
+/// Invite token or enrollment signature to create this cloud on the remote.
+const CREATION_TOKEN_TABLE: &str = "creation_token";
+/// Nonces hashed between yields while searching for a proof of work.
+const POW_BATCH_SIZE: u64 = 10_000;
+/// Websocket connection attempts that may fail before changes are long polled over http.
+const LONG_POLL_AFTER_FAILED_CONNECTIONS: u32 = 3;
+/// Seconds the remote may hold a long poll open.
+const LONG_POLL_WAIT_SECS: u64 = 30;
+/// Shown once the remote rejects our protocol version.
+const INCOMPATIBLE_STATUS: &str = "Server requires a newer BTK";
+/// Shown once the remote announces a protocol version older than we accept.
+const OUTDATED_REMOTE_STATUS: &str = "Server is too old for this BTK";
 
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct CloudSyncState {
@@ -25,6 +45,28 @@ pub struct CloudSyncState {
     pub ws_url: String,
     pub latest_confirmed_index: Option<u64>,
     pub synchronization_enabled: bool,
+    pub tls_trust: TlsTrust,
+}
+
+/// Sync state written before `tls_trust` was added.
+#[derive(Deserialize)]
+struct LegacyCloudSyncState {
+    http_url: String,
+    ws_url: String,
+    latest_confirmed_index: Option<u64>,
+    synchronization_enabled: bool,
+}
+
+impl From<LegacyCloudSyncState> for CloudSyncState {
+    fn from(value: LegacyCloudSyncState) -> Self {
+        Self {
+            http_url: value.http_url,
+            ws_url: value.ws_url,
+            latest_confirmed_index: value.latest_confirmed_index,
+            synchronization_enabled: value.synchronization_enabled,
+            tls_trust: TlsTrust::default(),
+        }
+    }
 }
 
 impl Default for CloudSyncState {
@@ -34,6 +76,7 @@ impl Default for CloudSyncState {
             ws_url: DEFAULT_SYNC_WS_URL.to_string(),
             latest_confirmed_index: None,
             synchronization_enabled: true,
+            tls_trust: TlsTrust::default(),
         }
     }
 }
@@ -45,10 +88,31 @@ pub struct RemoteCloud {
     ctx: egui::Context,
     db: Journal,
     sync_state: Arc<RwLock<CloudSyncState>>,
+    /// Built from `sync_state.tls_trust`.
+    http_client: Arc<RwLock<reqwest::Client>>,
     connection_maybe: Arc<RwLock<Option<NetworkConnection>>>,
     pub(crate) cloud: Arc<Cloud>,
     initial_sync_complete: Arc<RwLock<bool>>,
+    /// Set once every mutation the remote had when we connected has been downloaded.
+    caught_up: Arc<RwLock<bool>>,
     last_keepalive: Arc<RwLock<Instant>>,
+    /// Set while a proof of work is being computed in the background.
+    pow_searching: Arc<RwLock<bool>>,
+    /// Consecutive websocket connection attempts that didn't open.
+    failed_connections: Arc<RwLock<u32>>,
+    /// Set while a `/state` long poll is waiting in the background.
+    long_polling: Arc<RwLock<bool>>,
+    /// Set by the long poll when the remote has mutations we don't.
+    remote_changed: Arc<RwLock<bool>>,
+    /// Status explaining why synchronization stopped, set when the remote no longer accepts our
+    /// protocol version or uses one older than we accept. Synchronization stops until the app or
+    /// the remote is updated.
+    incompatible: Arc<RwLock<Option<&'static str>>>,
+    /// Set while the cloud is rebuilt from the remote. The rebuilt cloud gets a new instance,
+    /// this one stays stopped.
+    repairing: Arc<RwLock<bool>>,
+    /// Public key and algorithms of a public cloud, taken from its verified genesis mutation.
+    public_key_record: Arc<RwLock<Option<PublicKeyRecord>>>,
     pub filepath_maybe: Option<PathBuf>,
 }
 
@@ -64,26 +128,64 @@ impl RemoteCloud {
         } else {
             (Journal::in_memory(None)?, None)
         };
-        let sync_state = Arc::new(RwLock::new(
-            db.get::<(), CloudSyncState>("sync_state", &())?
+        let sync_state = match db.get::<(), CloudSyncState>("sync_state", &()) {
+            Ok(sync_state) => sync_state.unwrap_or_default(),
+            Err(_) => db
+                .get::<(), LegacyCloudSyncState>("sync_state", &())?
+                .map(CloudSyncState::from)
                 .unwrap_or_default(),
-        ));
+        };
+        let http_client = Arc::new(RwLock::new(http_client(&sync_state.tls_trust)?));
         Ok(Self {
-            sync_state,
+            sync_state: Arc::new(RwLock::new(sync_state)),
+            http_client,
             ctx,
             connection_maybe: Arc::new(RwLock::new(None)),
             db,
             cloud,
             initial_sync_complete: Arc::new(RwLock::new(false)),
+            caught_up: Arc::new(RwLock::new(false)),
             last_keepalive: Arc::new(RwLock::new(Instant::now())),
+            pow_searching: Arc::new(RwLock::new(false)),
+            failed_connections: Arc::new(RwLock::new(0)),
+            long_polling: Arc::new(RwLock::new(false)),
+            remote_changed: Arc::new(RwLock::new(false)),
+            incompatible: Arc::new(RwLock::new(None)),
+            repairing: Arc::new(RwLock::new(false)),
+            public_key_record: Arc::new(RwLock::new(None)),
             filepath_maybe,
         })
     }
 
+    /// Synchronize `cloud` in place of ours with the same sync state and settings. Used once our
+    /// cloud was rebuilt, see `AppState::replace_cloud`.
+    pub fn with_cloud(&self, cloud: Arc<Cloud>) -> Self {
+        Self {
+            ctx: self.ctx.clone(),
+            db: self.db.clone(),
+            sync_state: self.sync_state.clone(),
+            http_client: self.http_client.clone(),
+            connection_maybe: Arc::new(RwLock::new(None)),
+            cloud,
+            initial_sync_complete: Arc::new(RwLock::new(false)),
+            caught_up: Arc::new(RwLock::new(false)),
+            last_keepalive: Arc::new(RwLock::new(Instant::now())),
+            pow_searching: self.pow_searching.clone(),
+            failed_connections: Arc::new(RwLock::new(0)),
+            long_polling: Arc::new(RwLock::new(false)),
+            remote_changed: Arc::new(RwLock::new(false)),
+            incompatible: self.incompatible.clone(),
+            repairing: Arc::new(RwLock::new(false)),
+            public_key_record: self.public_key_record.clone(),
+            filepath_maybe: self.filepath_maybe.clone(),
+        }
+    }
+
     pub fn set_synchronization_enabled(&self, enabled: bool) -> Result<()> {
         self.sync_state.write().unwrap().synchronization_enabled = enabled;
         if !enabled {
             *self.initial_sync_complete.write().unwrap() = false;
+            *self.caught_up.write().unwrap() = false;
         }
         self.write_sync_state()
     }
@@ -109,6 +211,63 @@ impl RemoteCloud {
         self.sync_state.read().unwrap().ws_url.clone()
     }
 
+    pub fn tls_trust(&self) -> TlsTrust {
+        self.sync_state.read().unwrap().tls_trust.clone()
+    }
+
+    /// Change how the remote certificate is verified. Reconnects on the next tick.
+    pub fn set_tls_trust(&self, tls_trust: TlsTrust) -> Result<()> {
+        *self.http_client.write().unwrap() = http_client(&tls_trust)?;
+        self.sync_state.write().unwrap().tls_trust = tls_trust;
+        *self.connection_maybe.write().unwrap() = None;
+        self.write_sync_state()
+    }
+
+    fn http(&self) -> reqwest::Client {
+        self.http_client.read().unwrap().clone()
+    }
+
+    /// Stop synchronizing if the remote rejected our protocol version, or its version is older
+    /// than we accept.
+    fn check_protocol(&self, res: &reqwest::Response) -> Result<()> {
+        if res.status() == StatusCode::UPGRADE_REQUIRED {
+            *self.incompatible.write().unwrap() = Some(INCOMPATIBLE_STATUS);
+            anyhow::bail!(INCOMPATIBLE_STATUS);
+        }
+        let remote_version = header_version(
+            res.headers()
+                .get(PROTOCOL_HEADER)
+                .and_then(|v| v.to_str().ok()),
+        );
+        if let Err(e) = check_server_version(remote_version) {
+            println!("{e}");
+            *self.incompatible.write().unwrap() = Some(OUTDATED_REMOTE_STATUS);
+            anyhow::bail!(OUTDATED_REMOTE_STATUS);
+        }
+        Ok(())
+    }
+
+    /// Whether the remote history has been downloaded. Local transactions written before then
+    /// land after the remote's, on top of tables that are still missing entries.
+    pub fn is_caught_up(&self) -> bool {
+        *self.caught_up.read().unwrap()
+    }
+
+    pub fn creation_token(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(CREATION_TOKEN_TABLE, &())?)
+    }
+
+    /// Set the token used to create the cloud on the remote. Either a 32 byte invite token for
+    /// invite only remotes, or a 64 byte enrollment signed by the operator of a private remote.
+    pub fn set_creation_token(&self, token_str: &str) -> Result<()> {
+        let token = hex::decode(token_str.trim())?;
+        if token.len() != 32 && token.len() != 64 {
+            anyhow::bail!("token must be 32 or 64 bytes");
+        }
+        self.db.insert(CREATION_TOKEN_TABLE, &(), &token)?;
+        Ok(())
+    }
+
     pub fn write_sync_state(&self) -> Result<()> {
         self.db
             .insert("sync_state", &(), &*self.sync_state.read().unwrap())?;
@@ -117,17 +276,29 @@ impl RemoteCloud {
 
     /// A single synchronization tick. Should be a short lived task to advance the state of
     /// synchronization.
-    pub async fn tick(
-        &self,
-        events_tx: flume::Sender<AppEvent>,
-        sync_status_tx: flume::Sender<([u8; 32], String)>,
-    ) -> Result<()> {
+    pub async fn tick(&self, sync_status_tx: flume::Sender<([u8; 32], String)>) -> Result<()> {
+        if *self.repairing.read().unwrap() {
+            self.ctx.request_repaint();
+            sync_status_tx.send((*self.cloud.id(), "Repairing...".to_string()))?;
+            *self.connection_maybe.write().unwrap() = None;
+            return Ok(());
+        }
         if !self.synchronization_enabled() {
             self.ctx.request_repaint();
             sync_status_tx.send((*self.cloud.id(), format!("Synchronization disabled")))?;
             *self.connection_maybe.write().unwrap() = None;
             return Ok(());
         }
+        let incompatible = *self.incompatible.read().unwrap();
+        if let Some(status) = incompatible {
+            self.ctx.request_repaint();
+            sync_status_tx.send((*self.cloud.id(), status.to_string()))?;
+            *self.connection_maybe.write().unwrap() = None;
+            return Ok(());
+        }
+        if self.cloud.is_read_only() {
+            return self.tick_public(sync_status_tx).await;
+        }
         self.reconnect_if_needed();
         if Instant::now()
             .duration_since(*self.last_keepalive.read().unwrap())
@@ -154,7 +325,8 @@ impl RemoteCloud {
             // load the corresponding mutation from the server
             let mut url = base_url.join("/mutation")?;
             url.set_query(Some(&format!("cloud_id={}&index={i}", self.cloud.id_hex())));
-            let res = reqwest::get(url).await?;
+            let res = self.http().get(url).send().await?;
+            self.check_protocol(&res)?;
 
             if res.status().is_success() {
                 let mutation = Bytes::from(res.bytes().await?.to_vec()).parse::<Mutation>()?;
@@ -180,6 +352,10 @@ impl RemoteCloud {
                 ))?;
                 // TODO: handle merge
 
+                return Ok(());
+            } else if res.status() == StatusCode::GONE {
+                self.ctx.request_repaint();
+                sync_status_tx.send((*self.cloud.id(), format!("Deleted from remote")))?;
                 return Ok(());
             } else if res.status() == StatusCode::FAILED_DEPENDENCY {
                 self.ctx.request_repaint();
@@ -188,15 +364,29 @@ impl RemoteCloud {
                     format!("Broadcasting mutation #{}", i + 1),
                 ))?;
                 // send the mutation
-                let mutation = self.cloud.encrypt_tx(tx.clone(), i)?;
+                let mut mutation = self.cloud.encrypt_tx(tx.clone(), i)?;
+                if i == 0 {
+                    match self.creation_proof().await {
+                        Ok(proof) => mutation.creation_proof = proof,
+                        Err(e) => {
+                            self.ctx.request_repaint();
+                            sync_status_tx.send((
+                                *self.cloud.id(),
+                                format!("Waiting to create cloud: {e}"),
+                            ))?;
+                            return Ok(());
+                        }
+                    }
+                }
                 let mut url = base_url.join("/mutate")?;
                 url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex(),)));
-                let client = reqwest::Client::new();
-                let res = client
+                let res = self
+                    .http()
                     .post(url)
                     .body(Bytes::encode(&mutation)?.to_vec())
                     .send()
                     .await?;
+                self.check_protocol(&res)?;
                 if res.status().is_success() {
                     println!("successfully sent mutation {}", i);
                     self.set_latest_confirmed_index(i)?;
@@ -211,15 +401,47 @@ impl RemoteCloud {
             }
         }
 
-        if self
-            .receive()?
-            .iter()
-            .filter(|v| !matches!(v, Response::Pong))
-            .collect::<Vec<_>>()
-            .len()
-            == 0
+        let responses = self.receive()?;
+        let incompatible = responses.iter().find_map(|v| match v {
+            Response::Incompatible { .. } => Some(INCOMPATIBLE_STATUS),
+            Response::Hello(hello) => match check_server_version(hello.protocol_version) {
+                Ok(()) => None,
+                Err(e) => {
+                    println!("{e}");
+                    Some(OUTDATED_REMOTE_STATUS)
+                }
+            },
+            _ => None,
+        });
+        if let Some(status) = incompatible {
+            *self.incompatible.write().unwrap() = Some(status);
+            *self.connection_maybe.write().unwrap() = None;
+            self.ctx.request_repaint();
+            sync_status_tx.send((*self.cloud.id(), status.to_string()))?;
+            return Ok(());
+        }
+        for response in &responses {
+            if let Response::Hello(Hello {
+                nonce: Some(nonce), ..
+            }) = response
+            {
+                self.auth_cloud(nonce);
+            }
+        }
+        let remote_changed = std::mem::take(&mut *self.remote_changed.write().unwrap());
+        if !remote_changed
+            && responses
+                .iter()
+                .filter(|v| !matches!(v, Response::Pong | Response::Hello(_)))
+                .collect::<Vec<_>>()
+                .len()
+                == 0
             && *self.initial_sync_complete.read().unwrap()
         {
+            // without a websocket we won't be notified of remote changes
+            if *self.failed_connections.read().unwrap() >= LONG_POLL_AFTER_FAILED_CONNECTIONS {
+                self.start_long_poll(journal_len);
+            }
             self.ctx.request_repaint();
             sync_status_tx.send((
                 *self.cloud.id(),
@@ -230,9 +452,14 @@ impl RemoteCloud {
 
         let mut url = base_url.join("/state")?;
         url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex(),)));
-        let res = reqwest::get(url).await?;
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
         let remote_index = if res.status().is_success() {
             Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?
+        } else if res.status() == StatusCode::GONE {
+            self.ctx.request_repaint();
+            sync_status_tx.send((*self.cloud.id(), format!("Deleted from remote")))?;
+            return Ok(());
         } else {
             println!("failed to get server state");
             return Ok(());
@@ -253,14 +480,16 @@ impl RemoteCloud {
                 self.cloud.id_hex(),
                 current_index
             )));
-            let res = reqwest::get(url).await?;
+            let res = self.http().get(url).send().await?;
+            self.check_protocol(&res)?;
             if res.status().is_success() {
                 // received a new change, apply it
                 let mutation = Bytes::from(res.bytes().await?.to_vec()).parse::<Mutation>()?;
                 let (remote_tx, _index) = self.cloud.decrypt_tx(mutation)?;
                 self.cloud.db.append_tx(&remote_tx)?;
                 self.set_latest_confirmed_index(current_index)?;
-                events_tx.send(AppEvent::RemoteCloudUpdate(*self.cloud.id()))?;
+                // picked up by `AppState::collect_changes`
+                self.ctx.request_repaint();
             } else {
                 self.ctx.request_repaint();
                 sync_status_tx.send((
@@ -273,6 +502,9 @@ impl RemoteCloud {
             current_index += 1;
         }
 
+        if current_index >= remote_index {
+            *self.caught_up.write().unwrap() = true;
+        }
         if current_index == remote_index {
             self.ctx.request_repaint();
             sync_status_tx.send((
@@ -284,16 +516,476 @@ impl RemoteCloud {
         Ok(())
     }
 
+    /// Download a public mutation, verify it was signed by the cloud's key and decrypt it with the
+    /// published mutation key. The remote is never trusted to decrypt for us.
+    async fn public_tx(&self, index: u64) -> Result<JournalTransaction> {
+        let record = self.public_key_record().await?;
+        let mutation = self.fetch_public_mutation(index).await?;
+        if mutation.index != index {
+            anyhow::bail!("remote sent mutation {} for index {index}", mutation.index);
+        }
+        let tx_bytes = mutation.verify_public(self.cloud.id(), &record)?;
+        Bytes::from(tx_bytes).parse::<JournalTransaction>()
+    }
+
+    /// The public key record of a public cloud, built from its genesis mutation the first time
+    /// it's needed. The genesis public key must hash to the cloud id.
+    async fn public_key_record(&self) -> Result<PublicKeyRecord> {
+        if let Some(record) = self.public_key_record.read().unwrap().clone() {
+            return Ok(record);
+        }
+        let genesis = self.fetch_public_mutation(0).await?;
+        let record = PublicKeyRecord::from_genesis(&genesis)?;
+        genesis.verify_public(self.cloud.id(), &record)?;
+        *self.public_key_record.write().unwrap() = Some(record.clone());
+        Ok(record)
+    }
+
+    async fn fetch_public_mutation(&self, index: u64) -> Result<Mutation> {
+        let mut url = reqwest::Url::parse(&self.http_url())?.join("/public/mutation")?;
+        url.set_query(Some(&format!(
+            "cloud_id={}&index={index}",
+            self.cloud.id_hex()
+        )));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to load public mutation {index}: {:?}", res.status());
+        }
+        Bytes::from(res.bytes().await?.to_vec()).parse::<Mutation>()
+    }
+
+    /// Replicate a public cloud by verifying and decrypting the mutations published on the
+    /// remote.
+    async fn tick_public(&self, sync_status_tx: flume::Sender<([u8; 32], String)>) -> Result<()> {
+        let base_url = reqwest::Url::parse(&self.http_url())?;
+        let mut url = base_url.join("/public/state")?;
+        url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex())));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        let remote_index = if res.status().is_success() {
+            Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?
+        } else {
+            println!("failed to get public state");
+            return Ok(());
+        };
+
+        let mut current_index = self.cloud.db.journal_tx_len()?;
+        while remote_index > current_index {
+            self.ctx.request_repaint();
+            sync_status_tx.send((
+                *self.cloud.id(),
+                format!("Downloading public change {}", current_index),
+            ))?;
+            match self.public_tx(current_index).await {
+                Ok(remote_tx) => {
+                    self.cloud.db.append_tx(&remote_tx)?;
+                    self.set_latest_confirmed_index(current_index)?;
+                    // picked up by `AppState::collect_changes`
+                    self.ctx.request_repaint();
+                }
+                Err(e) => {
+                    println!("failed to load public change {current_index}: {:?}", e);
+                    self.ctx.request_repaint();
+                    sync_status_tx.send((
+                        *self.cloud.id(),
+                        format!("Error downloading public change {}", current_index),
+                    ))?;
+                    break;
+                }
+            }
+
+            current_index += 1;
+        }
+
+        self.ctx.request_repaint();
+        if current_index > remote_index {
+            sync_status_tx.send((
+                *self.cloud.id(),
+                "Read only! Local changes will not be synchronized".to_string(),
+            ))?;
+        } else if current_index == remote_index {
+            sync_status_tx.send((
+                *self.cloud.id(),
+                format!(
+                    "Read only, fully synchronized! ({}/{})",
+                    current_index, remote_index
+                ),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of mutations on the remote.
+    async fn remote_len(&self) -> Result<u64> {
+        let path = if self.cloud.is_read_only() {
+            "/public/state"
+        } else {
+            "/state"
+        };
+        let mut url = reqwest::Url::parse(&self.http_url())?.join(path)?;
+        url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex())));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to get remote state: {:?}", res.status());
+        }
+        Ok(Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?)
+    }
+
+    /// Download and decrypt the remote's mutation at `index`.
+    async fn remote_tx(&self, index: u64) -> Result<JournalTransaction> {
+        if self.cloud.is_read_only() {
+            return self.public_tx(index).await;
+        }
+        let mut url = reqwest::Url::parse(&self.http_url())?.join("/mutation")?;
+        url.set_query(Some(&format!(
+            "cloud_id={}&index={index}",
+            self.cloud.id_hex()
+        )));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to load mutation {index}: {:?}", res.status());
+        }
+        let bytes = Bytes::from(res.bytes().await?.to_vec());
+        let (tx, remote_index) = self.cloud.decrypt_tx(bytes.parse::<Mutation>()?)?;
+        if remote_index != index {
+            anyhow::bail!("remote sent mutation {remote_index} for index {index}");
+        }
+        Ok(tx)
+    }
+
+    /// Walk the local journal, checking the hash chain and comparing each transaction with the
+    /// remote's mutation. Stops at the first problem.
+    pub async fn verify(&self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport {
+            local_len: self.cloud.db.journal_tx_len()?,
+            remote_len: self.remote_len().await?,
+            verified_len: 0,
+            first_bad: None,
+            latest_confirmed_index: self.latest_confirmed_index(),
+        };
+        let mut last_hash = None;
+        for index in 0..report.local_len {
+            let tx = match self.cloud.db.journal_tx_by_index(index) {
+                Ok(Some(tx)) => tx,
+                Ok(None) => {
+                    let problem = IntegrityProblem::Unreadable("missing".to_string());
+                    report.first_bad = Some((index, problem));
+                    break;
+                }
+                Err(e) => {
+                    let problem = IntegrityProblem::Unreadable(e.to_string());
+                    report.first_bad = Some((index, problem));
+                    break;
+                }
+            };
+            let hash = tx.hash()?;
+            if let Some(last_hash) = last_hash
+                && tx.last_tx_hash != last_hash
+            {
+                report.first_bad = Some((index, IntegrityProblem::BrokenChain));
+                break;
+            }
+            last_hash = Some(hash);
+            if index < report.remote_len {
+                match self.remote_tx(index).await {
+                    Ok(remote_tx) if remote_tx.hash()? == hash => {}
+                    Ok(_) => {
+                        report.first_bad = Some((index, IntegrityProblem::Diverged));
+                        break;
+                    }
+                    Err(e) => {
+                        let problem = IntegrityProblem::RemoteUnreadable(e.to_string());
+                        report.first_bad = Some((index, problem));
+                        break;
+                    }
+                }
+            }
+            report.verified_len = index + 1;
+        }
+        Ok(report)
+    }
+
+    /// Forget which transactions were confirmed. The next tick compares every local
+    /// transaction with the remote again.
+    pub fn reset_confirmations(&self) -> Result<()> {
+        self.sync_state.write().unwrap().latest_confirmed_index = None;
+        *self.initial_sync_complete.write().unwrap() = false;
+        *self.caught_up.write().unwrap() = false;
+        self.write_sync_state()
+    }
+
+    /// Replace the local journal with the remote's mutations, for when it's corrupted or has
+    /// diverged. Local changes the remote doesn't have are lost. This instance stops
+    /// synchronizing, pass the rebuilt cloud to `AppState::replace_cloud`.
+    pub async fn rebuild(&self) -> Result<Arc<Cloud>> {
+        *self.repairing.write().unwrap() = true;
+        let mut transactions = Vec::default();
+        let downloaded: Result<()> = async {
+            for index in 0..self.remote_len().await? {
+                transactions.push(self.remote_tx(index).await?);
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = downloaded {
+            *self.repairing.write().unwrap() = false;
+            return Err(e);
+        }
+        // the current journal is only replaced once every transaction is appended
+        let cloud = match self.cloud.rebuilt(&transactions) {
+            Ok(cloud) => cloud,
+            Err(e) => {
+                *self.repairing.write().unwrap() = false;
+                return Err(e);
+            }
+        };
+        self.sync_state.write().unwrap().latest_confirmed_index =
+            (transactions.len() as u64).checked_sub(1);
+        self.write_sync_state()?;
+        Ok(Arc::new(cloud))
+    }
+
+    /// The proof the remote requires to create this cloud, `None` if the remote doesn't require
+    /// one. Proof of work is computed in a background task, an error describing progress is
+    /// returned until it completes.
+    async fn creation_proof(&self) -> Result<Option<CreationProof>> {
+        let token = self.creation_token()?;
+        // private remotes accept an enrollment regardless of the creation policy
+        if let Some(token) = &token
+            && token.len() == 64
+        {
+            return Ok(Some(CreationProof::Enrollment(token.clone())));
+        }
+        let base_url = reqwest::Url::parse(&self.http_url())?;
+        let res = self.http().get(base_url.join("/policy")?).send().await?;
+        self.check_protocol(&res)?;
+        // remotes without a policy allow anyone to create clouds
+        let policy = if res.status().is_success() {
+            Bytes::from(res.bytes().await?.to_vec()).parse::<CreationPolicy>()?
+        } else {
+            CreationPolicy::Open
+        };
+        match policy {
+            CreationPolicy::Open => Ok(None),
+            CreationPolicy::InviteToken => match token.and_then(|v| <[u8; 32]>::try_from(v).ok()) {
+                Some(token) => Ok(Some(CreationProof::InviteToken(token))),
+                None => anyhow::bail!("remote requires an invite token, add one in settings"),
+            },
+            CreationPolicy::ProofOfWork { difficulty } => {
+                if let Some(proof) = self.db.get(CREATION_POW_TABLE, &difficulty)? {
+                    return Ok(Some(proof));
+                }
+                self.start_pow_search(difficulty);
+                anyhow::bail!("computing proof of work (difficulty {difficulty})")
+            }
+        }
+    }
+
+    /// Search for a proof of work in batches, yielding in between so other tasks (and the UI on
+    /// web) keep running. The solution is written to `CREATION_POW_TABLE`.
+    fn start_pow_search(&self, difficulty: u8) {
+        {
+            let mut pow_searching = self.pow_searching.write().unwrap();
+            if *pow_searching {
+                return;
+            }
+            *pow_searching = true;
+        }
+        let db = self.db.clone();
+        let cloud_id = *self.cloud.id();
+        let pow_searching = self.pow_searching.clone();
+        let ctx = self.ctx.clone();
+        tokio::spawn(async move {
+            let mut start = 0u64;
+            loop {
+                if let Some(proof) =
+                    CreationProof::search_pow(&cloud_id, difficulty, start..start + POW_BATCH_SIZE)
+                {
+                    if let Err(e) = db.insert(CREATION_POW_TABLE, &difficulty, &proof) {
+                        println!("failed to store proof of work: {:?}", e);
+                    }
+                    break;
+                }
+                start += POW_BATCH_SIZE;
+                tokio::time::sleep(Duration::ZERO).await;
+            }
+            *pow_searching.write().unwrap() = false;
+            ctx.request_repaint();
+        });
+    }
+
+    /// Wait in the background for the remote to have more than `after` mutations, for networks
+    /// where the websocket can't connect. Sets `remote_changed` so the next tick downloads them.
+    fn start_long_poll(&self, after: u64) {
+        {
+            let mut long_polling = self.long_polling.write().unwrap();
+            if *long_polling {
+                return;
+            }
+            *long_polling = true;
+        }
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = self_clone.long_poll(after).await {
+                println!("long poll failed: {:?}", e);
+            }
+            *self_clone.long_polling.write().unwrap() = false;
+        });
+    }
+
+    async fn long_poll(&self, after: u64) -> Result<()> {
+        let mut url = reqwest::Url::parse(&self.http_url())?.join("/state")?;
+        url.set_query(Some(&format!(
+            "cloud_id={}&after={after}&wait={LONG_POLL_WAIT_SECS}",
+            self.cloud.id_hex()
+        )));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        // let the next tick report other statuses
+        if !res.status().is_success() {
+            *self.remote_changed.write().unwrap() = true;
+            return Ok(());
+        }
+        let remote_index = Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?;
+        if remote_index > after {
+            *self.remote_changed.write().unwrap() = true;
+            self.ctx.request_repaint();
+        }
+        Ok(())
+    }
+
+    /// Reveal the mutation keys for confirmed mutations up to and including `upto`. Anyone with
+    /// the cloud id will be able to read the cloud as of that mutation. This is irreversible.
+    ///
+    /// Returns the number of public mutations.
+    pub async fn publish(&self, upto: u64) -> Result<u64> {
+        let confirmed_index = self
+            .latest_confirmed_index()
+            .ok_or(anyhow::anyhow!("no confirmed mutations to publish"))?;
+        if upto > confirmed_index {
+            anyhow::bail!("mutation {upto} is not confirmed by the remote");
+        }
+        let base_url = reqwest::Url::parse(&self.http_url())?;
+
+        let mut url = base_url.join("/public/state")?;
+        url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex())));
+        let res = self.http().get(url).send().await?;
+        self.check_protocol(&res)?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to get public state: {:?}", res.status());
+        }
+        let public_count = Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?;
+
+        let mut mutation_keys = Vec::default();
+        for i in public_count..=upto {
+            let mut url = base_url.join("/mutation")?;
+            url.set_query(Some(&format!("cloud_id={}&index={i}", self.cloud.id_hex())));
+            let res = self.http().get(url).send().await?;
+            self.check_protocol(&res)?;
+            if !res.status().is_success() {
+                anyhow::bail!("failed to load mutation {i}: {:?}", res.status());
+            }
+            let mutation = Bytes::from(res.bytes().await?.to_vec()).parse::<Mutation>()?;
+            if mutation.mutation_key.is_some() {
+                continue;
+            }
+            mutation_keys.push((i, self.cloud.mutation_key(&mutation)?));
+        }
+        let signature = self.cloud.sign(&PublishRequest::signed_bytes(
+            self.cloud.id(),
+            &mutation_keys,
+        )?)?;
+        let publish = PublishRequest {
+            cloud_id: *self.cloud.id(),
+            mutation_keys,
+            signature,
+        };
+
+        let mut url = base_url.join("/publish")?;
+        url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex())));
+        let res = self
+            .http()
+            .post(url)
+            .body(Bytes::encode(&publish)?.to_vec())
+            .send()
+            .await?;
+        self.check_protocol(&res)?;
+        if !res.status().is_success() {
+            anyhow::bail!("failed to publish: {:?}", res.status());
+        }
+        Ok(Bytes::from(res.bytes().await?.to_vec()).parse::<u64>()?)
+    }
+
+    /// Delete all mutations for this cloud from the remote. The remote keeps a tombstone so the
+    /// cloud can't be created there again. Local data is not changed.
+    pub async fn delete_remote(&self) -> Result<()> {
+        let mut url = reqwest::Url::parse(&self.http_url())?.join("/delete")?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => anyhow::bail!("sync url has no host"),
+        };
+        let signature = self
+            .cloud
+            .sign(&DeleteRequest::signed_bytes(self.cloud.id(), &host)?)?;
+        let delete = DeleteRequest {
+            cloud_id: *self.cloud.id(),
+            host,
+            signature,
+        };
+        url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex())));
+        let res = self
+            .http()
+            .post(url)
+            .body(Bytes::encode(&delete)?.to_vec())
+            .send()
+            .await?;
+        self.check_protocol(&res)?;
+        // already deleted
+        if res.status() == StatusCode::GONE {
+            return Ok(());
+        }
+        if !res.status().is_success() {
+            anyhow::bail!("failed to delete: {:?}", res.status());
+        }
+        Ok(())
+    }
+
     pub fn reconnect_if_needed(&self) {
-        if !self.is_connected() {
+        if self.is_connected() {
+            *self.failed_connections.write().unwrap() = 0;
+        } else {
+            if self.connection_maybe.read().unwrap().is_some() {
+                *self.failed_connections.write().unwrap() += 1;
+            }
             let mut full_url = reqwest::Url::parse(&self.ws_url()).expect("failed to parse ws url");
             full_url.set_query(Some(&format!("cloud_id={}", self.cloud.id_hex(),)));
-            *self.connection_maybe.write().unwrap() =
-                Some(NetworkConnection::attempt_connection(full_url.to_string()));
+            *self.connection_maybe.write().unwrap() = Some(NetworkConnection::attempt_connection(
+                full_url.to_string(),
+                self.tls_trust(),
+            ));
             *self.last_keepalive.write().unwrap() = Instant::now();
         }
     }
 
+    /// Answer the nonce in a remote's `Response::Hello`. Remotes only notify authenticated
+    /// sockets of mutations.
+    fn auth_cloud(&self, nonce: &[u8; 32]) {
+        match Action::auth_cloud_signed_bytes(self.cloud.id(), nonce)
+            .and_then(|message| self.cloud.sign(&message))
+        {
+            Ok(signature) => {
+                self.send(Action::AuthCloud(*self.cloud.id(), signature))
+                    .ok();
+            }
+            Err(e) => println!("failed to sign auth: {:?}", e),
+        }
+    }
+
     pub fn is_connected(&self) -> bool {
         if let Some(connection) = &*self.connection_maybe.read().unwrap() {
             connection.is_open().is_ok()
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+
+use anondb::Bytes;
+use anondb::Journal;
+use anondb::TransactionOperation;
+use anyhow::Result;
+use network_common::SignatureAlgorithm;
+use redb::TableHandle;
+
+/// Number of entries kept in `StorageStats::largest_entries`.
+const LARGEST_ENTRIES: usize = 10;
+
+/// Tables outside an applet namespace, e.g. cloud metadata or tables written before migrations.
+const NO_NAMESPACE: &str = "(none)";
+
+#[derive(Clone, Debug, Default)]
+pub struct TableStats {
+    pub keys: u64,
+    /// Encoded size of the keys and values currently in the table.
+    pub bytes: u64,
+    /// Encoded size of every insert and remove journaled for the table. Grows with history,
+    /// e.g. each saved note diff, even after the entries are removed.
+    pub journal_bytes: u64,
+}
+
+impl TableStats {
+    fn add(&mut self, other: &TableStats) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+        self.journal_bytes += other.journal_bytes;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LargeEntry {
+    pub table_name: String,
+    /// The key as a string if it is one, otherwise hex.
+    pub key: String,
+    pub bytes: u64,
+}
+
+/// Where the space in a cloud goes, see `Cloud::storage_stats`.
+#[derive(Clone, Debug, Default)]
+pub struct StorageStats {
+    /// Tables that exist or were written by the journal, by name.
+    pub tables: BTreeMap<String, TableStats>,
+    pub journal_len: u64,
+    /// Size of the encrypted journal transactions and their signatures, what the remote stores
+    /// and a new device downloads. The remaining mutation fields are under 100 bytes each and
+    /// aren't included.
+    pub mutation_bytes: u64,
+    /// Largest entries first.
+    pub largest_entries: Vec<LargeEntry>,
+}
+
+impl StorageStats {
+    /// `signature_algorithm` is the cloud's, each mutation carries one signature.
+    pub fn collect(db: &Journal, signature_algorithm: SignatureAlgorithm) -> Result<Self> {
+        let mut stats = Self::default();
+        let read_tx = db.begin_read()?;
+        for handle in read_tx.list_tables()? {
+            let table_name = handle.name().to_string();
+            let table = read_tx.open_table(Journal::table_definition(&table_name))?;
+            let table_stats = stats.tables.entry(table_name.clone()).or_default();
+            for entry in table.range::<Bytes>(..)? {
+                let (key, value) = entry?;
+                let (key, value) = (key.value(), value.value());
+                let bytes = (key.len() + value.len()) as u64;
+                table_stats.keys += 1;
+                table_stats.bytes += bytes;
+                if stats.largest_entries.len() < LARGEST_ENTRIES
+                    || stats
+                        .largest_entries
+                        .last()
+                        .is_some_and(|entry| entry.bytes < bytes)
+                {
+                    stats.largest_entries.push(LargeEntry {
+                        table_name: table_name.clone(),
+                        key: display_key(&key),
+                        bytes,
+                    });
+                    stats
+                        .largest_entries
+                        .sort_by_key(|entry| std::cmp::Reverse(entry.bytes));
+                    stats.largest_entries.truncate(LARGEST_ENTRIES);
+                }
+            }
+        }
+        for tx in db.journal_transactions()? {
+            stats.journal_len += 1;
+            // the stream cipher doesn't change the length
+            stats.mutation_bytes +=
+                (Bytes::encode(&tx)?.len() + signature_algorithm.signature_len()) as u64;
+            for operation in &tx.operations {
+                let (table_name, bytes) = match operation {
+                    TransactionOperation::Insert {
+                        table_name,
+                        key,
+                        value,
+                    } => (table_name, key.len() + value.len()),
+                    TransactionOperation::Remove(table_name, key) => (table_name, key.len()),
+                    _ => continue,
+                };
+                stats
+                    .tables
+                    .entry(table_name.clone())
+                    .or_default()
+                    .journal_bytes += bytes as u64;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Table stats summed by applet namespace, see `AppletStore`.
+    pub fn by_namespace(&self) -> BTreeMap<String, TableStats> {
+        let mut namespaces = BTreeMap::<String, TableStats>::default();
+        for (table_name, table_stats) in &self.tables {
+            let namespace = match table_name.split_once('/') {
+                Some((namespace, _)) => namespace,
+                None => NO_NAMESPACE,
+            };
+            namespaces
+                .entry(namespace.to_string())
+                .or_default()
+                .add(table_stats);
+        }
+        namespaces
+    }
+}
+
+fn display_key(key: &Bytes) -> String {
+    match key.parse::<String>() {
+        Ok(key) => key,
+        Err(_) => hex::encode(key.to_vec()),
+    }
+}
+
+/// `bytes` in B, KiB or MiB.
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{bytes} B")
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,140 @@
+use std::ops::RangeBounds;
+
+use anondb::Bytes;
+use anondb::Journal;
+use anyhow::Result;
+use redb::TableHandle;
+
+use super::Cloud;
+
+/// Records copied between clouds by `AppState::transfer`, or marked deleted by
+/// `Trash::delete`. Table and key names are the same in both clouds.
+#[derive(Clone, Debug)]
+pub enum TransferRecord {
+    /// Every entry of a table, e.g. the diffs of a note. The table is deleted from the source on
+    /// a move.
+    Table(String),
+    /// A single entry keyed by a string, e.g. a file. Removed from the source on a move.
+    Entry(String, String),
+}
+
+impl TransferRecord {
+    fn table_name(&self) -> &str {
+        match self {
+            Self::Table(table_name) | Self::Entry(table_name, _) => table_name,
+        }
+    }
+}
+
+/// Entries of a table in their encoded form, empty if the table doesn't exist.
+fn raw_entries<R: RangeBounds<Bytes>>(
+    db: &Journal,
+    table_name: &str,
+    range: R,
+) -> Result<Vec<(Bytes, Bytes)>> {
+    let tx = db.begin_read()?;
+    if !tx.list_tables()?.any(|table| table.name() == table_name) {
+        return Ok(Vec::default());
+    }
+    let table = tx.open_table(Journal::table_definition(table_name))?;
+    let mut entries = Vec::default();
+    for entry in table.range::<Bytes>(range)? {
+        let (key, value) = entry?;
+        entries.push((key.value(), value.value()));
+    }
+    Ok(entries)
+}
+
+fn record_entries(db: &Journal, record: &TransferRecord) -> Result<Vec<(Bytes, Bytes)>> {
+    match record {
+        TransferRecord::Table(table_name) => raw_entries(db, table_name, ..),
+        TransferRecord::Entry(table_name, key) => {
+            let key = Bytes::encode(key)?;
+            raw_entries(db, table_name, key.clone()..=key)
+        }
+    }
+}
+
+/// Copy `records` from `source` to `destination` in one journaled transaction, then remove them
+/// from `source` in another if `remove_source` is set. Nothing is written if a record already
+/// exists in the destination.
+pub(crate) fn transfer(
+    source: &Cloud,
+    destination: &Cloud,
+    records: &[TransferRecord],
+    remove_source: bool,
+) -> Result<()> {
+    if destination.is_read_only() || (remove_source && source.is_read_only()) {
+        anyhow::bail!("cannot transfer to or move from a read only cloud");
+    }
+    if source.id() == destination.id() {
+        anyhow::bail!("cannot transfer a record to the cloud it's in");
+    }
+    let mut copied = Vec::default();
+    for record in records {
+        if !record_entries(&destination.db, record)?.is_empty() {
+            anyhow::bail!("{} already exists in the destination", record.table_name());
+        }
+        copied.push((record.table_name(), record_entries(&source.db, record)?));
+    }
+
+    let mut tx = destination.db.begin_write()?;
+    for (table_name, entries) in copied {
+        let mut table = tx.open_table(table_name)?;
+        for (key, value) in &entries {
+            table.insert_bytes(key, value)?;
+        }
+    }
+    tx.commit()?;
+
+    if remove_source {
+        let mut tx = source.db.begin_write()?;
+        for record in records {
+            match record {
+                TransferRecord::Table(table_name) => {
+                    tx.delete_table(table_name)?;
+                }
+                TransferRecord::Entry(table_name, key) => {
+                    let mut table = tx.open_table(table_name)?;
+                    table.remove(key)?;
+                }
+            }
+        }
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Write the entries of `records` in `snapshot` to `cloud` in one journaled transaction,
+/// replacing the current ones. Records missing from the snapshot are removed.
+pub(crate) fn restore(snapshot: &Cloud, cloud: &Cloud, records: &[TransferRecord]) -> Result<()> {
+    if cloud.is_read_only() {
+        anyhow::bail!("cannot restore to a read only cloud");
+    }
+    let mut restored = Vec::default();
+    for record in records {
+        restored.push((record, record_entries(&snapshot.db, record)?));
+    }
+
+    let mut tx = cloud.db.begin_write()?;
+    for (record, entries) in restored {
+        match record {
+            TransferRecord::Table(table_name) => {
+                tx.delete_table(table_name)?;
+            }
+            TransferRecord::Entry(table_name, key) if entries.is_empty() => {
+                let mut table = tx.open_table(table_name)?;
+                table.remove(key)?;
+            }
+            TransferRecord::Entry(..) => {}
+        }
+        if !entries.is_empty() {
+            let mut table = tx.open_table(record.table_name())?;
+            for (key, value) in &entries {
+                table.insert_bytes(key, value)?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use network_common::SignatureAlgorithm;
+use serde::Deserialize;
+use serde::Serialize;
+use web_time::Duration;
+use web_time::SystemTime;
+
+use super::AppletStore;
+use super::Cloud;
+use super::TransferRecord;
+
+pub const TRASH_NAMESPACE: &str = "trash";
+/// Random id keyed to a `TrashItem`.
+const ITEMS_TABLE: &str = "items";
+
+/// How long deleted items and clouds are kept before they're purged.
+pub const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Seconds since the epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Whether something deleted at `deleted_at` is past `TRASH_RETENTION`.
+pub(crate) fn is_expired(deleted_at: u64) -> bool {
+    now().saturating_sub(deleted_at) > TRASH_RETENTION.as_secs()
+}
+
+/// Time since `deleted_at` in the largest whole unit, e.g. "3 days ago".
+pub fn format_age(deleted_at: u64) -> String {
+    let seconds = now().saturating_sub(deleted_at);
+    if seconds < 60 * 60 {
+        format!("{} minutes ago", seconds / 60)
+    } else if seconds < 24 * 60 * 60 {
+        format!("{} hours ago", seconds / (60 * 60))
+    } else {
+        format!("{} days ago", seconds / (24 * 60 * 60))
+    }
+}
+
+/// Marks records deleted by `Trash::delete`. The records stay in place until the item is
+/// purged, applets hide them with `Trash::deleted_keys`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrashItem {
+    /// What was deleted, e.g. "note" or "file".
+    pub kind: String,
+    pub name: String,
+    pub deleted_at: u64,
+    /// Table name and key of each record, `None` for a whole table.
+    records: Vec<(String, Option<String>)>,
+}
+
+impl TrashItem {
+    fn marks(&self, table_name: &str) -> impl Iterator<Item = &Option<String>> {
+        self.records
+            .iter()
+            .filter(move |(name, _)| name == table_name)
+            .map(|(_, key)| key)
+    }
+}
+
+/// Deleted records of a cloud. Items live in the cloud's journal, so they sync like any other
+/// change and every device sees the same trash.
+pub struct Trash<'a> {
+    cloud: &'a Cloud,
+}
+
+impl<'a> Trash<'a> {
+    pub fn new(cloud: &'a Cloud) -> Self {
+        Self { cloud }
+    }
+
+    /// Changes to this table change which records are hidden, for `ChangeSet::matches`.
+    pub fn items_table(&self) -> String {
+        AppletStore::new(&self.cloud.db, TRASH_NAMESPACE).table(ITEMS_TABLE)
+    }
+
+    /// Mark `records` deleted. Only the marker is journaled, the records are removed when the
+    /// item is purged.
+    pub fn delete(&self, kind: &str, name: &str, records: &[TransferRecord]) -> Result<()> {
+        let item = TrashItem {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            deleted_at: now(),
+            records: records
+                .iter()
+                .map(|record| match record {
+                    TransferRecord::Table(table_name) => (table_name.clone(), None),
+                    TransferRecord::Entry(table_name, key) => {
+                        (table_name.clone(), Some(key.clone()))
+                    }
+                })
+                .collect(),
+        };
+        self.cloud
+            .db
+            .insert(&self.items_table(), &rand::random::<[u8; 32]>(), &item)
+    }
+
+    /// Items in the trash, most recently deleted first.
+    pub fn items(&self) -> Result<Vec<([u8; 32], TrashItem)>> {
+        let mut items = self
+            .cloud
+            .db
+            .find_many::<[u8; 32], TrashItem, _>(&self.items_table(), |_, _| true)?;
+        items.sort_by_key(|(_, item)| std::cmp::Reverse(item.deleted_at));
+        Ok(items)
+    }
+
+    /// Keys of `table_name` in the trash, applets leave them out of what they show.
+    pub fn deleted_keys(&self, table_name: &str) -> Result<HashSet<String>> {
+        let mut keys = HashSet::default();
+        for (_, item) in self.items()? {
+            keys.extend(item.marks(table_name).flatten().cloned());
+        }
+        Ok(keys)
+    }
+
+    /// Whether `table_name` or its entry at `key` is in the trash.
+    pub fn is_deleted(&self, table_name: &str, key: Option<&str>) -> Result<bool> {
+        Ok(self.items()?.iter().any(|(_, item)| {
+            item.marks(table_name)
+                .any(|marked| marked.is_none() || marked.as_deref() == key)
+        }))
+    }
+
+    /// Take an item out of the trash, its records are shown again.
+    pub fn restore(&self, id: &[u8; 32]) -> Result<TrashItem> {
+        self.cloud
+            .db
+            .remove::<[u8; 32], TrashItem>(&self.items_table(), id)?
+            .ok_or(anyhow::anyhow!("item is not in the trash"))
+    }
+
+    /// Remove an item and its records permanently. The records remain in the journal history.
+    pub fn purge(&self, id: &[u8; 32]) -> Result<()> {
+        self.purge_items(&[*id])
+    }
+
+    /// Purge items past `TRASH_RETENTION`. Returns the number purged.
+    pub fn purge_expired(&self) -> Result<usize> {
+        let expired = self
+            .items()?
+            .into_iter()
+            .filter(|(_, item)| is_expired(item.deleted_at))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        self.purge_items(&expired)?;
+        Ok(expired.len())
+    }
+
+    /// Remove the markers and the records they mark in one journaled transaction.
+    fn purge_items(&self, ids: &[[u8; 32]]) -> Result<()> {
+        let mut tx = self.cloud.db.begin_write()?;
+        let mut items = tx.open_table(&self.items_table())?;
+        let mut purged = Vec::default();
+        for id in ids {
+            if let Some(item) = items.get::<[u8; 32], TrashItem>(id)? {
+                items.remove(id)?;
+                purged.push(item);
+            }
+        }
+        drop(items);
+        for item in purged {
+            for (table_name, key) in &item.records {
+                match key {
+                    Some(key) => {
+                        let mut table = tx.open_table(table_name)?;
+                        table.remove(key)?;
+                    }
+                    None => {
+                        tx.delete_table(table_name)?;
+                    }
+                }
+            }
+        }
+        tx.commit()
+    }
+}
+
+/// A cloud removed from this device by `AppState::delete_cloud`. Stored locally only, in the
+/// application database. The private key is stored apart, see `AppState::restore_cloud`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeletedCloud {
+    pub name: String,
+    pub deleted_at: u64,
+    /// Opened without a key, so restoring only needs the id.
+    pub is_public: bool,
+    pub algorithm: SignatureAlgorithm,
+    /// Database files left in place so restoring doesn't need the remote. Empty in the browser.
+    pub filepaths: Vec<PathBuf>,
+}
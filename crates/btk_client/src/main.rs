@@ -10,6 +10,8 @@ pub use tokio;
 
 mod app;
 mod applets;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
 mod data;
 mod network;
 mod theme;
@@ -18,6 +20,15 @@ mod widgets;
 #[cfg(not(target_arch = "wasm32"))]
 #[tokio::main]
 async fn main() -> eframe::Result {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
 
     let native_options = eframe::NativeOptions {
@@ -1,4 +1,6 @@
 use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
 
 use network_common::*;
 
@@ -6,12 +8,59 @@ use network_common::*;
 mod network_native;
 #[cfg(target_arch = "wasm32")]
 mod network_wasm;
+#[cfg(not(target_arch = "wasm32"))]
+mod tls;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub use network_native::NetworkConnection;
 #[cfg(target_arch = "wasm32")]
 pub use network_wasm::NetworkConnection;
 
+/// How the certificate of a remote is verified. Browsers always verify with their own roots, so
+/// this only applies to native builds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TlsTrust {
+    /// The platform roots.
+    #[default]
+    System,
+    /// Only the certificate with this SHA-256 fingerprint, e.g. for a self-signed remote.
+    PinnedCertificate([u8; 32]),
+    /// PEM encoded CA certificates that sign the remote certificate.
+    CustomCa(String),
+}
+
+impl TlsTrust {
+    /// Parse a hex encoded SHA-256 fingerprint, with or without `:` separators as printed by
+    /// `openssl x509 -fingerprint -sha256`.
+    pub fn pinned(fingerprint_str: &str) -> Result<Self> {
+        let mut fingerprint = [0u8; 32];
+        hex::decode_to_slice(fingerprint_str.trim().replace(':', ""), &mut fingerprint)?;
+        Ok(TlsTrust::PinnedCertificate(fingerprint))
+    }
+
+    /// Check that the CA certificates parse.
+    pub fn custom_ca(pem: &str) -> Result<Self> {
+        let tls_trust = TlsTrust::CustomCa(pem.trim().to_string());
+        http_client(&tls_trust)?;
+        Ok(tls_trust)
+    }
+}
+
+/// Build an http client that verifies the remote certificate using `tls_trust`. Every request
+/// carries the protocol version.
+pub fn http_client(tls_trust: &TlsTrust) -> Result<reqwest::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(PROTOCOL_HEADER, PROTOCOL_VERSION.into());
+    let builder = reqwest::Client::builder().default_headers(headers);
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(config) = tls::client_config(tls_trust)? {
+        return Ok(builder.use_preconfigured_tls(config).build()?);
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = tls_trust;
+    Ok(builder.build()?)
+}
+
 /// Abstraction around a concrete network connection. Handles reconnect logic, send/receive,
 /// switching servers.
 #[derive(Default)]
@@ -25,7 +74,10 @@ impl NetworkManager {
     pub fn new(url: &str) -> Self {
         Self {
             active_url: url.into(),
-            connection_maybe: Some(NetworkConnection::attempt_connection(url.into())),
+            connection_maybe: Some(NetworkConnection::attempt_connection(
+                url.into(),
+                TlsTrust::default(),
+            )),
         }
     }
 
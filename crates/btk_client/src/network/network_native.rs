@@ -1,12 +1,18 @@
+use std::sync::Arc;
+
 use anondb::Bytes;
 use anyhow::Result;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::Connector;
+use tokio_tungstenite::connect_async_tls_with_config;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
 use network_common::*;
 
+use super::TlsTrust;
+use super::tls;
+
 pub struct NetworkConnection {
     url: String,
     send_tx: flume::Sender<Action>,
@@ -44,9 +50,11 @@ impl NetworkConnection {
         }
     }
 
-    pub fn attempt_connection(url: String) -> Self {
+    pub fn attempt_connection(url: String, tls_trust: TlsTrust) -> Self {
         let url_clone = url.clone();
         let (send_tx, send_rx) = flume::unbounded::<Action>();
+        // queued so it's the first message once the connection opens
+        send_tx.send(Action::Hello(Hello::current())).ok();
         let (receive_tx, receive_rx) = flume::unbounded::<Response>();
         let (connected_tx, connected_rx) = flume::unbounded::<Result<()>>();
         Self {
@@ -58,7 +66,16 @@ impl NetworkConnection {
             worker_thread: std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async {
-                    let connection = connect_async(url_clone).await;
+                    let connector = match tls::client_config(&tls_trust) {
+                        Ok(config) => config.map(|config| Connector::Rustls(Arc::new(config))),
+                        Err(e) => {
+                            println!("invalid tls configuration: {:?}", e);
+                            connected_tx.send(Err(e)).ok();
+                            return; // thread ends
+                        }
+                    };
+                    let connection =
+                        connect_async_tls_with_config(url_clone, None, false, connector).await;
                     if let Err(e) = connection {
                         println!("Connection errored: {:?}", e);
                         connected_tx.send(Err(anyhow::format_err!(e))).ok();
@@ -9,6 +9,8 @@ use wasm_bindgen_futures::spawn_local;
 
 use network_common::*;
 
+use super::TlsTrust;
+
 pub struct NetworkConnection {
     url: String,
     send_tx: flume::Sender<Action>,
@@ -34,9 +36,12 @@ impl NetworkConnection {
         }
     }
 
-    pub fn attempt_connection(url: String) -> Self {
+    /// The browser verifies certificates, `_tls_trust` is ignored.
+    pub fn attempt_connection(url: String, _tls_trust: TlsTrust) -> Self {
         let url_clone = url.clone();
         let (send_tx, send_rx) = flume::unbounded::<Action>();
+        // queued so it's the first message once the connection opens
+        send_tx.send(Action::Hello(Hello::current())).ok();
         let (receive_tx, receive_rx) = flume::unbounded::<Response>();
         let (close_tx, close_rx) = flume::bounded::<()>(2);
         let (connected_tx, connected_rx) = flume::unbounded::<Result<()>>();
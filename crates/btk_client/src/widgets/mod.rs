@@ -1,5 +1,10 @@
 mod confirm_button;
 mod editable_label;
+mod secret_label;
+mod transfer_menu;
 
 pub use confirm_button::ConfirmButton;
 pub use editable_label::EditableLabel;
+pub use secret_label::SecretLabel;
+pub use transfer_menu::TransferMenu;
+pub use transfer_menu::TransferTarget;
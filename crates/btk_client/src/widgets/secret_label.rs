@@ -0,0 +1,104 @@
+//! Displays a secret masked by default. The secret can be revealed for display or copied to the
+//! clipboard. Copied secrets are removed from the clipboard after a timeout.
+
+use std::hash::Hash;
+
+use egui::Widget;
+use web_time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use zeroize::Zeroizing;
+
+/// How long a copied secret stays in the clipboard.
+const CLIPBOARD_CLEAR_TIMEOUT: Duration = Duration::from_secs(30);
+const MASK: &str = "••••••••••••••••";
+
+pub struct SecretLabel<'a> {
+    id: egui::Id,
+    secret: &'a str,
+}
+
+impl<'a> SecretLabel<'a> {
+    pub fn new(id_salt: impl Hash, secret: &'a str) -> Self {
+        Self {
+            id: egui::Id::new(id_salt),
+            secret,
+        }
+    }
+}
+
+impl Widget for SecretLabel<'_> {
+    fn ui(self, ui: &mut egui::Ui) -> egui::Response {
+        // only the reveal state is kept in egui memory, never the secret
+        let revealed = ui
+            .ctx()
+            .data(|d| d.get_temp::<bool>(self.id))
+            .unwrap_or(false);
+        ui.horizontal(|ui| {
+            if revealed {
+                ui.label(self.secret);
+                if ui.button("hide").clicked() {
+                    ui.ctx().data_mut(|d| d.insert_temp(self.id, false));
+                }
+            } else {
+                ui.label(MASK);
+                if ui.button("reveal").clicked() {
+                    ui.ctx().data_mut(|d| d.insert_temp(self.id, true));
+                }
+            }
+            if ui
+                .button("copy")
+                .on_hover_text(format!(
+                    "clipboard is cleared after {} seconds",
+                    CLIPBOARD_CLEAR_TIMEOUT.as_secs()
+                ))
+                .clicked()
+            {
+                copy_secret(ui.ctx(), self.secret);
+            }
+        })
+        .response
+    }
+}
+
+/// The clipboard is only cleared if it still contains the secret.
+#[cfg(not(target_arch = "wasm32"))]
+fn copy_secret(_ctx: &egui::Context, secret: &str) {
+    let secret = Zeroizing::new(secret.to_string());
+    // on some platforms the clipboard contents are dropped with the `Clipboard`, keep it alive
+    // in a thread until the timeout
+    std::thread::spawn(move || {
+        let mut clipboard = match arboard::Clipboard::new() {
+            Ok(clipboard) => clipboard,
+            Err(e) => {
+                println!("failed to open clipboard: {:?}", e);
+                return;
+            }
+        };
+        if let Err(e) = clipboard.set_text(secret.as_str()) {
+            println!("failed to copy secret: {:?}", e);
+            return;
+        }
+        std::thread::sleep(CLIPBOARD_CLEAR_TIMEOUT);
+        if let Ok(current) = clipboard.get_text() {
+            let current = Zeroizing::new(current);
+            if *current == *secret {
+                clipboard.clear().ok();
+            }
+        }
+    });
+}
+
+/// Browsers don't allow reading the clipboard without a prompt, so it's overwritten
+/// unconditionally.
+#[cfg(target_arch = "wasm32")]
+fn copy_secret(ctx: &egui::Context, secret: &str) {
+    use crate::tokio;
+
+    ctx.copy_text(secret.to_string());
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(CLIPBOARD_CLEAR_TIMEOUT).await;
+        ctx.copy_text(String::new());
+        ctx.request_repaint();
+    });
+}
@@ -0,0 +1,55 @@
+//! "Copy to" and "Move to" submenus listing the other writable clouds. Shown in the context menu
+//! of records that can be sent with `AppState::transfer`.
+
+use crate::data::AppState;
+
+pub struct TransferTarget {
+    pub cloud_id: [u8; 32],
+    /// Set for "Move to", the record is removed from the active cloud.
+    pub remove_source: bool,
+}
+
+pub struct TransferMenu;
+
+impl TransferMenu {
+    /// Returns the chosen cloud once one is clicked.
+    pub fn show(ui: &mut egui::Ui, state: &AppState) -> Option<TransferTarget> {
+        let targets = state
+            .sorted_clouds
+            .iter()
+            .filter(|(cloud, _)| {
+                Some(*cloud.id()) != state.active_cloud_id && !cloud.is_read_only()
+            })
+            .map(|(cloud, metadata)| (*cloud.id(), metadata.name.clone()))
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            ui.label("no other clouds");
+            return None;
+        }
+        let can_move = state
+            .active_cloud()
+            .is_some_and(|(cloud, _)| !cloud.is_read_only());
+        let mut target = None;
+        for (label, remove_source) in [("Copy to", false), ("Move to", true)] {
+            if remove_source && !can_move {
+                continue;
+            }
+            ui.menu_button(label, |ui| {
+                for (cloud_id, name) in &targets {
+                    let name = if name.trim().is_empty() {
+                        "(unnamed cloud)"
+                    } else {
+                        name.as_str()
+                    };
+                    if ui.button(name).clicked() {
+                        target = Some(TransferTarget {
+                            cloud_id: *cloud_id,
+                            remove_source,
+                        });
+                    }
+                }
+            });
+        }
+        target
+    }
+}
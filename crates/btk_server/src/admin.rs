@@ -0,0 +1,380 @@
+//! Operator commands for inspecting and moving cloud data. Like the other commands these open
+//! the database directly, the server must be stopped first.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anondb::Bytes;
+use anondb::Journal;
+use anyhow::Result;
+use redb::TableHandle;
+use serde::Deserialize;
+use serde::Serialize;
+
+use network_common::*;
+
+use super::allowlist;
+use super::cli::open_db;
+use super::cli::parse_id;
+use super::delete;
+use super::delete::DELETED_CLOUD_TABLE;
+use super::publish::PUBLIC_COUNT_TABLE;
+use super::server::LEGACY_PUBLIC_KEY_TABLE;
+use super::server::PUBLIC_KEY_TABLE;
+use super::server::public_key_record;
+
+const USAGE: &str = "usage: btk_server admin <command>
+
+commands:
+  list                        print each cloud with its mutation count and size
+  keys                        print the registered public keys
+  inspect <cloud_id>          print the public key record and mutations of a cloud
+  verify [cloud_id]           verify the signature of every stored mutation
+  export <cloud_id> <file>    write a cloud log to a file
+  import <file>               load a cloud log written by `export`
+  migrate-r2 <dir>            load clouds from a directory of btk_worker objects, one file per
+                              object named by its key";
+
+/// A cloud log written by `admin export`.
+#[derive(Serialize, Deserialize)]
+struct CloudExport {
+    record: PublicKeyRecord,
+    mutations: Vec<Mutation>,
+    /// Number of published mutations.
+    public_count: u64,
+}
+
+pub fn run(args: &[&str]) -> Result<()> {
+    match args {
+        ["list"] => list(&open_db()?),
+        ["keys"] => keys(&open_db()?),
+        ["inspect", cloud_id] => inspect(&open_db()?, &parse_id(cloud_id)?),
+        ["verify"] => {
+            let db = open_db()?;
+            verify(&db, &cloud_ids(&db)?)
+        }
+        ["verify", cloud_id] => verify(&open_db()?, &[parse_id(cloud_id)?]),
+        ["export", cloud_id, path] => export(&open_db()?, &parse_id(cloud_id)?, Path::new(path)),
+        ["import", path] => {
+            let export = Bytes::from(std::fs::read(path)?).parse::<CloudExport>()?;
+            let cloud_id = import_cloud(&open_db()?, &export)?;
+            println!(
+                "imported {} ({} mutations)",
+                hex::encode(cloud_id),
+                export.mutations.len()
+            );
+            Ok(())
+        }
+        ["migrate-r2", dir] => migrate_r2(&open_db()?, Path::new(dir)),
+        [] | ["help"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => anyhow::bail!("unknown admin command\n\n{USAGE}"),
+    }
+}
+
+/// Mutations are stored in a table named by the hex encoded cloud id.
+fn cloud_ids(db: &Journal) -> Result<Vec<[u8; 32]>> {
+    let tx = db.begin_read()?;
+    let mut out = Vec::default();
+    for table in tx.list_tables()? {
+        let mut cloud_id = [0u8; 32];
+        if hex::decode_to_slice(table.name(), &mut cloud_id).is_ok() {
+            out.push(cloud_id);
+        }
+    }
+    Ok(out)
+}
+
+fn load_mutations(db: &Journal, cloud_id: &[u8; 32]) -> Result<Vec<Mutation>> {
+    let table_name = hex::encode(cloud_id);
+    let count = db.count::<Bytes, Bytes>(&table_name)?;
+    (0..count)
+        .map(|index| {
+            db.get::<u64, Mutation>(&table_name, &index)?
+                .ok_or(anyhow::anyhow!("missing mutation #{index}"))
+        })
+        .collect()
+}
+
+fn encoded_len(mutation: &Mutation) -> Result<usize> {
+    Ok(Bytes::encode(mutation)?.len())
+}
+
+fn public_count(db: &Journal, cloud_id: &[u8; 32]) -> Result<u64> {
+    Ok(db
+        .get::<[u8; 32], u64>(PUBLIC_COUNT_TABLE, cloud_id)?
+        .unwrap_or_default())
+}
+
+fn list(db: &Journal) -> Result<()> {
+    for cloud_id in cloud_ids(db)? {
+        let mutations = load_mutations(db, &cloud_id)?;
+        let mut size = 0;
+        for mutation in &mutations {
+            size += encoded_len(mutation)?;
+        }
+        let algorithm = match public_key_record(db, &cloud_id)? {
+            Some(record) => record.signature_algorithm.name(),
+            None => "no public key",
+        };
+        let mut flags = String::default();
+        if delete::is_deleted(db, &cloud_id)? {
+            flags += " deleted";
+        }
+        if allowlist::contains(db, &cloud_id)? {
+            flags += " allowlisted";
+        }
+        println!(
+            "{} {} mutations, {} bytes, {}, {} public{}",
+            hex::encode(cloud_id),
+            mutations.len(),
+            size,
+            algorithm,
+            public_count(db, &cloud_id)?,
+            flags
+        );
+    }
+    Ok(())
+}
+
+fn keys(db: &Journal) -> Result<()> {
+    for (cloud_id, record) in
+        db.find_many::<[u8; 32], PublicKeyRecord, _>(PUBLIC_KEY_TABLE, |_, _| true)?
+    {
+        println!(
+            "{} {} {:?} {} byte public key",
+            hex::encode(cloud_id),
+            record.signature_algorithm.name(),
+            record.cipher_algorithm,
+            record.public_key.len()
+        );
+    }
+    for (cloud_id, public_key) in
+        db.find_many::<[u8; 32], Bytes, _>(LEGACY_PUBLIC_KEY_TABLE, |_, _| true)?
+    {
+        println!(
+            "{} legacy {} byte public key",
+            hex::encode(cloud_id),
+            public_key.len()
+        );
+    }
+    Ok(())
+}
+
+fn inspect(db: &Journal, cloud_id: &[u8; 32]) -> Result<()> {
+    println!("cloud {}", hex::encode(cloud_id));
+    match public_key_record(db, cloud_id)? {
+        Some(record) => {
+            println!("signature algorithm: {}", record.signature_algorithm.name());
+            println!("cipher algorithm: {:?}", record.cipher_algorithm);
+            println!("public key: {} bytes", record.public_key.len());
+        }
+        None => println!("public key: none"),
+    }
+    println!("deleted: {}", delete::is_deleted(db, cloud_id)?);
+    println!("allowlisted: {}", allowlist::contains(db, cloud_id)?);
+    println!("public mutations: {}", public_count(db, cloud_id)?);
+    let mutations = load_mutations(db, cloud_id)?;
+    println!("mutations: {}", mutations.len());
+    for mutation in mutations {
+        println!(
+            "  #{} {} bytes{}{}",
+            mutation.index,
+            encoded_len(&mutation)?,
+            if mutation.mutation_key.is_some() {
+                " public"
+            } else {
+                ""
+            },
+            match &mutation.creation_proof {
+                Some(proof) => format!(" creation proof {:?}", proof),
+                None => String::default(),
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Check that each mutation is at the index it claims and is signed by the cloud key.
+fn verify_mutations(record: &PublicKeyRecord, mutations: &[Mutation]) -> Result<()> {
+    for (index, mutation) in mutations.iter().enumerate() {
+        if mutation.index != index as u64 {
+            anyhow::bail!("mutation #{index} has index {}", mutation.index);
+        }
+        mutation
+            .verify(record)
+            .map_err(|e| anyhow::anyhow!("mutation #{index} failed verification: {e}"))?;
+    }
+    Ok(())
+}
+
+fn verify(db: &Journal, cloud_ids: &[[u8; 32]]) -> Result<()> {
+    let mut failures = 0;
+    for cloud_id in cloud_ids {
+        let mutations = load_mutations(db, cloud_id)?;
+        let result = match public_key_record(db, cloud_id)? {
+            Some(record) => verify_mutations(&record, &mutations),
+            None if mutations.is_empty() => Ok(()),
+            None => Err(anyhow::anyhow!("no public key")),
+        };
+        match result {
+            Ok(()) => println!(
+                "ok {} ({} mutations)",
+                hex::encode(cloud_id),
+                mutations.len()
+            ),
+            Err(e) => {
+                failures += 1;
+                println!("FAILED {}: {e}", hex::encode(cloud_id));
+            }
+        }
+    }
+    if failures > 0 {
+        anyhow::bail!("{failures} clouds failed verification");
+    }
+    Ok(())
+}
+
+fn export(db: &Journal, cloud_id: &[u8; 32], path: &Path) -> Result<()> {
+    let record = public_key_record(db, cloud_id)?
+        .ok_or(anyhow::anyhow!("cloud {} not found", hex::encode(cloud_id)))?;
+    let export = CloudExport {
+        record,
+        mutations: load_mutations(db, cloud_id)?,
+        public_count: public_count(db, cloud_id)?,
+    };
+    std::fs::write(path, Bytes::encode(&export)?.to_vec())?;
+    println!(
+        "exported {} mutations to {}",
+        export.mutations.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Write a verified cloud log. Clouds that already have mutations or were deleted are refused.
+fn import_cloud(db: &Journal, export: &CloudExport) -> Result<[u8; 32]> {
+    let cloud_id = export
+        .mutations
+        .first()
+        .ok_or(anyhow::anyhow!("cloud has no mutations"))?
+        .public_key_hash;
+    verify_mutations(&export.record, &export.mutations)?;
+    if export.public_count > export.mutations.len() as u64 {
+        anyhow::bail!("public count is greater than the number of mutations");
+    }
+    if delete::is_deleted(db, &cloud_id)? {
+        anyhow::bail!("cloud {} was deleted", hex::encode(cloud_id));
+    }
+
+    let mut tx = db.begin_write()?;
+    let mut table = tx.open_table(&hex::encode(cloud_id))?;
+    if table.len()? != 0 {
+        anyhow::bail!("cloud {} already exists", hex::encode(cloud_id));
+    }
+    for mutation in &export.mutations {
+        table.insert(&mutation.index, mutation)?;
+    }
+    drop(table);
+    let mut pubkey_table = tx.open_table(PUBLIC_KEY_TABLE)?;
+    pubkey_table.insert(&cloud_id, &export.record)?;
+    drop(pubkey_table);
+    if export.public_count > 0 {
+        let mut count_table = tx.open_table(PUBLIC_COUNT_TABLE)?;
+        count_table.insert(&cloud_id, &export.public_count)?;
+    }
+    tx.commit()?;
+    Ok(cloud_id)
+}
+
+/// Objects for a single cloud in the btk_worker bucket.
+#[derive(Default)]
+struct R2Cloud {
+    record: Option<PublicKeyRecord>,
+    legacy_public_key: Option<Vec<u8>>,
+    count: Option<u64>,
+    public_count: u64,
+    mutations: BTreeMap<u64, Mutation>,
+}
+
+/// Load a directory containing btk_worker objects, e.g. from `rclone copy`. Files are named by
+/// their key: `mutation-{index}-{id}`, `count-{id}`, `pubkey-{id}`, `pubkey-record-{id}`,
+/// `public-count-{id}` and `deleted-{id}`. Other objects are skipped.
+fn migrate_r2(db: &Journal, dir: &Path) -> Result<()> {
+    let mut clouds = BTreeMap::<[u8; 32], R2Cloud>::default();
+    let mut deleted = Vec::default();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let bytes = std::fs::read(entry.path())?;
+        // longer prefixes first, `pubkey-record-` also matches `pubkey-`
+        if let Some(id) = name.strip_prefix("pubkey-record-") {
+            clouds.entry(parse_id(id)?).or_default().record =
+                Some(Bytes::from(bytes).parse::<PublicKeyRecord>()?);
+        } else if let Some(id) = name.strip_prefix("pubkey-") {
+            clouds.entry(parse_id(id)?).or_default().legacy_public_key = Some(bytes);
+        } else if let Some(id) = name.strip_prefix("public-count-") {
+            clouds.entry(parse_id(id)?).or_default().public_count =
+                Bytes::from(bytes).parse::<u64>()?;
+        } else if let Some(id) = name.strip_prefix("count-") {
+            clouds.entry(parse_id(id)?).or_default().count =
+                Some(Bytes::from(bytes).parse::<u64>()?);
+        } else if let Some(id) = name.strip_prefix("deleted-") {
+            deleted.push((parse_id(id)?, Bytes::from(bytes).parse::<u64>()?));
+        } else if let Some((index, id)) = name
+            .strip_prefix("mutation-")
+            .and_then(|rest| rest.split_once('-'))
+        {
+            let mutation = Bytes::from(bytes).parse::<Mutation>()?;
+            clouds
+                .entry(parse_id(id)?)
+                .or_default()
+                .mutations
+                .insert(index.parse::<u64>()?, mutation);
+        } else {
+            println!("skipping {name}");
+        }
+    }
+
+    let mut failures = 0;
+    for (cloud_id, cloud) in clouds {
+        let result = (|| -> Result<usize> {
+            let record = cloud
+                .record
+                .or(cloud.legacy_public_key.map(PublicKeyRecord::legacy))
+                .ok_or(anyhow::anyhow!("no public key"))?;
+            if let Some(count) = cloud.count {
+                if count != cloud.mutations.len() as u64 {
+                    anyhow::bail!(
+                        "count is {count} but found {} mutations",
+                        cloud.mutations.len()
+                    );
+                }
+            }
+            let export = CloudExport {
+                record,
+                mutations: cloud.mutations.into_values().collect(),
+                public_count: cloud.public_count,
+            };
+            import_cloud(db, &export)?;
+            Ok(export.mutations.len())
+        })();
+        match result {
+            Ok(count) => println!("migrated {} ({count} mutations)", hex::encode(cloud_id)),
+            Err(e) => {
+                failures += 1;
+                println!("FAILED {}: {e}", hex::encode(cloud_id));
+            }
+        }
+    }
+    for (cloud_id, deleted_at) in deleted {
+        db.insert(DELETED_CLOUD_TABLE, &cloud_id, &deleted_at)?;
+        println!("migrated tombstone for {}", hex::encode(cloud_id));
+    }
+    if failures > 0 {
+        anyhow::bail!("{failures} clouds failed to migrate");
+    }
+    Ok(())
+}
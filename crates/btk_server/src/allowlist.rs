@@ -0,0 +1,125 @@
+use anondb::Journal;
+use anyhow::Result;
+use tiny_http::Method;
+
+use network_common::*;
+
+use super::server::BTKServer;
+use super::server::Req;
+
+/// cloud ids and mailbox recipient ids a private server will serve
+pub const ALLOWLIST_TABLE: &str = "allowed_clouds";
+
+pub fn allow(db: &Journal, cloud_id: &[u8; 32]) -> Result<()> {
+    db.insert(ALLOWLIST_TABLE, cloud_id, &())?;
+    Ok(())
+}
+
+/// Returns true if the cloud was in the allowlist.
+pub fn disallow(db: &Journal, cloud_id: &[u8; 32]) -> Result<bool> {
+    Ok(db
+        .remove::<[u8; 32], ()>(ALLOWLIST_TABLE, cloud_id)?
+        .is_some())
+}
+
+pub fn contains(db: &Journal, cloud_id: &[u8; 32]) -> Result<bool> {
+    Ok(db.get::<[u8; 32], ()>(ALLOWLIST_TABLE, cloud_id)?.is_some())
+}
+
+pub fn list(db: &Journal) -> Result<Vec<[u8; 32]>> {
+    Ok(db
+        .find_many::<[u8; 32], (), _>(ALLOWLIST_TABLE, |_, _| true)?
+        .into_iter()
+        .map(|(cloud_id, _)| cloud_id)
+        .collect())
+}
+
+impl BTKServer {
+    /// Public servers serve every cloud and mailbox, private servers only serve allowlisted
+    /// ones. Mailboxes are allowlisted by recipient id.
+    pub fn cloud_allowed(&self, cloud_id: &[u8; 32]) -> Result<bool> {
+        if !self.config.private {
+            return Ok(true);
+        }
+        contains(&self.db, cloud_id)
+    }
+
+    /// Websocket connections must name an allowed cloud in the `cloud_id` query.
+    pub fn websocket_allowed(&self, cloud_id: Option<[u8; 32]>) -> bool {
+        if !self.config.private {
+            return true;
+        }
+        match cloud_id {
+            Some(cloud_id) => self.cloud_allowed(&cloud_id).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Check a genesis mutation on a private server. The cloud must already be allowlisted or
+    /// carry an enrollment signed by the operator. Returns true if the cloud should be added to
+    /// the allowlist.
+    pub fn check_private_creation(&self, mutation: &Mutation) -> Result<bool> {
+        if contains(&self.db, &mutation.public_key_hash)? {
+            return Ok(false);
+        }
+        let enrollment_key = self
+            .config
+            .enrollment_key
+            .ok_or(anyhow::anyhow!("cloud is not in the allowlist"))?;
+        let proof = mutation
+            .creation_proof
+            .as_ref()
+            .ok_or(anyhow::anyhow!("cloud is not in the allowlist"))?;
+        proof.verify_enrollment(&enrollment_key, &mutation.public_key_hash)?;
+        Ok(true)
+    }
+
+    /// Requests must include `Authorization: Bearer <BTK_ADMIN_TOKEN>`.
+    pub(crate) fn admin_authorized(&self, req: &Req) -> bool {
+        let admin_token = match &self.config.admin_token {
+            Some(admin_token) => admin_token,
+            None => return false,
+        };
+        match req
+            .header("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            // compare hashes, blake3 hash equality is constant time
+            Some(token) => {
+                blake3::hash(token.trim().as_bytes()) == blake3::hash(admin_token.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    /// `GET` lists the allowlist, `POST` and `DELETE` add and remove the `cloud_id` in the query.
+    pub async fn admin_allowlist(&self, req: Req) -> Result<()> {
+        if self.config.admin_token.is_none() {
+            return req.respond_empty(404);
+        }
+        if !self.admin_authorized(&req) {
+            return req.respond_empty(401);
+        }
+        if req.method == Method::Get {
+            return req.respond(200, Some(list(&self.db)?));
+        }
+        let cloud_id = match req.query_id("cloud_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        match req.method {
+            Method::Post => {
+                allow(&self.db, &cloud_id)?;
+                req.respond_empty(204)
+            }
+            Method::Delete => {
+                if disallow(&self.db, &cloud_id)? {
+                    req.respond_empty(204)
+                } else {
+                    req.respond_empty(404)
+                }
+            }
+            _ => req.respond_empty(405),
+        }
+    }
+}
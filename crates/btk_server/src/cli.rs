@@ -0,0 +1,85 @@
+//! Operator commands. Commands that touch the database open it directly, the server must be
+//! stopped first. Use the `/admin` endpoints to manage a running server.
+
+use anondb::Journal;
+use anyhow::Result;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+
+use network_common::CreationProof;
+
+use super::admin;
+use super::allowlist;
+use super::config;
+
+const USAGE: &str = "usage: btk_server [command]
+
+With no command the server is started. The database is read from BTK_DB_PATH, default /data.redb.
+
+commands:
+  allow <id>              add a cloud or mailbox recipient to the allowlist
+  disallow <id>           remove a cloud or mailbox recipient from the allowlist
+  allowlist               print the allowlist
+  enrollment-keygen       generate an enrollment keypair
+  enroll <cloud_id>       sign an enrollment for a cloud using BTK_ENROLLMENT_SECRET
+  admin <command>         inspect and move cloud data, see `admin help`";
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["allow", cloud_id] => {
+            allowlist::allow(&open_db()?, &parse_id(cloud_id)?)?;
+            println!("allowed {cloud_id}");
+        }
+        ["disallow", cloud_id] => {
+            if allowlist::disallow(&open_db()?, &parse_id(cloud_id)?)? {
+                println!("disallowed {cloud_id}");
+            } else {
+                println!("{cloud_id} is not in the allowlist");
+            }
+        }
+        ["allowlist"] => {
+            for cloud_id in allowlist::list(&open_db()?)? {
+                println!("{}", hex::encode(cloud_id));
+            }
+        }
+        ["enrollment-keygen"] => {
+            let signing_key = SigningKey::from_bytes(&rand::random());
+            println!(
+                "BTK_ENROLLMENT_SECRET={}",
+                hex::encode(signing_key.to_bytes())
+            );
+            println!(
+                "BTK_ENROLLMENT_KEY={}",
+                hex::encode(signing_key.verifying_key().to_bytes())
+            );
+        }
+        ["enroll", cloud_id] => {
+            let secret = std::env::var("BTK_ENROLLMENT_SECRET")
+                .map_err(|_| anyhow::anyhow!("BTK_ENROLLMENT_SECRET is not set"))?;
+            let signing_key = SigningKey::from_bytes(&parse_id(&secret)?);
+            let signature =
+                signing_key.sign(&CreationProof::enrollment_message(&parse_id(cloud_id)?));
+            // entered in the client as the invite token for the cloud
+            println!("{}", hex::encode(signature.to_bytes()));
+        }
+        ["admin", args @ ..] => admin::run(args)?,
+        ["help"] | ["--help"] | ["-h"] => println!("{USAGE}"),
+        _ => anyhow::bail!("unknown command\n\n{USAGE}"),
+    }
+    Ok(())
+}
+
+pub fn open_db() -> Result<Journal> {
+    Ok(redb::Database::create(config::db_path())?.into())
+}
+
+pub fn parse_id(id_str: &str) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    hex::decode_to_slice(id_str.trim(), &mut out)?;
+    Ok(out)
+}
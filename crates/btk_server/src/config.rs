@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use network_common::CreationPolicy;
+
+use super::server::DB_PATH;
+
+/// Default lifetime of a mailbox entry, 14 days.
+const DEFAULT_MAILBOX_TTL_SECS: u64 = 14 * 24 * 60 * 60;
+const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:8000";
+const DEFAULT_WS_ADDR: &str = "0.0.0.0:5001";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+/// Location of the server database. Read separately from `Config` so operator commands can open
+/// the database without a complete configuration.
+/// `BTK_DB_PATH`
+pub fn db_path() -> String {
+    std::env::var("BTK_DB_PATH")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or(DB_PATH.to_string())
+}
+
+/// Operator configuration, read from the environment at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// `BTK_HTTP_ADDR`
+    pub http_addr: String,
+    /// `BTK_WS_ADDR`
+    pub ws_addr: String,
+    /// How long to wait for in-progress requests and websocket connections to finish when
+    /// shutting down.
+    /// `BTK_SHUTDOWN_TIMEOUT_SECS`
+    pub shutdown_timeout: Duration,
+    /// PEM encoded certificate chain. When set with `tls_key` both listeners only accept TLS.
+    /// Send SIGHUP to reload the certificate and key.
+    /// `BTK_TLS_CERT`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key.
+    /// `BTK_TLS_KEY`
+    pub tls_key: Option<PathBuf>,
+    /// How long mailbox entries are kept before they're pruned.
+    /// `BTK_MAILBOX_TTL_SECS`
+    pub mailbox_ttl: Duration,
+    /// Who may create new clouds, `open`, `pow:<difficulty>` or `invite`.
+    /// `BTK_CREATION_POLICY`
+    pub creation_policy: CreationPolicy,
+    /// Hex encoded 32 byte tokens, comma separated. Each token may create one cloud when
+    /// `creation_policy` is `invite`.
+    /// `BTK_CREATION_TOKENS`
+    pub creation_tokens: HashSet<[u8; 32]>,
+    /// Only serve clouds in the allowlist.
+    /// `BTK_PRIVATE`
+    pub private: bool,
+    /// Bearer token for the `/admin` endpoints and `/metrics`. They are disabled if unset.
+    /// `BTK_ADMIN_TOKEN`
+    pub admin_token: Option<String>,
+    /// Hex encoded Ed25519 public key. Clouds created with an enrollment signed by the matching
+    /// secret key are added to the allowlist.
+    /// `BTK_ENROLLMENT_KEY`
+    pub enrollment_key: Option<[u8; 32]>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let http_addr =
+            std::env::var("BTK_HTTP_ADDR").unwrap_or_else(|_| DEFAULT_HTTP_ADDR.to_string());
+        let ws_addr = std::env::var("BTK_WS_ADDR").unwrap_or_else(|_| DEFAULT_WS_ADDR.to_string());
+        let shutdown_timeout_secs = match std::env::var("BTK_SHUTDOWN_TIMEOUT_SECS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("invalid BTK_SHUTDOWN_TIMEOUT_SECS: {e}"))?,
+            Err(_) => DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+        };
+        let tls_cert = std::env::var("BTK_TLS_CERT").ok().map(PathBuf::from);
+        let tls_key = std::env::var("BTK_TLS_KEY").ok().map(PathBuf::from);
+        if tls_cert.is_some() != tls_key.is_some() {
+            anyhow::bail!("BTK_TLS_CERT and BTK_TLS_KEY must be set together");
+        }
+        let mailbox_ttl_secs = match std::env::var("BTK_MAILBOX_TTL_SECS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .map_err(|e| anyhow::anyhow!("invalid BTK_MAILBOX_TTL_SECS: {e}"))?,
+            Err(_) => DEFAULT_MAILBOX_TTL_SECS,
+        };
+        let creation_policy = match std::env::var("BTK_CREATION_POLICY") {
+            Ok(v) => v
+                .parse::<CreationPolicy>()
+                .map_err(|e| anyhow::anyhow!("invalid BTK_CREATION_POLICY: {e}"))?,
+            Err(_) => CreationPolicy::default(),
+        };
+        let mut creation_tokens = HashSet::default();
+        for token_str in std::env::var("BTK_CREATION_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+        {
+            let mut token = [0u8; 32];
+            hex::decode_to_slice(token_str, &mut token)
+                .map_err(|e| anyhow::anyhow!("invalid token in BTK_CREATION_TOKENS: {e}"))?;
+            creation_tokens.insert(token);
+        }
+        let private = match std::env::var("BTK_PRIVATE") {
+            Ok(v) => matches!(v.trim(), "1" | "true"),
+            Err(_) => false,
+        };
+        let admin_token = std::env::var("BTK_ADMIN_TOKEN")
+            .ok()
+            .filter(|v| !v.trim().is_empty());
+        let enrollment_key = match std::env::var("BTK_ENROLLMENT_KEY") {
+            Ok(v) => {
+                let mut key = [0u8; 32];
+                hex::decode_to_slice(v.trim(), &mut key)
+                    .map_err(|e| anyhow::anyhow!("invalid BTK_ENROLLMENT_KEY: {e}"))?;
+                Some(key)
+            }
+            Err(_) => None,
+        };
+        Ok(Self {
+            http_addr,
+            ws_addr,
+            shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+            tls_cert,
+            tls_key,
+            mailbox_ttl: Duration::from_secs(mailbox_ttl_secs),
+            creation_policy,
+            creation_tokens,
+            private,
+            admin_token,
+            enrollment_key,
+        })
+    }
+}
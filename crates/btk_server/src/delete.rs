@@ -0,0 +1,80 @@
+use anondb::Bytes;
+use anondb::Journal;
+use anyhow::Result;
+use tracing::info;
+use tracing::warn;
+
+use network_common::*;
+
+use super::mailbox::now_secs;
+use super::publish::PUBLIC_COUNT_TABLE;
+use super::server::BTKServer;
+use super::server::LEGACY_PUBLIC_KEY_TABLE;
+use super::server::PUBLIC_KEY_TABLE;
+use super::server::Req;
+
+/// cloud id keyed to the time it was deleted. Deleted ids can't be created again.
+pub const DELETED_CLOUD_TABLE: &str = "deleted_clouds";
+
+pub fn is_deleted(db: &Journal, cloud_id: &[u8; 32]) -> Result<bool> {
+    Ok(db
+        .get::<[u8; 32], u64>(DELETED_CLOUD_TABLE, cloud_id)?
+        .is_some())
+}
+
+impl BTKServer {
+    pub fn is_deleted(&self, cloud_id: &[u8; 32]) -> Result<bool> {
+        is_deleted(&self.db, cloud_id)
+    }
+
+    /// Remove every mutation and the public key record for a cloud, leaving a tombstone.
+    pub async fn delete_cloud(&self, req: Req) -> Result<()> {
+        let delete = match req.parse_body::<DeleteRequest>() {
+            Ok(delete) => delete,
+            Err(_) => return req.respond_empty(400),
+        };
+        // the allowlist and tombstone are checked against the query in `handle_req`
+        if req.query_id("cloud_id") != Some(delete.cloud_id) {
+            return req.respond_empty(400);
+        }
+        if self.is_deleted(&delete.cloud_id)? {
+            return req.respond_empty(410);
+        }
+        let record = match self.public_key_record(&delete.cloud_id)? {
+            Some(record) => record,
+            None => return req.respond_empty(404),
+        };
+        if let Err(e) = delete.verify(req.header("Host"), &record) {
+            warn!(cloud_id = %hex::encode(delete.cloud_id), error = ?e, "error verifying delete request");
+            return req.respond_empty(401);
+        }
+
+        // hold the creation lock so the id can't be recreated while it's removed
+        let _creation_guard = self.creation_lock.lock().await;
+        let table_name = hex::encode(delete.cloud_id);
+        let mutation_count = self.db.count::<Bytes, Bytes>(&table_name)?;
+
+        let mut tx = self.db.begin_write()?;
+        let mut table = tx.open_table(&table_name)?;
+        for index in 0..mutation_count {
+            table.remove(&index)?;
+        }
+        drop(table);
+        for record_table in [
+            PUBLIC_KEY_TABLE,
+            LEGACY_PUBLIC_KEY_TABLE,
+            PUBLIC_COUNT_TABLE,
+        ] {
+            let mut table = tx.open_table(record_table)?;
+            table.remove(&delete.cloud_id)?;
+        }
+        let mut deleted_table = tx.open_table(DELETED_CLOUD_TABLE)?;
+        deleted_table.insert(&delete.cloud_id, &now_secs())?;
+        drop(deleted_table);
+        tx.commit()?;
+        self.notify_mutation(&delete.cloud_id);
+
+        info!(cloud_id = %table_name, mutation_count, "deleted cloud");
+        req.respond_empty(204)
+    }
+}
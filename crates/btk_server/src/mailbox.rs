@@ -0,0 +1,152 @@
+use std::time::SystemTime;
+
+use anondb::Bytes;
+use anyhow::Result;
+
+use network_common::*;
+
+use super::server::BTKServer;
+use super::server::Req;
+
+/// recipient id keyed to a published encapsulation key
+const MAILBOX_KEY_TABLE: &str = "mailbox_keys";
+/// recipient id keyed to the next index to be assigned in the mailbox
+const MAILBOX_INDEX_TABLE: &str = "mailbox_next_index";
+
+fn mailbox_table_name(recipient_id: &[u8; 32]) -> String {
+    format!("mailbox-{}", hex::encode(recipient_id))
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+impl BTKServer {
+    /// Publish an encapsulation key for the recipient id given in the query.
+    pub async fn publish_mailbox_key(&self, req: Req) -> Result<()> {
+        let recipient_id = match req.query_id("recipient_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        let encapsulation_key = match req.parse_body::<ByteString>() {
+            Ok(ByteString(key)) => key,
+            Err(_) => return req.respond_empty(400),
+        };
+        if network_common::recipient_id(&encapsulation_key) != recipient_id {
+            return req.respond_empty(400);
+        }
+        self.db.insert::<[u8; 32], Bytes>(
+            MAILBOX_KEY_TABLE,
+            &recipient_id,
+            &encapsulation_key.into(),
+        )?;
+        req.respond_empty(204)
+    }
+
+    pub async fn mailbox_key(&self, req: Req) -> Result<()> {
+        let recipient_id = match req.query_id("recipient_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        if let Some(key) = self
+            .db
+            .get::<[u8; 32], Bytes>(MAILBOX_KEY_TABLE, &recipient_id)?
+        {
+            req.respond(200, Some(ByteString(key.to_vec())))
+        } else {
+            req.respond_empty(404)
+        }
+    }
+
+    /// Append a message to a mailbox. Expired messages are pruned in the same transaction.
+    pub async fn append_mailbox(&self, req: Req) -> Result<()> {
+        let recipient_id = match req.query_id("recipient_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        let message = match req.parse_body::<MailboxMessage>() {
+            Ok(message) => message,
+            Err(_) => return req.respond_empty(400),
+        };
+        if message.encoded_len() > MAILBOX_MESSAGE_MAX_LEN {
+            return req.respond_empty(413);
+        }
+        // only accept messages for recipients that can decrypt them
+        if self
+            .db
+            .get::<[u8; 32], Bytes>(MAILBOX_KEY_TABLE, &recipient_id)?
+            .is_none()
+        {
+            return req.respond_empty(404);
+        }
+
+        // appends to a mailbox are serialized so indices are never reused
+        let _guard = self.mailbox_lock.lock().await;
+
+        let table_name = mailbox_table_name(&recipient_id);
+        let now = now_secs();
+        let ttl_secs = self.config.mailbox_ttl.as_secs();
+        let expired = self
+            .db
+            .find_many::<u64, MailboxEntry, _>(&table_name, |_, entry| {
+                entry.is_expired(now, ttl_secs)
+            })?;
+        let index = self
+            .db
+            .get::<[u8; 32], u64>(MAILBOX_INDEX_TABLE, &recipient_id)?
+            .unwrap_or_default();
+
+        let mut tx = self.db.begin_write()?;
+        let mut table = tx.open_table(&table_name)?;
+        for (expired_index, _) in expired {
+            table.remove(&expired_index)?;
+        }
+        table.insert(
+            &index,
+            &MailboxEntry {
+                index,
+                created_at: now,
+                message,
+            },
+        )?;
+        drop(table);
+        let mut index_table = tx.open_table(MAILBOX_INDEX_TABLE)?;
+        index_table.insert(&recipient_id, &(index + 1))?;
+        drop(index_table);
+        tx.commit()?;
+
+        req.respond(200, Some(index))
+    }
+
+    /// List unexpired messages in a mailbox with `index >= after`.
+    pub async fn list_mailbox(&self, req: Req) -> Result<()> {
+        let recipient_id = match req.query_id("recipient_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        let after = if let Some(after_str) = req.query.get("after") {
+            match after_str.parse::<u64>() {
+                Ok(after) => after,
+                Err(_) => return req.respond_empty(400),
+            }
+        } else {
+            0
+        };
+        let now = now_secs();
+        let ttl_secs = self.config.mailbox_ttl.as_secs();
+        let mut entries = self
+            .db
+            .find_many::<u64, MailboxEntry, _>(
+                &mailbox_table_name(&recipient_id),
+                |index, entry| *index >= after && !entry.is_expired(now, ttl_secs),
+            )?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.index);
+        req.respond(200, Some(entries))
+    }
+}
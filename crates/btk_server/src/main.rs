@@ -2,117 +2,237 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
+use tokio::net::TcpListener;
 use tokio::signal::unix::SignalKind;
 use tokio::signal::unix::signal;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
 
+mod admin;
+mod allowlist;
+mod cli;
+mod config;
+mod delete;
+mod mailbox;
+mod metrics;
 mod network;
+mod publish;
 mod server;
+mod shutdown;
+mod tls;
+
+/// Plaintext listeners behind the TLS proxies, any free port.
+const LOOPBACK_ADDR: &str = "127.0.0.1:0";
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
+    // `RUST_LOG` sets the level, defaults to info. `BTK_LOG_FORMAT=json` for structured output
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    if std::env::var("BTK_LOG_FORMAT").as_deref() == Ok("json") {
+        tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
+
     // shutdown channel
-    let (shutdown_tx, shutdown_rx) = flume::bounded::<()>(1);
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
     tokio::spawn(async move {
         let mut sigterm = signal(SignalKind::terminate()).unwrap();
         let mut sigint = signal(SignalKind::interrupt()).unwrap();
 
         tokio::select! {
-            _ = sigterm.recv() => println!("Received SIGTERM"),
-            _ = sigint.recv() => println!("Received SIGINT"),
-            _ = tokio::signal::ctrl_c() => println!("Received Ctrl+C"),
+            _ = sigterm.recv() => info!("received SIGTERM"),
+            _ = sigint.recv() => info!("received SIGINT"),
+            _ = tokio::signal::ctrl_c() => info!("received Ctrl+C"),
         }
-        shutdown_tx.send(()).unwrap();
-        println!("Goodbye!");
+        shutdown_tx.send_replace(true);
     });
 
-    let server = Arc::new(server::BTKServer::new().await?);
+    let mut config = config::Config::from_env()?;
+    // with TLS the public addresses are served by the TLS proxies, the plaintext listeners are
+    // bound on loopback
+    let tls_listeners = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = Arc::new(tls::TlsCerts::load(cert_path, key_path)?);
+            let http_listener = TcpListener::bind(&config.http_addr).await?;
+            let ws_listener = TcpListener::bind(&config.ws_addr).await?;
+            config.http_addr = LOOPBACK_ADDR.to_string();
+            config.ws_addr = LOOPBACK_ADDR.to_string();
+            Some((certs, http_listener, ws_listener))
+        }
+        _ => None,
+    };
+    let http_server = Arc::new(
+        tiny_http::Server::http(&config.http_addr)
+            .map_err(|e| anyhow::anyhow!("failed to start http server: {e}"))?,
+    );
+    let server = Arc::new(server::BTKServer::new(config).await?);
+
+    if let Some((certs, http_listener, ws_listener)) = tls_listeners {
+        let acceptor = certs.acceptor()?;
+        let http_upstream = http_server.server_addr().to_ip().ok_or(anyhow::anyhow!(
+            "http server is not listening on an ip address"
+        ))?;
+        let ws_upstream = server.network_server.listener.local_addr()?;
+        info!(
+            http_addr = %http_listener.local_addr()?,
+            ws_addr = %ws_listener.local_addr()?,
+            "serving TLS"
+        );
+        tokio::spawn(tls::proxy(
+            http_listener,
+            http_upstream,
+            acceptor.clone(),
+            shutdown_rx.clone(),
+        ));
+        tokio::spawn(tls::proxy(
+            ws_listener,
+            ws_upstream,
+            acceptor,
+            shutdown_rx.clone(),
+        ));
+        // reload the certificate and key, e.g. after renewal
+        let mut sighup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                match certs.reload() {
+                    Ok(()) => info!("reloaded TLS certificate"),
+                    Err(e) => error!(error = ?e, "failed to reload TLS certificate"),
+                }
+            }
+        });
+    }
 
     // WebSocket core loop
     // start the websocket server loop in it's own thread
-    {
+    let websocket_task = {
         let server_clone = server.clone();
-        let shutdown_rx_clone = shutdown_rx.clone();
+        let mut shutdown_rx_clone = shutdown_rx.clone();
         tokio::spawn(async move {
-            println!("Starting websocket server");
-            while let Ok((stream, _)) = server_clone.network_server.listener.accept().await {
-                if shutdown_rx_clone.is_full() {
-                    break;
-                }
+            info!("starting websocket server");
+            let mut connections = JoinSet::new();
+            loop {
+                let stream = tokio::select! {
+                    accepted = server_clone.network_server.listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            error!(error = ?e, "websocket server errored");
+                            break;
+                        }
+                    },
+                    _ = shutdown_rx_clone.wait_for(|shutdown| *shutdown) => break,
+                };
                 let server_clone = server_clone.clone();
-                tokio::spawn(async move {
-                    server_clone.network_server.accept_connection(stream).await;
+                connections.spawn(async move {
+                    server_clone
+                        .network_server
+                        .accept_connection(stream, |cloud_id| {
+                            server_clone.websocket_allowed(cloud_id)
+                        })
+                        .await;
                 });
+                // reap finished connections
+                while connections.try_join_next().is_some() {}
             }
-        });
-    }
+            // connections finish once `close_all` sends them a close frame
+            while connections.join_next().await.is_some() {}
+        })
+    };
 
     // http core loop
-    {
+    let http_task = {
         let server_clone = server.clone();
-        let shutdown_rx_clone = shutdown_rx.clone();
+        let http_server = http_server.clone();
         tokio::spawn(async move {
-            println!("Starting http server");
-            let http_server =
-                tiny_http::Server::http("0.0.0.0:8000").expect("failed to start http server");
+            info!("starting http server");
             loop {
-                if shutdown_rx_clone.is_full() {
-                    break;
-                }
                 match http_server.recv_timeout(Duration::from_secs(1)) {
                     Ok(req) => {
                         if req.is_none() {
+                            if server_clone.drain.is_closing() {
+                                break;
+                            }
                             continue;
                         }
                         let req = req.unwrap();
                         let server_clone = server_clone.clone();
                         tokio::spawn(async move {
                             if let Err(e) = server_clone.handle_req(req).await {
-                                println!("error handling http req: {:?}", e);
+                                error!(error = ?e, "error handling http request");
                             }
                         });
                         tokio::task::yield_now().await;
                     }
                     Err(e) => {
-                        println!("http server errored: {:?}", e);
+                        // `unblock` interrupts the receive during shutdown
+                        if !server_clone.drain.is_closing() {
+                            error!(error = ?e, "http server errored");
+                        }
                         break;
                     }
                 }
             }
-        });
-    }
+        })
+    };
 
     // run the final task on the main thread
     {
         let server_clone = server.clone();
-        let shutdown_rx_clone = shutdown_rx.clone();
         // continuously handle client events as they are received
-        println!("Listening for websocket actions");
+        info!("listening for websocket actions");
         loop {
-            if shutdown_rx_clone.is_full() {
-                break;
-            }
             // handle inputs from the clients
-            match server_clone
-                .network_server
-                .pending_actions
-                .1
-                .recv_timeout(Duration::from_secs(1))
-            {
-                Ok((socket_id, action)) => {
-                    if let Err(e) = server.handle_action(socket_id, action.clone()).await {
-                        println!("failed to handle action: {:?} {:?}", action, e);
-                    }
-                }
-                Err(e) => {
-                    if matches!(e, flume::RecvTimeoutError::Timeout) {
-                        continue;
-                    } else {
-                        panic!("no senders for pending_actions channel");
-                    }
+            let (socket_id, action) = tokio::select! {
+                received = server_clone.network_server.pending_actions.1.recv_async() => {
+                    received.expect("no senders for pending_actions channel")
                 }
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+            };
+            if let Err(e) = server
+                .handle_action(socket_id.clone(), action.clone())
+                .await
+            {
+                error!(socket_id = %socket_id, ?action, error = ?e, "failed to handle action");
             }
         }
     }
 
+    // stop accepting requests, let in-progress requests finish, then close websockets
+    info!("shutting down");
+    let deadline = Instant::now() + server.config.shutdown_timeout;
+    let drained = tokio::time::timeout_at(deadline, async {
+        server.drain.close().await;
+        http_server.unblock();
+        server.network_server.close_all().await;
+        http_task.await.ok();
+        websocket_task.await.ok();
+    })
+    .await;
+    if drained.is_err() {
+        warn!("shutdown timed out, remaining requests and connections are abandoned");
+    }
+
+    // the database is closed when the last reference is dropped, uncommitted write
+    // transactions are rolled back
+    drop(http_server);
+    match Arc::try_unwrap(server) {
+        Ok(server) => drop(server),
+        Err(_) => warn!("database is still in use, exiting anyway"),
+    }
+    info!("goodbye!");
+
     Ok(())
 }
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use prometheus::Encoder;
+use prometheus::HistogramOpts;
+use prometheus::HistogramVec;
+use prometheus::IntCounter;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::Opts;
+use prometheus::Registry;
+use prometheus::TextEncoder;
+
+/// Routes are used as a label, anything else is reported as `other` to bound the cardinality.
+const ROUTES: &[&str] = &[
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/api",
+    "/state",
+    "/mutation",
+    "/mutate",
+    "/policy",
+    "/admin/allowlist",
+    "/mailbox",
+    "/mailbox/key",
+    "/publish",
+    "/delete",
+    "/public/state",
+    "/public/mutation",
+];
+
+/// Reasons a mutation is rejected, used as the `reason` label.
+pub mod reject {
+    /// The body could not be parsed or the cloud is unknown.
+    pub const INVALID: &str = "invalid";
+    /// The signature or algorithms don't match the cloud.
+    pub const SIGNATURE: &str = "signature";
+    /// The cloud isn't served here or the creation policy wasn't satisfied.
+    pub const FORBIDDEN: &str = "forbidden";
+    pub const DELETED: &str = "deleted";
+    /// The index is not the next index for the cloud.
+    pub const INDEX: &str = "index";
+}
+
+/// Served at `GET /metrics` in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    pub mutations_accepted: IntCounter,
+    pub mutations_rejected: IntCounterVec,
+    /// Encoded size of accepted mutations.
+    pub bytes_stored: IntCounter,
+    pub websocket_connections: IntGauge,
+    pub request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let mutations_accepted = IntCounter::new(
+            "btk_mutations_accepted_total",
+            "Mutations written to the database",
+        )?;
+        let mutations_rejected = IntCounterVec::new(
+            Opts::new(
+                "btk_mutations_rejected_total",
+                "Mutations rejected by reason",
+            ),
+            &["reason"],
+        )?;
+        let bytes_stored = IntCounter::new(
+            "btk_mutation_bytes_stored_total",
+            "Bytes of mutations written to the database",
+        )?;
+        let websocket_connections =
+            IntGauge::new("btk_websocket_connections", "Open websocket connections")?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "btk_http_request_duration_seconds",
+                "Time to handle http requests",
+            ),
+            &["method", "route"],
+        )?;
+        registry.register(Box::new(mutations_accepted.clone()))?;
+        registry.register(Box::new(mutations_rejected.clone()))?;
+        registry.register(Box::new(bytes_stored.clone()))?;
+        registry.register(Box::new(websocket_connections.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        Ok(Self {
+            registry,
+            mutations_accepted,
+            mutations_rejected,
+            bytes_stored,
+            websocket_connections,
+            request_duration,
+        })
+    }
+
+    pub fn reject_mutation(&self, reason: &str) {
+        self.mutations_rejected.with_label_values(&[reason]).inc();
+    }
+
+    pub fn route_label(path: &str) -> &str {
+        ROUTES
+            .iter()
+            .find(|route| **route == path)
+            .copied()
+            .unwrap_or("other")
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = Vec::default();
+        TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        Ok(out)
+    }
+}
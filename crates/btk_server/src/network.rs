@@ -7,13 +7,22 @@ use futures_util::SinkExt;
 use futures_util::StreamExt;
 use futures_util::stream::SplitSink;
 use futures_util::stream::SplitStream;
+use prometheus::IntGauge;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::sync::watch;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake;
+use tokio_tungstenite::tungstenite::handshake::server::ErrorResponse;
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
 
 use network_common::*;
 
@@ -25,18 +34,25 @@ pub struct Server {
         flume::Receiver<(String, Action)>,
     ),
     pub socket_sender: DashMap<String, mpsc::Sender<Response>>,
+    /// Challenge sent in each connection's `Response::Hello`, signed by `Action::AuthCloud`.
+    pub socket_nonce: DashMap<String, [u8; 32]>,
+    /// Open connections, reported in `/metrics`.
+    connections: IntGauge,
+    /// Set when the server is shutting down, connections are sent a close frame.
+    closing: watch::Sender<bool>,
 }
 
 impl Server {
-    pub async fn new() -> Result<Self> {
-        let addr = "0.0.0.0:5001";
-        let try_socket = TcpListener::bind(addr).await;
-        let listener = try_socket.expect("Failed to bind");
+    pub async fn new(addr: &str, connections: IntGauge) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
 
         Ok(Self {
             pending_actions: flume::unbounded(),
             socket_sender: DashMap::new(),
+            socket_nonce: DashMap::new(),
             listener,
+            connections,
+            closing: watch::channel(false).0,
         })
     }
 
@@ -52,23 +68,58 @@ impl Server {
         }
     }
 
+    /// Send a close frame to every connection and wait for them to be cleaned up.
+    pub async fn close_all(&self) {
+        self.closing.send_replace(true);
+        while !self.socket_sender.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
     /// This will be invoked from a non-main thread
-    pub async fn accept_connection(&self, stream: TcpStream) {
+    ///
+    /// `authorize` receives the `cloud_id` from the handshake query. Rejected handshakes receive
+    /// a 403.
+    pub async fn accept_connection(
+        &self,
+        stream: TcpStream,
+        authorize: impl Fn(Option<[u8; 32]>) -> bool + Unpin,
+    ) {
         let addr = stream
             .peer_addr()
             .expect("connected streams should have a peer address");
-        println!("Peer address: {}", addr);
-
-        let ws_stream = tokio_tungstenite::accept_async(stream).await;
-        if ws_stream.is_err() {
-            println!("WARNING: error accepting connection, aborting");
-            return;
-        }
-        let ws_stream = ws_stream.unwrap();
+        debug!(%addr, "accepting websocket connection");
 
-        println!("New WebSocket connection: {}", addr);
+        let check_handshake = |req: &Request, res: handshake::server::Response| {
+            let cloud_id = req.uri().query().and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "cloud_id")
+                    .and_then(|(_, value)| {
+                        let mut cloud_id = [0u8; 32];
+                        hex::decode_to_slice(value.as_ref(), &mut cloud_id).ok()?;
+                        Some(cloud_id)
+                    })
+            });
+            if authorize(cloud_id) {
+                Ok(res)
+            } else {
+                let mut res = ErrorResponse::new(Some("cloud is not served here".to_string()));
+                *res.status_mut() = StatusCode::FORBIDDEN;
+                Err(res)
+            }
+        };
+        let ws_stream = tokio_tungstenite::accept_hdr_async(stream, check_handshake).await;
+        let ws_stream = match ws_stream {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                warn!(%addr, error = ?e, "error accepting websocket connection");
+                return;
+            }
+        };
 
         let socket_id = nanoid::nanoid!();
+        info!(%addr, socket_id = %socket_id, "websocket connected");
+        self.connections.inc();
         let (mut write, mut read) = ws_stream.split();
 
         let (sendv, mut recv) = mpsc::channel::<Response>(64);
@@ -79,7 +130,7 @@ impl Server {
             .client_loop(&socket_id, &mut write, &mut read, &mut recv)
             .await
         {
-            println!("websocket client loop errored: {:?}", e);
+            warn!(socket_id = %socket_id, error = ?e, "websocket client loop errored");
             // we'll cleanup now with the assumption that the connection will be forcibly closed
             self.cleanup_connection(&socket_id, &mut recv).await;
 
@@ -94,9 +145,11 @@ impl Server {
 
             // close the connection
             if let Err(e) = write.close().await {
-                println!("error closing websocket connection: {:?}", e);
+                debug!(socket_id = %socket_id, error = ?e, "error closing websocket connection");
             }
         }
+        self.connections.dec();
+        info!(socket_id = %socket_id, "websocket disconnected");
     }
 
     /// Returning Ok indicates the connection has been closed and cleaned up.
@@ -109,8 +162,26 @@ impl Server {
         recv: &mut mpsc::Receiver<Response>,
     ) -> Result<()> {
         let mut interval = tokio::time::interval(Duration::from_millis(5000));
+        let mut closing = self.closing.subscribe();
+        // connections accepted after shutdown started are closed immediately
+        closing.mark_changed();
         loop {
             tokio::select! {
+                // the server is shutting down
+                Ok(()) = closing.changed() => {
+                    if !*closing.borrow_and_update() {
+                        continue;
+                    }
+                    let close_frame = Message::Close(Some(CloseFrame {
+                        code: tungstenite::protocol::frame::coding::CloseCode::Away,
+                        reason: "server shutting down".to_string().into(),
+                    }));
+                    tokio::time::timeout(Duration::from_millis(500), write.send(close_frame))
+                        .await
+                        .ok();
+                    self.cleanup_connection(socket_id, recv).await;
+                    break;
+                }
                 // we have a response from the game server to give to the client
                 res = recv.recv() => {
                     match res {
@@ -121,7 +192,7 @@ impl Server {
                         None => {
                             // this should be unreachable, but we'll include logic for it
                             // just in case
-                            println!("mpsc channel closed");
+                            warn!(socket_id, "mpsc channel closed");
                             self.cleanup_connection(socket_id, recv).await;
                             break;
                         },
@@ -132,13 +203,20 @@ impl Server {
                     match msg {
                         Some(msg) => {
                             if let Err(e) = msg {
-                                println!("websocket client error: {}", e);
+                                debug!(socket_id, error = %e, "websocket client error");
                                 self.cleanup_connection(socket_id, recv).await;
                                 break;
                             }
                             let msg = msg.unwrap();
                             if msg.is_binary() {
                                 let action = Bytes::from(&msg.clone().into_data().to_vec()).parse::<Action>()?;
+                                if let Action::Hello(hello) = &action {
+                                    if self.hello(socket_id, hello, write).await? {
+                                        continue;
+                                    }
+                                    self.cleanup_connection(socket_id, recv).await;
+                                    break;
+                                }
                                 // println!("{:?}", action);
                                 self.pending_actions.0.send((socket_id.to_string(), action)).unwrap();
                             } else if msg.is_close() {
@@ -164,8 +242,48 @@ impl Server {
         Ok(())
     }
 
+    /// Answer a client's `Hello`. Returns false if the client is incompatible, after sending
+    /// `Response::Incompatible` and a close frame.
+    async fn hello(
+        &self,
+        socket_id: &str,
+        hello: &Hello,
+        write: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
+    ) -> Result<bool> {
+        if let Err(e) = check_client_version(hello.protocol_version) {
+            info!(socket_id, error = %e, "closing incompatible websocket client");
+            let res = Response::Incompatible {
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+            };
+            write
+                .send(Message::binary(Bytes::encode(&res)?.to_vec()))
+                .await?;
+            let close_frame = Message::Close(Some(CloseFrame {
+                code: tungstenite::protocol::frame::coding::CloseCode::Policy,
+                reason: "server requires a newer BTK".to_string().into(),
+            }));
+            tokio::time::timeout(Duration::from_millis(500), write.send(close_frame))
+                .await
+                .ok();
+            return Ok(false);
+        }
+        debug!(
+            socket_id,
+            protocol_version = hello.protocol_version,
+            "websocket hello"
+        );
+        let nonce: [u8; 32] = rand::random();
+        self.socket_nonce.insert(socket_id.to_string(), nonce);
+        let res = Response::Hello(Hello::reply(nonce));
+        write
+            .send(Message::binary(Bytes::encode(&res)?.to_vec()))
+            .await?;
+        Ok(true)
+    }
+
     async fn cleanup_connection(&self, socket_id: &str, recv: &mut mpsc::Receiver<Response>) {
         self.socket_sender.remove(socket_id);
+        self.socket_nonce.remove(socket_id);
         recv.close();
     }
 }
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+
+use anondb::Bytes;
+use anondb::JournalTransaction;
+use anyhow::Result;
+use tracing::warn;
+
+use network_common::*;
+
+use super::server::BTKServer;
+use super::server::Req;
+
+/// cloud id keyed to the number of leading mutations that are public
+pub const PUBLIC_COUNT_TABLE: &str = "public_mutation_counts";
+
+impl BTKServer {
+    /// Number of mutations, starting at index 0, that may be read without a key.
+    pub fn public_mutation_count(&self, cloud_id: &[u8; 32]) -> Result<u64> {
+        Ok(self
+            .db
+            .get::<[u8; 32], u64>(PUBLIC_COUNT_TABLE, cloud_id)?
+            .unwrap_or_default())
+    }
+
+    /// Accept mutation keys for existing mutations. Each key is checked by decrypting the
+    /// mutation and parsing the result as a transaction.
+    pub async fn publish(&self, req: Req) -> Result<()> {
+        let publish = match req.parse_body::<PublishRequest>() {
+            Ok(publish) => publish,
+            Err(_) => return req.respond_empty(400),
+        };
+        // the allowlist and tombstone are checked against the query in `handle_req`
+        if req.query_id("cloud_id") != Some(publish.cloud_id) {
+            return req.respond_empty(400);
+        }
+        let record = match self.public_key_record(&publish.cloud_id)? {
+            Some(record) => record,
+            None => return req.respond_empty(404),
+        };
+        if let Err(e) = publish.verify(&record) {
+            warn!(cloud_id = %hex::encode(publish.cloud_id), error = ?e, "error verifying publish request");
+            return req.respond_empty(401);
+        }
+
+        let table_name = hex::encode(publish.cloud_id);
+        let mut mutations = Vec::default();
+        for (index, mutation_key) in &publish.mutation_keys {
+            let mut mutation = match self.db.get::<u64, Mutation>(&table_name, index)? {
+                Some(mutation) => mutation,
+                None => return req.respond_empty(424),
+            };
+            mutation.mutation_key = Some(*mutation_key);
+            let tx_bytes = mutation
+                .decrypt_public()
+                .expect("mutation key was just set");
+            if Bytes::from(tx_bytes).parse::<JournalTransaction>().is_err() {
+                return req.respond_empty(400);
+            }
+            mutations.push(mutation);
+        }
+
+        // advance past any mutations that are now contiguously public
+        let published = mutations.iter().map(|m| m.index).collect::<HashSet<_>>();
+        let mut public_count = self.public_mutation_count(&publish.cloud_id)?;
+        loop {
+            let is_public = published.contains(&public_count)
+                || self
+                    .db
+                    .get::<u64, Mutation>(&table_name, &public_count)?
+                    .map(|m| m.mutation_key.is_some())
+                    .unwrap_or(false);
+            if !is_public {
+                break;
+            }
+            public_count += 1;
+        }
+
+        let mut tx = self.db.begin_write()?;
+        let mut table = tx.open_table(&table_name)?;
+        for mutation in &mutations {
+            table.insert(&mutation.index, mutation)?;
+        }
+        drop(table);
+        let mut count_table = tx.open_table(PUBLIC_COUNT_TABLE)?;
+        count_table.insert(&publish.cloud_id, &public_count)?;
+        drop(count_table);
+        tx.commit()?;
+
+        req.respond(200, Some(public_count))
+    }
+
+    pub async fn public_state(&self, req: Req) -> Result<()> {
+        let cloud_id = match req.query_id("cloud_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        let public_count = self.public_mutation_count(&cloud_id)?;
+        req.respond(200, Some(public_count))
+    }
+
+    /// Retrieve a public mutation with its mutation key. Clients verify the signature and
+    /// decrypt it themselves.
+    pub async fn public_mutation(&self, req: Req) -> Result<()> {
+        let cloud_id = match req.query_id("cloud_id") {
+            Some(id) => id,
+            None => return req.respond_empty(400),
+        };
+        let index = match req.query.get("index").map(|v| v.parse::<u64>()) {
+            Some(Ok(index)) => index,
+            _ => return req.respond_empty(400),
+        };
+        if index >= self.public_mutation_count(&cloud_id)? {
+            return req.respond_empty(424);
+        }
+        match self
+            .db
+            .get::<u64, Mutation>(&hex::encode(cloud_id), &index)?
+        {
+            Some(mutation) if mutation.mutation_key.is_some() => req.respond(200, Some(mutation)),
+            Some(_) => req.respond_empty(403),
+            None => req.respond_empty(424),
+        }
+    }
+}
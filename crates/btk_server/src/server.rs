@@ -1,20 +1,41 @@
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anondb::Bytes;
 use anondb::Journal;
 use anyhow::Result;
+use dashmap::DashMap;
 
 use network_common::*;
 use serde::Serialize;
+use serde::de::DeserializeOwned;
 use tiny_http::Header;
 use tiny_http::Method;
 use tiny_http::Request;
+use tokio::sync::Notify;
+use tracing::info;
+use tracing::warn;
 use url::Url;
 
+use super::allowlist;
+use super::config;
+use super::config::Config;
+use super::metrics::Metrics;
+use super::metrics::reject;
 use super::network;
+use super::shutdown::Drain;
 
-const PUBLIC_KEY_TABLE: &str = "known_public_keys";
+/// Default location of the server database, override with `BTK_DB_PATH`.
+pub const DB_PATH: &str = "/data.redb";
+
+/// cloud id keyed to raw ML-DSA-87 public key bytes, written before algorithms were recorded
+pub const LEGACY_PUBLIC_KEY_TABLE: &str = "known_public_keys";
+/// cloud id keyed to `PublicKeyRecord`
+pub const PUBLIC_KEY_TABLE: &str = "public_key_records";
+/// hash of each creation token that has been used
+const USED_CREATION_TOKEN_TABLE: &str = "used_creation_tokens";
 
 pub struct Req {
     pub url: url::Url,
@@ -22,6 +43,12 @@ pub struct Req {
     pub query: HashMap<String, String>,
     pub body: Vec<u8>,
     pub method: tiny_http::Method,
+    /// Response body encoding, from the `Accept` header.
+    pub accept: Encoding,
+    /// Request body encoding, from the `Content-Type` header.
+    pub content: Encoding,
+    /// From the `X-BTK-Protocol` header, 0 for clients that don't send it.
+    pub protocol_version: u32,
     request: tiny_http::Request,
 }
 
@@ -37,8 +64,22 @@ impl TryFrom<tiny_http::Request> for Req {
         let mut body = Vec::default();
         value.as_reader().read_to_end(&mut body).unwrap();
 
+        let header = |name: &str| {
+            value
+                .headers()
+                .iter()
+                .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+                .map(|header| header.value.as_str().to_string())
+        };
+        let accept = Encoding::from_accept(header("Accept").as_deref());
+        let content = Encoding::from_content_type(header("Content-Type").as_deref());
+        let protocol_version = header_version(header(PROTOCOL_HEADER).as_deref());
+
         Ok(Self {
             method: value.method().clone(),
+            accept,
+            content,
+            protocol_version,
             path: url.path().to_string(),
             url,
             query,
@@ -53,6 +94,26 @@ impl Req {
         (&self.method, &self.path)
     }
 
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.request
+            .headers()
+            .iter()
+            .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    /// Parse a 32 byte hex encoded identifier from the query.
+    pub fn query_id(&self, name: &str) -> Option<[u8; 32]> {
+        let mut out = [0u8; 32];
+        hex::decode_to_slice(self.query.get(name)?, &mut out).ok()?;
+        Some(out)
+    }
+
+    /// Parse the body using the negotiated encoding.
+    pub fn parse_body<T: DeserializeOwned>(&self) -> Result<T> {
+        self.content.decode(&self.body)
+    }
+
     pub fn respond_empty(self, status: u32) -> Result<()> {
         self.respond::<()>(status, None)
     }
@@ -61,38 +122,244 @@ impl Req {
     where
         T: Serialize,
     {
-        let data = if let Some(data) = data_maybe {
-            let bytes = Bytes::encode(&data)?;
-            bytes.to_vec()
-        } else {
-            vec![]
+        let Some(data) = data_maybe else {
+            let response = with_common_headers(tiny_http::Response::empty(status));
+            self.request.respond(response)?;
+            return Ok(());
         };
-        let response = tiny_http::Response::empty(status)
-            .with_data(data.as_slice(), Some(data.len()))
-            .with_header(Header::from_str("Access-Control-Allow-Origin:*").unwrap());
+        let data = self.accept.encode(&data)?;
+        let mut response =
+            with_common_headers(tiny_http::Response::from_data(data).with_status_code(status));
+        if let Some(content_type) = self.accept.content_type() {
+            response = response
+                .with_header(Header::from_str(&format!("Content-Type:{content_type}")).unwrap());
+        }
         self.request.respond(response)?;
         Ok(())
     }
+
+    /// Respond with a body that isn't bincode encoded.
+    pub fn respond_raw(self, status: u32, data: Vec<u8>, content_type: &str) -> Result<()> {
+        let response = with_common_headers(tiny_http::Response::from_data(data))
+            .with_status_code(status)
+            .with_header(Header::from_str(&format!("Content-Type:{content_type}")).unwrap());
+        self.request.respond(response)?;
+        Ok(())
+    }
+
+    /// Answer a CORS preflight. Browsers send one before requests with the protocol header or a
+    /// JSON body.
+    pub fn respond_preflight(self) -> Result<()> {
+        let response = with_common_headers(tiny_http::Response::empty(204))
+            .with_header(Header::from_str("Access-Control-Allow-Methods:*").unwrap())
+            .with_header(Header::from_str("Access-Control-Allow-Headers:*").unwrap())
+            .with_header(Header::from_str("Access-Control-Max-Age:86400").unwrap());
+        self.request.respond(response)?;
+        Ok(())
+    }
+}
+
+/// CORS and the protocol version, sent with every response.
+fn with_common_headers<R: std::io::Read>(
+    mut response: tiny_http::Response<R>,
+) -> tiny_http::Response<R> {
+    for header in [
+        "Access-Control-Allow-Origin:*".to_string(),
+        format!("Access-Control-Expose-Headers:{PROTOCOL_HEADER}"),
+        format!("{PROTOCOL_HEADER}:{PROTOCOL_VERSION}"),
+    ] {
+        response.add_header(Header::from_str(&header).unwrap());
+    }
+    response
+}
+
+/// Load the public key and algorithms a cloud was created with, falling back to the legacy table.
+pub fn public_key_record(db: &Journal, cloud_id: &[u8; 32]) -> Result<Option<PublicKeyRecord>> {
+    if let Some(record) = db.get::<[u8; 32], PublicKeyRecord>(PUBLIC_KEY_TABLE, cloud_id)? {
+        return Ok(Some(record));
+    }
+    Ok(db
+        .get::<[u8; 32], Bytes>(LEGACY_PUBLIC_KEY_TABLE, cloud_id)?
+        .map(|public_key| PublicKeyRecord::legacy(public_key.to_vec())))
 }
 
 pub struct BTKServer {
     pub db: Journal,
     pub network_server: network::Server,
+    pub config: Config,
+    pub mailbox_lock: tokio::sync::Mutex<()>,
+    /// Serializes cloud creation so an invite token can't be used twice.
+    pub creation_lock: tokio::sync::Mutex<()>,
+    pub metrics: Metrics,
+    pub drain: Drain,
+    /// Cloud id keyed to a notify that wakes its `/state` long polls after it's mutated or
+    /// deleted. Entries only exist while a poll is waiting.
+    pub mutation_notify: DashMap<[u8; 32], Arc<Notify>>,
+}
+
+/// Registration of a `/state` long poll. The cloud's notify is removed when the last poll for it
+/// finishes.
+struct MutationWaiter<'a> {
+    notify: Arc<Notify>,
+    cloud_id: [u8; 32],
+    mutation_notify: &'a DashMap<[u8; 32], Arc<Notify>>,
+}
+
+impl Drop for MutationWaiter<'_> {
+    fn drop(&mut self) {
+        // held by the map and this waiter only
+        self.mutation_notify
+            .remove_if(&self.cloud_id, |_, notify| Arc::strong_count(notify) <= 2);
+    }
 }
 
 impl BTKServer {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(config: Config) -> Result<Self> {
+        let metrics = Metrics::new()?;
         Ok(Self {
-            db: redb::Database::create("/data.redb")?.into(),
+            db: redb::Database::create(config::db_path())?.into(),
             // db: Journal::in_memory(None)?,
-            network_server: network::Server::new().await?,
+            network_server: network::Server::new(
+                &config.ws_addr,
+                metrics.websocket_connections.clone(),
+            )
+            .await?,
+            config,
+            mailbox_lock: tokio::sync::Mutex::new(()),
+            creation_lock: tokio::sync::Mutex::new(()),
+            metrics,
+            drain: Drain::default(),
+            mutation_notify: DashMap::new(),
         })
     }
 
+    /// Load the public key and algorithms a cloud was created with.
+    pub fn public_key_record(&self, cloud_id: &[u8; 32]) -> Result<Option<PublicKeyRecord>> {
+        public_key_record(&self.db, cloud_id)
+    }
+
+    fn mutation_waiter(&self, cloud_id: &[u8; 32]) -> MutationWaiter<'_> {
+        let notify = self
+            .mutation_notify
+            .entry(*cloud_id)
+            .or_insert_with(|| Arc::new(Notify::new()))
+            .clone();
+        MutationWaiter {
+            notify,
+            cloud_id: *cloud_id,
+            mutation_notify: &self.mutation_notify,
+        }
+    }
+
+    /// Wake the `/state` long polls waiting on `cloud_id`.
+    pub fn notify_mutation(&self, cloud_id: &[u8; 32]) {
+        if let Some(notify) = self.mutation_notify.get(cloud_id) {
+            notify.notify_waiters();
+        }
+    }
+
+    /// Check a genesis mutation against the creation policy. Returns the hash of the invite
+    /// token to mark as used, if any.
+    fn check_creation_proof(&self, mutation: &Mutation) -> Result<Option<[u8; 32]>> {
+        let proof = mutation.creation_proof.as_ref();
+        self.config
+            .creation_policy
+            .verify(&mutation.public_key_hash, proof)?;
+        match (self.config.creation_policy, proof) {
+            (CreationPolicy::InviteToken, Some(CreationProof::InviteToken(token))) => {
+                if !self.config.creation_tokens.contains(token) {
+                    anyhow::bail!("unknown creation token");
+                }
+                let token_hash = CreationProof::token_hash(token);
+                if self
+                    .db
+                    .get::<[u8; 32], ()>(USED_CREATION_TOKEN_TABLE, &token_hash)?
+                    .is_some()
+                {
+                    anyhow::bail!("creation token already used");
+                }
+                Ok(Some(token_hash))
+            }
+            _ => Ok(None),
+        }
+    }
+
     /// Handle an http action
     pub async fn handle_req(&self, req: Request) -> Result<()> {
         let req = Req::try_from(req)?;
+        let _drain_guard = match self.drain.enter() {
+            Some(guard) => guard,
+            None => return req.respond_empty(503),
+        };
+        if req.method == Method::Options {
+            return req.respond_preflight();
+        }
+        // probes and scrapers don't speak the protocol
+        let unversioned = matches!(
+            req.path.as_str(),
+            "/healthz" | "/readyz" | "/metrics" | "/api"
+        );
+        if !unversioned && let Err(e) = check_client_version(req.protocol_version) {
+            warn!(
+                protocol_version = req.protocol_version,
+                "rejecting incompatible client"
+            );
+            return req.respond_raw(426, e.to_string().into_bytes(), "text/plain");
+        }
+        // observed when dropped
+        let _timer = self
+            .metrics
+            .request_duration
+            .with_label_values(&[req.method.as_str(), Metrics::route_label(&req.path)])
+            .start_timer();
+        if matches!(
+            req.path.as_str(),
+            "/state" | "/mutation" | "/publish" | "/delete" | "/public/state" | "/public/mutation"
+        ) {
+            match req.query_id("cloud_id") {
+                Some(cloud_id) if !self.cloud_allowed(&cloud_id)? => {
+                    return req.respond_empty(403);
+                }
+                Some(cloud_id) if self.is_deleted(&cloud_id)? => return req.respond_empty(410),
+                Some(_) => {}
+                None if self.config.private => return req.respond_empty(400),
+                None => {}
+            }
+        }
+        // private servers only keep mailboxes for allowlisted recipients
+        if self.config.private && matches!(req.path.as_str(), "/mailbox" | "/mailbox/key") {
+            match req.query_id("recipient_id") {
+                Some(recipient_id) if !self.cloud_allowed(&recipient_id)? => {
+                    return req.respond_empty(403);
+                }
+                Some(_) => {}
+                None => return req.respond_empty(400),
+            }
+        }
         match req.path_tuple() {
+            (Method::Get, "/healthz") => req.respond_empty(200),
+            (Method::Get, "/readyz") => {
+                if self.db.begin_read().is_ok() {
+                    req.respond_empty(200)
+                } else {
+                    req.respond_empty(503)
+                }
+            }
+            (Method::Get, "/api") => {
+                let body = Encoding::Json.encode(&network_common::api::description())?;
+                req.respond_raw(200, body, JSON_CONTENT_TYPE)
+            }
+            (Method::Get, "/metrics") => {
+                // scraped with the admin token, disabled without one
+                if self.config.admin_token.is_none() {
+                    return req.respond_empty(404);
+                }
+                if !self.admin_authorized(&req) {
+                    return req.respond_empty(401);
+                }
+                let body = self.metrics.encode()?;
+                req.respond_raw(200, body, "text/plain; version=0.0.4")
+            }
             (Method::Get, "/state") => {
                 let cloud_id = if let Some(cloud_id_str) = req.query.get("cloud_id") {
                     match hex::decode(cloud_id_str.to_string()) {
@@ -104,12 +371,40 @@ impl BTKServer {
                 } else {
                     return req.respond_empty(400);
                 };
-                if cloud_id.len() != 32 {
+                let Ok(cloud_id) = <[u8; 32]>::try_from(cloud_id) else {
                     return req.respond_empty(400);
-                }
+                };
                 let table_name = hex::encode(cloud_id);
-                let mutation_count = self.db.count::<Bytes, Bytes>(&table_name)?;
-                req.respond(200, Some(mutation_count))
+                // long poll if `after` is given
+                let after = req.query.get("after").and_then(|v| v.parse::<u64>().ok());
+                let wait = req
+                    .query
+                    .get("wait")
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_default()
+                    .min(MAX_STATE_WAIT_SECS);
+                let deadline = tokio::time::Instant::now() + Duration::from_secs(wait);
+                let waiter = self.mutation_waiter(&cloud_id);
+                loop {
+                    // registered before the count is read so a commit in between isn't missed
+                    let mutated = waiter.notify.notified();
+                    let mutation_count = self.db.count::<Bytes, Bytes>(&table_name)?;
+                    if after.is_none_or(|after| mutation_count > after) {
+                        return req.respond(200, Some(mutation_count));
+                    }
+                    tokio::select! {
+                        _ = mutated => {}
+                        _ = tokio::time::sleep_until(deadline) => {
+                            return req.respond(200, Some(mutation_count));
+                        }
+                        _ = self.drain.closed() => {
+                            return req.respond(200, Some(mutation_count));
+                        }
+                    }
+                    if self.is_deleted(&cloud_id)? {
+                        return req.respond_empty(410);
+                    }
+                }
             }
             (Method::Get, "/mutation") => {
                 // retrieve a mutation for a cloud by index
@@ -136,45 +431,126 @@ impl BTKServer {
                 }
             }
             (Method::Post, "/mutate") => {
-                let mutation = Bytes::from(&req.body).parse::<Mutation>()?;
+                let mutation = match req.parse_body::<Mutation>() {
+                    Ok(mutation) => mutation,
+                    Err(_) => {
+                        self.metrics.reject_mutation(reject::INVALID);
+                        return req.respond_empty(400);
+                    }
+                };
+                if mutation.index != 0 && !self.cloud_allowed(&mutation.public_key_hash)? {
+                    self.metrics.reject_mutation(reject::FORBIDDEN);
+                    return req.respond_empty(403);
+                }
                 let table_name = hex::encode(mutation.public_key_hash);
-                let public_key = if let Some(public_key) = &mutation.public_key {
-                    public_key.clone()
-                } else if let Some(public_key) = self
-                    .db
-                    .get::<[u8; 32], Bytes>(PUBLIC_KEY_TABLE, &mutation.public_key_hash)?
-                {
-                    public_key.to_vec()
+                // a deleted cloud has no public key record, answer before looking for one
+                if self.is_deleted(&mutation.public_key_hash)? {
+                    self.metrics.reject_mutation(reject::DELETED);
+                    return req.respond_empty(410);
+                }
+                // the algorithms are fixed by the mutation that creates the cloud
+                let record = if mutation.index == 0 {
+                    match PublicKeyRecord::from_genesis(&mutation) {
+                        Ok(record) => record,
+                        Err(_) => {
+                            self.metrics.reject_mutation(reject::INVALID);
+                            return req.respond_empty(400);
+                        }
+                    }
+                } else if let Some(record) = self.public_key_record(&mutation.public_key_hash)? {
+                    record
                 } else {
+                    self.metrics.reject_mutation(reject::INVALID);
                     return req.respond_empty(400);
                 };
-                if let Err(e) = mutation.verify(public_key.clone()) {
-                    println!("error verifying mutation: {:?}", e);
+                if let Err(e) = mutation.verify(&record) {
+                    warn!(cloud_id = %table_name, index = mutation.index, error = ?e, "error verifying mutation");
+                    self.metrics.reject_mutation(reject::SIGNATURE);
                     return req.respond_empty(401);
                 }
+                let _creation_guard = if mutation.index == 0 {
+                    Some(self.creation_lock.lock().await)
+                } else {
+                    None
+                };
+                // checked again while holding the creation lock so a deletion in progress can't be
+                // raced by a new genesis mutation
+                if self.is_deleted(&mutation.public_key_hash)? {
+                    self.metrics.reject_mutation(reject::DELETED);
+                    return req.respond_empty(410);
+                }
+                // private servers gate creation with the allowlist instead of the policy
+                let (used_token_hash, enroll) = if mutation.index != 0 {
+                    (None, false)
+                } else if self.config.private {
+                    match self.check_private_creation(&mutation) {
+                        Ok(enroll) => (None, enroll),
+                        Err(e) => {
+                            warn!(cloud_id = %table_name, error = ?e, "rejecting cloud creation");
+                            self.metrics.reject_mutation(reject::FORBIDDEN);
+                            return req.respond_empty(403);
+                        }
+                    }
+                } else {
+                    match self.check_creation_proof(&mutation) {
+                        Ok(used_token_hash) => (used_token_hash, false),
+                        Err(e) => {
+                            warn!(cloud_id = %table_name, error = ?e, "rejecting cloud creation");
+                            self.metrics.reject_mutation(reject::FORBIDDEN);
+                            return req.respond_empty(403);
+                        }
+                    }
+                };
 
                 let mut tx = self.db.begin_write()?;
                 let mut table = tx.open_table(&table_name)?;
                 let existing_mutation_count = table.len()?;
 
                 if mutation.index != existing_mutation_count {
+                    self.metrics.reject_mutation(reject::INDEX);
                     return req.respond_empty(410);
                 }
 
                 if mutation.index == 0 {
                     let mut pubkey_table = tx.open_table(&PUBLIC_KEY_TABLE)?;
-                    pubkey_table
-                        .insert::<[u8; 32], Bytes>(&mutation.public_key_hash, &public_key.into())?;
+                    pubkey_table.insert(&mutation.public_key_hash, &record)?;
+                    drop(pubkey_table);
+                    if let Some(token_hash) = used_token_hash {
+                        let mut token_table = tx.open_table(USED_CREATION_TOKEN_TABLE)?;
+                        token_table.insert(&token_hash, &())?;
+                    }
+                    if enroll {
+                        let mut allowlist_table = tx.open_table(allowlist::ALLOWLIST_TABLE)?;
+                        allowlist_table.insert(&mutation.public_key_hash, &())?;
+                    }
                 }
+                // the proof is only needed to accept the genesis mutation
+                let mut mutation = mutation;
+                mutation.creation_proof = None;
+                let stored_len = Bytes::encode(&mutation)?.len();
                 table.insert(&mutation.index, &mutation)?;
                 drop(table);
 
                 tx.commit()?;
+                self.notify_mutation(&mutation.public_key_hash);
 
+                self.metrics.mutations_accepted.inc();
+                self.metrics.bytes_stored.inc_by(stored_len as u64);
+                info!(cloud_id = %table_name, index = mutation.index, "accepted mutation");
                 req.respond_empty(204)
 
                 // TODO: broadcast the new mutation
             }
+            (Method::Get, "/policy") => req.respond(200, Some(self.config.creation_policy)),
+            (_, "/admin/allowlist") => self.admin_allowlist(req).await,
+            (Method::Get, "/mailbox") => self.list_mailbox(req).await,
+            (Method::Post, "/mailbox") => self.append_mailbox(req).await,
+            (Method::Get, "/mailbox/key") => self.mailbox_key(req).await,
+            (Method::Post, "/mailbox/key") => self.publish_mailbox_key(req).await,
+            (Method::Post, "/publish") => self.publish(req).await,
+            (Method::Post, "/delete") => self.delete_cloud(req).await,
+            (Method::Get, "/public/state") => self.public_state(req).await,
+            (Method::Get, "/public/mutation") => self.public_mutation(req).await,
             _ => req.respond_empty(410),
         }
     }
@@ -186,9 +562,38 @@ impl BTKServer {
                 // TODO: flood prevention
                 self.network_server.send(&socket_id, Response::Pong).await?;
             }
+            // answered by the network layer when the connection opens
+            Action::Hello(_) => {}
             Action::MutateCloud(_mutation) => {}
-            Action::AuthCloud(_cloud_id, _sig_bytes) => {
-                unimplemented!();
+            Action::AuthCloud(cloud_id, sig_bytes) => {
+                if !self.cloud_allowed(&cloud_id)? {
+                    warn!(
+                        socket_id = %socket_id,
+                        cloud_id = %hex::encode(cloud_id),
+                        "rejecting auth for unlisted cloud"
+                    );
+                    return Ok(());
+                }
+                let Some(nonce) = self
+                    .network_server
+                    .socket_nonce
+                    .get(&socket_id)
+                    .map(|nonce| *nonce)
+                else {
+                    warn!(socket_id = %socket_id, "rejecting auth before hello");
+                    return Ok(());
+                };
+                let Some(record) = self.public_key_record(&cloud_id)? else {
+                    return Ok(());
+                };
+                if let Err(e) = Action::verify_auth_cloud(&cloud_id, &nonce, &sig_bytes, &record) {
+                    warn!(socket_id = %socket_id, error = %e, "rejecting auth");
+                    return Ok(());
+                }
+                let mutation_count = self.db.count::<Bytes, Bytes>(&hex::encode(cloud_id))?;
+                self.network_server
+                    .send(&socket_id, Response::Authenticated(mutation_count))
+                    .await?;
             }
         }
         Ok(())
@@ -0,0 +1,65 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use tokio::sync::Notify;
+
+/// Tracks in-progress http requests so shutdown can wait for them, e.g. to let a `/mutate`
+/// commit finish before the database is closed.
+#[derive(Default)]
+pub struct Drain {
+    closing: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    closed: Notify,
+}
+
+/// Held for the duration of a request.
+pub struct DrainGuard<'a>(&'a Drain);
+
+impl Drop for DrainGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl Drain {
+    /// Returns `None` once the server is shutting down.
+    pub fn enter(&self) -> Option<DrainGuard<'_>> {
+        // count the request before checking so `close` can't miss it
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = DrainGuard(self);
+        if self.closing.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(guard)
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once shutdown starts, so long running requests can return early.
+    pub async fn closed(&self) {
+        let closed = self.closed.notified();
+        if self.is_closing() {
+            return;
+        }
+        closed.await;
+    }
+
+    /// Stop accepting requests and wait for in-progress requests to finish.
+    pub async fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        self.closed.notify_waiters();
+        loop {
+            let idle = self.idle.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! TLS termination. Connections are decrypted and forwarded to the plaintext http and websocket
+//! listeners bound on loopback, so neither server needs to know about TLS.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::Result;
+use rustls::ServerConfig;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::server::ClientHello;
+use rustls::server::ResolvesServerCert;
+use rustls::sign::CertifiedKey;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+use tracing::warn;
+
+/// Connections that don't finish the handshake in time are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and key read from disk. `reload` swaps them without restarting the
+/// listeners, existing connections keep the certificate they were established with.
+#[derive(Debug)]
+pub struct TlsCerts {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TlsCerts {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        Ok(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(read_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// Read the certificate and key again. The current certificate is kept if either is invalid.
+    pub fn reload(&self) -> Result<()> {
+        let certified_key = read_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor> {
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_cert_resolver(self.clone());
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ResolvesServerCert for TlsCerts {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// PEM encoded certificate chain, leaf first, and a PEM encoded private key.
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e:?}", cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates in {}", cert_path.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e:?}", key_path.display()))?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Accept TLS connections on `listener` and forward the decrypted streams to `upstream` until
+/// shutdown.
+pub async fn proxy(
+    listener: TcpListener,
+    upstream: SocketAddr,
+    acceptor: TlsAcceptor,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = ?e, "error accepting TLS connection");
+                    continue;
+                }
+            },
+            _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+        };
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let mut tls_stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => tls_stream,
+                    Ok(Err(e)) => {
+                        debug!(%addr, error = ?e, "TLS handshake failed");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(%addr, "TLS handshake timed out");
+                        return;
+                    }
+                };
+            let mut upstream_stream = match TcpStream::connect(upstream).await {
+                Ok(upstream_stream) => upstream_stream,
+                Err(e) => {
+                    warn!(%upstream, error = ?e, "failed to connect to upstream listener");
+                    return;
+                }
+            };
+            tokio::io::copy_bidirectional(&mut tls_stream, &mut upstream_stream)
+                .await
+                .ok();
+        });
+    }
+}
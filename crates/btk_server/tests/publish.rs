@@ -0,0 +1,171 @@
+//! Publishes part of a cloud and checks only the requested mutations had their keys revealed.
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use anondb::Bytes;
+use anondb::JournalTransaction;
+use network_common::*;
+
+const BIN: &str = env!("CARGO_BIN_EXE_btk_server");
+const MUTATIONS: u64 = 4;
+const PUBLISH_UPTO: u64 = 1;
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Minimal http/1.1 client, returns the status code and body.
+fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> std::io::Result<(u16, Vec<u8>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: {addr}\r\n{PROTOCOL_HEADER}: {PROTOCOL_VERSION}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut response = Vec::default();
+    stream.read_to_end(&mut response)?;
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(std::io::Error::other("response has no header terminator"))?;
+    let status_line = String::from_utf8_lossy(&response[..header_end])
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(std::io::Error::other(format!(
+            "bad status line: {status_line}"
+        )))?;
+    Ok((status, response.split_off(header_end + 4)))
+}
+
+/// A mutation holding an empty transaction, encrypted with a key derived from `private_key`.
+/// Returns the encoded mutation and its mutation key.
+fn encrypted_mutation(
+    signer: &CloudSigner,
+    private_key: &[u8; 32],
+    index: u64,
+) -> (Vec<u8>, [u8; 32]) {
+    let salt: [u8; 32] = rand::random();
+    let mutation_key = Mutation::derive_key(private_key, index, &salt).unwrap();
+    let mut data: Vec<u8> = Bytes::encode(&JournalTransaction {
+        operations: Vec::default(),
+        last_tx_hash: [0; 32],
+    })
+    .unwrap()
+    .into();
+    CipherAlgorithm::default().apply_keystream(&mutation_key, &mut data);
+    let mutation = Mutation {
+        index,
+        signature: signer.sign(&data),
+        data,
+        signature_algorithm: signer.algorithm(),
+        cipher_algorithm: CipherAlgorithm::default(),
+        public_key_hash: signer.id(),
+        public_key: (index == 0).then(|| signer.public_key().clone()),
+        salt,
+        mutation_key: None,
+        creation_proof: None,
+    };
+    (Bytes::encode(&mutation).unwrap().to_vec(), mutation_key)
+}
+
+#[test]
+fn publish_only_reveals_requested_mutations() {
+    let dir = std::env::temp_dir().join(format!("btk-publish-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let http_addr = free_addr();
+
+    let mut server = Command::new(BIN)
+        .env("BTK_DB_PATH", dir.join("data.redb"))
+        .env("BTK_HTTP_ADDR", &http_addr)
+        .env("BTK_WS_ADDR", free_addr())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while request(&http_addr, "GET", "/readyz", &[])
+        .ok()
+        .map(|(status, _)| status)
+        != Some(200)
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "server did not become ready"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let signer = CloudSigner::from_seed(&[7; 32], SignatureAlgorithm::MlDsa44);
+    let private_key: [u8; 32] = rand::random();
+    let cloud_id = hex::encode(signer.id());
+    let mut mutation_keys = Vec::default();
+    for index in 0..MUTATIONS {
+        let (body, mutation_key) = encrypted_mutation(&signer, &private_key, index);
+        let (status, _) = request(&http_addr, "POST", "/mutate", &body).unwrap();
+        assert_eq!(status, 204, "mutation {index} was rejected");
+        mutation_keys.push((index, mutation_key));
+    }
+
+    mutation_keys.truncate(PUBLISH_UPTO as usize + 1);
+    let publish = PublishRequest {
+        cloud_id: signer.id(),
+        signature: signer
+            .sign(&PublishRequest::signed_bytes(&signer.id(), &mutation_keys).unwrap()),
+        mutation_keys,
+    };
+    let (status, body) = request(
+        &http_addr,
+        "POST",
+        &format!("/publish?cloud_id={cloud_id}"),
+        &Bytes::encode(&publish).unwrap().to_vec(),
+    )
+    .unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(Bytes::from(body).parse::<u64>().unwrap(), PUBLISH_UPTO + 1);
+
+    for index in 0..MUTATIONS {
+        let (status, body) = request(
+            &http_addr,
+            "GET",
+            &format!("/mutation?cloud_id={cloud_id}&index={index}"),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(status, 200);
+        let mutation = Bytes::from(body).parse::<Mutation>().unwrap();
+        assert_eq!(
+            mutation.mutation_key.is_some(),
+            index <= PUBLISH_UPTO,
+            "mutation {index} has the wrong visibility"
+        );
+    }
+
+    let (status, body) = request(
+        &http_addr,
+        "GET",
+        &format!("/public/state?cloud_id={cloud_id}"),
+        &[],
+    )
+    .unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(Bytes::from(body).parse::<u64>().unwrap(), PUBLISH_UPTO + 1);
+
+    server.kill().ok();
+    server.wait().ok();
+    std::fs::remove_dir_all(&dir).ok();
+}
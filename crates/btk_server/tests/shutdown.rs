@@ -0,0 +1,191 @@
+//! Sends SIGTERM to a running server during a burst of uploads, then checks the database only
+//! contains complete, verifiable clouds.
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use anondb::Bytes;
+use network_common::*;
+
+const BIN: &str = env!("CARGO_BIN_EXE_btk_server");
+const UPLOADERS: u8 = 8;
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Minimal http/1.1 client, returns the status code.
+fn request(addr: &str, method: &str, path: &str, body: &[u8]) -> std::io::Result<u16> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: {addr}\r\n{PROTOCOL_HEADER}: {PROTOCOL_VERSION}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut response = Vec::default();
+    stream.read_to_end(&mut response)?;
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(std::io::Error::other(format!(
+            "bad status line: {status_line}"
+        )))
+}
+
+fn signed_mutation(signer: &CloudSigner, index: u64) -> Vec<u8> {
+    let data: [u8; 32] = rand::random();
+    let mutation = Mutation {
+        index,
+        data: data.to_vec(),
+        signature: signer.sign(&data),
+        signature_algorithm: signer.algorithm(),
+        cipher_algorithm: CipherAlgorithm::default(),
+        public_key_hash: signer.id(),
+        public_key: (index == 0).then(|| signer.public_key().clone()),
+        salt: rand::random(),
+        mutation_key: None,
+        creation_proof: None,
+    };
+    Bytes::encode(&mutation).unwrap().to_vec()
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> ExitStatus {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if start.elapsed() > timeout {
+            child.kill().ok();
+            panic!("server did not exit within {timeout:?}");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn admin(db_path: &Path, args: &[&str]) -> String {
+    let output = Command::new(BIN)
+        .arg("admin")
+        .args(args)
+        .env("BTK_DB_PATH", db_path)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(
+        output.status.success(),
+        "admin {args:?} failed: {stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+#[test]
+fn sigterm_during_uploads_leaves_no_partial_state() {
+    let dir = std::env::temp_dir().join(format!("btk-shutdown-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db_path = dir.join("data.redb");
+    let http_addr = free_addr();
+
+    let mut server = Command::new(BIN)
+        .env("BTK_DB_PATH", &db_path)
+        .env("BTK_HTTP_ADDR", &http_addr)
+        .env("BTK_WS_ADDR", free_addr())
+        .env("BTK_SHUTDOWN_TIMEOUT_SECS", "10")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while request(&http_addr, "GET", "/readyz", &[]).ok() != Some(200) {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "server did not become ready"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // each uploader creates a cloud and appends until the server stops accepting
+    let uploaders = (0..UPLOADERS)
+        .map(|i| {
+            let http_addr = http_addr.clone();
+            std::thread::spawn(move || {
+                let signer = CloudSigner::from_seed(&[i; 32], SignatureAlgorithm::MlDsa44);
+                let mut acknowledged = 0;
+                loop {
+                    let body = signed_mutation(&signer, acknowledged);
+                    match request(&http_addr, "POST", "/mutate", &body) {
+                        Ok(204) => acknowledged += 1,
+                        _ => break,
+                    }
+                }
+                (signer.id(), acknowledged)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    std::thread::sleep(Duration::from_millis(500));
+    let killed = Command::new("kill")
+        .arg("-TERM")
+        .arg(server.id().to_string())
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    let status = wait_with_timeout(&mut server, Duration::from_secs(30));
+    assert!(status.success(), "server exited with {status}");
+    let acknowledged = uploaders
+        .into_iter()
+        .map(|uploader| uploader.join().unwrap())
+        .collect::<Vec<_>>();
+    assert!(
+        acknowledged.iter().any(|(_, count)| *count > 0),
+        "no uploads finished before shutdown"
+    );
+
+    // every stored mutation is contiguous and signed
+    admin(&db_path, &["verify"]);
+
+    // `<cloud_id> <count> mutations, ...`
+    let stored = admin(&db_path, &["list"])
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let cloud_id = parts.next()?.to_string();
+            let count = parts.next()?.parse::<u64>().ok()?;
+            Some((cloud_id, count))
+        })
+        .collect::<HashMap<_, _>>();
+    for (cloud_id, acknowledged) in acknowledged {
+        let stored = stored
+            .get(&hex::encode(cloud_id))
+            .copied()
+            .unwrap_or_default();
+        // acknowledged mutations were committed, at most one more may have been committed
+        // without the response reaching the client
+        assert!(
+            stored == acknowledged || stored == acknowledged + 1,
+            "cloud {} has {stored} mutations, {acknowledged} were acknowledged",
+            hex::encode(cloud_id)
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).ok();
+}
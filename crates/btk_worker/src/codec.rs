@@ -0,0 +1,36 @@
+use network_common::Encoding;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use worker::*;
+
+/// Body encodings negotiated from the `Accept` and `Content-Type` request headers.
+#[derive(Clone, Copy)]
+pub(crate) struct Codec {
+    accept: Encoding,
+    content: Encoding,
+}
+
+impl Codec {
+    pub(crate) fn from_request(req: &Request) -> Result<Self> {
+        Ok(Self {
+            accept: Encoding::from_accept(req.headers().get("Accept")?.as_deref()),
+            content: Encoding::from_content_type(req.headers().get("Content-Type")?.as_deref()),
+        })
+    }
+
+    pub(crate) fn accepts_bincode(&self) -> bool {
+        self.accept == Encoding::Bincode
+    }
+
+    pub(crate) fn parse<T: DeserializeOwned>(&self, body: &[u8]) -> anyhow::Result<T> {
+        self.content.decode(body)
+    }
+
+    pub(crate) fn respond<T: Serialize>(&self, value: &T, headers: Headers) -> Result<Response> {
+        let body = self.accept.encode(value).map_err(|_| "encoding failed")?;
+        if let Some(content_type) = self.accept.content_type() {
+            headers.set("Content-Type", content_type)?;
+        }
+        Ok(Response::from_bytes(body)?.with_headers(headers))
+    }
+}
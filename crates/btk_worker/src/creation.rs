@@ -0,0 +1,123 @@
+use network_common::CreationPolicy;
+use network_common::CreationProof;
+use network_common::Mutation;
+use worker::*;
+
+use super::StorageCoordinator;
+use super::codec::Codec;
+
+/// R2 record of a used token, written before claims moved to `creation_token_object_name`.
+fn used_creation_token_key(token_hash: &[u8; 32]) -> String {
+    format!("creation-token-{}", hex::encode(token_hash))
+}
+
+/// Durable object that records which cloud used an invite token. Each cloud has its own
+/// object, so claims are made in one named after the token where they're serialized.
+fn creation_token_object_name(token_hash: &[u8; 32]) -> String {
+    format!("creation-token-{}", hex::encode(token_hash))
+}
+
+/// Hex cloud id that claimed the token, in the token object's storage.
+const CLAIMED_BY_KEY: &str = "claimed_by";
+
+/// Read from the `CREATION_POLICY` var: `open`, `pow:<difficulty>` or `invite`. Defaults to
+/// open.
+pub(crate) fn creation_policy(env: &Env) -> Result<CreationPolicy> {
+    match env.var("CREATION_POLICY") {
+        Ok(v) => v
+            .to_string()
+            .parse::<CreationPolicy>()
+            .map_err(|e| format!("invalid CREATION_POLICY: {e}").into()),
+        Err(_) => Ok(CreationPolicy::default()),
+    }
+}
+
+pub(crate) fn policy_response(env: &Env, codec: Codec, headers: Headers) -> Result<Response> {
+    let policy = creation_policy(env)?;
+    codec.respond(&policy, headers)
+}
+
+/// Hex encoded tokens in the `CREATION_TOKENS` secret, comma separated.
+fn creation_token_issued(env: &Env, token: &[u8; 32]) -> bool {
+    let tokens = match env.secret("CREATION_TOKENS") {
+        Ok(tokens) => tokens.to_string(),
+        Err(_) => return false,
+    };
+    tokens
+        .split(',')
+        .map(str::trim)
+        .any(|token_str| token_str == hex::encode(token))
+}
+
+impl StorageCoordinator {
+    /// Check a genesis mutation against the creation policy. Returns the hash of the invite
+    /// token to mark as used, if any.
+    pub(crate) async fn check_creation_proof(
+        &self,
+        mutation: &Mutation,
+    ) -> Result<anyhow::Result<Option<[u8; 32]>>> {
+        let policy = creation_policy(&self.env)?;
+        let proof = mutation.creation_proof.as_ref();
+        if let Err(e) = policy.verify(&mutation.public_key_hash, proof) {
+            return Ok(Err(e));
+        }
+        match (policy, proof) {
+            (CreationPolicy::InviteToken, Some(CreationProof::InviteToken(token))) => {
+                if !creation_token_issued(&self.env, token) {
+                    return Ok(Err(anyhow::anyhow!("unknown creation token")));
+                }
+                let token_hash = CreationProof::token_hash(token);
+                if self
+                    .get_object_bytes(used_creation_token_key(&token_hash))
+                    .await?
+                    .is_some()
+                {
+                    return Ok(Err(anyhow::anyhow!("creation token already used")));
+                }
+                Ok(Ok(Some(token_hash)))
+            }
+            _ => Ok(Ok(None)),
+        }
+    }
+
+    /// Claim the token for `cloud_id` in the token's durable object. Returns false if another
+    /// cloud claimed it first.
+    pub(crate) async fn claim_creation_token(
+        &self,
+        token_hash: &[u8; 32],
+        cloud_id: &[u8; 32],
+    ) -> Result<bool> {
+        let stub = self
+            .env
+            .durable_object("BTK_PRERELEASE")?
+            .id_from_name(&creation_token_object_name(token_hash))?
+            .get_stub()?;
+        let req = Request::new_with_init(
+            &format!(
+                "https://btk/creation-token/claim?cloud_id={}",
+                hex::encode(cloud_id)
+            ),
+            RequestInit::new().with_method(Method::Post),
+        )?;
+        let res = stub.fetch_with_request(req).await?;
+        Ok(res.status_code() == 204)
+    }
+
+    /// Handle a claim in the token's durable object. The same cloud may claim again, e.g. when
+    /// retrying a genesis mutation that failed to store.
+    pub(crate) async fn handle_creation_token_claim(&self, cloud_id: [u8; 32]) -> Result<Response> {
+        let storage = self.state.storage();
+        // other requests aren't delivered while storage is awaited, so claims can't interleave
+        let claimed_by = storage.get::<String>(CLAIMED_BY_KEY).await?;
+        match claimed_by {
+            Some(claimed_by) if claimed_by != hex::encode(cloud_id) => {
+                Ok(Response::empty()?.with_status(409))
+            }
+            Some(_) => Ok(Response::empty()?.with_status(204)),
+            None => {
+                storage.put(CLAIMED_BY_KEY, hex::encode(cloud_id)).await?;
+                Ok(Response::empty()?.with_status(204))
+            }
+        }
+    }
+}
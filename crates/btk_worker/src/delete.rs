@@ -0,0 +1,72 @@
+use network_common::DeleteRequest;
+use worker::*;
+
+use super::StorageCoordinator;
+use super::cloud_pubkey_key;
+use super::codec::Codec;
+use super::legacy_cloud_pubkey_key;
+use super::mutation_count_key;
+use super::mutation_key;
+use super::publish::public_count_key;
+use super::store::MutationStore;
+
+/// Tombstone holding the time a cloud was deleted. Deleted ids can't be created again.
+fn deleted_cloud_key(cloud_id: &[u8; 32]) -> String {
+    format!("deleted-{}", hex::encode(cloud_id))
+}
+
+impl StorageCoordinator {
+    pub(crate) async fn is_deleted(&self, cloud_id: &[u8; 32]) -> Result<bool> {
+        Ok(self
+            .get_object_bytes(deleted_cloud_key(cloud_id))
+            .await?
+            .is_some())
+    }
+
+    /// Remove every mutation and the public key record for a cloud, leaving a tombstone.
+    pub async fn delete_cloud(
+        &self,
+        cloud_id: [u8; 32],
+        host: Option<String>,
+        body: Vec<u8>,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let delete = match codec.parse::<DeleteRequest>(&body) {
+            Ok(delete) => delete,
+            Err(_) => return Ok(Response::empty()?.with_status(400).with_headers(headers)),
+        };
+        if delete.cloud_id != cloud_id {
+            return Ok(Response::empty()?.with_status(400).with_headers(headers));
+        }
+        if self.is_deleted(&cloud_id).await? {
+            return Ok(Response::empty()?.with_status(410).with_headers(headers));
+        }
+        let record = match self.get_public_key_record(&cloud_id).await? {
+            Some(record) => record,
+            None => return Ok(Response::empty()?.with_status(404).with_headers(headers)),
+        };
+        if let Err(e) = delete.verify(host.as_deref(), &record) {
+            println!("error verifying delete request: {:?}", e);
+            return Ok(Response::empty()?.with_status(401).with_headers(headers));
+        }
+
+        let _append = self.append_lock.lock().await;
+        let store = self.mutation_store(&cloud_id)?;
+        let mutation_count = store.count().await?;
+        let bucket = self.env.bucket("btk_storage")?;
+        // write the tombstone first so a partial deletion can't be recreated
+        self.put_u64(deleted_cloud_key(&cloud_id), Date::now().as_millis() / 1000)
+            .await?;
+        store.clear().await?;
+        for index in 0..mutation_count {
+            bucket.delete(mutation_key(&cloud_id, index)).await?;
+        }
+        bucket.delete(mutation_count_key(&cloud_id)).await?;
+        bucket.delete(cloud_pubkey_key(&cloud_id)).await?;
+        bucket.delete(legacy_cloud_pubkey_key(&cloud_id)).await?;
+        bucket.delete(public_count_key(&cloud_id)).await?;
+
+        Ok(Response::empty()?.with_status(204).with_headers(headers))
+    }
+}
@@ -3,71 +3,140 @@ use std::sync::RwLock;
 
 use anondb::Bytes;
 use anondb::Journal;
+use network_common::Action;
+use network_common::Encoding;
+use network_common::JSON_CONTENT_TYPE;
 use network_common::Mutation;
+use network_common::PROTOCOL_HEADER;
+use network_common::PROTOCOL_VERSION;
+use network_common::PublicKeyRecord;
+use network_common::check_client_version;
+use network_common::header_version;
 use worker::*;
 
+use codec::Codec;
+use socket::SocketAttachment;
+use store::DurableStore;
+use store::Lookup;
+use store::MutationStore;
+
+mod codec;
+mod creation;
+mod delete;
+mod mailbox;
+mod publish;
+mod socket;
+mod store;
+
+/// How often a long polling `/state` request checks the mutation count.
+const STATE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// CORS and the protocol version, sent with every response.
+fn common_headers() -> Result<Headers> {
+    let headers = Headers::new();
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("Access-Control-Expose-Headers", PROTOCOL_HEADER)?;
+    headers.set(PROTOCOL_HEADER, &PROTOCOL_VERSION.to_string())?;
+    Ok(headers)
+}
+
 fn mutation_key(cloud_id: &[u8; 32], index: u32) -> String {
     format!("mutation-{}-{}", index, hex::encode(cloud_id))
 }
 
+/// Count kept in R2 before it moved to durable object storage, see `store`.
 fn mutation_count_key(cloud_id: &[u8; 32]) -> String {
     format!("count-{}", hex::encode(cloud_id))
 }
 
-fn cloud_pubkey_key(cloud_id: &[u8; 32]) -> String {
+/// Raw ML-DSA-87 public key, written before algorithms were recorded.
+fn legacy_cloud_pubkey_key(cloud_id: &[u8; 32]) -> String {
     format!("pubkey-{}", hex::encode(cloud_id))
 }
 
+/// Encoded `PublicKeyRecord`
+fn cloud_pubkey_key(cloud_id: &[u8; 32]) -> String {
+    format!("pubkey-record-{}", hex::encode(cloud_id))
+}
+
 #[durable_object]
 pub struct StorageCoordinator {
     /// cloud id keyed to number of mutations
     db: Journal,
-    mutation_count_lock: RwLock<Option<u32>>,
+    /// Serializes appends and deletions so the count read before writing is still current.
+    append_lock: futures_util::lock::Mutex<()>,
+    /// Sockets that sent a valid `Action::AuthCloud`, notified of new mutations.
     authed_listeners: RwLock<Vec<WebSocket>>,
+    /// Serializes appends so mailbox indices are never reused.
+    mailbox_lock: futures_util::lock::Mutex<()>,
     env: Env,
     state: State,
 }
 
 impl StorageCoordinator {
-    pub async fn get_public_key(&self, cloud_id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
-        let bucket = self.env.bucket("btk_storage")?;
-        let obj = bucket.get(cloud_pubkey_key(&cloud_id)).execute().await?;
-        if obj.is_none() {
-            return Ok(None);
-        }
-        let obj = obj.unwrap();
-        if obj.body().is_none() {
-            return Ok(None);
+    /// Load the public key and algorithms a cloud was created with.
+    pub async fn get_public_key_record(
+        &self,
+        cloud_id: &[u8; 32],
+    ) -> Result<Option<PublicKeyRecord>> {
+        if let Some(bytes) = self.get_object_bytes(cloud_pubkey_key(cloud_id)).await? {
+            return Ok(Some(
+                Bytes::from(bytes)
+                    .parse::<PublicKeyRecord>()
+                    .map_err(|_| "failed to parse public key record")?,
+            ));
         }
-        let body = obj.body().unwrap();
-        Ok(Some(body.bytes().await?))
+        Ok(self
+            .get_object_bytes(legacy_cloud_pubkey_key(cloud_id))
+            .await?
+            .map(PublicKeyRecord::legacy))
+    }
+
+    pub(crate) fn mutation_store(&self, cloud_id: &[u8; 32]) -> Result<DurableStore> {
+        Ok(DurableStore {
+            storage: self.state.storage(),
+            bucket: self.env.bucket("btk_storage")?,
+            cloud_id: *cloud_id,
+        })
     }
 
-    pub async fn load_mutation_count(&self, cloud_id: &[u8; 32]) -> Result<u32> {
+    pub(crate) async fn get_object_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
         let bucket = self.env.bucket("btk_storage")?;
-        let count = bucket.get(mutation_count_key(cloud_id)).execute().await?;
-        if count.is_none() {
-            return Ok(0);
+        let obj = match bucket.get(key).execute().await? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+        match obj.body() {
+            Some(body) => Ok(Some(body.bytes().await?)),
+            None => Ok(None),
         }
-        let count = count.unwrap();
-        let body = count.body();
-        if body.is_none() {
-            return Ok(0);
+    }
+
+    pub(crate) async fn get_u64(&self, key: String) -> Result<u64> {
+        match self.get_object_bytes(key).await? {
+            Some(bytes) => Ok(Bytes::from(bytes)
+                .parse::<u64>()
+                .map_err(|_| "failed to parse u64 body")?),
+            None => Ok(0),
         }
-        let body = body.unwrap();
-        let count = Bytes::parse::<u32>(&body.bytes().await?.into())
-            .map_err(|_| "failed to parse count body")?;
-        Ok(count)
+    }
+
+    pub(crate) async fn put_u64(&self, key: String, value: u64) -> Result<()> {
+        let bucket = self.env.bucket("btk_storage")?;
+        bucket
+            .put(
+                key,
+                Bytes::encode(&value)
+                    .map_err(|_| "failed to encode u64")?
+                    .to_vec(),
+            )
+            .execute()
+            .await?;
+        Ok(())
     }
 
     pub async fn mutation_count(&self, cloud_id: &[u8; 32]) -> Result<u32> {
-        if let Some(count) = *self.mutation_count_lock.read().unwrap() {
-            return Ok(count);
-        }
-        let mut mutation_count_maybe = self.mutation_count_lock.write().unwrap();
-        let mutation_count = self.load_mutation_count(cloud_id).await?;
-        *mutation_count_maybe = Some(mutation_count);
-        Ok(mutation_count)
+        self.mutation_store(cloud_id)?.count().await
     }
 }
 
@@ -75,9 +144,22 @@ impl DurableObject for StorageCoordinator {
     fn new(state: State, env: Env) -> Self {
         Self {
             db: Journal::in_memory(None).expect("failed to init anondb"),
-            mutation_count_lock: RwLock::new(None),
+            append_lock: futures_util::lock::Mutex::new(()),
             env,
-            authed_listeners: RwLock::new(state.get_websockets()),
+            // sockets survive hibernation, their attachment says if they authenticated
+            authed_listeners: RwLock::new(
+                state
+                    .get_websockets()
+                    .into_iter()
+                    .filter(|ws| {
+                        ws.deserialize_attachment::<SocketAttachment>()
+                            .ok()
+                            .flatten()
+                            .is_some_and(|attachment| attachment.authenticated)
+                    })
+                    .collect(),
+            ),
+            mailbox_lock: futures_util::lock::Mutex::new(()),
             state,
         }
     }
@@ -100,41 +182,66 @@ impl DurableObject for StorageCoordinator {
 
     async fn websocket_message(
         &self,
-        _ws: WebSocket,
+        ws: WebSocket,
         message: WebSocketIncomingMessage,
     ) -> Result<()> {
         match message {
             WebSocketIncomingMessage::String(_) => {}
-            WebSocketIncomingMessage::Binary(_bytes) => {}
+            WebSocketIncomingMessage::Binary(bytes) => match Bytes::from(bytes).parse::<Action>() {
+                Ok(Action::Hello(hello)) => self.hello(&ws, &hello)?,
+                Ok(Action::AuthCloud(cloud_id, signature)) => {
+                    self.auth_cloud(&ws, cloud_id, &signature).await?
+                }
+                Ok(Action::Ping) => self.send_response(&ws, &network_common::Response::Pong)?,
+                // mutations are only accepted over http
+                Ok(Action::MutateCloud(_)) | Err(_) => {}
+            },
         }
         Ok(())
     }
 
     async fn fetch(&self, mut req: Request) -> worker::Result<Response> {
+        // build the url query into a usable format
+        let mut query: HashMap<String, String> = HashMap::default();
+        for (key, val) in req.url()?.query_pairs() {
+            query.insert(key.to_string(), val.to_string());
+        }
+
         let upgrade_header = req.headers().get("Upgrade")?.unwrap_or_default();
         if upgrade_header == "websocket" {
+            let mut cloud_id = [0u8; 32];
+            let cloud_id_str = query.get("cloud_id").cloned().unwrap_or_default();
+            if hex::decode_to_slice(cloud_id_str, &mut cloud_id).is_err() {
+                return Response::error("websockets require a cloud_id", 400);
+            }
             let ws = WebSocketPair::new()?;
             let client = ws.client;
             let server = ws.server;
-            server.accept()?;
-            self.authed_listeners.write().unwrap().push(server);
+            // mutations are only broadcast to the socket after it sends `Action::AuthCloud`
+            server.serialize_attachment(SocketAttachment {
+                cloud_id,
+                authenticated: false,
+                nonce: None,
+            })?;
+            self.state.accept_web_socket(&server);
 
             return worker::Response::from_websocket(client);
         }
 
-        let bucket = self.env.bucket("btk_storage")?;
-
-        let headers = Headers::new();
-        headers.set("Access-Control-Allow-Origin", "*")?;
+        let headers = common_headers()?;
         headers.set("Access-Control-Allow-Methods", "*")?;
-
-        // build the url query into a usable format
-        let mut query: HashMap<String, String> = HashMap::default();
-        for (key, val) in req.url()?.query_pairs() {
-            query.insert(key.to_string(), val.to_string());
-        }
+        let codec = Codec::from_request(&req)?;
         match (req.method(), req.path().as_str()) {
             (Method::Get, "/") => Response::ok("hello"),
+            // sent by `claim_creation_token` to the token's object, `main` doesn't forward it
+            (Method::Post, "/creation-token/claim") => {
+                let mut cloud_id = [0u8; 32];
+                let cloud_id_str = query.get("cloud_id").cloned().unwrap_or_default();
+                if hex::decode_to_slice(cloud_id_str, &mut cloud_id).is_err() {
+                    return Ok(Response::empty()?.with_status(400));
+                }
+                self.handle_creation_token_claim(cloud_id).await
+            }
             (Method::Get, "/state") => {
                 let cloud_id = if let Some(cloud_id_str) = query.get("cloud_id") {
                     let mut out = [0u8; 32];
@@ -147,13 +254,31 @@ impl DurableObject for StorageCoordinator {
                 } else {
                     return Ok(Response::empty()?.with_status(400).with_headers(headers));
                 };
-                let count = self.mutation_count(&cloud_id).await?;
-                Ok(Response::from_bytes(
-                    Bytes::encode(&(count as u64))
-                        .map_err(|_| "encoding failed")?
-                        .into(),
-                )?
-                .with_headers(headers))
+                if self.is_deleted(&cloud_id).await? {
+                    return Ok(Response::empty()?.with_status(410).with_headers(headers));
+                }
+                // long poll if `after` is given. The object handles other requests while this
+                // one is waiting, so the cached count picks up new mutations.
+                let after = query.get("after").and_then(|v| v.parse::<u64>().ok());
+                let wait = query
+                    .get("wait")
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or_default()
+                    .min(network_common::MAX_STATE_WAIT_SECS);
+                let deadline = Date::now().as_millis() + wait * 1000;
+                let mut count = self.mutation_count(&cloud_id).await?;
+                while after.is_some_and(|after| count as u64 <= after)
+                    && Date::now().as_millis() < deadline
+                {
+                    Delay::from(STATE_POLL_INTERVAL).await;
+                    let previous_count = count;
+                    count = self.mutation_count(&cloud_id).await?;
+                    // deleting resets the cached count
+                    if count < previous_count && self.is_deleted(&cloud_id).await? {
+                        return Ok(Response::empty()?.with_status(410).with_headers(headers));
+                    }
+                }
+                codec.respond(&(count as u64), headers)
             }
             (Method::Get, "/mutation") => {
                 let cloud_id = if let Some(cloud_id_str) = query.get("cloud_id") {
@@ -174,71 +299,102 @@ impl DurableObject for StorageCoordinator {
                 } else {
                     return Ok(Response::empty()?.with_status(400).with_headers(headers));
                 };
-                let mutation_count = self.mutation_count(&cloud_id).await?;
-                if index >= mutation_count {
-                    return Ok(Response::empty()?.with_status(424).with_headers(headers));
-                    // return Ok(Response::empty()?.with_status(424).with_headers(headers));
+                let body = match store::lookup(&self.mutation_store(&cloud_id)?, index).await? {
+                    Lookup::Found(body) => body,
+                    Lookup::Pending => {
+                        return Ok(Response::empty()?.with_status(424).with_headers(headers));
+                    }
+                    Lookup::Missing => {
+                        println!("mutation {index} is committed but missing from the bucket");
+                        return Ok(Response::empty()?.with_status(404).with_headers(headers));
+                    }
+                };
+                if codec.accepts_bincode() {
+                    // stored bincode encoded
+                    return Ok(Response::from_bytes(body)?.with_headers(headers));
                 }
-                let obj_maybe = bucket.get(mutation_key(&cloud_id, index)).execute().await?;
-                Ok(
-                    Response::from_body(obj_maybe.unwrap().body().unwrap().response_body()?)?
-                        .with_headers(headers),
-                )
+                let mutation = Bytes::from(body)
+                    .parse::<Mutation>()
+                    .map_err(|_| "failed to parse mutation")?;
+                codec.respond(&mutation, headers)
             }
             (Method::Post, "/mutate") => {
                 let mutation_bytes = req.bytes().await?;
-                let mutation = Bytes::from(&mutation_bytes)
-                    .parse::<Mutation>()
+                let mutation = codec
+                    .parse::<Mutation>(&mutation_bytes)
                     .map_err(|_| "failed to parse body")?;
-                let public_key = if let Some(public_key) = &mutation.public_key {
-                    public_key.clone()
-                } else if let Some(public_key) =
-                    self.get_public_key(&mutation.public_key_hash).await?
+                // the algorithms are fixed by the mutation that creates the cloud
+                let record = if mutation.index == 0 {
+                    match PublicKeyRecord::from_genesis(&mutation) {
+                        Ok(record) => record,
+                        Err(_) => {
+                            return Ok(Response::empty()?.with_status(400).with_headers(headers));
+                        }
+                    }
+                } else if let Some(record) = self
+                    .get_public_key_record(&mutation.public_key_hash)
+                    .await?
                 {
-                    public_key
+                    record
                 } else {
                     return Ok(Response::empty()?.with_status(400).with_headers(headers));
                 };
-                if let Err(e) = mutation.verify(public_key.clone()) {
+                if let Err(e) = mutation.verify(&record) {
                     println!("error verifying mutation: {:?}", e);
                     return Ok(Response::empty()?.with_status(401).with_headers(headers));
                 }
                 let cloud_id = mutation.public_key_hash;
+                if mutation.index == 0 && self.is_deleted(&cloud_id).await? {
+                    return Ok(Response::empty()?.with_status(410).with_headers(headers));
+                }
+                let used_token_hash = if mutation.index == 0 {
+                    match self.check_creation_proof(&mutation).await? {
+                        Ok(used_token_hash) => used_token_hash,
+                        Err(e) => {
+                            println!("rejecting cloud creation: {:?}", e);
+                            return Ok(Response::empty()?.with_status(403).with_headers(headers));
+                        }
+                    }
+                } else {
+                    None
+                };
 
-                let mut mutation_count_maybe = self.mutation_count_lock.write().unwrap();
-                let mutation_count = self.load_mutation_count(&cloud_id).await?;
-
-                if mutation.index != mutation_count as u64 {
+                let append_guard = self.append_lock.lock().await;
+                let store = self.mutation_store(&cloud_id)?;
+                if mutation.index != store.count().await? as u64 {
                     return Ok(Response::empty()?.with_status(400).with_headers(headers));
                 }
-                let new_mutation_count = mutation.index + 1;
 
+                if let Some(token_hash) = used_token_hash
+                    && !self.claim_creation_token(&token_hash, &cloud_id).await?
+                {
+                    println!("rejecting cloud creation: creation token already used");
+                    return Ok(Response::empty()?.with_status(403).with_headers(headers));
+                }
                 if mutation.index == 0 {
-                    bucket
-                        .put(cloud_pubkey_key(&cloud_id), public_key)
+                    self.env
+                        .bucket("btk_storage")?
+                        .put(
+                            cloud_pubkey_key(&cloud_id),
+                            Bytes::encode(&record)
+                                .map_err(|_| "failed to encode public key record")?
+                                .to_vec(),
+                        )
                         .execute()
                         .await?;
                 }
 
-                bucket
-                    .put(
-                        mutation_key(&mutation.public_key_hash, mutation.index as u32),
-                        // req.inner().body().unwrap(),
-                        mutation_bytes,
-                    )
-                    .execute()
-                    .await?;
-                bucket
-                    .put(
-                        mutation_count_key(&cloud_id),
-                        Bytes::encode(&new_mutation_count)
-                            .map_err(|_| "failed to encode new count")?
-                            .to_vec(),
-                    )
-                    .execute()
-                    .await?;
-                *mutation_count_maybe = Some(new_mutation_count as u32);
-                drop(mutation_count_maybe);
+                // the proof is only needed to accept the genesis mutation
+                let mut mutation = mutation;
+                mutation.creation_proof = None;
+                let body = Bytes::encode(&mutation)
+                    .map_err(|_| "failed to encode mutation")?
+                    .to_vec();
+                let new_mutation_count = match store::append(&store, mutation.index, body).await? {
+                    Some(count) => count as u64,
+                    None => return Ok(Response::empty()?.with_status(400).with_headers(headers)),
+                };
+                drop(append_guard);
 
                 match Bytes::encode(&network_common::Response::CloudMutated(new_mutation_count)) {
                     Ok(bytes) => {
@@ -253,6 +409,75 @@ impl DurableObject for StorageCoordinator {
 
                 Ok(Response::empty()?.with_status(204).with_headers(headers))
             }
+            (Method::Post, "/publish")
+            | (Method::Post, "/delete")
+            | (Method::Get, "/public/state")
+            | (Method::Get, "/public/mutation") => {
+                let mut cloud_id = [0u8; 32];
+                let cloud_id_str = query.get("cloud_id").cloned().unwrap_or_default();
+                if hex::decode_to_slice(cloud_id_str, &mut cloud_id).is_err() {
+                    return Ok(Response::empty()?.with_status(400).with_headers(headers));
+                }
+                match req.path().as_str() {
+                    "/publish" => {
+                        let body = req.bytes().await?;
+                        self.publish(cloud_id, body, codec, headers).await
+                    }
+                    "/delete" => {
+                        let host = req.headers().get("Host")?;
+                        let body = req.bytes().await?;
+                        self.delete_cloud(cloud_id, host, body, codec, headers)
+                            .await
+                    }
+                    "/public/state" => self.public_state(cloud_id, codec, headers).await,
+                    _ => {
+                        let index = match query.get("index").map(|v| v.parse::<u64>()) {
+                            Some(Ok(index)) => index,
+                            _ => {
+                                return Ok(Response::empty()?
+                                    .with_status(400)
+                                    .with_headers(headers));
+                            }
+                        };
+                        self.public_mutation(cloud_id, index, codec, headers).await
+                    }
+                }
+            }
+            (method, "/mailbox") | (method, "/mailbox/key") => {
+                let mut recipient_id = [0u8; 32];
+                let recipient_id_str = query.get("recipient_id").cloned().unwrap_or_default();
+                if hex::decode_to_slice(recipient_id_str, &mut recipient_id).is_err() {
+                    return Ok(Response::empty()?.with_status(400).with_headers(headers));
+                }
+                match (method, req.path().as_str()) {
+                    (Method::Get, "/mailbox") => {
+                        let after = match query.get("after").map(|v| v.parse::<u64>()) {
+                            Some(Ok(after)) => after,
+                            Some(Err(_)) => {
+                                return Ok(Response::empty()?
+                                    .with_status(400)
+                                    .with_headers(headers));
+                            }
+                            None => 0,
+                        };
+                        self.list_mailbox(recipient_id, after, codec, headers).await
+                    }
+                    (Method::Post, "/mailbox") => {
+                        let body = req.bytes().await?;
+                        self.append_mailbox(recipient_id, body, codec, headers)
+                            .await
+                    }
+                    (Method::Get, "/mailbox/key") => {
+                        self.mailbox_key(recipient_id, codec, headers).await
+                    }
+                    (Method::Post, "/mailbox/key") => {
+                        let body = req.bytes().await?;
+                        self.publish_mailbox_key(recipient_id, body, codec, headers)
+                            .await
+                    }
+                    _ => Ok(Response::empty()?.with_status(404).with_headers(headers)),
+                }
+            }
             _ => Ok(Response::empty()?.with_status(404).with_headers(headers)),
         }
     }
@@ -260,20 +485,56 @@ impl DurableObject for StorageCoordinator {
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+    if req.method() == Method::Options {
+        let headers = common_headers()?;
+        headers.set("Access-Control-Allow-Methods", "*")?;
+        headers.set("Access-Control-Allow-Headers", "*")?;
+        headers.set("Access-Control-Max-Age", "86400")?;
+        return Ok(Response::empty()?.with_status(204).with_headers(headers));
+    }
+    // browsers can't set headers on websockets, they're checked by `Action::Hello` instead
+    let is_websocket = req.headers().get("Upgrade")?.as_deref() == Some("websocket");
+    let protocol_version = header_version(req.headers().get(PROTOCOL_HEADER)?.as_deref());
+    if !is_websocket && let Err(e) = check_client_version(protocol_version) {
+        return Ok(Response::error(e.to_string(), 426)?.with_headers(common_headers()?));
+    }
+    // token claims are only made between durable objects
+    if req.path().starts_with("/creation-token/") {
+        return Ok(Response::empty()?.with_status(404));
+    }
+    // the creation policy is the same for every cloud
+    if req.method() == Method::Get && req.path() == "/policy" {
+        let headers = common_headers()?;
+        return creation::policy_response(&env, Codec::from_request(&req)?, headers);
+    }
+    if req.method() == Method::Get && req.path() == "/api" {
+        let headers = common_headers()?;
+        headers.set("Content-Type", JSON_CONTENT_TYPE)?;
+        let body = Encoding::Json
+            .encode(&network_common::api::description())
+            .map_err(|_| "encoding failed")?;
+        return Ok(Response::from_bytes(body)?.with_headers(headers));
+    }
     // build the url query into a usable format
     let mut query: HashMap<String, String> = HashMap::default();
     for (key, val) in req.url()?.query_pairs() {
         query.insert(key.to_string(), val.to_string());
     }
-    let cloud_id = if let Some(cloud_id_str) = query.get("cloud_id") {
+    // mailboxes get their own durable objects, separate from any cloud
+    let object_name = if let Some(cloud_id_str) = query.get("cloud_id") {
         if cloud_id_str.len() != 64 {
             return Ok(Response::empty()?.with_status(400));
         }
-        cloud_id_str
+        cloud_id_str.to_string()
+    } else if let Some(recipient_id_str) = query.get("recipient_id") {
+        if recipient_id_str.len() != 64 {
+            return Ok(Response::empty()?.with_status(400));
+        }
+        format!("mailbox-{recipient_id_str}")
     } else {
         return Ok(Response::empty()?.with_status(400));
     };
     let namespace = env.durable_object("BTK_PRERELEASE")?;
-    let stub = namespace.id_from_name(cloud_id)?.get_stub()?;
+    let stub = namespace.id_from_name(&object_name)?.get_stub()?;
     stub.fetch_with_request(req).await
 }
@@ -0,0 +1,176 @@
+use anondb::Bytes;
+use network_common::ByteString;
+use network_common::MAILBOX_MESSAGE_MAX_LEN;
+use network_common::MailboxEntry;
+use network_common::MailboxMessage;
+use worker::*;
+
+use super::StorageCoordinator;
+use super::codec::Codec;
+
+/// Default lifetime of a mailbox entry if `MAILBOX_TTL_SECS` is not set, 14 days.
+const DEFAULT_MAILBOX_TTL_SECS: u64 = 14 * 24 * 60 * 60;
+
+fn mailbox_entry_key(recipient_id: &[u8; 32], index: u64) -> String {
+    format!("mailbox-{}-{}", index, hex::encode(recipient_id))
+}
+
+/// Next index to be assigned in the mailbox.
+fn mailbox_count_key(recipient_id: &[u8; 32]) -> String {
+    format!("mailbox-count-{}", hex::encode(recipient_id))
+}
+
+/// Lowest index that may not have expired yet.
+fn mailbox_first_key(recipient_id: &[u8; 32]) -> String {
+    format!("mailbox-first-{}", hex::encode(recipient_id))
+}
+
+fn mailbox_pubkey_key(recipient_id: &[u8; 32]) -> String {
+    format!("mailbox-key-{}", hex::encode(recipient_id))
+}
+
+fn now_secs() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+impl StorageCoordinator {
+    fn mailbox_ttl_secs(&self) -> u64 {
+        self.env
+            .var("MAILBOX_TTL_SECS")
+            .ok()
+            .and_then(|v| v.to_string().parse::<u64>().ok())
+            .unwrap_or(DEFAULT_MAILBOX_TTL_SECS)
+    }
+
+    pub async fn publish_mailbox_key(
+        &self,
+        recipient_id: [u8; 32],
+        body: Vec<u8>,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let encapsulation_key = match codec.parse::<ByteString>(&body) {
+            Ok(ByteString(key)) => key,
+            Err(_) => return Ok(Response::empty()?.with_status(400).with_headers(headers)),
+        };
+        if network_common::recipient_id(&encapsulation_key) != recipient_id {
+            return Ok(Response::empty()?.with_status(400).with_headers(headers));
+        }
+        let bucket = self.env.bucket("btk_storage")?;
+        bucket
+            .put(mailbox_pubkey_key(&recipient_id), encapsulation_key)
+            .execute()
+            .await?;
+        Ok(Response::empty()?.with_status(204).with_headers(headers))
+    }
+
+    pub async fn mailbox_key(
+        &self,
+        recipient_id: [u8; 32],
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        match self
+            .get_object_bytes(mailbox_pubkey_key(&recipient_id))
+            .await?
+        {
+            Some(key) => codec.respond(&ByteString(key), headers),
+            None => Ok(Response::empty()?.with_status(404).with_headers(headers)),
+        }
+    }
+
+    /// Append a message and prune expired entries from the front of the mailbox.
+    pub async fn append_mailbox(
+        &self,
+        recipient_id: [u8; 32],
+        body: Vec<u8>,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let message = match codec.parse::<MailboxMessage>(&body) {
+            Ok(message) => message,
+            Err(_) => return Ok(Response::empty()?.with_status(400).with_headers(headers)),
+        };
+        if message.encoded_len() > MAILBOX_MESSAGE_MAX_LEN {
+            return Ok(Response::empty()?.with_status(413).with_headers(headers));
+        }
+        if self
+            .get_object_bytes(mailbox_pubkey_key(&recipient_id))
+            .await?
+            .is_none()
+        {
+            return Ok(Response::empty()?.with_status(404).with_headers(headers));
+        }
+
+        let _guard = self.mailbox_lock.lock().await;
+        let bucket = self.env.bucket("btk_storage")?;
+        let now = now_secs();
+        let ttl_secs = self.mailbox_ttl_secs();
+
+        let index = self.get_u64(mailbox_count_key(&recipient_id)).await?;
+        let entry = MailboxEntry {
+            index,
+            created_at: now,
+            message,
+        };
+        bucket
+            .put(
+                mailbox_entry_key(&recipient_id, index),
+                Bytes::encode(&entry)
+                    .map_err(|_| "failed to encode entry")?
+                    .to_vec(),
+            )
+            .execute()
+            .await?;
+        self.put_u64(mailbox_count_key(&recipient_id), index + 1)
+            .await?;
+
+        // entries are appended in time order, so expired entries are always at the front
+        let mut first = self.get_u64(mailbox_first_key(&recipient_id)).await?;
+        while first < index {
+            let entry_key = mailbox_entry_key(&recipient_id, first);
+            if let Some(bytes) = self.get_object_bytes(entry_key.clone()).await?
+                && let Ok(entry) = Bytes::from(bytes).parse::<MailboxEntry>()
+                && !entry.is_expired(now, ttl_secs)
+            {
+                break;
+            }
+            bucket.delete(entry_key).await?;
+            first += 1;
+        }
+        self.put_u64(mailbox_first_key(&recipient_id), first)
+            .await?;
+
+        codec.respond(&index, headers)
+    }
+
+    pub async fn list_mailbox(
+        &self,
+        recipient_id: [u8; 32],
+        after: u64,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let now = now_secs();
+        let ttl_secs = self.mailbox_ttl_secs();
+        let first = self.get_u64(mailbox_first_key(&recipient_id)).await?;
+        let count = self.get_u64(mailbox_count_key(&recipient_id)).await?;
+        let mut entries = Vec::default();
+        for index in first.max(after)..count {
+            let bytes = match self
+                .get_object_bytes(mailbox_entry_key(&recipient_id, index))
+                .await?
+            {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let entry = Bytes::from(bytes)
+                .parse::<MailboxEntry>()
+                .map_err(|_| "failed to parse mailbox entry")?;
+            if !entry.is_expired(now, ttl_secs) {
+                entries.push(entry);
+            }
+        }
+        codec.respond(&entries, headers)
+    }
+}
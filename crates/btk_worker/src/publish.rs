@@ -0,0 +1,126 @@
+use anondb::Bytes;
+use anondb::JournalTransaction;
+use network_common::Mutation;
+use network_common::PublishRequest;
+use worker::*;
+
+use super::StorageCoordinator;
+use super::codec::Codec;
+use super::mutation_key;
+
+/// Number of leading mutations that are public.
+pub(crate) fn public_count_key(cloud_id: &[u8; 32]) -> String {
+    format!("public-count-{}", hex::encode(cloud_id))
+}
+
+impl StorageCoordinator {
+    async fn load_mutation(&self, cloud_id: &[u8; 32], index: u64) -> Result<Option<Mutation>> {
+        match self
+            .get_object_bytes(mutation_key(cloud_id, index as u32))
+            .await?
+        {
+            Some(bytes) => Ok(Some(
+                Bytes::from(bytes)
+                    .parse::<Mutation>()
+                    .map_err(|_| "failed to parse mutation")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Accept mutation keys for existing mutations. Each key is checked by decrypting the
+    /// mutation and parsing the result as a transaction.
+    pub async fn publish(
+        &self,
+        cloud_id: [u8; 32],
+        body: Vec<u8>,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let publish = match codec.parse::<PublishRequest>(&body) {
+            Ok(publish) => publish,
+            Err(_) => return Ok(Response::empty()?.with_status(400).with_headers(headers)),
+        };
+        if publish.cloud_id != cloud_id {
+            return Ok(Response::empty()?.with_status(400).with_headers(headers));
+        }
+        let record = match self.get_public_key_record(&cloud_id).await? {
+            Some(record) => record,
+            None => return Ok(Response::empty()?.with_status(404).with_headers(headers)),
+        };
+        if let Err(e) = publish.verify(&record) {
+            println!("error verifying publish request: {:?}", e);
+            return Ok(Response::empty()?.with_status(401).with_headers(headers));
+        }
+
+        let mut mutations = Vec::default();
+        for (index, revealed_key) in &publish.mutation_keys {
+            let mut mutation = match self.load_mutation(&cloud_id, *index).await? {
+                Some(mutation) => mutation,
+                None => return Ok(Response::empty()?.with_status(424).with_headers(headers)),
+            };
+            mutation.mutation_key = Some(*revealed_key);
+            let tx_bytes = mutation
+                .decrypt_public()
+                .expect("mutation key was just set");
+            if Bytes::from(tx_bytes).parse::<JournalTransaction>().is_err() {
+                return Ok(Response::empty()?.with_status(400).with_headers(headers));
+            }
+            mutations.push(mutation);
+        }
+
+        let bucket = self.env.bucket("btk_storage")?;
+        for mutation in &mutations {
+            bucket
+                .put(
+                    mutation_key(&cloud_id, mutation.index as u32),
+                    Bytes::encode(mutation)
+                        .map_err(|_| "failed to encode mutation")?
+                        .to_vec(),
+                )
+                .execute()
+                .await?;
+        }
+
+        // advance past any mutations that are now contiguously public
+        let mut public_count = self.get_u64(public_count_key(&cloud_id)).await?;
+        while let Some(mutation) = self.load_mutation(&cloud_id, public_count).await?
+            && mutation.mutation_key.is_some()
+        {
+            public_count += 1;
+        }
+        self.put_u64(public_count_key(&cloud_id), public_count)
+            .await?;
+
+        codec.respond(&public_count, headers)
+    }
+
+    pub async fn public_state(
+        &self,
+        cloud_id: [u8; 32],
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        let public_count = self.get_u64(public_count_key(&cloud_id)).await?;
+        codec.respond(&public_count, headers)
+    }
+
+    /// Retrieve a public mutation with its mutation key. Clients verify the signature and
+    /// decrypt it themselves.
+    pub async fn public_mutation(
+        &self,
+        cloud_id: [u8; 32],
+        index: u64,
+        codec: Codec,
+        headers: Headers,
+    ) -> Result<Response> {
+        if index >= self.get_u64(public_count_key(&cloud_id)).await? {
+            return Ok(Response::empty()?.with_status(424).with_headers(headers));
+        }
+        match self.load_mutation(&cloud_id, index).await? {
+            Some(mutation) if mutation.mutation_key.is_some() => codec.respond(&mutation, headers),
+            Some(_) => Ok(Response::empty()?.with_status(403).with_headers(headers)),
+            None => Ok(Response::empty()?.with_status(424).with_headers(headers)),
+        }
+    }
+}
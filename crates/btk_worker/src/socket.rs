@@ -0,0 +1,174 @@
+use anondb::Bytes;
+use network_common::Action;
+use network_common::Hello;
+use network_common::MIN_PROTOCOL_VERSION;
+use network_common::PublicKeyRecord;
+use network_common::check_client_version;
+use serde::Deserialize;
+use serde::Serialize;
+use worker::*;
+
+use super::StorageCoordinator;
+
+/// Stored with each websocket so it survives the object hibernating.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SocketAttachment {
+    /// Cloud from the upgrade request, the only cloud the socket may authenticate as.
+    pub cloud_id: [u8; 32],
+    pub authenticated: bool,
+    /// Challenge sent in the socket's `Response::Hello`, `None` until the client says hello.
+    pub nonce: Option<[u8; 32]>,
+}
+
+impl SocketAttachment {
+    /// Check an `Action::AuthCloud` against the socket's cloud and hello nonce.
+    pub(crate) fn verify_auth(
+        &self,
+        cloud_id: &[u8; 32],
+        signature: &[u8],
+        record: &PublicKeyRecord,
+    ) -> anyhow::Result<()> {
+        if &self.cloud_id != cloud_id {
+            anyhow::bail!("socket was opened for a different cloud");
+        }
+        let Some(nonce) = &self.nonce else {
+            anyhow::bail!("auth before hello");
+        };
+        Action::verify_auth_cloud(cloud_id, nonce, signature, record)
+    }
+}
+
+impl StorageCoordinator {
+    pub(crate) fn send_response(
+        &self,
+        ws: &WebSocket,
+        res: &network_common::Response,
+    ) -> Result<()> {
+        ws.send_with_bytes(Bytes::encode(res).map_err(|_| "encoding failed")?)
+    }
+
+    /// Answer a client's `Hello`, closing the socket if the client is incompatible.
+    pub(crate) fn hello(&self, ws: &WebSocket, hello: &Hello) -> Result<()> {
+        if check_client_version(hello.protocol_version).is_err() {
+            let res = network_common::Response::Incompatible {
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+            };
+            self.send_response(ws, &res)?;
+            ws.close(Some(1008), Some("server requires a newer BTK"))?;
+            self.authed_listeners.write().unwrap().retain(|v| v != ws);
+            return Ok(());
+        }
+        let Some(mut attachment) = ws.deserialize_attachment::<SocketAttachment>()? else {
+            return Ok(());
+        };
+        let mut nonce = [0u8; 32];
+        getrandom::fill(&mut nonce).map_err(|e| e.to_string())?;
+        attachment.nonce = Some(nonce);
+        ws.serialize_attachment(&attachment)?;
+        self.send_response(ws, &network_common::Response::Hello(Hello::reply(nonce)))
+    }
+
+    /// Verify an `Action::AuthCloud` and start notifying the socket of mutations. Invalid
+    /// attempts are ignored, the socket stays unauthenticated.
+    pub(crate) async fn auth_cloud(
+        &self,
+        ws: &WebSocket,
+        cloud_id: [u8; 32],
+        signature: &[u8],
+    ) -> Result<()> {
+        let Some(mut attachment) = ws.deserialize_attachment::<SocketAttachment>()? else {
+            return Ok(());
+        };
+        if attachment.cloud_id != cloud_id {
+            println!("rejecting auth for a different cloud");
+            return Ok(());
+        }
+        let Some(record) = self.get_public_key_record(&cloud_id).await? else {
+            return Ok(());
+        };
+        if let Err(e) = attachment.verify_auth(&cloud_id, signature, &record) {
+            println!("rejecting auth: {:?}", e);
+            return Ok(());
+        }
+        if !attachment.authenticated {
+            attachment.authenticated = true;
+            ws.serialize_attachment(&attachment)?;
+            self.authed_listeners.write().unwrap().push(ws.clone());
+        }
+        let mutation_count = self.mutation_count(&cloud_id).await?;
+        self.send_response(
+            ws,
+            &network_common::Response::Authenticated(mutation_count as u64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use network_common::CipherAlgorithm;
+    use network_common::CloudSigner;
+    use network_common::SignatureAlgorithm;
+
+    use super::*;
+
+    fn signer_and_record() -> (CloudSigner, PublicKeyRecord) {
+        let signer = CloudSigner::from_seed(&[7; 32], SignatureAlgorithm::MlDsa44);
+        let record = PublicKeyRecord {
+            signature_algorithm: signer.algorithm(),
+            cipher_algorithm: CipherAlgorithm::ChaCha20,
+            public_key: signer.public_key().clone(),
+        };
+        (signer, record)
+    }
+
+    fn attachment(cloud_id: [u8; 32], nonce: Option<[u8; 32]>) -> SocketAttachment {
+        SocketAttachment {
+            cloud_id,
+            authenticated: false,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn accepts_signature_over_socket_nonce() {
+        let (signer, record) = signer_and_record();
+        let cloud_id = signer.id();
+        let message = Action::auth_cloud_signed_bytes(&cloud_id, &[1; 32]).unwrap();
+        let signature = signer.sign(&message);
+        attachment(cloud_id, Some([1; 32]))
+            .verify_auth(&cloud_id, &signature, &record)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_replayed_signature() {
+        let (signer, record) = signer_and_record();
+        let cloud_id = signer.id();
+        // captured from another connection
+        let message = Action::auth_cloud_signed_bytes(&cloud_id, &[1; 32]).unwrap();
+        let signature = signer.sign(&message);
+        assert!(
+            attachment(cloud_id, Some([2; 32]))
+                .verify_auth(&cloud_id, &signature, &record)
+                .is_err()
+        );
+        assert!(
+            attachment(cloud_id, None)
+                .verify_auth(&cloud_id, &signature, &record)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_other_cloud() {
+        let (signer, record) = signer_and_record();
+        let cloud_id = signer.id();
+        let message = Action::auth_cloud_signed_bytes(&cloud_id, &[1; 32]).unwrap();
+        let signature = signer.sign(&message);
+        assert!(
+            attachment([9; 32], Some([1; 32]))
+                .verify_auth(&cloud_id, &signature, &record)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+
+use anondb::Bytes;
+use worker::*;
+
+use super::mutation_count_key;
+use super::mutation_key;
+
+/// Number of committed mutations, in durable object storage.
+const COUNT_KEY: &str = "count";
+
+/// Length of the committed mutation at an index, in durable object storage. Written in the same
+/// transaction as the count so the two always agree.
+fn index_key(index: u32) -> String {
+    format!("mutation-{index}")
+}
+
+/// Storage for the mutations of a single cloud. Bodies are written first and only become
+/// visible once `commit` records them, so a failed append never leaves a count pointing at a
+/// missing mutation.
+pub(crate) trait MutationStore {
+    /// Number of committed mutations.
+    async fn count(&self) -> Result<u32>;
+    /// Write the body of the mutation at `index`. Overwrites a body left by a failed append.
+    async fn put_body(&self, index: u32, body: Vec<u8>) -> Result<()>;
+    /// Atomically record the mutation at `index` and set the count to `index + 1`.
+    async fn commit(&self, index: u32, len: u32) -> Result<()>;
+    async fn get_body(&self, index: u32) -> Result<Option<Vec<u8>>>;
+    /// Reset the count to 0. Bodies are removed separately.
+    async fn clear(&self) -> Result<()>;
+}
+
+pub(crate) enum Lookup {
+    /// The index hasn't been committed yet.
+    Pending,
+    /// Committed but the body is missing from the bucket.
+    Missing,
+    Found(Vec<u8>),
+}
+
+/// Append a mutation body if `index` is the next index. Returns the new count, or `None` if the
+/// index is wrong. Callers serialize appends so the count can't change in between.
+pub(crate) async fn append<S: MutationStore>(
+    store: &S,
+    index: u64,
+    body: Vec<u8>,
+) -> Result<Option<u32>> {
+    let count = store.count().await?;
+    if index != count as u64 {
+        return Ok(None);
+    }
+    let len = u32::try_from(body.len()).map_err(|_| "mutation too large")?;
+    store.put_body(count, body).await?;
+    store.commit(count, len).await?;
+    Ok(Some(count + 1))
+}
+
+pub(crate) async fn lookup<S: MutationStore>(store: &S, index: u32) -> Result<Lookup> {
+    if index >= store.count().await? {
+        return Ok(Lookup::Pending);
+    }
+    Ok(match store.get_body(index).await? {
+        Some(body) => Lookup::Found(body),
+        None => Lookup::Missing,
+    })
+}
+
+/// The durable object storage calls `DurableStore` makes, so tests can stand in for it.
+pub(crate) trait CountStorage {
+    async fn get_count(&self, key: &str) -> Result<Option<u32>>;
+    /// Write all values or none of them.
+    async fn put_counts(&self, values: HashMap<String, u32>) -> Result<()>;
+    async fn delete_all(&self) -> Result<()>;
+}
+
+impl CountStorage for Storage {
+    async fn get_count(&self, key: &str) -> Result<Option<u32>> {
+        self.get::<u32>(key).await
+    }
+
+    async fn put_counts(&self, values: HashMap<String, u32>) -> Result<()> {
+        // a single put_multiple is atomic
+        self.put_multiple(values).await
+    }
+
+    async fn delete_all(&self) -> Result<()> {
+        Storage::delete_all(self).await
+    }
+}
+
+/// The R2 calls `DurableStore` makes, so tests can stand in for them.
+pub(crate) trait BodyBucket {
+    async fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>>;
+    async fn put_bytes(&self, key: String, body: Vec<u8>) -> Result<()>;
+}
+
+impl BodyBucket for Bucket {
+    async fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let obj = match self.get(key).execute().await? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+        match obj.body() {
+            Some(body) => Ok(Some(body.bytes().await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_bytes(&self, key: String, body: Vec<u8>) -> Result<()> {
+        self.put(key, body).execute().await?;
+        Ok(())
+    }
+}
+
+/// Count and index in the durable object's transactional storage, bodies in R2.
+pub(crate) struct DurableStore<S = Storage, B = Bucket> {
+    pub(crate) storage: S,
+    pub(crate) bucket: B,
+    pub(crate) cloud_id: [u8; 32],
+}
+
+impl<S: CountStorage, B: BodyBucket> DurableStore<S, B> {
+    /// Count written to R2 before the durable object stored it.
+    async fn legacy_count(&self) -> Result<u32> {
+        let Some(body) = self
+            .bucket
+            .get_bytes(mutation_count_key(&self.cloud_id))
+            .await?
+        else {
+            return Ok(0);
+        };
+        Ok(Bytes::from(body)
+            .parse::<u32>()
+            .map_err(|_| "failed to parse count body")?)
+    }
+}
+
+impl<S: CountStorage, B: BodyBucket> MutationStore for DurableStore<S, B> {
+    async fn count(&self) -> Result<u32> {
+        match self.storage.get_count(COUNT_KEY).await? {
+            Some(count) => Ok(count),
+            None => self.legacy_count().await,
+        }
+    }
+
+    async fn put_body(&self, index: u32, body: Vec<u8>) -> Result<()> {
+        self.bucket
+            .put_bytes(mutation_key(&self.cloud_id, index), body)
+            .await
+    }
+
+    async fn commit(&self, index: u32, len: u32) -> Result<()> {
+        let mut values = HashMap::new();
+        values.insert(COUNT_KEY.to_string(), index + 1);
+        values.insert(index_key(index), len);
+        self.storage.put_counts(values).await
+    }
+
+    async fn get_body(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        self.bucket
+            .get_bytes(mutation_key(&self.cloud_id, index))
+            .await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        // the object belongs to a single cloud, the count is written so the legacy R2 count
+        // isn't read again
+        self.storage.delete_all().await?;
+        let mut values = HashMap::new();
+        values.insert(COUNT_KEY.to_string(), 0);
+        self.storage.put_counts(values).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::cell::RefCell;
+
+    use futures_lite::future::block_on;
+    use futures_util::lock::Mutex;
+
+    use super::*;
+
+    /// Stand-in for durable object storage and R2. `fail_puts` makes body writes fail like an
+    /// unavailable bucket.
+    #[derive(Default)]
+    struct MemoryStore {
+        committed: RefCell<HashMap<String, u32>>,
+        bodies: RefCell<HashMap<u32, Vec<u8>>>,
+        fail_puts: Cell<bool>,
+    }
+
+    impl MutationStore for MemoryStore {
+        async fn count(&self) -> Result<u32> {
+            Ok(self
+                .committed
+                .borrow()
+                .get(COUNT_KEY)
+                .copied()
+                .unwrap_or_default())
+        }
+
+        async fn put_body(&self, index: u32, body: Vec<u8>) -> Result<()> {
+            if self.fail_puts.get() {
+                return Err(Error::RustError("bucket unavailable".to_string()));
+            }
+            // yield so concurrent appends interleave
+            futures_lite::future::yield_now().await;
+            self.bodies.borrow_mut().insert(index, body);
+            Ok(())
+        }
+
+        async fn commit(&self, index: u32, len: u32) -> Result<()> {
+            let mut committed = self.committed.borrow_mut();
+            committed.insert(COUNT_KEY.to_string(), index + 1);
+            committed.insert(index_key(index), len);
+            Ok(())
+        }
+
+        async fn get_body(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            Ok(self.bodies.borrow().get(&index).cloned())
+        }
+
+        async fn clear(&self) -> Result<()> {
+            self.committed.borrow_mut().clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn appends_in_order() {
+        let store = MemoryStore::default();
+        block_on(async {
+            assert_eq!(append(&store, 0, vec![0]).await.unwrap(), Some(1));
+            assert_eq!(append(&store, 1, vec![1, 1]).await.unwrap(), Some(2));
+            assert_eq!(append(&store, 1, vec![1]).await.unwrap(), None);
+            assert_eq!(append(&store, 3, vec![3]).await.unwrap(), None);
+            assert_eq!(store.count().await.unwrap(), 2);
+            assert!(
+                matches!(lookup(&store, 1).await.unwrap(), Lookup::Found(body) if body == [1, 1])
+            );
+            assert!(matches!(lookup(&store, 2).await.unwrap(), Lookup::Pending));
+        });
+        assert_eq!(store.committed.borrow().get(&index_key(1)), Some(&2));
+    }
+
+    #[test]
+    fn failed_write_is_not_committed() {
+        let store = MemoryStore::default();
+        block_on(async {
+            append(&store, 0, vec![0]).await.unwrap();
+            store.fail_puts.set(true);
+            assert!(append(&store, 1, vec![1]).await.is_err());
+            assert_eq!(store.count().await.unwrap(), 1);
+            assert!(matches!(lookup(&store, 1).await.unwrap(), Lookup::Pending));
+            store.fail_puts.set(false);
+            assert_eq!(append(&store, 1, vec![1]).await.unwrap(), Some(2));
+        });
+    }
+
+    #[test]
+    fn missing_body_is_reported() {
+        let store = MemoryStore::default();
+        block_on(async {
+            append(&store, 0, vec![0]).await.unwrap();
+            store.bodies.borrow_mut().clear();
+            assert!(matches!(lookup(&store, 0).await.unwrap(), Lookup::Missing));
+        });
+    }
+
+    #[test]
+    fn serialized_appends_use_each_index_once() {
+        let store = MemoryStore::default();
+        let lock = Mutex::new(());
+        let append_locked = |body: u8| {
+            let store = &store;
+            let lock = &lock;
+            async move {
+                let _append = lock.lock().await;
+                append(store, 0, vec![body]).await.unwrap()
+            }
+        };
+        let (a, b) = block_on(futures_lite::future::zip(
+            append_locked(1),
+            append_locked(2),
+        ));
+        assert_eq!(a, Some(1));
+        assert_eq!(b, None);
+        assert_eq!(store.bodies.borrow().get(&0), Some(&vec![1]));
+    }
+
+    #[test]
+    fn clear_resets_count() {
+        let store = MemoryStore::default();
+        block_on(async {
+            append(&store, 0, vec![0]).await.unwrap();
+            store.clear().await.unwrap();
+            assert!(matches!(lookup(&store, 0).await.unwrap(), Lookup::Pending));
+            assert_eq!(append(&store, 0, vec![0]).await.unwrap(), Some(1));
+        });
+    }
+
+    /// Behaves like durable object storage under Miniflare: `put_counts` writes every value or,
+    /// with `fail_puts`, none of them.
+    #[derive(Default)]
+    struct MiniflareStorage {
+        values: RefCell<HashMap<String, u32>>,
+        fail_puts: Cell<bool>,
+    }
+
+    impl CountStorage for MiniflareStorage {
+        async fn get_count(&self, key: &str) -> Result<Option<u32>> {
+            Ok(self.values.borrow().get(key).copied())
+        }
+
+        async fn put_counts(&self, values: HashMap<String, u32>) -> Result<()> {
+            if self.fail_puts.get() {
+                return Err(Error::RustError("storage unavailable".to_string()));
+            }
+            self.values.borrow_mut().extend(values);
+            Ok(())
+        }
+
+        async fn delete_all(&self) -> Result<()> {
+            self.values.borrow_mut().clear();
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MiniflareBucket {
+        objects: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl BodyBucket for MiniflareBucket {
+        async fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+            Ok(self.objects.borrow().get(&key).cloned())
+        }
+
+        async fn put_bytes(&self, key: String, body: Vec<u8>) -> Result<()> {
+            self.objects.borrow_mut().insert(key, body);
+            Ok(())
+        }
+    }
+
+    fn durable_store() -> DurableStore<MiniflareStorage, MiniflareBucket> {
+        DurableStore {
+            storage: MiniflareStorage::default(),
+            bucket: MiniflareBucket::default(),
+            cloud_id: [3; 32],
+        }
+    }
+
+    #[test]
+    fn durable_commit_writes_count_and_index() {
+        let store = durable_store();
+        block_on(async {
+            assert_eq!(append(&store, 0, vec![0, 0, 0]).await.unwrap(), Some(1));
+            assert_eq!(append(&store, 1, vec![1]).await.unwrap(), Some(2));
+            assert!(
+                matches!(lookup(&store, 0).await.unwrap(), Lookup::Found(body) if body == [0, 0, 0])
+            );
+        });
+        let values = store.storage.values.borrow();
+        assert_eq!(values.get(COUNT_KEY), Some(&2));
+        assert_eq!(values.get(&index_key(0)), Some(&3));
+        assert_eq!(values.get(&index_key(1)), Some(&1));
+    }
+
+    #[test]
+    fn durable_failed_commit_writes_nothing() {
+        let store = durable_store();
+        block_on(async {
+            append(&store, 0, vec![0]).await.unwrap();
+            store.storage.fail_puts.set(true);
+            assert!(append(&store, 1, vec![1]).await.is_err());
+            assert_eq!(store.count().await.unwrap(), 1);
+            assert!(matches!(lookup(&store, 1).await.unwrap(), Lookup::Pending));
+        });
+        assert_eq!(store.storage.values.borrow().get(&index_key(1)), None);
+    }
+
+    #[test]
+    fn durable_count_falls_back_to_legacy_count() {
+        let store = durable_store();
+        store.bucket.objects.borrow_mut().insert(
+            mutation_count_key(&store.cloud_id),
+            Bytes::encode(&2u32).unwrap().to_vec(),
+        );
+        block_on(async {
+            assert_eq!(store.count().await.unwrap(), 2);
+            assert_eq!(append(&store, 2, vec![2]).await.unwrap(), Some(3));
+            assert_eq!(store.count().await.unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn durable_clear_ignores_legacy_count() {
+        let store = durable_store();
+        store.bucket.objects.borrow_mut().insert(
+            mutation_count_key(&store.cloud_id),
+            Bytes::encode(&2u32).unwrap().to_vec(),
+        );
+        block_on(async {
+            append(&store, 2, vec![2]).await.unwrap();
+            store.clear().await.unwrap();
+            assert_eq!(store.count().await.unwrap(), 0);
+            assert_eq!(append(&store, 0, vec![0]).await.unwrap(), Some(1));
+        });
+        let values = store.storage.values.borrow();
+        assert_eq!(values.get(&index_key(2)), None);
+        assert_eq!(values.get(COUNT_KEY), Some(&1));
+    }
+}
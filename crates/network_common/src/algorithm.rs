@@ -0,0 +1,234 @@
+use anyhow::Result;
+use chacha20::ChaCha20;
+use chacha20::cipher::KeyIvInit;
+use chacha20::cipher::StreamCipher;
+use ed25519_dalek::PUBLIC_KEY_LENGTH;
+use ed25519_dalek::SIGNATURE_LENGTH;
+use ml_dsa::EncodedSignature;
+use ml_dsa::EncodedVerifyingKey;
+use ml_dsa::KeyGen;
+use ml_dsa::KeyPair;
+use ml_dsa::MlDsa44;
+use ml_dsa::MlDsa65;
+use ml_dsa::MlDsa87;
+use ml_dsa::MlDsaParams;
+use ml_dsa::Signature;
+use ml_dsa::VerifyingKey;
+use ml_dsa::signature::Signer;
+use ml_dsa::signature::Verifier;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+/// Signature scheme used to authenticate mutations to a cloud. Chosen when the cloud is created
+/// and fixed for the life of the cloud.
+///
+/// Approximate signature sizes: ML-DSA-44 2.4 KB, ML-DSA-65 3.3 KB, ML-DSA-87 4.6 KB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[repr(u8)]
+pub enum SignatureAlgorithm {
+    MlDsa44 = 0,
+    MlDsa65 = 1,
+    #[default]
+    MlDsa87 = 2,
+    /// Ed25519 and ML-DSA-65, both signatures must be valid. Public keys and signatures are the
+    /// Ed25519 value followed by the ML-DSA value.
+    Ed25519MlDsa65 = 3,
+}
+
+impl SignatureAlgorithm {
+    pub const ALL: [SignatureAlgorithm; 4] = [
+        SignatureAlgorithm::MlDsa44,
+        SignatureAlgorithm::MlDsa65,
+        SignatureAlgorithm::MlDsa87,
+        SignatureAlgorithm::Ed25519MlDsa65,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::MlDsa44 => "ML-DSA-44",
+            SignatureAlgorithm::MlDsa65 => "ML-DSA-65",
+            SignatureAlgorithm::MlDsa87 => "ML-DSA-87",
+            SignatureAlgorithm::Ed25519MlDsa65 => "Ed25519+ML-DSA-65",
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.to_byte() == byte)
+            .ok_or(anyhow::anyhow!("unknown signature algorithm: {byte}"))
+    }
+
+    /// Length of a signature in bytes, from FIPS 204 for ML-DSA.
+    pub fn signature_len(&self) -> usize {
+        match self {
+            SignatureAlgorithm::MlDsa44 => 2420,
+            SignatureAlgorithm::MlDsa65 => 3309,
+            SignatureAlgorithm::MlDsa87 => 4627,
+            SignatureAlgorithm::Ed25519MlDsa65 => SIGNATURE_LENGTH + 3309,
+        }
+    }
+
+    /// Verify `signature` over `message`.
+    pub fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            SignatureAlgorithm::MlDsa44 => ml_dsa_verify::<MlDsa44>(public_key, message, signature),
+            SignatureAlgorithm::MlDsa65 => ml_dsa_verify::<MlDsa65>(public_key, message, signature),
+            SignatureAlgorithm::MlDsa87 => ml_dsa_verify::<MlDsa87>(public_key, message, signature),
+            SignatureAlgorithm::Ed25519MlDsa65 => {
+                if public_key.len() < PUBLIC_KEY_LENGTH || signature.len() < SIGNATURE_LENGTH {
+                    anyhow::bail!("hybrid public key or signature too short");
+                }
+                let (ed_public_key, ml_public_key) = public_key.split_at(PUBLIC_KEY_LENGTH);
+                let (ed_signature, ml_signature) = signature.split_at(SIGNATURE_LENGTH);
+                ed25519_verify(ed_public_key, message, ed_signature)?;
+                ml_dsa_verify::<MlDsa65>(ml_public_key, message, ml_signature)
+            }
+        }
+    }
+}
+
+/// Symmetric cipher used to encrypt mutation data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[repr(u8)]
+pub enum CipherAlgorithm {
+    #[default]
+    ChaCha20 = 0,
+}
+
+impl CipherAlgorithm {
+    /// Encrypt or decrypt data in place.
+    pub fn apply_keystream(&self, key: &[u8; 32], data: &mut [u8]) {
+        match self {
+            CipherAlgorithm::ChaCha20 => {
+                let mut chacha = ChaCha20::new(
+                    key.into(),
+                    // we can safely choose 0 as the nonce because the encryption key is salted
+                    // with a strong random value preventing any encryption key from being used
+                    // twice.
+                    vec![0_u8; 12].as_slice().into(),
+                );
+                chacha.apply_keystream(data);
+            }
+        }
+    }
+}
+
+/// Signing keys for a cloud, derived once from the 32 byte cloud key. Key material is zeroized
+/// on drop.
+pub struct CloudSigner {
+    keys: SigningKeys,
+    public_key: Vec<u8>,
+}
+
+enum SigningKeys {
+    MlDsa44(KeyPair<MlDsa44>),
+    MlDsa65(KeyPair<MlDsa65>),
+    MlDsa87(KeyPair<MlDsa87>),
+    Ed25519MlDsa65(ed25519_dalek::SigningKey, KeyPair<MlDsa65>),
+}
+
+impl CloudSigner {
+    pub fn from_seed(seed: &[u8; 32], algorithm: SignatureAlgorithm) -> Self {
+        let keys = match algorithm {
+            SignatureAlgorithm::MlDsa44 => SigningKeys::MlDsa44(ml_dsa_key_pair(seed)),
+            SignatureAlgorithm::MlDsa65 => SigningKeys::MlDsa65(ml_dsa_key_pair(seed)),
+            SignatureAlgorithm::MlDsa87 => SigningKeys::MlDsa87(ml_dsa_key_pair(seed)),
+            SignatureAlgorithm::Ed25519MlDsa65 => {
+                let ed_seed = Zeroizing::new(ed25519_seed(seed));
+                SigningKeys::Ed25519MlDsa65(
+                    ed25519_dalek::SigningKey::from_bytes(&ed_seed),
+                    ml_dsa_key_pair(seed),
+                )
+            }
+        };
+        let public_key = match &keys {
+            SigningKeys::MlDsa44(key_pair) => key_pair.verifying_key().encode().to_vec(),
+            SigningKeys::MlDsa65(key_pair) => key_pair.verifying_key().encode().to_vec(),
+            SigningKeys::MlDsa87(key_pair) => key_pair.verifying_key().encode().to_vec(),
+            SigningKeys::Ed25519MlDsa65(ed_key, key_pair) => {
+                let mut public_key = ed_key.verifying_key().to_bytes().to_vec();
+                public_key.extend(key_pair.verifying_key().encode().to_vec());
+                public_key
+            }
+        };
+        Self { keys, public_key }
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self.keys {
+            SigningKeys::MlDsa44(_) => SignatureAlgorithm::MlDsa44,
+            SigningKeys::MlDsa65(_) => SignatureAlgorithm::MlDsa65,
+            SigningKeys::MlDsa87(_) => SignatureAlgorithm::MlDsa87,
+            SigningKeys::Ed25519MlDsa65(_, _) => SignatureAlgorithm::Ed25519MlDsa65,
+        }
+    }
+
+    pub fn public_key(&self) -> &Vec<u8> {
+        &self.public_key
+    }
+
+    /// The cloud id, `H(public_key)`.
+    pub fn id(&self) -> [u8; 32] {
+        blake3::hash(&self.public_key).into()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match &self.keys {
+            SigningKeys::MlDsa44(key_pair) => key_pair.sign(message).encode().to_vec(),
+            SigningKeys::MlDsa65(key_pair) => key_pair.sign(message).encode().to_vec(),
+            SigningKeys::MlDsa87(key_pair) => key_pair.sign(message).encode().to_vec(),
+            SigningKeys::Ed25519MlDsa65(ed_key, key_pair) => {
+                let mut signature = ed_key.sign(message).to_bytes().to_vec();
+                signature.extend(key_pair.sign(message).encode().to_vec());
+                signature
+            }
+        }
+    }
+}
+
+/// The Ed25519 half of a hybrid key is derived separately so the two keys are independent.
+fn ed25519_seed(seed: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key("btk hybrid ed25519 seed", seed)
+}
+
+fn ed25519_verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = <[u8; PUBLIC_KEY_LENGTH]>::try_from(public_key)?;
+    let vk = ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+        .map_err(|err| anyhow::anyhow!("invalid ed25519 public key: {:?}", err))?;
+    let sig = ed25519_dalek::Signature::from_slice(signature)
+        .map_err(|err| anyhow::anyhow!("failed to parse ed25519 signature: {:?}", err))?;
+    vk.verify_strict(message, &sig)
+        .map_err(|err| anyhow::anyhow!("ed25519 signature verification failed: {:?}", err))
+}
+
+fn ml_dsa_key_pair<P: MlDsaParams>(seed: &[u8; 32]) -> KeyPair<P> {
+    P::key_gen_internal(&(*seed).into())
+}
+
+fn ml_dsa_verify<P: MlDsaParams>(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let encoded_vk = EncodedVerifyingKey::<P>::try_from(public_key)?;
+    let vk = VerifyingKey::<P>::decode(&encoded_vk);
+
+    let sig_bytes = EncodedSignature::<P>::try_from(signature)?;
+    let sig = Signature::<P>::decode(&sig_bytes);
+    if sig.is_none() {
+        anyhow::bail!("failed to parse signature");
+    }
+    let sig = sig.unwrap();
+
+    vk.verify(message, &sig)
+        .map_err(|err| anyhow::anyhow!("signature verification failed: {:?}", err))?;
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! Machine readable description of the http api, served at `GET /api`. Schemas are generated
+//! from the wire types and describe the JSON encoding.
+
+use schemars::SchemaGenerator;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::json;
+
+use super::*;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Endpoint {
+    pub method: &'static str,
+    pub path: &'static str,
+    /// Query parameters. Ids are hex encoded.
+    pub query: &'static [&'static str],
+    /// Schema name of the request body.
+    pub request: Option<&'static str>,
+    /// Schema name of a successful response body.
+    pub response: Option<&'static str>,
+    pub description: &'static str,
+}
+
+pub const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "GET",
+        path: "/state",
+        query: &["cloud_id", "after", "wait"],
+        request: None,
+        response: Some("u64"),
+        description: "Number of mutations in the cloud. With `after`, waits up to `wait` seconds for more than `after` mutations.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/mutation",
+        query: &["cloud_id", "index"],
+        request: None,
+        response: Some("Mutation"),
+        description: "The mutation at `index`, 424 if it doesn't exist yet.",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/mutate",
+        query: &["cloud_id"],
+        request: Some("Mutation"),
+        response: None,
+        description: "Append the next mutation to a cloud. Mutation 0 creates the cloud.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/policy",
+        query: &[],
+        request: None,
+        response: Some("CreationPolicy"),
+        description: "What must be attached to mutation 0 to create a cloud.",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/publish",
+        query: &["cloud_id"],
+        request: Some("PublishRequest"),
+        response: Some("u64"),
+        description: "Reveal mutation keys, making the mutations public. Returns the number of public mutations.",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/delete",
+        query: &["cloud_id"],
+        request: Some("DeleteRequest"),
+        response: None,
+        description: "Permanently delete a cloud.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/public/state",
+        query: &["cloud_id"],
+        request: None,
+        response: Some("u64"),
+        description: "Number of public mutations.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/public/mutation",
+        query: &["cloud_id", "index"],
+        request: None,
+        response: Some("Mutation"),
+        description: "A public mutation including its mutation key.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/mailbox/key",
+        query: &["recipient_id"],
+        request: None,
+        response: Some("ByteString"),
+        description: "The encapsulation key published for a mailbox.",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/mailbox/key",
+        query: &["recipient_id"],
+        request: Some("ByteString"),
+        response: None,
+        description: "Publish the encapsulation key for a mailbox.",
+    },
+    Endpoint {
+        method: "POST",
+        path: "/mailbox",
+        query: &["recipient_id"],
+        request: Some("MailboxMessage"),
+        response: Some("u64"),
+        description: "Append a message to a mailbox. Returns the message index.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/mailbox",
+        query: &["recipient_id", "after"],
+        request: None,
+        response: Some("Vec<MailboxEntry>"),
+        description: "Unexpired messages with an index of at least `after`.",
+    },
+    Endpoint {
+        method: "GET",
+        path: "/api",
+        query: &[],
+        request: None,
+        response: None,
+        description: "This document.",
+    },
+];
+
+/// The endpoints, the schemas of their bodies, and the websocket messages.
+pub fn description() -> Value {
+    let mut generator = SchemaGenerator::default();
+    let mut schemas = serde_json::Map::default();
+    let mut add = |name: &str, schema: schemars::Schema| {
+        schemas.insert(name.to_string(), schema.to_value());
+    };
+    add("u64", generator.subschema_for::<u64>());
+    add("Mutation", generator.subschema_for::<Mutation>());
+    add(
+        "CreationPolicy",
+        generator.subschema_for::<CreationPolicy>(),
+    );
+    add(
+        "PublishRequest",
+        generator.subschema_for::<PublishRequest>(),
+    );
+    add("DeleteRequest", generator.subschema_for::<DeleteRequest>());
+    add("ByteString", generator.subschema_for::<ByteString>());
+    add(
+        "MailboxMessage",
+        generator.subschema_for::<MailboxMessage>(),
+    );
+    add(
+        "Vec<MailboxEntry>",
+        generator.subschema_for::<Vec<MailboxEntry>>(),
+    );
+    add("Hello", generator.subschema_for::<Hello>());
+    add("Action", generator.subschema_for::<Action>());
+    add("Response", generator.subschema_for::<Response>());
+    json!({
+        "encodings": {
+            "default": "bincode",
+            "json": "Send `Accept: application/json` for JSON responses and `Content-Type: application/json` for JSON request bodies. Fixed length byte fields are hex strings, variable length byte fields are base64 strings.",
+        },
+        "protocol": {
+            "version": PROTOCOL_VERSION,
+            "min_version": MIN_PROTOCOL_VERSION,
+            "header": PROTOCOL_HEADER,
+            "features": FEATURES,
+            "http": "Send the version in the header, servers respond 426 to versions they no longer accept.",
+        },
+        "endpoints": ENDPOINTS,
+        "websocket": {
+            "query": ["cloud_id"],
+            "hello": "The first client message is `Action::Hello`, answered with `Response::Hello` or `Response::Incompatible`. The server's `Hello` carries the `nonce` that `Action::AuthCloud` signs.",
+            "client_messages": "Action",
+            "server_messages": "Response",
+            "encoding": "bincode",
+        },
+        "schemas": schemas,
+        "$defs": generator.definitions(),
+    })
+}
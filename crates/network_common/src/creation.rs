@@ -0,0 +1,142 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Highest proof of work difficulty a server may require, in leading zero bits.
+pub const MAX_POW_DIFFICULTY: u8 = 64;
+
+/// Decides who may create new clouds on a server. Served at `GET /policy` so clients know what
+/// to attach to the genesis mutation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum CreationPolicy {
+    /// Anyone may create a cloud.
+    #[default]
+    Open,
+    /// The genesis mutation must include a nonce such that `H(cloud_id, nonce)` has `difficulty`
+    /// leading zero bits.
+    ProofOfWork { difficulty: u8 },
+    /// The genesis mutation must include an unused token issued by the operator.
+    InviteToken,
+}
+
+/// Parsed from operator configuration: `open`, `pow:<difficulty>`, or `invite`.
+impl FromStr for CreationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "open" => Ok(CreationPolicy::Open),
+            "invite" => Ok(CreationPolicy::InviteToken),
+            s => {
+                let difficulty = s
+                    .strip_prefix("pow:")
+                    .ok_or(anyhow::anyhow!("unknown creation policy: {s}"))?
+                    .parse::<u8>()?;
+                if difficulty > MAX_POW_DIFFICULTY {
+                    anyhow::bail!("proof of work difficulty must be <= {MAX_POW_DIFFICULTY}");
+                }
+                Ok(CreationPolicy::ProofOfWork { difficulty })
+            }
+        }
+    }
+}
+
+impl CreationPolicy {
+    /// Check the proof attached to a genesis mutation. Invite tokens are only checked for
+    /// presence, the server must verify the token was issued and has not been used.
+    pub fn verify(&self, cloud_id: &[u8; 32], proof: Option<&CreationProof>) -> Result<()> {
+        match (self, proof) {
+            (CreationPolicy::Open, _) => Ok(()),
+            (
+                CreationPolicy::ProofOfWork { difficulty },
+                Some(CreationProof::ProofOfWork { nonce }),
+            ) => {
+                let hash = CreationProof::pow_hash(cloud_id, *nonce);
+                if leading_zero_bits(&hash) < u32::from(*difficulty) {
+                    anyhow::bail!("insufficient proof of work");
+                }
+                Ok(())
+            }
+            (CreationPolicy::InviteToken, Some(CreationProof::InviteToken(_))) => Ok(()),
+            _ => anyhow::bail!("creation proof does not match server policy"),
+        }
+    }
+}
+
+/// Attached to the genesis mutation to satisfy a server's `CreationPolicy`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum CreationProof {
+    ProofOfWork {
+        nonce: u64,
+    },
+    InviteToken(
+        #[serde(with = "crate::hex_string")]
+        #[schemars(with = "String")]
+        [u8; 32],
+    ),
+    /// Ed25519 signature by a private server operator over `enrollment_message(cloud_id)`.
+    /// Clouds created with a valid signature are added to the server's allowlist.
+    Enrollment(
+        #[serde(with = "crate::base64_string")]
+        #[schemars(with = "String")]
+        Vec<u8>,
+    ),
+}
+
+impl CreationProof {
+    /// The work is bound to the cloud id so a solution can't be reused for another cloud.
+    pub fn pow_hash(cloud_id: &[u8; 32], nonce: u64) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key("btk cloud creation proof of work");
+        hasher.update(cloud_id);
+        hasher.update(&nonce.to_le_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Search `nonces` for a solution to `difficulty`. Expect `2^difficulty` hashes in total,
+    /// callers searching on a UI thread should search in small ranges and yield in between.
+    pub fn search_pow(cloud_id: &[u8; 32], difficulty: u8, nonces: Range<u64>) -> Option<Self> {
+        nonces
+            .into_iter()
+            .find(|nonce| {
+                leading_zero_bits(&Self::pow_hash(cloud_id, *nonce)) >= u32::from(difficulty)
+            })
+            .map(|nonce| CreationProof::ProofOfWork { nonce })
+    }
+
+    pub fn enrollment_message(cloud_id: &[u8; 32]) -> Vec<u8> {
+        [b"btk enrollment".as_slice(), cloud_id.as_slice()].concat()
+    }
+
+    /// Verify an enrollment signature for `cloud_id` against the operator's Ed25519 public key.
+    pub fn verify_enrollment(&self, operator_key: &[u8; 32], cloud_id: &[u8; 32]) -> Result<()> {
+        let CreationProof::Enrollment(signature) = self else {
+            anyhow::bail!("creation proof is not an enrollment");
+        };
+        let vk = ed25519_dalek::VerifyingKey::from_bytes(operator_key)
+            .map_err(|err| anyhow::anyhow!("invalid enrollment public key: {:?}", err))?;
+        let sig = ed25519_dalek::Signature::from_slice(signature)
+            .map_err(|err| anyhow::anyhow!("failed to parse enrollment signature: {:?}", err))?;
+        vk.verify_strict(&Self::enrollment_message(cloud_id), &sig)
+            .map_err(|err| anyhow::anyhow!("enrollment verification failed: {:?}", err))
+    }
+
+    /// Identifier stored by servers once a token is used, so the token itself is never written.
+    pub fn token_hash(token: &[u8; 32]) -> [u8; 32] {
+        blake3::hash(token).into()
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut out = 0;
+    for byte in bytes {
+        out += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    out
+}
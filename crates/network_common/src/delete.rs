@@ -0,0 +1,47 @@
+use anondb::Bytes;
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::PublicKeyRecord;
+
+/// Permanently delete a cloud from a server. The server removes every mutation and the public
+/// key record, and keeps a tombstone so the cloud id can't be created again.
+///
+/// Signed by the cloud key so only keyholders may delete. The signature covers the host of the
+/// server so a request can't be replayed against another server holding the same cloud.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DeleteRequest {
+    #[serde(with = "crate::hex_string")]
+    #[schemars(with = "String")]
+    pub cloud_id: [u8; 32],
+    /// `Host` the request is sent to, including the port if it isn't the default.
+    pub host: String,
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
+    pub signature: Vec<u8>,
+}
+
+impl DeleteRequest {
+    /// Bytes that must be signed to delete `cloud_id` from the server at `host`.
+    pub fn signed_bytes(cloud_id: &[u8; 32], host: &str) -> Result<Vec<u8>> {
+        Ok(Bytes::encode(&("btk delete", cloud_id, host.to_ascii_lowercase()))?.to_vec())
+    }
+
+    /// Verify the request was made for the server receiving it, the public key matches the
+    /// cloud id and the request is signed. `host` is the `Host` header of the request.
+    pub fn verify(&self, host: Option<&str>, record: &PublicKeyRecord) -> Result<()> {
+        if !host.is_some_and(|host| host.eq_ignore_ascii_case(&self.host)) {
+            anyhow::bail!("delete request was signed for {}", self.host);
+        }
+        let pubkey_hash: [u8; 32] = blake3::hash(&record.public_key).into();
+        if pubkey_hash != self.cloud_id {
+            anyhow::bail!("public key hash mismatch");
+        }
+        let message = Self::signed_bytes(&self.cloud_id, &self.host)?;
+        record
+            .signature_algorithm
+            .verify(&record.public_key, &message, &self.signature)
+    }
+}
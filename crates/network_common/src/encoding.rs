@@ -0,0 +1,213 @@
+use anondb::Bytes;
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Body encoding for http requests and responses. Bincode unless the client asks for JSON with
+/// the `Accept` header (responses) or `Content-Type` header (request bodies).
+///
+/// In JSON, fixed length byte fields (ids, hashes, keys) are hex strings and variable length byte
+/// fields (ciphertexts, signatures) are base64 strings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl Encoding {
+    /// Negotiate the response encoding from an `Accept` header.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if Self::lists_json(accept) => Encoding::Json,
+            _ => Encoding::Bincode,
+        }
+    }
+
+    /// Determine the request body encoding from a `Content-Type` header.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        Self::from_accept(content_type)
+    }
+
+    fn lists_json(header: &str) -> bool {
+        header.split(',').any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case(JSON_CONTENT_TYPE)
+        })
+    }
+
+    /// `None` for bincode, which has never been labelled.
+    pub fn content_type(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Bincode => None,
+            Encoding::Json => Some(JSON_CONTENT_TYPE),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Bincode => Ok(Bytes::encode(value)?.to_vec()),
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Bincode => Ok(Bytes::from(bytes).parse::<T>()?),
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+}
+
+/// Generates `serialize`/`deserialize` for a byte field that is a string in human readable
+/// formats. Binary formats use the field's own implementation so the wire format is unchanged.
+macro_rules! bytes_as_string {
+    ($encode:expr, $decode:expr) => {
+        use serde::Deserialize;
+        use serde::Deserializer;
+        use serde::Serialize;
+        use serde::Serializer;
+        use serde::de::Error;
+
+        pub fn serialize<S, T>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: AsRef<[u8]> + Serialize,
+        {
+            if serializer.is_human_readable() {
+                serializer.serialize_str(&$encode(bytes.as_ref()))
+            } else {
+                bytes.serialize(serializer)
+            }
+        }
+
+        pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: TryFrom<Vec<u8>> + Deserialize<'de>,
+        {
+            if deserializer.is_human_readable() {
+                let bytes = $decode(String::deserialize(deserializer)?.as_str())
+                    .map_err(D::Error::custom)?;
+                T::try_from(bytes).map_err(|_| D::Error::custom("invalid byte length"))
+            } else {
+                T::deserialize(deserializer)
+            }
+        }
+
+        pub mod option {
+            use super::*;
+
+            pub fn serialize<S, T>(bytes: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+                T: AsRef<[u8]> + Serialize,
+            {
+                if serializer.is_human_readable() {
+                    bytes
+                        .as_ref()
+                        .map(|bytes| $encode(bytes.as_ref()))
+                        .serialize(serializer)
+                } else {
+                    bytes.serialize(serializer)
+                }
+            }
+
+            pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+            where
+                D: Deserializer<'de>,
+                T: TryFrom<Vec<u8>> + Deserialize<'de>,
+            {
+                if deserializer.is_human_readable() {
+                    Option::<String>::deserialize(deserializer)?
+                        .map(|encoded| {
+                            let bytes = $decode(encoded.as_str()).map_err(D::Error::custom)?;
+                            T::try_from(bytes).map_err(|_| D::Error::custom("invalid byte length"))
+                        })
+                        .transpose()
+                } else {
+                    Option::<T>::deserialize(deserializer)
+                }
+            }
+        }
+    };
+}
+
+/// Hex string in JSON, for fixed length fields.
+pub mod hex_string {
+    bytes_as_string!(hex::encode, hex::decode);
+
+    /// `(index, bytes)` pairs, e.g. `PublishRequest::mutation_keys`.
+    pub mod indexed {
+        use super::*;
+
+        pub fn serialize<S, T>(pairs: &Vec<(u64, T)>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: AsRef<[u8]> + Serialize,
+        {
+            if serializer.is_human_readable() {
+                pairs
+                    .iter()
+                    .map(|(index, bytes)| (*index, hex::encode(bytes.as_ref())))
+                    .collect::<Vec<_>>()
+                    .serialize(serializer)
+            } else {
+                pairs.serialize(serializer)
+            }
+        }
+
+        pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<(u64, T)>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: TryFrom<Vec<u8>> + Deserialize<'de>,
+        {
+            if deserializer.is_human_readable() {
+                Vec::<(u64, String)>::deserialize(deserializer)?
+                    .into_iter()
+                    .map(|(index, encoded)| {
+                        let bytes = hex::decode(encoded).map_err(D::Error::custom)?;
+                        let bytes = T::try_from(bytes)
+                            .map_err(|_| D::Error::custom("invalid byte length"))?;
+                        Ok((index, bytes))
+                    })
+                    .collect()
+            } else {
+                Vec::<(u64, T)>::deserialize(deserializer)
+            }
+        }
+    }
+}
+
+/// Base64 string (standard alphabet, padded) in JSON, for variable length fields.
+pub mod base64_string {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    bytes_as_string!(encode, decode);
+
+    fn encode(bytes: &[u8]) -> String {
+        STANDARD.encode(bytes)
+    }
+
+    fn decode(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+        STANDARD.decode(encoded)
+    }
+}
+
+/// A variable length byte string that is the entire body, e.g. a mailbox encapsulation key.
+/// Encodes exactly like `Vec<u8>` in bincode.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ByteString(
+    #[serde(with = "base64_string")]
+    #[schemars(with = "String")]
+    pub Vec<u8>,
+);
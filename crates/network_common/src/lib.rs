@@ -1,11 +1,36 @@
+mod algorithm;
+pub mod api;
+mod creation;
+mod delete;
+mod encoding;
+mod mailbox;
 mod mutation;
+mod protocol;
+mod publish;
 
+pub use algorithm::CipherAlgorithm;
+pub use algorithm::CloudSigner;
+pub use algorithm::SignatureAlgorithm;
+pub use creation::CreationPolicy;
+pub use creation::CreationProof;
+pub use delete::DeleteRequest;
+pub use encoding::*;
+pub use mailbox::*;
 pub use mutation::Mutation;
+pub use mutation::PublicKeyRecord;
+pub use protocol::*;
+pub use publish::PublishRequest;
 
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// Longest a `GET /state?after=N&wait=S` request is held open waiting for a mutation after `N`.
+/// Larger `wait` values are clamped.
+pub const MAX_STATE_WAIT_SECS: u64 = 60;
+
+/// Bincode encodes the variant index, new variants must be appended.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[repr(u8)]
 pub enum Action {
     /// Register a mutation to an encrypted cloud.
@@ -14,13 +39,52 @@ pub enum Action {
     /// All clouds are implicitly initialized with 0 mutations (no data).
     MutateCloud(Mutation),
     /// Authenticate as a member of a cloud. Begin receiving `CloudMutated` responses.
-    /// `pubkey_hash, signature_bytes`
-    AuthCloud([u8; 32], Vec<u8>),
+    /// `pubkey_hash, signature_bytes`, signed over `Action::auth_cloud_signed_bytes` with the
+    /// nonce from the server's `Response::Hello`. Answered with `Response::Authenticated`.
+    AuthCloud(
+        #[serde(with = "hex_string")]
+        #[schemars(with = "String")]
+        [u8; 32],
+        #[serde(with = "base64_string")]
+        #[schemars(with = "String")]
+        Vec<u8>,
+    ),
     /// keepalive mechanism
     Ping,
+    /// Must be the first message on a connection. Answered with `Response::Hello`, or
+    /// `Response::Incompatible` before the server closes the connection.
+    Hello(Hello),
+}
+
+impl Action {
+    /// Bytes that must be signed by the cloud key to authenticate as a member of `cloud_id` on
+    /// the connection that was issued `nonce`.
+    pub fn auth_cloud_signed_bytes(
+        cloud_id: &[u8; 32],
+        nonce: &[u8; 32],
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(anondb::Bytes::encode(&("btk auth", cloud_id, nonce))?.to_vec())
+    }
+
+    /// Verify an `AuthCloud` signature using the key the cloud was created with.
+    pub fn verify_auth_cloud(
+        cloud_id: &[u8; 32],
+        nonce: &[u8; 32],
+        signature: &[u8],
+        record: &PublicKeyRecord,
+    ) -> anyhow::Result<()> {
+        let pubkey_hash: [u8; 32] = blake3::hash(&record.public_key).into();
+        if &pubkey_hash != cloud_id {
+            anyhow::bail!("public key hash mismatch");
+        }
+        let message = Self::auth_cloud_signed_bytes(cloud_id, nonce)?;
+        record
+            .signature_algorithm
+            .verify(&record.public_key, &message, signature)
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[repr(u8)]
 pub enum Response {
     /// `latest_known_index`
@@ -31,4 +95,8 @@ pub enum Response {
     CloudMutated(u64),
     /// keepalive mechanism
     Pong,
+    /// Only sent in reply to `Action::Hello`, older clients never receive it.
+    Hello(Hello),
+    /// The client's protocol version is older than the server accepts.
+    Incompatible { min_protocol_version: u32 },
 }
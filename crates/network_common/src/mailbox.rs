@@ -0,0 +1,51 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Largest message a server will accept into a mailbox.
+pub const MAILBOX_MESSAGE_MAX_LEN: usize = 16 * 1024;
+
+/// Compute the mailbox identifier for an encapsulation key. Anyone may publish a key, the server
+/// only checks that the key hashes to the recipient id it is published under.
+pub fn recipient_id(encapsulation_key: &[u8]) -> [u8; 32] {
+    blake3::hash(encapsulation_key).into()
+}
+
+/// A message sent anonymously to a recipient. The server can't read or authenticate the sender,
+/// it only stores the bytes.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MailboxMessage {
+    /// KEM ciphertext encapsulating a shared key for the recipient.
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
+    pub kem_ciphertext: Vec<u8>,
+    /// Payload encrypted with a key derived from the shared key.
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
+    pub ciphertext: Vec<u8>,
+    /// Keyed hash of `ciphertext`, using a key derived from the shared key.
+    #[serde(with = "crate::hex_string")]
+    #[schemars(with = "String")]
+    pub tag: [u8; 32],
+}
+
+impl MailboxMessage {
+    pub fn encoded_len(&self) -> usize {
+        self.kem_ciphertext.len() + self.ciphertext.len() + self.tag.len()
+    }
+}
+
+/// A message as stored in an append-only mailbox.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MailboxEntry {
+    pub index: u64,
+    /// Seconds since the unix epoch at which the server accepted the message.
+    pub created_at: u64,
+    pub message: MailboxMessage,
+}
+
+impl MailboxEntry {
+    pub fn is_expired(&self, now: u64, ttl_secs: u64) -> bool {
+        now.saturating_sub(self.created_at) > ttl_secs
+    }
+}
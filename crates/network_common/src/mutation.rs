@@ -1,76 +1,379 @@
 use anyhow::Result;
-use ml_dsa::EncodedSignature;
-use ml_dsa::EncodedVerifyingKey;
-use ml_dsa::MlDsa87;
-use ml_dsa::Signature;
-use ml_dsa::VerifyingKey;
-use ml_dsa::signature::Verifier;
+use schemars::JsonSchema;
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use serde::Serializer;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde::ser::SerializeTuple;
 
 use anondb::Bytes;
 
+use super::CipherAlgorithm;
+use super::CreationProof;
+use super::SignatureAlgorithm;
+
 /// Public data for a mutation to an encrypted cloud.
 /// Used to ensure consistency among synchronized devices.
 ///
 /// Data is encypted with key H(H(private_key), index, salt), and the encrypted bytes are signed.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+///
+/// Binary encodings are versioned, see `ENVELOPE_MARKER`. JSON is the plain struct.
+#[derive(Clone, Debug, JsonSchema)]
 pub struct Mutation {
     pub index: u64,
     /// Encrypted mutation/diff/action
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
     pub data: Vec<u8>,
-    /// Variable length signature, impl defined algo
+    /// Variable length signature using `signature_algorithm`
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
     pub signature: Vec<u8>,
-    /// 32 byte public key hash, impl defined algo
+    /// Must match the algorithm the cloud was created with.
+    pub signature_algorithm: SignatureAlgorithm,
+    /// Cipher used to encrypt `data`. Must match the cipher the cloud was created with.
+    pub cipher_algorithm: CipherAlgorithm,
+    /// 32 byte blake3 hash of the public key
+    #[serde(with = "crate::hex_string")]
+    #[schemars(with = "String")]
     pub public_key_hash: [u8; 32],
     /// Optional full public key. This must be provided if `index == 0` as the encrypted cloud is
     /// being created.
+    #[serde(with = "crate::base64_string::option")]
+    #[schemars(with = "Option<String>")]
     pub public_key: Option<Vec<u8>>,
     /// Salt used to compute a distinct encryption key for the mutation. This is necessary to
     /// prevent cases where two changes for the same index are created and broadcasted, but encrypted
     /// with the same key+nonce. Such a case would leak the key in most symmetric
     /// encryption constructions.
+    #[serde(with = "crate::hex_string")]
+    #[schemars(with = "String")]
     pub salt: [u8; 32],
     /// Optionally provide the encryption key for the mutation. Setting this value makes the
     /// mutation irreversibly public.
+    #[serde(with = "crate::hex_string::option")]
+    #[schemars(with = "Option<String>")]
     pub mutation_key: Option<[u8; 32]>,
+    /// Satisfies the server's `CreationPolicy`. Only read when `index == 0`.
+    pub creation_proof: Option<CreationProof>,
+}
+
+/// Written in place of the index at the start of versioned binary encodings. Mutations encoded
+/// before algorithms were recorded start with their index, which never reaches this.
+const ENVELOPE_MARKER: u64 = u64::MAX;
+/// Version of the fields following `ENVELOPE_MARKER`.
+const ENVELOPE_VERSION: u8 = 1;
+
+/// Field definitions for `Mutation`, used by the JSON encoding and inside the binary envelope.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Mutation")]
+struct MutationDef {
+    index: u64,
+    #[serde(with = "crate::base64_string")]
+    data: Vec<u8>,
+    #[serde(with = "crate::base64_string")]
+    signature: Vec<u8>,
+    signature_algorithm: SignatureAlgorithm,
+    cipher_algorithm: CipherAlgorithm,
+    #[serde(with = "crate::hex_string")]
+    public_key_hash: [u8; 32],
+    #[serde(with = "crate::base64_string::option")]
+    public_key: Option<Vec<u8>>,
+    #[serde(with = "crate::hex_string")]
+    salt: [u8; 32],
+    #[serde(with = "crate::hex_string::option")]
+    mutation_key: Option<[u8; 32]>,
+    creation_proof: Option<CreationProof>,
+}
+
+struct CurrentRef<'a>(&'a Mutation);
+
+impl Serialize for CurrentRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        MutationDef::serialize(self.0, serializer)
+    }
+}
+
+#[derive(Deserialize)]
+struct Current(#[serde(with = "MutationDef")] Mutation);
+
+/// Fields after the index of a mutation encoded before algorithms were recorded. These always
+/// used ML-DSA-87 and ChaCha20.
+#[derive(Serialize, Deserialize)]
+struct LegacyFields {
+    data: Vec<u8>,
+    signature: Vec<u8>,
+    public_key_hash: [u8; 32],
+    public_key: Option<Vec<u8>>,
+    salt: [u8; 32],
+    mutation_key: Option<[u8; 32]>,
+}
+
+impl Serialize for Mutation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return MutationDef::serialize(self, serializer);
+        }
+        let mut envelope = serializer.serialize_tuple(3)?;
+        envelope.serialize_element(&ENVELOPE_MARKER)?;
+        envelope.serialize_element(&ENVELOPE_VERSION)?;
+        envelope.serialize_element(&CurrentRef(self))?;
+        envelope.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Mutation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            return MutationDef::deserialize(deserializer);
+        }
+        // the number of elements depends on the first one, bincode only needs an upper bound
+        deserializer.deserialize_tuple(3, EnvelopeVisitor)
+    }
+}
+
+struct EnvelopeVisitor;
+
+impl<'de> Visitor<'de> for EnvelopeVisitor {
+    type Value = Mutation;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a versioned or legacy mutation")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Mutation, A::Error> {
+        use serde::de::Error;
+
+        let first = seq
+            .next_element::<u64>()?
+            .ok_or(Error::invalid_length(0, &self))?;
+        if first != ENVELOPE_MARKER {
+            let legacy = seq
+                .next_element::<LegacyFields>()?
+                .ok_or(Error::invalid_length(1, &self))?;
+            return Ok(Mutation {
+                index: first,
+                data: legacy.data,
+                signature: legacy.signature,
+                signature_algorithm: SignatureAlgorithm::MlDsa87,
+                cipher_algorithm: CipherAlgorithm::ChaCha20,
+                public_key_hash: legacy.public_key_hash,
+                public_key: legacy.public_key,
+                salt: legacy.salt,
+                mutation_key: legacy.mutation_key,
+                creation_proof: None,
+            });
+        }
+        let version = seq
+            .next_element::<u8>()?
+            .ok_or(Error::invalid_length(1, &self))?;
+        if version != ENVELOPE_VERSION {
+            return Err(Error::custom(format!(
+                "unknown mutation encoding version {version}"
+            )));
+        }
+        let Current(mutation) = seq
+            .next_element::<Current>()?
+            .ok_or(Error::invalid_length(2, &self))?;
+        Ok(mutation)
+    }
 }
 
 impl Mutation {
+    /// Excludes the mutation key and creation proof, which may change without affecting the
+    /// contents of the mutation. ML-DSA-87/ChaCha20 mutations hash the legacy encoding so their
+    /// hashes are unchanged.
     pub fn hash(&self) -> Result<[u8; 32]> {
-        let mut to_hash = self.clone();
-        // we want to exclude the mutation key from the hash. This value may change without
-        // affecting the contents of the mutation.
-        to_hash.mutation_key = None;
+        let bytes = if self.signature_algorithm == SignatureAlgorithm::MlDsa87
+            && self.cipher_algorithm == CipherAlgorithm::ChaCha20
+        {
+            let legacy = LegacyFields {
+                data: self.data.clone(),
+                signature: self.signature.clone(),
+                public_key_hash: self.public_key_hash,
+                public_key: self.public_key.clone(),
+                salt: self.salt,
+                mutation_key: None,
+            };
+            Bytes::encode(&(self.index, legacy))?
+        } else {
+            let mut to_hash = self.clone();
+            to_hash.mutation_key = None;
+            to_hash.creation_proof = None;
+            Bytes::encode(&to_hash)?
+        };
+        Ok(blake3::hash(bytes.as_slice()).into())
+    }
 
-        Ok(blake3::hash(Bytes::encode(&to_hash)?.as_slice()).into())
+    /// Compute the encryption key for the mutation at `index`.
+    pub fn derive_key(private_key: &[u8; 32], index: u64, salt: &[u8; 32]) -> Result<[u8; 32]> {
+        let mutation_key_preimage = Bytes::encode(&(private_key, index, salt))?;
+        Ok(blake3::hash(mutation_key_preimage.as_slice()).into())
     }
 
-    /// Verify that the public_key_hash is correct. Verify that public_key is correct, if present.
-    /// Verify the signature.
-    pub fn verify(&self, public_key: Vec<u8>) -> Result<()> {
-        let pubkey_hash: [u8; 32] = blake3::hash(&public_key).into();
+    /// Decrypt the mutation data using the revealed `mutation_key`, if the mutation is public.
+    pub fn decrypt_public(&self) -> Option<Vec<u8>> {
+        let mutation_key = self.mutation_key.as_ref()?;
+        let mut data = self.data.clone();
+        self.cipher_algorithm
+            .apply_keystream(mutation_key, &mut data);
+        Some(data)
+    }
+
+    /// Verify a mutation served by a public cloud endpoint and decrypt it with the revealed key.
+    /// `record` must come from the genesis mutation of `cloud_id`, see
+    /// `PublicKeyRecord::from_genesis`.
+    pub fn verify_public(&self, cloud_id: &[u8; 32], record: &PublicKeyRecord) -> Result<Vec<u8>> {
+        if &self.public_key_hash != cloud_id {
+            anyhow::bail!("mutation belongs to a different cloud");
+        }
+        self.verify(record)?;
+        self.decrypt_public()
+            .ok_or(anyhow::anyhow!("mutation {} is not public", self.index))
+    }
+
+    /// Verify the algorithms match the record. Verify that the public_key_hash is correct.
+    /// Verify that public_key is correct, if present. Verify the signature.
+    pub fn verify(&self, record: &PublicKeyRecord) -> Result<()> {
+        if self.signature_algorithm != record.signature_algorithm {
+            anyhow::bail!(
+                "signature algorithm mismatch, cloud uses {}",
+                record.signature_algorithm.name()
+            );
+        }
+        if self.cipher_algorithm != record.cipher_algorithm {
+            anyhow::bail!("cipher algorithm mismatch");
+        }
+        let pubkey_hash: [u8; 32] = blake3::hash(&record.public_key).into();
         if pubkey_hash != self.public_key_hash {
             anyhow::bail!("public key hash mismatch");
         }
         if let Some(pubkey) = &self.public_key {
-            if pubkey != &public_key {
+            if pubkey != &record.public_key {
                 anyhow::bail!("mismatched public keys");
             }
         }
-        let encoded_vk = EncodedVerifyingKey::<MlDsa87>::try_from(public_key.as_slice())?;
-        let vk = VerifyingKey::<MlDsa87>::decode(&encoded_vk);
+        self.signature_algorithm
+            .verify(&record.public_key, &self.data, &self.signature)
+    }
+}
+
+/// Stored by servers when a cloud is created. All later mutations must use the same algorithms.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PublicKeyRecord {
+    pub signature_algorithm: SignatureAlgorithm,
+    pub cipher_algorithm: CipherAlgorithm,
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
+    pub public_key: Vec<u8>,
+}
+
+impl PublicKeyRecord {
+    /// Build the record for a cloud from the mutation that creates it.
+    pub fn from_genesis(mutation: &Mutation) -> Result<Self> {
+        if mutation.index != 0 {
+            anyhow::bail!("public key records are created by mutation 0");
+        }
+        let public_key = mutation
+            .public_key
+            .clone()
+            .ok_or(anyhow::anyhow!("mutation 0 must include the public key"))?;
+        Ok(Self {
+            signature_algorithm: mutation.signature_algorithm,
+            cipher_algorithm: mutation.cipher_algorithm,
+            public_key,
+        })
+    }
+
+    /// Records written before algorithms were recorded only stored the public key, and always
+    /// used ML-DSA-87 and ChaCha20.
+    pub fn legacy(public_key: Vec<u8>) -> Self {
+        Self {
+            signature_algorithm: SignatureAlgorithm::MlDsa87,
+            cipher_algorithm: CipherAlgorithm::ChaCha20,
+            public_key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let sig_bytes = EncodedSignature::<MlDsa87>::try_from(self.signature.as_slice())?;
-        let sig = Signature::<MlDsa87>::decode(&sig_bytes);
-        if sig.is_none() {
-            anyhow::bail!("failed to parse signature");
+    /// Layout of `Mutation` before algorithms and creation proofs were added.
+    #[derive(Serialize)]
+    struct BaselineMutation {
+        index: u64,
+        data: Vec<u8>,
+        signature: Vec<u8>,
+        public_key_hash: [u8; 32],
+        public_key: Option<Vec<u8>>,
+        salt: [u8; 32],
+        mutation_key: Option<[u8; 32]>,
+    }
+
+    fn baseline() -> BaselineMutation {
+        BaselineMutation {
+            index: 3,
+            data: vec![1, 2, 3, 4],
+            signature: vec![5; 64],
+            public_key_hash: [6; 32],
+            public_key: Some(vec![7; 16]),
+            salt: [8; 32],
+            mutation_key: Some([9; 32]),
         }
-        let sig = sig.unwrap();
+    }
 
-        vk.verify(self.data.as_slice(), &sig)
-            .map_err(|err| anyhow::anyhow!("signature verification failed: {:?}", err))?;
+    #[test]
+    fn decodes_baseline_encoding() -> Result<()> {
+        let bytes = Bytes::encode(&baseline())?;
+        let mutation = bytes.parse::<Mutation>()?;
+        assert_eq!(mutation.index, 3);
+        assert_eq!(mutation.data, vec![1, 2, 3, 4]);
+        assert_eq!(mutation.signature, vec![5; 64]);
+        assert_eq!(mutation.public_key_hash, [6; 32]);
+        assert_eq!(mutation.public_key, Some(vec![7; 16]));
+        assert_eq!(mutation.salt, [8; 32]);
+        assert_eq!(mutation.mutation_key, Some([9; 32]));
+        assert_eq!(mutation.signature_algorithm, SignatureAlgorithm::MlDsa87);
+        assert_eq!(mutation.cipher_algorithm, CipherAlgorithm::ChaCha20);
+        assert!(mutation.creation_proof.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn hash_matches_baseline() -> Result<()> {
+        let mutation = Bytes::encode(&baseline())?.parse::<Mutation>()?;
+        let mut unkeyed = baseline();
+        unkeyed.mutation_key = None;
+        let expected: [u8; 32] = blake3::hash(Bytes::encode(&unkeyed)?.as_slice()).into();
+        assert_eq!(mutation.hash()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_envelope() -> Result<()> {
+        let mut mutation = Bytes::encode(&baseline())?.parse::<Mutation>()?;
+        mutation.signature_algorithm = SignatureAlgorithm::MlDsa44;
+        mutation.creation_proof = Some(CreationProof::InviteToken([10; 32]));
+        let decoded = Bytes::encode(&mutation)?.parse::<Mutation>()?;
+        assert_eq!(decoded.index, mutation.index);
+        assert_eq!(decoded.data, mutation.data);
+        assert_eq!(decoded.signature_algorithm, SignatureAlgorithm::MlDsa44);
+        assert_eq!(decoded.mutation_key, mutation.mutation_key);
+        assert!(matches!(
+            decoded.creation_proof,
+            Some(CreationProof::InviteToken(token)) if token == [10; 32]
+        ));
+        Ok(())
+    }
 
+    #[test]
+    fn rejects_unknown_version() -> Result<()> {
+        let bytes = Bytes::encode(&(ENVELOPE_MARKER, ENVELOPE_VERSION + 1, 0_u64))?;
+        assert!(bytes.parse::<Mutation>().is_err());
         Ok(())
     }
 }
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Version of the wire format: `Action`, `Response`, `Mutation` and the http api. Increment when
+/// a change would fail to parse on a peer that doesn't know about it, e.g. a new `Response`
+/// variant.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version accepted from a peer. Peers written before versioning never send a
+/// version and are treated as version 0. They can't parse versioned mutations.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Carries the protocol version on http requests and responses.
+pub const PROTOCOL_HEADER: &str = "X-BTK-Protocol";
+
+/// Optional capabilities announced in `Hello`, for changes that don't need a version bump.
+pub const FEATURES: &[&str] = &["json", "long-poll", "publish", "delete", "mailbox"];
+
+/// First message on a websocket connection, sent by the client and answered by the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub features: Vec<String>,
+    /// Random per-connection challenge, only set by the server. `Action::AuthCloud` signs over
+    /// it so a captured signature can't be replayed on another connection.
+    #[serde(default, with = "crate::hex_string::option")]
+    #[schemars(with = "Option<String>")]
+    pub nonce: Option<[u8; 32]>,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            nonce: None,
+        }
+    }
+
+    /// Server reply carrying the challenge for `Action::AuthCloud` on this connection.
+    pub fn reply(nonce: [u8; 32]) -> Self {
+        Self {
+            nonce: Some(nonce),
+            ..Self::current()
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|v| v == feature)
+    }
+}
+
+/// Parse the version from a `PROTOCOL_HEADER` value. Missing or unparsable headers are version 0.
+pub fn header_version(value: Option<&str>) -> u32 {
+    value
+        .and_then(|value| value.trim().parse::<u32>().ok())
+        .unwrap_or_default()
+}
+
+/// Check a client version against `MIN_PROTOCOL_VERSION`.
+pub fn check_client_version(protocol_version: u32) -> Result<()> {
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        anyhow::bail!(
+            "server requires protocol version {MIN_PROTOCOL_VERSION} or newer, client uses {protocol_version}"
+        );
+    }
+    Ok(())
+}
+
+/// Check a server version, from `Hello` or the `PROTOCOL_HEADER` of a response, against
+/// `MIN_PROTOCOL_VERSION`.
+pub fn check_server_version(protocol_version: u32) -> Result<()> {
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        anyhow::bail!(
+            "client requires protocol version {MIN_PROTOCOL_VERSION} or newer, server uses {protocol_version}"
+        );
+    }
+    Ok(())
+}
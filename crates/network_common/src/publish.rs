@@ -0,0 +1,47 @@
+use anondb::Bytes;
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::PublicKeyRecord;
+
+/// Reveal the encryption keys for a set of mutations. Once accepted by a server the mutations
+/// are irreversibly public, anyone may read them using only the cloud id.
+///
+/// Signed by the cloud key so only keyholders may publish.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PublishRequest {
+    #[serde(with = "crate::hex_string")]
+    #[schemars(with = "String")]
+    pub cloud_id: [u8; 32],
+    /// `(index, mutation_key)`
+    #[serde(with = "crate::hex_string::indexed")]
+    #[schemars(with = "Vec<(u64, String)>")]
+    pub mutation_keys: Vec<(u64, [u8; 32])>,
+    #[serde(with = "crate::base64_string")]
+    #[schemars(with = "String")]
+    pub signature: Vec<u8>,
+}
+
+impl PublishRequest {
+    /// Bytes that must be signed to publish `mutation_keys`.
+    pub fn signed_bytes(
+        cloud_id: &[u8; 32],
+        mutation_keys: &Vec<(u64, [u8; 32])>,
+    ) -> Result<Vec<u8>> {
+        Ok(Bytes::encode(&("btk publish", cloud_id, mutation_keys))?.to_vec())
+    }
+
+    /// Verify the public key matches the cloud id and the request is signed.
+    pub fn verify(&self, record: &PublicKeyRecord) -> Result<()> {
+        let pubkey_hash: [u8; 32] = blake3::hash(&record.public_key).into();
+        if pubkey_hash != self.cloud_id {
+            anyhow::bail!("public key hash mismatch");
+        }
+        let message = Self::signed_bytes(&self.cloud_id, &self.mutation_keys)?;
+        record
+            .signature_algorithm
+            .verify(&record.public_key, &message, &self.signature)
+    }
+}